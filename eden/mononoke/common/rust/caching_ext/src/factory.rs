@@ -7,6 +7,7 @@
 
 use abomonation::Abomonation;
 use cachelib::VolatileLruCachePool;
+#[cfg(feature = "memcache")]
 use memcache::MemcacheClient;
 
 use crate::CachelibHandler;
@@ -14,7 +15,9 @@ use crate::MemcacheHandler;
 
 /// Builder to construct caches, depending on the desired caching mode.
 pub enum CacheHandlerFactory {
-    /// Caching is via a local cache (cachelib) and a shared cache (memcache).
+    /// Caching is via a local cache (cachelib) and a shared cache (memcache). Only available
+    /// with the `memcache` feature.
+    #[cfg(feature = "memcache")]
     Shared {
         /// The cachelib pool to use for local caching.
         cachelib_pool: VolatileLruCachePool,
@@ -41,9 +44,9 @@ impl CacheHandlerFactory {
         T: Abomonation + Send + Clone + 'static,
     {
         match self {
-            Self::Shared { cachelib_pool, .. } | Self::Local { cachelib_pool, .. } => {
-                cachelib_pool.clone().into()
-            }
+            #[cfg(feature = "memcache")]
+            Self::Shared { cachelib_pool, .. } => cachelib_pool.clone().into(),
+            Self::Local { cachelib_pool, .. } => cachelib_pool.clone().into(),
             Self::Mocked => CachelibHandler::create_mock(),
             Self::Noop => CachelibHandler::create_noop(),
         }
@@ -52,6 +55,7 @@ impl CacheHandlerFactory {
     /// Build memcache cache handler.
     pub fn memcache(&self) -> MemcacheHandler {
         match self {
+            #[cfg(feature = "memcache")]
             Self::Shared {
                 memcache_client, ..
             } => memcache_client.clone().into(),
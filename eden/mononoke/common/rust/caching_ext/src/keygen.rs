@@ -0,0 +1,47 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Stand-ins for the key generation and value size limit of the `memcache` crate, for builds
+//! without the `memcache` feature. Keys built here only ever reach the mock store, so they follow
+//! the same layout but aren't checked against memcache's key length limit.
+
+/// Largest value memcache stores, the same as `memcache::MEMCACHE_VALUE_MAX_SIZE`.
+pub const MEMCACHE_VALUE_MAX_SIZE: usize = 1024 * 1024;
+
+/// Builds keys out of a prefix, a code version and a site version, like `memcache::KeyGen`.
+#[derive(Clone, Debug)]
+pub struct KeyGen {
+    prefix: String,
+}
+
+impl KeyGen {
+    pub fn new<P: AsRef<[u8]>>(prefix: P, mc_codever: u32, mc_sitever: u32) -> Self {
+        let prefix = String::from_utf8_lossy(prefix.as_ref());
+        KeyGen {
+            prefix: format!("{}.{}.{}", prefix, mc_codever, mc_sitever),
+        }
+    }
+
+    pub fn key<K: AsRef<[u8]>>(&self, key: K) -> String {
+        format!("{}.{}", self.prefix, String::from_utf8_lossy(key.as_ref()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_key_versions() {
+        let keygen = KeyGen::new("scm.mononoke.test", 1, 2);
+        assert_eq!(keygen.key("key"), "scm.mononoke.test.1.2.key");
+        assert_ne!(
+            keygen.key("key"),
+            KeyGen::new("scm.mononoke.test", 1, 3).key("key")
+        );
+    }
+}
@@ -9,6 +9,8 @@
 
 mod cachelib_utils;
 mod factory;
+#[cfg(not(feature = "memcache"))]
+mod keygen;
 mod memcache_utils;
 mod mock_store;
 
@@ -28,14 +30,20 @@ use futures::stream;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
 use itertools::Itertools;
-use memcache::KeyGen;
-use memcache::MEMCACHE_VALUE_MAX_SIZE;
 use stats::prelude::*;
 
 pub use crate::cachelib_utils::CachelibHandler;
 pub use crate::factory::CacheHandlerFactory;
+#[cfg(not(feature = "memcache"))]
+pub use crate::keygen::KeyGen;
+#[cfg(not(feature = "memcache"))]
+pub use crate::keygen::MEMCACHE_VALUE_MAX_SIZE;
 pub use crate::memcache_utils::MemcacheHandler;
 pub use crate::mock_store::MockStoreStats;
+#[cfg(feature = "memcache")]
+pub use memcache::KeyGen;
+#[cfg(feature = "memcache")]
+pub use memcache::MEMCACHE_VALUE_MAX_SIZE;
 
 pub mod macro_reexport {
     pub use once_cell;
@@ -7,20 +7,24 @@
 
 use std::time::Duration;
 
+use anyhow::anyhow;
 use anyhow::Result;
 use bytes::Bytes;
+#[cfg(feature = "memcache")]
 use memcache::MemcacheClient;
-use memcache::MemcacheSetType;
 
 use crate::mock_store::MockStore;
 
 #[derive(Clone)]
 pub enum MemcacheHandler {
+    /// Only available with the `memcache` feature, without which no memcache client is linked.
+    #[cfg(feature = "memcache")]
     Real(MemcacheClient),
     Mock(MockStore<Bytes>),
     Noop,
 }
 
+#[cfg(feature = "memcache")]
 impl From<MemcacheClient> for MemcacheHandler {
     fn from(client: MemcacheClient) -> Self {
         MemcacheHandler::Real(client)
@@ -33,12 +37,15 @@ impl MemcacheHandler {
     pub fn is_noop(&self) -> bool {
         match self {
             MemcacheHandler::Noop => true,
-            MemcacheHandler::Real(_) | MemcacheHandler::Mock(_) => false,
+            #[cfg(feature = "memcache")]
+            MemcacheHandler::Real(_) => false,
+            MemcacheHandler::Mock(_) => false,
         }
     }
 
     pub fn is_async(&self) -> bool {
         match self {
+            #[cfg(feature = "memcache")]
             MemcacheHandler::Real(_) => true,
             MemcacheHandler::Mock(_) | MemcacheHandler::Noop => false,
         }
@@ -46,23 +53,34 @@ impl MemcacheHandler {
 
     pub async fn get(&self, key: String) -> Result<Option<Bytes>> {
         match self {
+            #[cfg(feature = "memcache")]
             MemcacheHandler::Real(ref client) => {
                 client.get(key).await.map(|value| value.map(Bytes::from))
             }
-            MemcacheHandler::Mock(store) => Ok(store.get(&key)),
+            MemcacheHandler::Mock(store) => {
+                if store.take_get_failure() {
+                    return Err(anyhow!("Injected failure getting {}", key));
+                }
+                if let Some(delay) = store.get_delay(&key) {
+                    tokio::time::sleep(delay).await;
+                }
+                Ok(store.get(&key))
+            }
             MemcacheHandler::Noop => Ok(None),
         }
     }
 
     pub async fn set<V>(&self, key: String, value: V) -> Result<()>
     where
-        MemcacheSetType: From<V>,
         Bytes: From<V>,
-        V: 'static,
     {
         match self {
-            MemcacheHandler::Real(ref client) => client.set(key, value).await,
+            #[cfg(feature = "memcache")]
+            MemcacheHandler::Real(ref client) => client.set(key, Bytes::from(value)).await,
             MemcacheHandler::Mock(store) => {
+                if store.take_set_failure() {
+                    return Err(anyhow!("Injected failure setting {}", key));
+                }
                 store.set(&key, value.into());
                 Ok(())
             }
@@ -72,15 +90,32 @@ impl MemcacheHandler {
 
     pub async fn set_with_ttl<V>(&self, key: String, value: V, duration: Duration) -> Result<()>
     where
-        MemcacheSetType: From<V>,
         Bytes: From<V>,
-        V: 'static,
     {
         match self {
-            MemcacheHandler::Real(ref client) => client.set_with_ttl(key, value, duration).await,
-            MemcacheHandler::Mock(_) => {
-                // For now we ignore TTLs here
-                self.set(key, value).await
+            #[cfg(feature = "memcache")]
+            MemcacheHandler::Real(ref client) => {
+                client.set_with_ttl(key, Bytes::from(value), duration).await
+            }
+            MemcacheHandler::Mock(store) => {
+                if store.take_set_failure() {
+                    return Err(anyhow!("Injected failure setting {}", key));
+                }
+                // TTLs are recorded, but values don't expire
+                store.set_with_ttl(&key, value.into(), duration);
+                Ok(())
+            }
+            MemcacheHandler::Noop => Ok(()),
+        }
+    }
+
+    pub async fn del(&self, key: String) -> Result<()> {
+        match self {
+            #[cfg(feature = "memcache")]
+            MemcacheHandler::Real(ref client) => client.del(key).await,
+            MemcacheHandler::Mock(store) => {
+                store.del(&key);
+                Ok(())
             }
             MemcacheHandler::Noop => Ok(()),
         }
@@ -98,7 +133,9 @@ impl MemcacheHandler {
     pub(crate) fn gets_count(&self) -> usize {
         use std::sync::atomic::Ordering;
         match self {
-            MemcacheHandler::Real(_) | MemcacheHandler::Noop => unimplemented!(),
+            #[cfg(feature = "memcache")]
+            MemcacheHandler::Real(_) => unimplemented!(),
+            MemcacheHandler::Noop => unimplemented!(),
             MemcacheHandler::Mock(MockStore { ref get_count, .. }) => {
                 get_count.load(Ordering::SeqCst)
             }
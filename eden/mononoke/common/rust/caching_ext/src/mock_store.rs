@@ -6,10 +6,12 @@
  */
 
 use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 
 #[derive(Debug, PartialEq)]
 pub struct MockStoreStats {
@@ -22,20 +24,30 @@ pub struct MockStoreStats {
 #[derive(Clone, Debug)]
 pub struct MockStore<T> {
     data: Arc<Mutex<HashMap<String, T>>>,
+    ttls: Arc<Mutex<HashMap<String, Duration>>>,
     pub(crate) set_count: Arc<AtomicUsize>,
     pub(crate) get_count: Arc<AtomicUsize>,
     pub(crate) hit_count: Arc<AtomicUsize>,
     pub(crate) miss_count: Arc<AtomicUsize>,
+    drop_sets: Arc<AtomicBool>,
+    failing_gets: Arc<AtomicUsize>,
+    failing_sets: Arc<AtomicUsize>,
+    get_delays: Arc<Mutex<HashMap<String, Duration>>>,
 }
 
 impl<T> MockStore<T> {
     pub(crate) fn new() -> Self {
         Self {
             data: Arc::new(Mutex::new(HashMap::new())),
+            ttls: Arc::new(Mutex::new(HashMap::new())),
             set_count: Arc::new(AtomicUsize::new(0)),
             get_count: Arc::new(AtomicUsize::new(0)),
             hit_count: Arc::new(AtomicUsize::new(0)),
             miss_count: Arc::new(AtomicUsize::new(0)),
+            drop_sets: Arc::new(AtomicBool::new(false)),
+            failing_gets: Arc::new(AtomicUsize::new(0)),
+            failing_sets: Arc::new(AtomicUsize::new(0)),
+            get_delays: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -47,6 +59,65 @@ impl<T> MockStore<T> {
             hits: self.hit_count.load(Ordering::SeqCst),
         }
     }
+
+    /// Make sets succeed without storing anything, like a misconfigured pool that silently drops
+    /// writes.
+    pub fn set_drop_sets(&self, drop_sets: bool) {
+        self.drop_sets.store(drop_sets, Ordering::SeqCst);
+    }
+
+    /// TTL the value under `key` was last set with, if it was set with one. Values are kept
+    /// regardless of their TTL.
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        self.ttls.lock().expect("poisoned lock").get(key).copied()
+    }
+
+    /// Make the next `count` gets fail, for handlers that can report errors.
+    pub fn fail_next_gets(&self, count: usize) {
+        self.failing_gets.store(count, Ordering::SeqCst);
+    }
+
+    /// Whether this get should fail, as requested by `fail_next_gets`.
+    pub(crate) fn take_get_failure(&self) -> bool {
+        self.failing_gets
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Make the next `count` sets fail, for handlers that can report errors. Failed sets aren't
+    /// counted.
+    pub fn fail_next_sets(&self, count: usize) {
+        self.failing_sets.store(count, Ordering::SeqCst);
+    }
+
+    /// Whether this set should fail, as requested by `fail_next_sets`.
+    pub(crate) fn take_set_failure(&self) -> bool {
+        self.failing_sets
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Make gets of `key` take `delay`, for handlers that can wait, e.g. to find out where the
+    /// time goes when some values are slower to read than others.
+    pub fn delay_gets(&self, key: &str, delay: Duration) {
+        self.get_delays
+            .lock()
+            .expect("poisoned lock")
+            .insert(key.to_owned(), delay);
+    }
+
+    /// How long gets of `key` should take, as requested by `delay_gets`.
+    pub(crate) fn get_delay(&self, key: &str) -> Option<Duration> {
+        self.get_delays
+            .lock()
+            .expect("poisoned lock")
+            .get(key)
+            .copied()
+    }
 }
 
 impl<T: Clone> MockStore<T> {
@@ -62,12 +133,31 @@ impl<T: Clone> MockStore<T> {
 
     pub fn set(&self, key: &str, value: T) {
         self.set_count.fetch_add(1, Ordering::SeqCst);
+        if self.drop_sets.load(Ordering::SeqCst) {
+            return;
+        }
+        self.ttls.lock().expect("poisoned lock").remove(key);
         self.data
             .lock()
             .expect("poisoned lock")
             .insert(key.to_owned(), value);
     }
 
+    pub fn set_with_ttl(&self, key: &str, value: T, ttl: Duration) {
+        self.set(key, value);
+        if !self.drop_sets.load(Ordering::SeqCst) {
+            self.ttls
+                .lock()
+                .expect("poisoned lock")
+                .insert(key.to_owned(), ttl);
+        }
+    }
+
+    pub fn del(&self, key: &str) {
+        self.ttls.lock().expect("poisoned lock").remove(key);
+        self.data.lock().expect("poisoned lock").remove(key);
+    }
+
     #[cfg(test)]
     pub(crate) fn data(&self) -> HashMap<String, T> {
         self.data.lock().expect("poisoned lock").clone()
@@ -124,4 +214,43 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn test_drop_sets() {
+        let store = MockStore::new();
+        store.set_drop_sets(true);
+        store.set("foo", &());
+        assert_eq!(store.get(&"foo".to_string()), None);
+        assert_eq!(store.stats().sets, 1);
+
+        store.set_drop_sets(false);
+        store.set("foo", &());
+        assert_eq!(store.get(&"foo".to_string()), Some(&()));
+    }
+
+    #[test]
+    fn test_ttls() {
+        let store = MockStore::new();
+        store.set_with_ttl("foo", &(), Duration::from_secs(60));
+        assert_eq!(store.ttl("foo"), Some(Duration::from_secs(60)));
+        assert_eq!(store.get(&"foo".to_string()), Some(&()));
+
+        store.set("foo", &());
+        assert_eq!(store.ttl("foo"), None);
+
+        store.set_with_ttl("foo", &(), Duration::from_secs(60));
+        store.del("foo");
+        assert_eq!(store.ttl("foo"), None);
+    }
+
+    #[test]
+    fn test_fail_next_gets() {
+        let store = MockStore::<()>::new();
+        assert!(!store.take_get_failure());
+
+        store.fail_next_gets(2);
+        assert!(store.take_get_failure());
+        assert!(store.take_get_failure());
+        assert!(!store.take_get_failure());
+    }
 }
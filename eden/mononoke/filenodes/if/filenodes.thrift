@@ -10,16 +10,90 @@ include "eden/mononoke/mercurial/types/if/mercurial_thrift.thrift"
 
 # Memcache constants. Should be change when we want to invalidate memcache
 # entries
-const i32 MC_CODEVER = 3;
+const i32 MC_CODEVER = 14;
 const i32 MC_SITEVER = 1;
 
 union FilenodeInfoList {
   1: list<FilenodeInfo> Data;
+  // Superseded by Chunks, which records more information about the chunks.
   2: list<i64> Pointers;
   // This actual value is ignored
   3: byte TooBig;
+  4: FilenodeInfoChunks Chunks;
+  5: InternedFilenodeInfoList Interned;
 }
 
+// Like Data, with the path of each copy source stored once, as the filenodes of
+// a history are often copied from the same few paths.
+struct InternedFilenodeInfoList {
+  1: required list<mononoke_types_thrift.RepoPath> copyfrom_paths;
+  2: required list<InternedFilenodeInfo> infos;
+} (rust.exhaustive)
+
+struct InternedFilenodeInfo {
+  // Without its copyfrom, which is given by the fields below.
+  1: required FilenodeInfo info;
+  // Index of the path of the copy source in copyfrom_paths.
+  2: optional i32 copyfrom_path;
+  3: optional mercurial_thrift.HgNodeHash copyfrom_filenode;
+} (rust.exhaustive)
+
+// Root of a list that was too big to be stored as a single value, and was
+// split into chunks stored under separate keys.
+struct FilenodeInfoChunks {
+  1: required list<i64> pointers;
+  // Unix timestamp (in seconds) at which the root expires.
+  2: required i64 expires_at;
+  // Incremented every time the list is replaced. Chunks record the generation
+  // they were written for, so that readers can tell if they belong to this root.
+  3: required i64 generation;
+  // Number of filenodes in the list, and size of the serialized list that was
+  // split into chunks. Not recorded by older roots.
+  4: optional i64 entry_count;
+  5: optional i64 size;
+  // If set, each chunk holds a separately serialized FilenodeInfoList, rather
+  // than a slice of a single one, so that the list can be written without
+  // being held in memory in full.
+  6: optional bool segmented;
+  // If set, the data of each chunk is separately compressed with zstd.
+  7: optional bool compressed;
+  // 8: linknode_filter, the filter now stored under linknode_filter_pointer.
+  // Size of the chunks, except the last one, or their target size if the list
+  // is segmented. Not recorded by older roots.
+  9: optional i64 chunk_size;
+  // Pointer to a Bloom filter of the linknodes of the list, stored like a
+  // chunk, so that lists can be known not to contain a linknode without
+  // reading the chunks. Kept out of the root, which every read fetches.
+  10: optional i64 linknode_filter_pointer;
+} (rust.exhaustive)
+
+struct FilenodeInfoChunk {
+  1: required i64 generation;
+  2: required binary data;
+  // Whether data is compressed with zstd, as chunks of a compressed list are
+  // stored uncompressed when that is smaller. If not set, the root tells.
+  3: optional bool compressed;
+} (rust.exhaustive)
+
+// Values stored in memcache record the repo they belong to, so that a value
+// can't be served to another repo if a key ever fails to tell them apart.
+struct CachedFilenodeInfo {
+  1: required i32 repo_id;
+  2: required FilenodeInfo info;
+  // Unix timestamp (in seconds) at which the value was written.
+  3: optional i64 written_at;
+  // How long after being written the value should be refreshed.
+  4: optional i64 soft_ttl_secs;
+  // Epoch the value was written at, so that readers can skip values written
+  // before a known invalidation point. Not recorded by older values.
+  5: optional i64 epoch;
+} (rust.exhaustive)
+
+struct CachedFilenodeInfoList {
+  1: required i32 repo_id;
+  2: required FilenodeInfoList list;
+} (rust.exhaustive)
+
 struct FilenodeInfo {
   // 1 was used previously.
   2: required mercurial_thrift.HgNodeHash filenode;
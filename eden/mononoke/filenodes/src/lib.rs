@@ -105,6 +105,42 @@ impl FilenodeInfo {
         catch_block()
     }
 
+    /// Like `from_thrift`, but the parents and copy source are treated as absent if their hash is
+    /// empty, which is how a field that was missing from a value written with an older schema is
+    /// read back. This lets such values still be used. Hashes that are present must be valid, so
+    /// that values that are corrupt rather than old still fail to convert.
+    pub fn from_thrift_lenient(info: thrift::FilenodeInfo) -> Result<Self> {
+        let copyfrom = match info.copyfrom {
+            Some(copyfrom) => hash_if_present(copyfrom.filenode)?
+                .map(|filenode| -> Result<_> {
+                    Ok((
+                        RepoPath::from_thrift(copyfrom.path)?,
+                        HgFileNodeId::new(filenode),
+                    ))
+                })
+                .transpose()?,
+            None => None,
+        };
+
+        Ok(Self {
+            filenode: HgFileNodeId::new(HgNodeHash::from_thrift(info.filenode)?),
+            p1: info
+                .p1
+                .map(hash_if_present)
+                .transpose()?
+                .flatten()
+                .map(HgFileNodeId::new),
+            p2: info
+                .p2
+                .map(hash_if_present)
+                .transpose()?
+                .flatten()
+                .map(HgFileNodeId::new),
+            copyfrom,
+            linknode: HgChangesetId::new(HgNodeHash::from_thrift(info.linknode)?),
+        })
+    }
+
     pub fn into_thrift(self) -> thrift::FilenodeInfo {
         thrift::FilenodeInfo {
             filenode: self.filenode.into_nodehash().into_thrift(),
@@ -151,6 +187,15 @@ pub trait Filenodes: Send + Sync {
     fn prime_cache(&self, ctx: &CoreContext, filenodes: &[PreparedFilenode]);
 }
 
+/// The hash a field that may be missing from a value written with an older schema holds, if it
+/// wasn't missing, see `FilenodeInfo::from_thrift_lenient`.
+fn hash_if_present(hash: mercurial_types::thrift::HgNodeHash) -> Result<Option<HgNodeHash>> {
+    if hash.0.0.is_empty() {
+        return Ok(None);
+    }
+    HgNodeHash::from_thrift(hash).map(Some)
+}
+
 #[cfg(test)]
 mod test {
     use quickcheck::quickcheck;
@@ -164,5 +209,20 @@ mod test {
                 .expect("converting a valid Thrift structure should always work");
             obj == obj2
         }
+
+        fn filenodes_info_thrift_lenient_roundtrip(obj: FilenodeInfo) -> bool {
+            let thrift_struct = obj.clone().into_thrift();
+            let obj2 = FilenodeInfo::from_thrift_lenient(thrift_struct)
+                .expect("converting a valid Thrift structure should always work");
+            obj == obj2
+        }
+
+        fn filenodes_info_thrift_lenient_rejects_invalid_parents(obj: FilenodeInfo) -> bool {
+            let mut thrift_struct = obj.into_thrift();
+            thrift_struct.p1 = Some(mercurial_types::thrift::HgNodeHash(
+                mercurial_types::thrift::Sha1(vec![1; 3].into()),
+            ));
+            FilenodeInfo::from_thrift_lenient(thrift_struct).is_err()
+        }
     }
 }
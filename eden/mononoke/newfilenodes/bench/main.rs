@@ -0,0 +1,193 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Compares getting then filling filenodes with their key computed for each operation, and with
+//! it computed once with `RemoteCache::filenode_key`, and reading chunked histories with and
+//! without a reused scratch buffer.
+
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use caching_ext::CacheHandlerFactory;
+use caching_ext::KeyGen;
+use caching_ext::MemcacheHandler;
+use criterion::Criterion;
+use filenodes::FilenodeInfo;
+use filenodes::FilenodeRange;
+use mercurial_types::HgFileNodeId;
+use mercurial_types_mocks::nodehash::ONES_CSID;
+use mononoke_types::RepoPath;
+use mononoke_types_mocks::repo::REPO_ZERO;
+use newfilenodes::CacheKey;
+use newfilenodes::RemoteCache;
+use newfilenodes::RemoteCacheOptions;
+use tokio::runtime::Runtime;
+
+const FILENODES: u64 = 1000;
+const HISTORY_READS: usize = 10;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts allocations, so that the number saved by precomputing keys or reusing buffers can be
+/// reported.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn filenodes() -> Vec<FilenodeInfo> {
+    (0..FILENODES)
+        .map(|i| {
+            let mut hash = [0u8; 20];
+            hash[..8].copy_from_slice(&i.to_be_bytes());
+            FilenodeInfo {
+                filenode: HgFileNodeId::from_bytes(&hash).expect("invalid filenode"),
+                p1: None,
+                p2: None,
+                copyfrom: None,
+                linknode: ONES_CSID,
+            }
+        })
+        .collect()
+}
+
+async fn get_fill_recomputing_keys(cache: &RemoteCache, path: &RepoPath, infos: &[FilenodeInfo]) {
+    for info in infos {
+        let key = cache.filenode_key(REPO_ZERO, path, info.filenode);
+        let _ = cache.get_filenode_by_key(&key).await;
+        let key = cache.filenode_key(REPO_ZERO, path, info.filenode);
+        cache.fill_filenode_by_key(&key, info.clone());
+    }
+    // Let the fills run.
+    tokio::task::yield_now().await;
+}
+
+async fn get_fill_precomputed_keys(cache: &RemoteCache, path: &RepoPath, infos: &[FilenodeInfo]) {
+    for info in infos {
+        let key = cache.filenode_key(REPO_ZERO, path, info.filenode);
+        let _ = cache.get_filenode_by_key(&key).await;
+        cache.fill_filenode_by_key(&key, info.clone());
+    }
+    // Let the fills run.
+    tokio::task::yield_now().await;
+}
+
+async fn read_histories(cache: &RemoteCache, key: &CacheKey<FilenodeRange>) {
+    for _ in 0..HISTORY_READS {
+        let _ = cache.get_history(key).await;
+    }
+}
+
+async fn read_histories_with_scratch(
+    cache: &RemoteCache,
+    key: &CacheKey<FilenodeRange>,
+    scratch: &mut Vec<u8>,
+) {
+    for _ in 0..HISTORY_READS {
+        let _ = cache.get_history_with_scratch(key, scratch).await;
+    }
+}
+
+fn count_allocations(runtime: &Runtime, fut: impl Future<Output = ()>) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    runtime.block_on(fut);
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    // Single threaded, so that the fills run within the measured loops.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to initialize runtime");
+    let cache = RemoteCache::with_handler(
+        MemcacheHandler::create_mock(),
+        KeyGen::new("benchmark", 0, 0),
+    );
+    let path = RepoPath::file("dir/subdir/file").expect("invalid path");
+    let infos = filenodes();
+
+    let recomputing = count_allocations(&runtime, get_fill_recomputing_keys(&cache, &path, &infos));
+    let precomputed = count_allocations(&runtime, get_fill_precomputed_keys(&cache, &path, &infos));
+    println!(
+        "Allocations for {} gets and fills: {} recomputing keys, {} with precomputed keys",
+        FILENODES, recomputing, precomputed
+    );
+
+    // Small chunks, so that the history is reassembled from many of them.
+    let (history_cache, fills) = RemoteCache::new_with_options(
+        &CacheHandlerFactory::Mocked,
+        "benchmark",
+        "",
+        RemoteCacheOptions {
+            chunk_size: Some(4096),
+            ..Default::default()
+        },
+    )
+    .with_fill_tracker();
+    let history_key = CacheKey {
+        key: "benchmark.history".to_string(),
+        repo_id: REPO_ZERO,
+        path_depth: None,
+        path_hash: None,
+        value: PhantomData,
+    };
+    runtime.block_on(async {
+        history_cache.fill_history(&history_key, FilenodeRange::Filenodes(infos.clone()));
+        fills.wait_for_fills().await;
+    });
+    let mut scratch = Vec::new();
+
+    let allocating = count_allocations(&runtime, read_histories(&history_cache, &history_key));
+    let reusing = count_allocations(
+        &runtime,
+        read_histories_with_scratch(&history_cache, &history_key, &mut scratch),
+    );
+    println!(
+        "Allocations for {} chunked history reads: {} allocating, {} with a scratch buffer",
+        HISTORY_READS, allocating, reusing
+    );
+
+    let mut criterion = Criterion::default().sample_size(10);
+
+    criterion.bench_function("get/fill, recomputing keys", |b| {
+        b.iter(|| runtime.block_on(get_fill_recomputing_keys(&cache, &path, &infos)))
+    });
+    criterion.bench_function("get/fill, precomputed keys", |b| {
+        b.iter(|| runtime.block_on(get_fill_precomputed_keys(&cache, &path, &infos)))
+    });
+    criterion.bench_function("history reads, allocating", |b| {
+        b.iter(|| runtime.block_on(read_histories(&history_cache, &history_key)))
+    });
+    criterion.bench_function("history reads, scratch buffer", |b| {
+        b.iter(|| {
+            runtime.block_on(read_histories_with_scratch(
+                &history_cache,
+                &history_key,
+                &mut scratch,
+            ))
+        })
+    });
+
+    criterion.final_summary();
+}
@@ -29,11 +29,43 @@ use filenodes::FilenodeRange;
 use filenodes::FilenodeResult;
 use filenodes::Filenodes;
 use filenodes::PreparedFilenode;
+pub use local_cache::CacheKey;
 use mercurial_types::HgFileNodeId;
 use mononoke_types::RepoPath;
 use mononoke_types::RepositoryId;
 pub use path_hash::PathHash;
 use reader::FilenodesReader;
+pub use remote_cache::AdaptiveTtlOptions;
+pub use remote_cache::CacheConfigSummary;
+pub use remote_cache::CacheError;
+pub use remote_cache::CacheEvent;
+pub use remote_cache::CacheEventKind;
+pub use remote_cache::CacheRead;
+pub use remote_cache::CacheStatsAccumulator;
+pub use remote_cache::ChunkExplain;
+pub use remote_cache::CircuitBreakerOptions;
+pub use remote_cache::FallbackCache;
+pub use remote_cache::FilenodeCache;
+pub use remote_cache::FilenodeCacheKey;
+pub use remote_cache::FilledValue;
+pub use remote_cache::FillHandle;
+pub use remote_cache::FillStats;
+pub use remote_cache::FillTracker;
+pub use remote_cache::HistoryExplain;
+pub use remote_cache::HistoryLayout;
+pub use remote_cache::HistorySummary;
+pub use remote_cache::NegativeCachingOptions;
+pub use remote_cache::ReadRetryPolicy;
+pub use remote_cache::RemoteCache;
+pub use remote_cache::RemoteCacheOptions;
+pub use remote_cache::RequestCacheStats;
+pub use remote_cache::SelfCheckResult;
+pub use remote_cache::ValueFormat;
+pub use remote_cache::Warmer;
+#[cfg(fuzzing)]
+pub use remote_cache::try_deserialize_filenode;
+#[cfg(fuzzing)]
+pub use remote_cache::try_deserialize_history;
 pub use sql_timeout_knobs::disable_sql_timeouts;
 use thiserror::Error as DeriveError;
 use writer::FilenodesWriter;
@@ -57,6 +89,30 @@ pub struct NewFilenodes {
     repo_id: RepositoryId,
 }
 
+impl NewFilenodes {
+    /// Fetch a filenode and keep it in the local cache until `unpin_filenode` is called, e.g. for
+    /// the filenode of the root manifest.
+    pub async fn pin_filenode(
+        &self,
+        ctx: &CoreContext,
+        path: &RepoPath,
+        filenode_id: HgFileNodeId,
+    ) -> Result<FilenodeResult<Option<FilenodeInfo>>> {
+        let ret = self
+            .reader
+            .clone()
+            .pin_filenode(ctx, self.repo_id, path, filenode_id)
+            .await
+            .with_context(|| ErrorKind::FailFetchFilenode(filenode_id, path.clone()))?;
+        Ok(ret)
+    }
+
+    /// Let a filenode pinned by `pin_filenode` be evicted again. Returns whether it was pinned.
+    pub fn unpin_filenode(&self, path: &RepoPath, filenode_id: HgFileNodeId) -> bool {
+        self.reader.unpin_filenode(self.repo_id, path, filenode_id)
+    }
+}
+
 #[async_trait]
 impl Filenodes for NewFilenodes {
     async fn add_filenodes(
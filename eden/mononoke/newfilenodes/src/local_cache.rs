@@ -5,30 +5,60 @@
  * GNU General Public License version 2.
  */
 
+use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::ops::Range;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::RwLock;
 
 use caching_ext::CacheHandlerFactory;
 use caching_ext::CachelibHandler;
 use filenodes::FilenodeInfo;
 use filenodes::FilenodeRange;
+use mononoke_types::RepositoryId;
 use stats::prelude::*;
 
 define_stats! {
     prefix = "mononoke.filenodes";
     fill_cache_fail: timeseries(Sum),
+    pinned_hit: timeseries(Sum),
+    pin_refused: timeseries(Sum),
 }
 
+// Filenodes that can be pinned at once. Pinning is meant for a few very hot filenodes, and pinned
+// filenodes are kept in memory on top of cachelib's capacity, so more pins than this are refused.
+pub(crate) const MAX_PINNED_FILENODES: usize = 1000;
+
 #[derive(Clone)]
 pub struct CacheKey<V> {
     pub key: String,
+    /// Repo the value belongs to, checked against the repo recorded in values read from memcache.
+    pub repo_id: RepositoryId,
+    /// Number of components in the path the value is for, if it's for a path. Only used for
+    /// stats.
+    pub path_depth: Option<usize>,
+    /// Where the encoded path hash is in `key`, if the value is for a path. Only used for stats.
+    pub path_hash: Option<Range<usize>>,
     /// value is used to enforce that a CacheKey for a given type V can only be used to fetch
     /// values of type V.
     pub value: PhantomData<V>,
 }
 
+impl<V> CacheKey<V> {
+    /// The encoded path hash in `key`, e.g. `n1.<base64>` in `history.0.n1.<base64>.0`.
+    pub fn path_hash(&self) -> Option<&str> {
+        self.path_hash.clone().map(|range| &self.key[range])
+    }
+}
+
 pub struct LocalCache {
     filenode_cache: CachelibHandler<FilenodeInfo>,
     history_cache: CachelibHandler<FilenodeRange>,
+    /// Filenodes that are served even if cachelib evicts them, until they are unpinned.
+    pinned_filenodes: RwLock<HashMap<String, FilenodeInfo>>,
+    /// Number of `pinned_filenodes`, so that misses don't lock them when there are none.
+    pinned_count: AtomicUsize,
 }
 
 impl LocalCache {
@@ -39,6 +69,8 @@ impl LocalCache {
         LocalCache {
             filenode_cache: filenode_cache_handler_factory.cachelib(),
             history_cache: history_cache_handler_factory.cachelib(),
+            pinned_filenodes: RwLock::new(HashMap::new()),
+            pinned_count: AtomicUsize::new(0),
         }
     }
 
@@ -54,7 +86,54 @@ impl LocalCache {
     pub fn get_filenode(&self, key: &CacheKey<FilenodeInfo>) -> Option<FilenodeInfo> {
         match self.filenode_cache.get_cached(&key.key) {
             Ok(Some(r)) => Some(r),
-            _ => None,
+            _ => self.get_pinned_filenode(key),
+        }
+    }
+
+    fn get_pinned_filenode(&self, key: &CacheKey<FilenodeInfo>) -> Option<FilenodeInfo> {
+        if self.pinned_count.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        let pinned = self.pinned_filenodes.read().expect("poisoned lock");
+        let r = pinned.get(&key.key).cloned();
+        if r.is_some() {
+            STATS::pinned_hit.add_value(1);
+        }
+        r
+    }
+
+    /// Keep serving `value` for `key` until it is unpinned. It is still stored in cachelib and
+    /// counts against its capacity like any other filenode, but outlives its eviction. Returns
+    /// whether it was pinned: nothing is pinned if there is no cachelib, and once
+    /// `MAX_PINNED_FILENODES` are, further pins are refused and counted as `pin_refused`.
+    pub fn pin_filenode(&self, key: &CacheKey<FilenodeInfo>, value: &FilenodeInfo) -> bool {
+        self.fill_filenode(key, value);
+        if matches!(self.filenode_cache, CachelibHandler::Noop) {
+            return false;
+        }
+
+        let mut pinned = self.pinned_filenodes.write().expect("poisoned lock");
+        if pinned.len() >= MAX_PINNED_FILENODES && !pinned.contains_key(&key.key) {
+            STATS::pin_refused.add_value(1);
+            return false;
+        }
+        pinned.insert(key.key.clone(), value.clone());
+        self.pinned_count.store(pinned.len(), Ordering::Relaxed);
+        true
+    }
+
+    /// Let cachelib evict the filenode under `key` again. Returns whether it was pinned.
+    pub fn unpin_filenode(&self, key: &CacheKey<FilenodeInfo>) -> bool {
+        let mut pinned = self.pinned_filenodes.write().expect("poisoned lock");
+        let unpinned = pinned.remove(&key.key).is_some();
+        self.pinned_count.store(pinned.len(), Ordering::Relaxed);
+        unpinned
+    }
+
+    #[cfg(test)]
+    pub fn evict_filenode(&self, key: &CacheKey<FilenodeInfo>) {
+        if let Some(store) = self.filenode_cache.mock_store() {
+            store.del(&key.key);
         }
     }
 
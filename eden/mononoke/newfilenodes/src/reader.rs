@@ -8,13 +8,13 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::marker::PhantomData;
+use std::ops::Range;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Error;
 use context::CoreContext;
 use context::PerfCounterType;
-use faster_hex::hex_encode;
 use filenodes::FilenodeInfo;
 use filenodes::FilenodeRange;
 use filenodes::FilenodeResult;
@@ -24,6 +24,8 @@ use futures::future::Future;
 use itertools::Itertools;
 use mercurial_types::HgChangesetId;
 use mercurial_types::HgFileNodeId;
+use mononoke_types::ContentId;
+use mononoke_types::MPath;
 use mononoke_types::RepoPath;
 use mononoke_types::RepositoryId;
 use path_hash::PathBytes;
@@ -43,6 +45,7 @@ use crate::connections::AcquireReason;
 use crate::connections::Connections;
 use crate::local_cache::CacheKey;
 use crate::local_cache::LocalCache;
+use crate::remote_cache::FilenodeCache;
 use crate::remote_cache::RemoteCache;
 use crate::shards::Shards;
 use crate::sql_timeout_knobs;
@@ -106,24 +109,116 @@ type FilenodeRow = (
     Option<HgFileNodeId>,
 );
 
+/// Version of the path normalization that produces the path bytes hashed into `PathHashBytes`.
+/// It is embedded in cache keys next to the path hash, so that if the normalization changes (e.g.
+/// in how it handles trailing slashes or case), keys computed with the old and new normalizations
+/// never collide, and both can be used side by side during the migration.
+pub const PATH_NORMALIZATION_VERSION: u32 = 1;
+
+/// Default number of leading bytes of the 32-byte path hash that go into cache keys, which makes
+/// keys 21 bytes shorter. Two paths whose hashes share these bytes share their history keys, and
+/// would read each other's histories. With 16 bytes, the odds that any two of a billion paths of
+/// a repo do are about 1 in 10^21, which is a lot less likely than a corrupt value, so it isn't
+/// guarded against.
+pub const DEFAULT_PATH_HASH_KEY_BYTES: usize = 16;
+
+/// How the path hash goes into cache keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PathHashEncoding {
+    /// Version of the path normalization the hash was computed with.
+    pub normalization: u32,
+    /// Number of leading bytes of the hash that go into keys. Keys built with different numbers
+    /// of bytes never collide, as their encoded hashes differ in length.
+    pub key_bytes: usize,
+}
+
+impl Default for PathHashEncoding {
+    fn default() -> Self {
+        Self {
+            normalization: PATH_NORMALIZATION_VERSION,
+            key_bytes: DEFAULT_PATH_HASH_KEY_BYTES,
+        }
+    }
+}
+
+/// Encode the path hash for use in a cache key, along with the version of the normalization it
+/// was computed with. We use unpadded URL-safe base64 rather than hex to keep keys short: it only
+/// produces memcache-safe characters and takes 22 bytes for the 16 bytes of the hash we keep by
+/// default instead of 32.
+fn encode_path_hash(hash: &PathHashBytes, encoding: PathHashEncoding) -> String {
+    let hash = &hash.0[..hash.0.len().min(encoding.key_bytes)];
+    format!(
+        "n{}.{}",
+        encoding.normalization,
+        escape_key_segment(base64::encode_config(hash, base64::URL_SAFE_NO_PAD))
+    )
+}
+
+/// Percent-escape anything in a key segment other than ASCII alphanumerics, `-` and `_`. Memcache
+/// rejects keys containing spaces or control characters, and `.` separates segments. This is a
+/// no-op for the current path hash encoding, but guards against changes to it.
+pub fn escape_key_segment(segment: String) -> String {
+    let is_safe = |b: u8| b.is_ascii_alphanumeric() || b == b'-' || b == b'_';
+    if segment.bytes().all(is_safe) {
+        return segment;
+    }
+
+    let mut escaped = String::with_capacity(segment.len() * 3);
+    for b in segment.bytes() {
+        if is_safe(b) {
+            escaped.push(b as char);
+        } else {
+            escaped.push_str(&format!("%{:02X}", b));
+        }
+    }
+    escaped
+}
+
+fn path_depth(pwh: &PathWithHash<'_>) -> usize {
+    pwh.path.mpath().map_or(0, MPath::num_components)
+}
+
+/// `key` followed by the encoded path hash and whether `pwh` is a tree, along with where the path
+/// hash is in it.
+fn with_path_hash(
+    mut key: String,
+    pwh: &PathWithHash<'_>,
+    encoding: PathHashEncoding,
+) -> (String, Range<usize>) {
+    let start = key.len();
+    key.push_str(&encode_path_hash(&pwh.hash, encoding));
+    let end = key.len();
+    key.push_str(&format!(".{}", pwh.is_tree as u8));
+    (key, start..end)
+}
+
 pub fn filenode_cache_key(
     repo_id: RepositoryId,
     pwh: &PathWithHash<'_>,
     filenode: &HgFileNodeId,
 ) -> CacheKey<FilenodeInfo> {
-    let mut v = vec![0; pwh.hash.0.len() * 2];
-    let is_tree = pwh.is_tree as u8;
-    hex_encode(pwh.hash.0.as_ref(), &mut v).expect("failed to hex encode");
-    let key = format!(
-        "filenode.{}.{}.{}.{}",
-        repo_id.id(),
-        filenode,
-        unsafe { String::from_utf8_unchecked(v) },
-        is_tree
+    filenode_cache_key_with_encoding(repo_id, pwh, filenode, PathHashEncoding::default())
+}
+
+/// Like `filenode_cache_key`, with the path hash encoded with `encoding`, e.g. for a path hashed
+/// with another version of the path normalization.
+pub fn filenode_cache_key_with_encoding(
+    repo_id: RepositoryId,
+    pwh: &PathWithHash<'_>,
+    filenode: &HgFileNodeId,
+    encoding: PathHashEncoding,
+) -> CacheKey<FilenodeInfo> {
+    let (key, path_hash) = with_path_hash(
+        format!("filenode.{}.{}.", repo_id.id(), filenode),
+        pwh,
+        encoding,
     );
 
     CacheKey {
         key,
+        repo_id,
+        path_depth: Some(path_depth(pwh)),
+        path_hash: Some(path_hash),
         value: PhantomData,
     }
 }
@@ -133,30 +228,46 @@ pub fn history_cache_key(
     pwh: &PathWithHash<'_>,
     limit: Option<u64>,
 ) -> CacheKey<FilenodeRange> {
-    let mut v = vec![0; pwh.hash.0.len() * 2];
-    let is_tree = pwh.is_tree as u8;
-    hex_encode(pwh.hash.0.as_ref(), &mut v).expect("failed to hex encode");
-    let key = match limit {
-        Some(limit) => format!(
-            "history.{}.limit.{}.{}.{}",
-            repo_id.id(),
-            limit,
-            unsafe { String::from_utf8_unchecked(v) },
-            is_tree
-        ),
-        None => format!(
-            "history.{}.{}.{}",
-            repo_id.id(),
-            unsafe { String::from_utf8_unchecked(v) },
-            is_tree
-        ),
+    history_cache_key_with_encoding(repo_id, pwh, limit, PathHashEncoding::default())
+}
+
+/// Like `history_cache_key`, with the path hash encoded with `encoding`.
+pub fn history_cache_key_with_encoding(
+    repo_id: RepositoryId,
+    pwh: &PathWithHash<'_>,
+    limit: Option<u64>,
+    encoding: PathHashEncoding,
+) -> CacheKey<FilenodeRange> {
+    let prefix = match limit {
+        Some(limit) => format!("history.{}.limit.{}.", repo_id.id(), limit),
+        None => format!("history.{}.", repo_id.id()),
     };
+    let (key, path_hash) = with_path_hash(prefix, pwh, encoding);
+
+    CacheKey {
+        key,
+        repo_id,
+        path_depth: Some(path_depth(pwh)),
+        path_hash: Some(path_hash),
+        value: PhantomData,
+    }
+}
+
+pub fn filenode_content_cache_key(
+    repo_id: RepositoryId,
+    content_id: &ContentId,
+) -> CacheKey<FilenodeInfo> {
+    let key = format!("filenode_by_content.{}.{}", repo_id.id(), content_id);
 
     CacheKey {
         key,
+        repo_id,
+        path_depth: None,
+        path_hash: None,
         value: PhantomData,
     }
 }
+
 pub struct FilenodesReader {
     read_connections: Connections,
     read_master_connections: Connections,
@@ -189,7 +300,9 @@ impl FilenodesReader {
         STATS::gets.add_value(1);
 
         let pwh = PathWithHash::from_repo_path_cow(Cow::Owned(path.clone()));
-        let key = filenode_cache_key(repo_id, &pwh, &filenode);
+        let key = self
+            .remote_cache
+            .filenode_cache_key(repo_id, &pwh, &filenode);
 
         if let Some(cached) = self.local_cache.get_filenode(&key) {
             return Ok(FilenodeResult::Present(Some(cached)));
@@ -286,6 +399,47 @@ impl FilenodesReader {
             .await?
     }
 
+    /// Fetch a filenode like `get_filenode`, and keep it in the local cache until it is unpinned,
+    /// for the few filenodes that are read so often that they must never be evicted. Filenodes
+    /// are only pinned if there is a local cache, and up to a limit, see
+    /// `LocalCache::pin_filenode`.
+    pub async fn pin_filenode(
+        self: Arc<Self>,
+        ctx: &CoreContext,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+        filenode: HgFileNodeId,
+    ) -> Result<FilenodeResult<Option<FilenodeInfo>>, Error> {
+        let res = self
+            .clone()
+            .get_filenode(ctx, repo_id, path, filenode)
+            .await?;
+
+        if let FilenodeResult::Present(Some(info)) = &res {
+            let pwh = PathWithHash::from_repo_path(path);
+            let key = self
+                .remote_cache
+                .filenode_cache_key(repo_id, &pwh, &filenode);
+            self.local_cache.pin_filenode(&key, info);
+        }
+
+        Ok(res)
+    }
+
+    /// Undo `pin_filenode`. Returns whether the filenode was pinned.
+    pub fn unpin_filenode(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+        filenode: HgFileNodeId,
+    ) -> bool {
+        let pwh = PathWithHash::from_repo_path(path);
+        let key = self
+            .remote_cache
+            .filenode_cache_key(repo_id, &pwh, &filenode);
+        self.local_cache.unpin_filenode(&key)
+    }
+
     pub async fn get_all_filenodes_for_path(
         self: Arc<Self>,
         ctx: &CoreContext,
@@ -296,7 +450,7 @@ impl FilenodesReader {
         STATS::range_gets.add_value(1);
 
         let pwh = PathWithHash::from_repo_path_cow(Cow::Owned(path.clone()));
-        let key = history_cache_key(repo_id, &pwh, limit);
+        let key = self.remote_cache.history_cache_key(repo_id, &pwh, limit);
 
         if let Some(cached) = self.local_cache.get_history(&key) {
             return Ok(FilenodeResult::Present(cached));
@@ -352,7 +506,9 @@ impl FilenodesReader {
     ) {
         for c in filenodes {
             let pwh = PathWithHash::from_repo_path(&c.path);
-            let key = filenode_cache_key(repo_id, &pwh, &c.info.filenode);
+            let key = self
+                .remote_cache
+                .filenode_cache_key(repo_id, &pwh, &c.info.filenode);
             self.local_cache.fill_filenode(&key, &c.info)
         }
     }
@@ -361,7 +517,7 @@ impl FilenodesReader {
 #[derive(Copy, Clone)]
 struct FilenodeCacheFiller<'a> {
     local_cache: &'a LocalCache,
-    remote_cache: &'a RemoteCache,
+    remote_cache: &'a dyn FilenodeCache,
     key: &'a CacheKey<FilenodeInfo>,
 }
 
@@ -450,7 +606,7 @@ async fn select_partial_filenode(
 #[derive(Copy, Clone)]
 struct HistoryCacheFiller<'a> {
     local_cache: &'a LocalCache,
-    remote_cache: &'a RemoteCache,
+    remote_cache: &'a dyn FilenodeCache,
     key: &'a CacheKey<FilenodeRange>,
 }
 
@@ -5,27 +5,85 @@
  * GNU General Public License version 2.
  */
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::marker::PhantomData;
+use std::num::NonZeroU32;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::Weak;
 use std::time::Duration;
 use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
+use anyhow::Error;
+use async_trait::async_trait;
 use bytes::Bytes;
 use caching_ext::CacheHandlerFactory;
+use caching_ext::KeyGen;
 use caching_ext::MemcacheHandler;
+use caching_ext::MEMCACHE_VALUE_MAX_SIZE;
 use fbthrift::compact_protocol;
 use filenodes::thrift;
 use filenodes::thrift::MC_CODEVER;
 use filenodes::thrift::MC_SITEVER;
 use filenodes::FilenodeInfo;
 use filenodes::FilenodeRange;
+use futures::future::join_all;
 use futures::future::try_join_all;
-use memcache::KeyGen;
-use memcache::MEMCACHE_VALUE_MAX_SIZE;
+use futures::stream;
+use futures::stream::BoxStream;
+use futures::Stream;
+use futures::StreamExt;
+use hostname::get_hostname;
+use lru::LruCache;
+use mercurial_types::HgChangesetId;
+use mercurial_types::HgFileNodeId;
+use mercurial_types::HgNodeHash;
+use mononoke_types::ContentId;
+use mononoke_types::RepoPath;
+use mononoke_types::RepositoryId;
+use once_cell::sync::Lazy;
+use path_hash::PathWithHash;
 use rand::random;
+use rand::thread_rng;
+use rand::Rng;
+use serde::Serialize;
+use slog::o;
+use slog::warn;
+use slog::Logger;
 use stats::prelude::*;
+use thiserror::Error as DeriveError;
 use time_ext::DurationExt;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::Notify;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use twox_hash::XxHash32;
 
 use crate::local_cache::CacheKey;
+use crate::reader::escape_key_segment;
+use crate::reader::filenode_cache_key_with_encoding;
+use crate::reader::filenode_content_cache_key;
+use crate::reader::history_cache_key_with_encoding;
+use crate::reader::PathHashEncoding;
+use crate::reader::DEFAULT_PATH_HASH_KEY_BYTES;
 
 define_stats! {
     prefix = "mononoke.filenodes";
@@ -36,16 +94,88 @@ define_stats! {
     point_filenode_hit: timeseries("point_filenode.memcache.hit"; Sum),
     point_filenode_miss: timeseries("point_filenode.memcache.miss"; Sum),
     point_filenode_internal_err: timeseries("point_filenode.memcache.internal_err"; Sum),
+    served_stale_on_error: timeseries("point_filenode.memcache.served_stale_on_error"; Sum),
+    corrupt_evicted: timeseries("memcache.corrupt_evicted"; Sum),
     point_filenode_deserialize_err: timeseries("point_filenode.memcache.deserialize_err"; Sum),
+    point_filenode_checksum_err: timeseries("point_filenode.memcache.checksum_err"; Sum),
+    point_filenode_kind_mismatch: timeseries("point_filenode.memcache.kind_mismatch"; Sum),
     point_filenode_pointers_err: timeseries("point_filenode.memcache.pointers_err"; Sum),
+    point_filenode_stale: timeseries("point_filenode.memcache.stale"; Sum),
+    point_filenode_before_epoch: timeseries("point_filenode.memcache.before_epoch"; Sum),
+    point_filenode_expired: timeseries("point_filenode.memcache.expired"; Sum),
+    point_filenode_parent_mismatch: timeseries("point_filenode.memcache.parent_mismatch"; Sum),
+    point_filenode_rejected: timeseries("point_filenode.memcache.rejected"; Sum),
+    lenient_conversion: timeseries("memcache.lenient_conversion"; Sum),
     gaf_hit: timeseries("get_all_filenodes.memcache.hit"; Sum),
     gaf_miss: timeseries("get_all_filenodes.memcache.miss"; Sum),
     gaf_pointers: timeseries("get_all_filenodes.memcache.pointers"; Sum),
+    pointer_collisions: timeseries("get_all_filenodes.memcache.pointer_collisions"; Sum),
     gaf_internal_err: timeseries("get_all_filenodes.memcache.internal_err"; Sum),
     gaf_deserialize_err: timeseries("get_all_filenodes.memcache.deserialize_err"; Sum),
+    gaf_kind_mismatch: timeseries("get_all_filenodes.memcache.kind_mismatch"; Sum),
     gaf_pointers_err: timeseries("get_all_filenodes.memcache.pointers_err"; Sum),
+    gaf_chunk_missing: timeseries("get_all_filenodes.memcache.chunk_missing"; Sum),
+    gaf_chunk_empty: timeseries("get_all_filenodes.memcache.chunk_empty"; Sum),
+    gaf_chunk_generation_mismatch: timeseries("get_all_filenodes.memcache.chunk_generation_mismatch"; Sum),
+    gaf_reassembled_deserialize_err: timeseries("get_all_filenodes.memcache.reassembled_deserialize_err"; Sum),
+    gaf_refill: timeseries("get_all_filenodes.memcache.refill"; Sum),
+    gaf_rechunk: timeseries("get_all_filenodes.memcache.rechunk"; Sum),
+    gaf_refill_coalesced: timeseries("get_all_filenodes.memcache.refill_coalesced"; Sum),
+    gaf_read_coalesced: timeseries("get_all_filenodes.memcache.read_coalesced"; Sum),
+    gaf_too_many_pointers: timeseries("get_all_filenodes.memcache.too_many_pointers"; Sum),
+    gaf_linknode_filter_negative: timeseries("get_all_filenodes.memcache.linknode_filter_negative"; Sum),
+    gaf_below_min_skipped: timeseries("get_all_filenodes.memcache.below_min_skipped"; Sum),
+    gaf_preferred_chunk_size_exceeded: timeseries("get_all_filenodes.memcache.preferred_chunk_size_exceeded"; Sum),
+    key_too_long: timeseries("memcache.key_too_long"; Sum),
+    fill_suppressed: timeseries("memcache.fill_suppressed"; Sum),
+    fill_attempted: timeseries("memcache.fill_attempted"; Sum),
+    fill_written: timeseries("memcache.fill_written"; Sum),
+    repo_mismatch: timeseries("memcache.repo_mismatch"; Sum),
+    fill_dropped_concurrency: timeseries("memcache.fill_dropped_concurrency"; Sum),
+    fill_dropped_bytes_budget: timeseries("memcache.fill_dropped_bytes_budget"; Sum),
+    fill_no_runtime: timeseries("memcache.fill_no_runtime"; Sum),
+    canary_ok: timeseries("memcache.canary_ok"; Sum),
+    canary_fail: timeseries("memcache.canary_fail"; Sum),
+    chunk_compression_skipped: timeseries("memcache.chunk_compression_skipped"; Sum),
+    chunk_compression_below_min: timeseries("memcache.chunk_compression_below_min"; Sum),
+    fill_verify_ok: timeseries("memcache.fill_verify_ok"; Sum),
+    fill_verify_fail: timeseries("memcache.fill_verify_fail"; Sum),
+    negative_cached: timeseries("get_or_fill.negative_cache.cached"; Sum),
+    negative_hit: timeseries("get_or_fill.negative_cache.hit"; Sum),
+    read_retry: timeseries("memcache.read_retry"; Sum),
+    event_dropped: timeseries("memcache.event_dropped"; Sum),
+    deserialize_err_fallback: timeseries("fallback_cache.deserialize_err_fallback"; Sum),
+    cold_miss: timeseries("memcache.cold_miss"; Sum),
+    evicted_miss: timeseries("memcache.evicted_miss"; Sum),
+    bypassed: timeseries("memcache.bypassed"; Sum),
+    legacy_key_migrated: timeseries("memcache.legacy_key_migrated"; Sum),
+    value_too_big_read: timeseries("memcache.value_too_big_read"; Sum),
+    warm_progress: timeseries("memcache.warm_progress"; Sum),
+    warm_fetch_err: timeseries("memcache.warm_fetch_err"; Sum),
+    format_read: dynamic_timeseries("memcache.format_read.{}", (format: &'static str); Sum),
+    fill_rate_per_sec: singleton_counter("memcache.fill_rate_per_sec"),
+    cache_killed: timeseries("memcache.cache_killed"; Sum),
+    repo_disabled: timeseries("memcache.repo_disabled"; Sum),
+    repo_disabled_read: timeseries("memcache.repo_disabled_read"; Sum),
+    circuit_open: timeseries("memcache.circuit_open"; Sum),
+    circuit_closed: timeseries("memcache.circuit_closed"; Sum),
+    circuit_skipped: timeseries("memcache.circuit_skipped"; Sum),
     get_latency: histogram("get.memcache.duration_us"; 100, 0, 10000, Average, Count; P 50; P 95; P 100),
     get_history: histogram("get_history.memcache.duration_us"; 100, 0, 10000, Average, Count; P 50; P 95; P 100),
+    get_history_root_read_us: histogram("get_history.root_read_us"; 100, 0, 10000, Average, Count; P 50; P 95; P 100),
+    get_history_chunk_read_us: histogram("get_history.chunk_read_us"; 100, 0, 10000, Average, Count; P 50; P 95; P 100),
+    get_history_deserialize_us: histogram("get_history.deserialize_us"; 100, 0, 10000, Average, Count; P 50; P 95; P 100),
+    gaf_compact_bytes_by_path_depth: dynamic_histogram(
+        "get_all_filenodes.thrift_compact.bytes.path_depth.{}", (depth: String);
+        500, 0, 1_000_000, Average, Sum, Count; P 50; P 95; P 99
+    ),
+    serialize_us: histogram("memcache.serialize.duration_us"; 1000, 0, 100_000, Average, Count; P 50; P 95; P 100),
+    deserialize_us: histogram("memcache.deserialize.duration_us"; 1000, 0, 100_000, Average, Count; P 50; P 95; P 100),
+    single_key_bytes: histogram("memcache.single_key.bytes"; 10, 0, 250, Average, Count; P 50; P 95; P 100),
+    list_key_bytes: histogram("memcache.list_key.bytes"; 10, 0, 250, Average, Count; P 50; P 95; P 100),
+    chunk_key_bytes: histogram("memcache.chunk_key.bytes"; 10, 0, 250, Average, Count; P 50; P 95; P 100),
+    fill_spawn_lag_us: histogram("fill.spawn_lag_us"; 1000, 0, 100_000, Average, Count; P 50; P 95; P 100),
+    backfill_latency: histogram("get_or_fill.backfill.duration_us"; 1000, 0, 100_000, Average, Count; P 50; P 95; P 100),
 }
 
 const SITEVER_OVERRIDE_VAR: &str = "MONONOKE_OVERRIDE_FILENODES_MC_SITEVER";
@@ -55,494 +185,10551 @@ const TTL_SEC: u64 = 8 * 60 * 60;
 // Adding a random to TTL helps preventing eviction of all related keys at once
 const TTL_SEC_RAND: u64 = 30 * 60; // 30min
 
-pub struct RemoteCache {
-    memcache: MemcacheHandler,
-    keygen: KeyGen,
-}
+// Fill rates and eviction pressure are averaged over this many seconds
+const FILL_RATE_WINDOW_SECS: usize = 60;
 
-impl RemoteCache {
-    pub fn new(
-        cache_handler_factory: &CacheHandlerFactory,
-        backing_store_name: &str,
-        backing_store_params: &str,
-    ) -> Self {
-        Self {
-            memcache: cache_handler_factory.memcache(),
-            keygen: Self::create_key_gen(backing_store_name, backing_store_params),
-        }
-    }
+// Paths at least this deep share a single bucket in per-depth stats
+const PATH_DEPTH_BUCKET_MAX: usize = 10;
 
-    pub fn new_noop() -> Self {
-        Self::new(&CacheHandlerFactory::Noop, "newfilenodes", "")
-    }
+// Memcache rejects longer keys
+const MEMCACHE_KEY_MAX_LEN: usize = 250;
 
-    #[cfg(test)]
-    pub fn new_mock() -> Self {
-        Self::new(&CacheHandlerFactory::Mocked, "newfilenodes", "test")
-    }
+// Keys tracked for negative caching. Past this, the least recently missed keys are forgotten.
+const NEGATIVE_CACHE_MAX_KEYS: usize = 100_000;
 
-    fn create_key_gen(backing_store_name: &str, backing_store_params: &str) -> KeyGen {
-        let key_prefix = format!(
-            "scm.mononoke.filenodes.{}.{}",
-            backing_store_name, backing_store_params,
-        );
+// Chunked history roots pointing to more chunks than this are assumed to be corrupt, rather than
+// reading all the chunks at once
+const DEFAULT_MAX_HISTORY_POINTERS: usize = 10_000;
 
-        let mc_sitever = match std::env::var(SITEVER_OVERRIDE_VAR) {
-            Ok(v) => v.parse().unwrap_or(MC_SITEVER as u32),
-            Err(_) => MC_SITEVER as u32,
-        };
+// Bloom filter parameters for remembering recently written keys, giving a false positive rate of
+// about 1%
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u64 = 7;
 
-        KeyGen::new(key_prefix, MC_CODEVER as u32, mc_sitever)
-    }
+// Count-min sketch parameters for counting reads of each path, overestimating the count of a
+// path by at most about 0.1% of all reads with a probability of about 98%
+const ACCESS_SKETCH_WIDTH: usize = 2048;
+const ACCESS_SKETCH_DEPTH: u64 = 4;
 
-    // TODO: Can we optimize to reuse the existing PathWithHash we got?
-    pub async fn get_filenode(&self, key: &CacheKey<FilenodeInfo>) -> Option<FilenodeInfo> {
-        let now = Instant::now();
+// First byte of the values stored for filenodes and for history roots, so that a value can't be
+// read as the other kind. History chunks are only ever reached through a root, so aren't tagged.
+const FILENODE_TAG: u8 = b'F';
+const HISTORY_TAG: u8 = b'H';
 
-        let ret = get_single_filenode_from_memcache(&self.memcache, &self.keygen, key).await;
+// Most bytes a `FilenodeInfoChunk` adds around its data: the field headers, the generation and the
+// length of the data as varints, the `compressed` flag and the stop field. Chunks are split so
+// that they are no bigger than the chunk size with it.
+const CHUNK_ENVELOPE_BYTES: usize = 1 + 10 + 1 + 5 + 1 + 1;
 
-        let elapsed = now.elapsed().as_micros_unchecked() as i64;
-        STATS::get_latency.add_value(elapsed);
+// Most bytes a history root adds around its list: the kind tag, the repo id, the list's field
+// header and the stop field.
+const ROOT_ENVELOPE_BYTES: usize = 1 + 1 + 5 + 1 + 1;
 
-        ret
-    }
+// Number of pointers listed by `debug_history_layout`.
+const DEBUG_POINTERS_SHOWN: usize = 4;
 
-    // TODO: Need to use the same CacheKey here.
-    pub fn fill_filenode(&self, key: &CacheKey<FilenodeInfo>, filenode: FilenodeInfo) {
-        // Avoid wasting time spawning a fill operation if the memcache is a no-op
-        if !self.memcache.is_noop() {
-            schedule_fill_filenode(&self.memcache, &self.keygen, key, filenode);
-        }
-    }
+// Length of the checksum appended to single filenodes if `filenode_checksum` is set.
+const CHECKSUM_LEN: usize = 4;
 
-    pub async fn get_history(&self, key: &CacheKey<FilenodeRange>) -> Option<FilenodeRange> {
-        let now = Instant::now();
+// Stands for no epoch in `RemoteCache::write_epoch`, so that the epoch fits in an atomic.
+const NO_WRITE_EPOCH: i64 = i64::MIN;
 
-        let ret = get_history_from_memcache(&self.memcache, &self.keygen, key).await;
+// Reserved for the canary. Keys built in `reader` always start with a key type followed by a dot,
+// so they can't clash with it.
+const CANARY_KEY: &str = "canary";
 
-        let elapsed = now.elapsed().as_micros_unchecked() as i64;
-        STATS::get_history.add_value(elapsed);
+// How long values written by the canary and `RemoteCache::self_check` are kept. They are read
+// back right after being written, so they only need to live long enough for that.
+const CANARY_TTL: Duration = Duration::from_secs(60);
 
-        ret
-    }
+/// Prefix of the keys of the values written by the canary and `RemoteCache::self_check` in this
+/// process. It names the host and the process, and has a nonce so that it is also unique to this
+/// run, so that checks running anywhere else can't overwrite the values being checked.
+static CANARY_PREFIX: Lazy<String> = Lazy::new(|| {
+    let hostname = get_hostname().unwrap_or_else(|_| "unknown_hostname".to_string());
+    format!(
+        "{}.{}.{}.{:016x}",
+        CANARY_KEY,
+        hostname,
+        std::process::id(),
+        random::<u64>()
+    )
+});
 
-    // TODO: Take ownership of key
-    pub fn fill_history(&self, key: &CacheKey<FilenodeRange>, filenodes: FilenodeRange) {
-        // Avoid wasting time spawning a fill operation if the memcache is a no-op
-        if !self.memcache.is_noop() {
-            schedule_fill_history(
-                self.memcache.clone(),
-                self.keygen.clone(),
-                key.clone(),
-                filenodes,
-            );
+/// Set by `RemoteCache::set_kill_switch`.
+static KILL_SWITCH: AtomicBool = AtomicBool::new(false);
+
+/// Used in place of the memcache of every cache while the kill switch is set, and of the memcache
+/// of disabled repos.
+static KILLED_MEMCACHE: MemcacheHandler = MemcacheHandler::Noop;
+
+#[derive(Debug, DeriveError, PartialEq)]
+#[error("Memcache key is {0} bytes long, longer than the maximum of {MEMCACHE_KEY_MAX_LEN}")]
+struct KeyTooLong(usize);
+
+// Only log one in this many history read failures, unless `history_error_log_sample_rate` is set
+const CHUNK_ERROR_LOG_SAMPLE_RATE: u32 = 100;
+
+// Only log one in this many fills dropped for lack of a runtime, as a shutdown path can drop many
+const NO_RUNTIME_LOG_SAMPLE_RATE: u32 = 100;
+
+#[derive(Debug, DeriveError, PartialEq)]
+#[error("History needs more than the maximum of {0} chunks")]
+struct TooManyChunks(usize);
+
+#[derive(Clone, Debug, DeriveError, PartialEq)]
+enum ChunkReadError {
+    #[error("Chunk {0} is missing")]
+    Missing(Pointer),
+    #[error("Chunk {0} is empty")]
+    Empty(Pointer),
+    #[error("Chunk {0} could not be fetched")]
+    Failed(Pointer),
+    #[error("Chunk {0} could not be deserialized")]
+    Malformed(Pointer),
+    #[error("Chunk {0} belongs to a different generation of the history")]
+    GenerationMismatch(Pointer),
+}
+
+impl ChunkReadError {
+    fn pointer(&self) -> Pointer {
+        match self {
+            ChunkReadError::Missing(pointer)
+            | ChunkReadError::Empty(pointer)
+            | ChunkReadError::Failed(pointer)
+            | ChunkReadError::Malformed(pointer)
+            | ChunkReadError::GenerationMismatch(pointer) => *pointer,
         }
     }
 }
 
-type Pointer = i64;
-
-fn get_mc_key_for_filenodes_list_chunk(
-    keygen: &KeyGen,
-    key: &CacheKey<FilenodeRange>,
-    pointer: Pointer,
-) -> String {
-    keygen.key(format!("{}.{}", key.key, pointer))
+#[derive(Clone, Debug, Default)]
+pub struct RemoteCacheOptions {
+    /// When a chunked history is read and its root has less than this long left to live, the
+    /// history is written again so that it doesn't expire. Disabled if `None`. Reads of a history
+    /// that is already being written again don't write it again, and are counted as
+    /// `gaf_refill_coalesced`.
+    pub refill_ttl_threshold: Option<Duration>,
+    /// When a chunked history is read and its chunks aren't of the size it would be split into
+    /// now, e.g. because `chunk_size` was changed, the history is written again, and counted as
+    /// `gaf_rechunk`, so that the cache moves to the new size as histories are read. Like for
+    /// `refill_ttl_threshold`, concurrent reads write it once.
+    pub rechunk_on_read: bool,
+    /// Number of most recently filled keys to remember, along with their values, so that they can
+    /// be used to seed another cache. Keys are only remembered once their value was written, and
+    /// histories filled with `fill_history_stream` aren't, as they are never held as a whole.
+    /// Remembered histories are kept in memory, so this should be small. Disabled if 0.
+    pub recent_fills_capacity: usize,
+    /// Maximum size of the values a history is split into when it's too big to be stored as a
+    /// single value. Never more than `MEMCACHE_VALUE_MAX_SIZE`, which is the default.
+    pub chunk_size: Option<usize>,
+    /// Size of the values histories are split into, if smaller than `chunk_size`, e.g. because
+    /// the memcache pool performs better with small values. Unlike `chunk_size`, this gives way
+    /// if a history would need more than `max_history_pointers` chunks of this size: the chunks
+    /// are then made bigger, up to `chunk_size`, and the history is logged.
+    pub preferred_chunk_size: Option<usize>,
+    /// Whether filenodes filled with `fill_filenode_with_content` are also stored under their
+    /// content id, so that they can be found with `get_filenode_by_content`.
+    pub content_index: bool,
+    /// Whether to also record the size of filled histories broken down by path depth.
+    pub path_depth_stats: bool,
+    /// Whether replacing a chunked history deletes the chunks of the history it replaces, rather
+    /// than leaving them to expire. Either way, writing a chunked history first reads the root it
+    /// replaces, to tag the new chunks with the next generation, which costs a get per chunked
+    /// fill.
+    pub delete_replaced_chunks: bool,
+    /// Filenodes read this long after being written are still served, but are written again so
+    /// that they stay cached, and aren't written again for another soft TTL.
+    pub soft_ttl: Option<Duration>,
+    /// Filenodes read this long after being written are treated as misses. Writing them again
+    /// for the soft TTL doesn't count as writing them, so this bounds how stale they can get.
+    pub hard_ttl: Option<Duration>,
+    /// Whether cached filenodes that fail to convert are converted again with
+    /// `FilenodeInfo::from_thrift_lenient`, so that values written with an older schema remain
+    /// usable across a schema change.
+    pub lenient_conversion: bool,
+    /// How often to write a canary value and read it back, to detect a cache that accepts writes
+    /// but drops them. Disabled if `None`. The canary only runs if the cache is created within a
+    /// tokio runtime.
+    pub canary_interval: Option<Duration>,
+    /// Whether `get_or_fill_filenode` remembers filenodes the backing store keeps not finding.
+    /// Disabled if `None`.
+    pub negative_caching: Option<NegativeCachingOptions>,
+    /// How failed reads of filenodes and history roots are retried. Not retried if `None`, which
+    /// is what latency-sensitive callers should use.
+    pub read_retry: Option<ReadRetryPolicy>,
+    /// Number of recently written keys to remember, so that misses can be counted as either
+    /// `cold_miss` (never written) or `evicted_miss` (written, then evicted or expired). Keys are
+    /// remembered in a bloom filter, so a few cold misses are counted as evicted. Also needed for
+    /// `RemoteCache::estimated_eviction_pressure`. Disabled if 0.
+    pub miss_classification_capacity: usize,
+    /// Number of most read paths to keep track of, see `RemoteCache::hot_paths`. Reads of every
+    /// path are counted in a count-min sketch of fixed size, so counts can be a little too high.
+    /// Each read costs a scan of the tracked paths, so this should be small. Disabled if 0.
+    pub hot_paths_capacity: usize,
+    /// Maximum number of chunks a history can be split into when read. Roots pointing to more
+    /// chunks are treated as misses, and the chunks they point to are never deleted. Defaults to
+    /// `DEFAULT_MAX_HISTORY_POINTERS`.
+    pub max_history_pointers: Option<usize>,
+    /// Whether single filenodes are stored with a trailing checksum, which is verified before
+    /// they are deserialized. Mismatches are counted as `point_filenode_checksum_err`, rather than
+    /// as deserialization errors, and treated as misses. Adds `CHECKSUM_LEN` bytes to each value.
+    /// Values written with the other setting are generally unreadable, and are refilled.
+    pub filenode_checksum: bool,
+    /// Histories with fewer filenodes than this aren't written, and are counted as
+    /// `gaf_below_min_skipped`, which saves space in pools where most reads are for files with
+    /// several versions. Histories that are too big are always written. Disabled if 0.
+    pub min_history_entries: usize,
+    /// TTLs to use instead of the default for some repos, e.g. longer ones for repos that rarely
+    /// change. By default, filenodes live for memcache's default TTL, and history roots for
+    /// `TTL_SEC`. Either way, history roots live for up to `TTL_SEC_RAND` longer, at random.
+    pub ttl_overrides: HashMap<RepositoryId, Duration>,
+    /// Mixed into the key prefix, so that caches with different salts, or none, never read each
+    /// other's values, e.g. to give a cohort of hosts a key space of its own for an experiment
+    /// without bumping `MC_SITEVER` for everyone. Ignored by `with_handler`, which is given its
+    /// key generator.
+    pub salt: Option<String>,
+    /// Number of leading bytes of the 32-byte path hash that go into keys, which trades shorter
+    /// keys for higher odds of two paths sharing theirs. `DEFAULT_PATH_HASH_KEY_BYTES` if `None`.
+    /// Caches with different lengths never read each other's values of paths, as the encoded
+    /// hashes differ in length.
+    pub path_hash_key_bytes: Option<usize>,
+    /// Adapts the TTL of histories to how often they are overwritten. The TTL is fixed if
+    /// `None`.
+    pub adaptive_ttl: Option<AdaptiveTtlOptions>,
+    /// Largest single filenode, history root or reassembled history that is deserialized when
+    /// read. Bigger values are counted as `value_too_big_read` and treated as misses, so that a
+    /// huge corrupt value doesn't cost the time and memory of deserializing it. Unlimited if
+    /// `None`.
+    pub max_value_bytes: Option<usize>,
+    /// Compress each chunk of a chunked history separately with zstd at this level, so that a
+    /// chunk can be decompressed without the others. Chunks that compression wouldn't make
+    /// smaller are stored uncompressed, and counted as `chunk_compression_skipped`. Inline
+    /// histories and filenodes are never compressed. Disabled if `None`.
+    pub chunk_compression_level: Option<i32>,
+    /// Chunks smaller than this are stored uncompressed even if `chunk_compression_level` is set,
+    /// and counted as `chunk_compression_below_min`, as compressing them costs more CPU than the
+    /// space it saves is worth. Also applies to the last chunk of a history, which is smaller.
+    pub compress_min_bytes: usize,
+    /// Log one in this many failures to read a history root or chunk, with the memcache keys of
+    /// the root and of the chunk, so that the failure can be reproduced. Defaults to
+    /// `CHUNK_ERROR_LOG_SAMPLE_RATE` if `None`. Not logged if 0.
+    pub history_error_log_sample_rate: Option<u32>,
+    /// Whether histories are written with the path of each copy source stored once, rather than
+    /// with each filenode copied from it, which shrinks the histories of files that were moved.
+    /// Only caches that can read this format should write it. Histories written by
+    /// `fill_history_stream` are unaffected.
+    pub intern_history_paths: bool,
+    /// Read back one in this many filenodes and history roots right after they are written, and
+    /// count `fill_verify_fail` if they are missing or differ, e.g. to find out whether a pool is
+    /// dropping writes. Each verified fill costs an extra read. Disabled if 0.
+    pub fill_verify_sample_rate: u32,
+    /// Only update the hottest stats (hits and misses, and read latencies) for one in this many
+    /// reads, to save their cost on hosts with very high read rates. Counters are bumped by this
+    /// much when they are updated, so that their totals stay about right. The reads counted for
+    /// `hot_paths` and miss classification are sampled the same way. Exact if 0 or 1.
+    pub stat_sample_rate: u32,
+    /// Maximum number of fills running in the background at once. Fills past this are dropped
+    /// and counted as `fill_dropped_concurrency`, rather than queued, so that a burst of misses
+    /// on a cold cache can't flood the runtime with tasks. Unbounded if `None`.
+    pub max_concurrent_fills: Option<usize>,
+    /// Approximate size of the values held by the fills running in the background, past which
+    /// new fills are dropped and counted as `fill_dropped_bytes_budget`. This bounds the memory
+    /// they hold more directly than `max_concurrent_fills`, as a fill of a long history holds a
+    /// lot more than one of a filenode. The fill that crosses it still runs. Unbounded if `None`.
+    pub fill_bytes_budget: Option<usize>,
+    /// Whether concurrent `get_history` calls for the same history share a single read of its
+    /// root and chunks, rather than each reading them, to cut the load of hot paths under bursts.
+    /// Calls that waited for another one's read are counted as `gaf_read_coalesced`.
+    pub coalesce_history_reads: bool,
+    /// Number of filenodes most recently read from or written to memcache to keep in memory, so
+    /// that they can be served when memcache can't be read, counted as `served_stale_on_error`,
+    /// rather than missed. They are only served if they would be if read from memcache, e.g. not
+    /// past `hard_ttl`. They may have changed in memcache since, so they are read again in the
+    /// background. Filenodes are kept before they are written, so that this process can read
+    /// them back even if the write fails. Disabled if 0.
+    pub stale_fallback_capacity: usize,
+    /// Whether values that can't be read, e.g. because they don't deserialize, are deleted from
+    /// memcache after the read, and counted as `corrupt_evicted`, so that they are missed rather
+    /// than failing to read again until they expire. Otherwise, they are left in place.
+    pub evict_corrupt: bool,
+    /// Stops using memcache for a while after repeated failures to read from it, so that an
+    /// unhealthy pool doesn't add its timeouts to every request. Disabled if `None`.
+    pub circuit_breaker: Option<CircuitBreakerOptions>,
+    /// Overrides `MEMCACHE_VALUE_MAX_SIZE`, so that tests can write chunked histories without
+    /// building huge ones.
+    #[cfg(test)]
+    pub(crate) value_max_size: Option<usize>,
 }
 
-async fn get_single_filenode_from_memcache(
-    memcache: &MemcacheHandler,
-    keygen: &KeyGen,
-    key: &CacheKey<FilenodeInfo>,
-) -> Option<FilenodeInfo> {
-    let key = keygen.key(&key.key);
-
-    let serialized = match memcache.get(key).await {
-        Ok(Some(serialized)) => serialized,
-        Ok(None) => {
-            STATS::point_filenode_miss.add_value(1);
-            return None;
-        }
-        Err(_) => {
-            STATS::point_filenode_internal_err.add_value(1);
-            return None;
-        }
-    };
+/// Retries of memcache reads that fail. Misses are not retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct ReadRetryPolicy {
+    /// Retries after the first attempt.
+    pub retries: usize,
+    /// Delay before the first retry, doubled before each of the next ones.
+    pub backoff: Duration,
+}
 
-    let thrift = match compact_protocol::deserialize(&serialized) {
-        Ok(thrift) => thrift,
-        Err(_) => {
-            STATS::point_filenode_deserialize_err.add_value(1);
-            return None;
-        }
-    };
+/// A filenode's key, along with its memcache key, so that both can be computed once by
+/// `RemoteCache::filenode_key` and reused by several operations.
+#[derive(Clone)]
+pub struct FilenodeCacheKey {
+    key: CacheKey<FilenodeInfo>,
+    /// `None` if the key is too long for memcache, in which case operations using it are no-ops.
+    mc_key: Option<String>,
+}
 
-    let info = match FilenodeInfo::from_thrift(thrift) {
-        Ok(info) => info,
-        Err(_) => {
-            STATS::point_filenode_deserialize_err.add_value(1);
-            return None;
-        }
-    };
+/// Once a filenode has been missed by both the cache and the backing store `miss_threshold` times
+/// within `window`, `get_or_fill_filenode` treats it as absent for `ttl` without asking the
+/// backing store, which gives the backing store a break from requests that can't succeed. The
+/// absences are only remembered by this process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct NegativeCachingOptions {
+    pub miss_threshold: usize,
+    pub window: Duration,
+    /// Extended by a random amount of up to half of it, so that absences cached at the same time
+    /// don't all expire at once.
+    pub ttl: Duration,
+}
 
-    STATS::point_filenode_hit.add_value(1);
+/// Once `errors` reads of filenodes or history roots in a row have failed within `window`, the
+/// cache behaves like a no-op one for `cooldown`, counting `circuit_open` when it starts to, and
+/// `circuit_skipped` for each memcache operation skipped. A single operation is then let through
+/// to probe memcache: a read that succeeds counts `circuit_closed`, and one that fails starts
+/// another cooldown. The other operations are skipped for another cooldown in the meantime, after
+/// which another probe is let through if the first one hasn't reported, e.g. because it was a
+/// write. The breaker is only shared by the users of this `RemoteCache`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct CircuitBreakerOptions {
+    pub errors: usize,
+    pub window: Duration,
+    pub cooldown: Duration,
+}
 
-    Some(info)
+/// Histories written once within `max_ttl` live for `max_ttl`, and histories written more often
+/// live for `max_ttl` divided by the number of times they were written, but at least `min_ttl`,
+/// so that histories that keep being replaced don't take space for longer than they are useful.
+/// Writes are only counted by this process, for up to `max_paths` paths at a time, forgetting
+/// the paths written least recently past that. Repos with a TTL in `ttl_overrides` keep it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct AdaptiveTtlOptions {
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+    pub max_paths: usize,
 }
 
-async fn get_history_from_memcache(
-    memcache: &MemcacheHandler,
-    keygen: &KeyGen,
-    key: &CacheKey<FilenodeRange>,
-) -> Option<FilenodeRange> {
-    // helper function for deserializing list of thrift FilenodeInfo into rust structure with proper
-    // error returned
-    fn deserialize_list(list: Vec<thrift::FilenodeInfo>) -> Option<Vec<FilenodeInfo>> {
-        let res: Result<Vec<_>, _> = list.into_iter().map(FilenodeInfo::from_thrift).collect();
-        if res.is_err() {
-            STATS::gaf_deserialize_err.add_value(1);
+impl RemoteCacheOptions {
+    /// Size of the biggest value memcache accepts.
+    fn value_max_size(&self) -> usize {
+        #[cfg(test)]
+        if let Some(size) = self.value_max_size {
+            return size;
         }
-        res.ok()
+        MEMCACHE_VALUE_MAX_SIZE
     }
 
-    let serialized = match memcache.get(keygen.key(&key.key)).await {
-        Ok(Some(serialized)) => serialized,
-        Ok(None) => {
-            STATS::gaf_miss.add_value(1);
-            return None;
-        }
-        Err(_) => {
-            STATS::gaf_internal_err.add_value(1);
-            return None;
-        }
-    };
+    fn chunk_size(&self) -> usize {
+        let max_size = self.value_max_size();
+        self.chunk_size
+            .map_or(max_size, |size| size.clamp(1, max_size))
+    }
 
-    let thrift = match compact_protocol::deserialize(&serialized) {
-        Ok(thrift) => thrift,
-        Err(_) => {
-            STATS::gaf_deserialize_err.add_value(1);
-            return None;
+    fn path_hash_encoding(&self) -> PathHashEncoding {
+        PathHashEncoding {
+            key_bytes: self
+                .path_hash_key_bytes
+                .unwrap_or(DEFAULT_PATH_HASH_KEY_BYTES),
+            ..Default::default()
         }
-    };
+    }
 
-    let res = match thrift {
-        thrift::FilenodeInfoList::UnknownField(_) => {
-            STATS::gaf_deserialize_err.add_value(1);
-            return None;
-        }
-        thrift::FilenodeInfoList::Data(list) => {
-            deserialize_list(list).map(FilenodeRange::Filenodes)
-        }
-        thrift::FilenodeInfoList::Pointers(list) => {
-            STATS::gaf_pointers.add_value(1);
+    /// Size of the chunks a history is split into if that doesn't make for too many chunks.
+    fn preferred_chunk_size(&self) -> usize {
+        let chunk_size = self.chunk_size();
+        self.preferred_chunk_size
+            .map_or(chunk_size, |size| size.clamp(1, chunk_size))
+    }
 
-            let read_chunks_fut = list.into_iter().map(move |pointer| {
-                let chunk_key = get_mc_key_for_filenodes_list_chunk(keygen, key, pointer);
+    /// Whether to read back a value that is being written, see `fill_verify_sample_rate`.
+    fn sample_fill_verify(&self) -> bool {
+        self.fill_verify_sample_rate > 0 && thread_rng().gen_ratio(1, self.fill_verify_sample_rate)
+    }
 
-                async move {
-                    match memcache.get(chunk_key).await {
-                        Ok(Some(chunk)) => Ok(chunk),
-                        _ => Err(()),
-                    }
-                }
-            });
+    /// Whether to log a failure to read a history, see `history_error_log_sample_rate`.
+    fn sample_history_error_log(&self) -> bool {
+        let rate = self
+            .history_error_log_sample_rate
+            .unwrap_or(CHUNK_ERROR_LOG_SAMPLE_RATE);
+        rate > 0 && thread_rng().gen_ratio(1, rate)
+    }
 
-            let blob = match try_join_all(read_chunks_fut).await {
-                Ok(chunks) => chunks
-                    .into_iter()
-                    .flat_map(|b| b.into_iter())
-                    .collect::<Vec<u8>>(),
-                Err(_) => {
-                    STATS::gaf_pointers_err.add_value(1);
-                    return None;
-                }
-            };
+    /// What to add to a hot counter for this read, if it is sampled by `stat_sample_rate`.
+    fn stat_sample(&self) -> Option<i64> {
+        sample_weight(self.stat_sample_rate)
+    }
 
-            match compact_protocol::deserialize(&blob) {
-                Ok(thrift::FilenodeInfoList::Data(list)) => {
-                    deserialize_list(list).map(FilenodeRange::Filenodes)
-                }
-                Ok(thrift::FilenodeInfoList::TooBig(_)) => Some(FilenodeRange::TooBig),
-                _ => {
-                    STATS::gaf_pointers_err.add_value(1);
-                    None
-                }
-            }
+    /// Whether a value of `len` bytes read from memcache is too big to be deserialized.
+    fn value_too_big(&self, len: usize) -> bool {
+        let too_big = self.max_value_bytes.is_some_and(|max| len > max);
+        if too_big {
+            STATS::value_too_big_read.add_value(1);
         }
-        thrift::FilenodeInfoList::TooBig(_) => Some(FilenodeRange::TooBig),
-    };
+        too_big
+    }
 
-    if res.is_some() {
-        STATS::gaf_hit.add_value(1);
+    fn max_history_pointers(&self) -> usize {
+        self.max_history_pointers
+            .unwrap_or(DEFAULT_MAX_HISTORY_POINTERS)
     }
 
-    res
+    /// TTL of the histories of `repo_id`, before the random extension of their roots.
+    fn history_ttl(&self, repo_id: RepositoryId) -> Duration {
+        self.ttl_overrides
+            .get(&repo_id)
+            .copied()
+            .unwrap_or(Duration::from_secs(TTL_SEC))
+    }
 }
 
-fn schedule_fill_filenode(
-    memcache: &MemcacheHandler,
-    keygen: &KeyGen,
-    key: &CacheKey<FilenodeInfo>,
-    filenode: FilenodeInfo,
-) {
-    let serialized = compact_protocol::serialize(&filenode.into_thrift());
+/// What a `CacheEvent` reports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheEventKind {
+    /// A filenode was written.
+    FilenodeFill,
+    /// A history was written.
+    HistoryFill,
+    /// A filenode or history was read.
+    Hit,
+    /// A filenode or history was not found.
+    Miss,
+}
 
-    // Quite unlikely that single filenode will be bigger than MEMCACHE_VALUE_MAX_SIZE
-    // It's probably not even worth logging it
-    if serialized.len() < MEMCACHE_VALUE_MAX_SIZE {
-        let memcache = memcache.clone();
-        let key = keygen.key(&key.key);
-        let fut = async move {
-            let _ = memcache.set(key, serialized).await;
-        };
+/// Sent to the channel passed to `RemoteCache::with_events`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheEvent {
+    pub repo_id: RepositoryId,
+    /// Key of the value, without the prefix added for memcache.
+    pub key: String,
+    /// Size of the value, or 0 for a miss. For a chunked history, this is the size of the history
+    /// split into chunks.
+    pub bytes: usize,
+    pub kind: CacheEventKind,
+}
 
-        tokio::spawn(fut);
-    }
+/// Where a `RemoteCache` sends its events.
+#[derive(Clone)]
+struct EventSink {
+    sender: mpsc::Sender<CacheEvent>,
+    /// Whether reads are reported, as well as writes.
+    reads: bool,
 }
 
-fn schedule_fill_history(
-    memcache: MemcacheHandler,
-    keygen: KeyGen,
-    key: CacheKey<FilenodeRange>,
-    filenodes: FilenodeRange,
-) {
-    let fut = async move {
-        let _ = fill_history(&memcache, &keygen, &key, filenodes).await;
-    };
+impl EventSink {
+    fn send(&self, event: CacheEvent) {
+        // Never wait for the receiver, so that a slow receiver can't slow down the cache.
+        if self.sender.try_send(event).is_err() {
+            STATS::event_dropped.add_value(1);
+        }
+    }
+}
 
-    tokio::spawn(fut);
+/// An event to send once a fill has succeeded, when its size is known.
+struct PendingEvent {
+    sink: EventSink,
+    event: CacheEvent,
 }
 
-fn serialize_history(filenodes: FilenodeRange) -> Bytes {
-    let filenodes = match filenodes {
-        FilenodeRange::Filenodes(filenodes) => thrift::FilenodeInfoList::Data(
-            filenodes
-                .into_iter()
-                .map(|filenode_info| filenode_info.into_thrift())
-                .collect(),
-        ),
-        // Value in TooBig is ignored, so any value would work
-        FilenodeRange::TooBig => thrift::FilenodeInfoList::TooBig(0),
-    };
-    compact_protocol::serialize(&filenodes)
+impl PendingEvent {
+    fn send(mut self, bytes: usize) {
+        self.event.bytes = bytes;
+        self.sink.send(self.event);
+    }
 }
 
-async fn fill_history(
-    memcache: &MemcacheHandler,
-    keygen: &KeyGen,
-    key: &CacheKey<FilenodeRange>,
-    filenodes: FilenodeRange,
-) -> Result<(), ()> {
-    let serialized = serialize_history(filenodes);
+/// Number of fills a `RemoteCache` was asked to do, and how many of them were written. The others
+/// were skipped, e.g. because the cache was read-only or the value was too big, failed, or are
+/// still in flight.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FillStats {
+    pub attempted: u64,
+    pub written: u64,
+    /// Number of fills that started running in the background.
+    pub started: u64,
+    /// Total time fills waited between being spawned and starting to run, also recorded as the
+    /// `fill_spawn_lag_us` stat. High lag means the runtime is too busy to run them promptly.
+    pub spawn_lag: Duration,
+}
 
-    STATS::gaf_compact_bytes.add_value(serialized.len() as i64);
+impl FillStats {
+    pub fn skipped(&self) -> u64 {
+        self.attempted.saturating_sub(self.written)
+    }
+}
 
-    let root = if serialized.len() < MEMCACHE_VALUE_MAX_SIZE {
-        serialized
-    } else {
-        let write_chunks_fut = serialized
-            .chunks(MEMCACHE_VALUE_MAX_SIZE)
-            .map(Vec::from) // takes ownership
-            .zip(PointersIter::new())
-            .map({
-                move |(chunk, pointer)| {
-                    async move {
-                        let chunk_key = get_mc_key_for_filenodes_list_chunk(keygen, key, pointer);
+/// Hits, misses and bytes read by the reads of a single request, e.g. to attach to its logs. Passed
+/// to the `*_with_stats` methods, which bump it alongside the global stats.
+#[derive(Debug, Default)]
+pub struct CacheStatsAccumulator {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bytes_read: AtomicU64,
+}
 
-                        // give chunks non-random max TTL_SEC_RAND so that they always live
-                        // longer than the pointer
-                        let chunk_ttl = Duration::from_secs(TTL_SEC + TTL_SEC_RAND);
+impl CacheStatsAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-                        memcache
-                            .set_with_ttl(chunk_key, chunk, chunk_ttl)
-                            .await
-                            .map_err(drop)?;
+    pub fn stats(&self) -> RequestCacheStats {
+        RequestCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+        }
+    }
+}
 
-                        Ok(pointer)
-                    }
-                }
-            })
-            .collect::<Vec<_>>();
+/// What a `CacheStatsAccumulator` counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RequestCacheStats {
+    pub hits: u64,
+    /// Values that weren't cached. Values that couldn't be read aren't counted.
+    pub misses: u64,
+    /// Size of the values that were hit. For a chunked history, this excludes the root.
+    pub bytes_read: u64,
+}
 
-        let pointers = try_join_all(write_chunks_fut).await?;
-        compact_protocol::serialize(&thrift::FilenodeInfoList::Pointers(pointers))
-    };
+/// Returned by `RemoteCache::with_fill_tracker`, to wait for the fills the cache runs in the
+/// background, e.g. so that tests don't have to poll for them. Only the number of fills still
+/// running is kept.
+#[derive(Clone, Default)]
+pub struct FillTracker {
+    inner: Arc<FillTrackerInner>,
+}
 
-    let root_key = keygen.key(&key.key);
-    let root_ttl = Duration::from_secs(TTL_SEC + random::<u64>() % TTL_SEC_RAND);
+#[derive(Default)]
+struct FillTrackerInner {
+    pending: AtomicUsize,
+    idle: Notify,
+}
 
-    memcache
-        .set_with_ttl(root_key, root, root_ttl)
-        .await
-        .map_err(drop)?;
+impl FillTracker {
+    /// Wait until no fills are running, including the ones started while waiting.
+    pub async fn wait_for_fills(&self) {
+        loop {
+            let idle = self.inner.idle.notified();
+            futures::pin_mut!(idle);
+            // Registers for the notification before checking, so that it can't be missed.
+            idle.as_mut().enable();
+            if self.inner.pending.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            idle.await;
+        }
+    }
 
-    Ok(())
+    /// Count a fill as running until the returned guard is dropped.
+    fn track(&self) -> TrackedFill {
+        self.inner.pending.fetch_add(1, Ordering::SeqCst);
+        TrackedFill {
+            inner: self.inner.clone(),
+        }
+    }
 }
 
-/// Infinite iterator over unique and random i64 values
-struct PointersIter {
-    seen: HashSet<Pointer>,
+/// A fill counted by a `FillTracker`, until it is dropped.
+struct TrackedFill {
+    inner: Arc<FillTrackerInner>,
 }
 
-impl PointersIter {
-    fn new() -> Self {
-        Self {
-            seen: HashSet::new(),
+impl Drop for TrackedFill {
+    fn drop(&mut self) {
+        if self.inner.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.idle.notify_waiters();
         }
     }
 }
 
-impl Iterator for PointersIter {
-    type Item = Pointer;
+/// Returned by `RemoteCache::warm`, to follow the progress of the warmer, and stop it.
+pub struct Warmer {
+    warmed: Arc<AtomicU64>,
+    handle: JoinHandle<()>,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            let pointer = random();
-            if self.seen.insert(pointer) {
-                break Some(pointer);
-            }
-        }
+impl Warmer {
+    /// Number of histories fetched and written so far.
+    pub fn warmed(&self) -> u64 {
+        self.warmed.load(Ordering::Relaxed)
+    }
+
+    /// Stop the warmer. If a history was being written, it may be left with only some of its
+    /// chunks written, in which case its previous value, if any, is still the one read.
+    pub fn cancel(&self) {
+        self.handle.abort();
+    }
+
+    /// Wait until the warmer has gone through all of its entries, or was cancelled, and return
+    /// the number of histories it warmed.
+    pub async fn wait(self) -> u64 {
+        // The only error is the cancellation.
+        let _ = self.handle.await;
+        self.warmed.load(Ordering::Relaxed)
     }
 }
 
-#[cfg(test)]
-pub mod test {
-    use std::time::Duration;
+/// Returned by `RemoteCache::fill_filenode_with_handle`, to find out when that fill landed.
+/// Dropping it leaves the fill running.
+pub struct FillHandle {
+    written: Option<oneshot::Receiver<()>>,
+}
 
-    use anyhow::Error;
-    use fbinit::FacebookInit;
-    use mercurial_types_mocks::nodehash::ONES_CSID;
-    use mercurial_types_mocks::nodehash::ONES_FNID;
-    use mononoke_types::RepoPath;
-    use mononoke_types_mocks::repo::REPO_ZERO;
-    use path_hash::PathWithHash;
-    use tokio::time;
+impl FillHandle {
+    /// Wait until the fill is done, and return whether it was written. Fills that were skipped,
+    /// e.g. because the cache is read-only, or that failed, were not.
+    pub async fn wait(self) -> bool {
+        match self.written {
+            Some(written) => written.await.is_ok(),
+            None => false,
+        }
+    }
+}
 
-    use super::*;
-    use crate::reader::filenode_cache_key;
-    use crate::reader::history_cache_key;
+/// Where fills are run from.
+#[derive(Clone, Copy)]
+struct FillSpawner<'a> {
+    logger: &'a Logger,
+    tracker: Option<&'a FillTracker>,
+    counts: &'a Arc<FillCounts>,
+    permits: Option<&'a Arc<Semaphore>>,
+    bytes_in_flight: &'a Arc<AtomicUsize>,
+    bytes_budget: Option<usize>,
+}
 
-    const TIMEOUT_MS: u64 = 100;
-    const SLEEP_MS: u64 = 5;
+/// Bytes a fill running in the background counts against `fill_bytes_budget`, released when it
+/// completes, or if it is dropped.
+struct FillBytes {
+    in_flight: Arc<AtomicUsize>,
+    bytes: usize,
+}
 
-    fn filenode() -> FilenodeInfo {
-        FilenodeInfo {
-            filenode: ONES_FNID,
-            p1: None,
-            p2: None,
-            copyfrom: Some((RepoPath::file("copiedfrom").unwrap(), ONES_FNID)),
-            linknode: ONES_CSID,
-        }
+impl Drop for FillBytes {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(self.bytes, Ordering::Relaxed);
     }
+}
 
-    pub async fn wait_for_filenode(
-        cache: &RemoteCache,
+/// Counts behind `FillStats`, shared with the fills running in the background.
+#[derive(Default)]
+struct FillCounts {
+    attempted: AtomicU64,
+    written: AtomicU64,
+    started: AtomicU64,
+    spawn_lag_us: AtomicU64,
+}
+
+/// What to record once a fill has been written.
+struct FillCompletion {
+    counts: Arc<FillCounts>,
+    event: Option<PendingEvent>,
+    /// Where to tell a `FillHandle` that the fill was written, if it was asked for.
+    handle: Option<oneshot::Sender<()>>,
+    /// Where to remember the fill once it was written, if `recent_fills_capacity` is set.
+    recent_fill: Option<(Arc<RecentFills>, String, FilledValue)>,
+}
+
+impl FillCompletion {
+    fn written(self, bytes: usize) {
+        STATS::fill_written.add_value(1);
+        self.counts.written.fetch_add(1, Ordering::Relaxed);
+        if let Some((recent_fills, key, value)) = self.recent_fill {
+            recent_fills.record(key, value);
+        }
+        if let Some(event) = self.event {
+            event.send(bytes);
+        }
+        if let Some(handle) = self.handle {
+            // The handle may have been dropped.
+            let _ = handle.send(());
+        }
+    }
+}
+
+/// Effective configuration of a `RemoteCache`, for introspection.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[allow(clippy::large_enum_variant)] // only built on demand, never stored in bulk
+pub enum CacheConfigSummary {
+    /// The cache doesn't store anything.
+    Noop,
+    Memcache {
+        /// Prefix of all keys, including the code and site versions.
+        key_prefix: String,
+        ttl_secs: u64,
+        ttl_jitter_secs: u64,
+        chunk_size: usize,
+        preferred_chunk_size: usize,
+        refill_ttl_threshold_secs: Option<u64>,
+        rechunk_on_read: bool,
+        recent_fills_capacity: usize,
+        content_index: bool,
+        path_depth_stats: bool,
+        delete_replaced_chunks: bool,
+        soft_ttl_secs: Option<u64>,
+        hard_ttl_secs: Option<u64>,
+        lenient_conversion: bool,
+        canary_interval_secs: Option<u64>,
+        negative_caching: Option<NegativeCachingOptions>,
+        adaptive_ttl: Option<AdaptiveTtlOptions>,
+        read_retry: Option<ReadRetryPolicy>,
+        miss_classification_capacity: usize,
+        hot_paths_capacity: usize,
+        max_history_pointers: usize,
+        max_value_bytes: Option<usize>,
+        chunk_compression_level: Option<i32>,
+        fill_verify_sample_rate: u32,
+        stat_sample_rate: u32,
+        max_concurrent_fills: Option<usize>,
+        coalesce_history_reads: bool,
+        stale_fallback_capacity: usize,
+        evict_corrupt: bool,
+        circuit_breaker: Option<CircuitBreakerOptions>,
+        compress_min_bytes: usize,
+        history_error_log_sample_rate: Option<u32>,
+        fill_bytes_budget: Option<usize>,
+        intern_history_paths: bool,
+        filenode_checksum: bool,
+        min_history_entries: usize,
+        /// Indexed by repo id.
+        ttl_overrides_secs: BTreeMap<i32, u64>,
+        salt: Option<String>,
+        path_hash_key_bytes: usize,
+        read_only: bool,
+    },
+}
+
+/// Memcache-backed cache for filenodes and file histories.
+///
+/// The backend is chosen by the `CacheHandlerFactory` passed at construction time. Factories
+/// without a memcache client (`Noop` and `Local`) produce a cache for which every operation is a
+/// no-op, which is what standalone and test builds should use. Such builds can also turn off the
+/// `memcache` feature (on by default), which drops the memcache client, and with it the
+/// `Shared` factory and the `Real` handler, along with the `memcache` crate: `KeyGen` and the
+/// value size limit then come from `caching_ext`. `cargo test --no-default-features` builds and
+/// runs the tests that way.
+pub struct RemoteCache {
+    memcache: MemcacheHandler,
+    keygen: KeyGen,
+    /// Key builder of the previous key scheme, values under which are moved to the current one
+    /// when they are missing from it.
+    legacy_keygen: Option<LegacyKeygen>,
+    options: Arc<RemoteCacheOptions>,
+    recent_fills: Option<Arc<RecentFills>>,
+    absences: Option<Mutex<LruCache<String, Absence>>>,
+    /// Recent writes of each history, if `adaptive_ttl` is set.
+    churn: Option<Mutex<LruCache<String, Churn>>>,
+    recent_writes: Option<RecentWrites>,
+    access_frequency: Option<AccessFrequency>,
+    read_only: AtomicBool,
+    logger: Logger,
+    events: Option<EventSink>,
+    fill_counts: Arc<FillCounts>,
+    /// Permits of the fills running in the background, if `max_concurrent_fills` is set.
+    fill_permits: Option<Arc<Semaphore>>,
+    /// Size of the values held by the fills running in the background, see `fill_bytes_budget`.
+    fill_bytes_in_flight: Arc<AtomicUsize>,
+    fill_tracker: Option<FillTracker>,
+    /// Successful reads of each format, also counted as `format_read`.
+    format_reads: FormatReads,
+    /// Recent fills, reported as the `fill_rate_per_sec` gauge.
+    fill_rate: Mutex<SecondCounts>,
+    /// Recent reads and evicted misses, if `miss_classification_capacity` is set.
+    eviction_counts: Option<EvictionCounts>,
+    /// Returns the current Unix timestamp.
+    clock: Arc<dyn Fn() -> i64 + Send + Sync>,
+    /// Returns how much longer than their TTL history roots live, less than `TTL_SEC_RAND`.
+    ttl_jitter: Arc<dyn Fn() -> Duration + Send + Sync>,
+    /// `KILL_SWITCH`, except in tests, which can't share it.
+    kill_switch: &'static AtomicBool,
+    /// Repos for which the cache behaves like a no-op one, see `set_repo_disabled`.
+    disabled_repos: DisabledRepos,
+    /// Epoch recorded in the filenodes written, see `set_write_epoch`, or `NO_WRITE_EPOCH` until
+    /// one is set.
+    write_epoch: AtomicI64,
+    /// Calls waiting for the history read in flight under each root key, if
+    /// `coalesce_history_reads` is set.
+    history_reads: Mutex<HashMap<String, Vec<oneshot::Sender<CacheRead<FilenodeRange>>>>>,
+    /// Keys of the histories being written again after being read, see `refill_history`.
+    history_refills: Arc<Mutex<HashSet<String>>>,
+    /// Filenodes served when memcache can't be read, if `stale_fallback_capacity` is set.
+    stale_filenodes: Option<Arc<StaleFilenodes>>,
+    /// State of the breaker, if `circuit_breaker` is set.
+    circuit_breaker: Option<CircuitBreaker>,
+}
+
+impl RemoteCache {
+    pub fn new(
+        cache_handler_factory: &CacheHandlerFactory,
+        backing_store_name: &str,
+        backing_store_params: &str,
+    ) -> Self {
+        Self::new_with_options(
+            cache_handler_factory,
+            backing_store_name,
+            backing_store_params,
+            RemoteCacheOptions::default(),
+        )
+    }
+
+    pub fn new_with_options(
+        cache_handler_factory: &CacheHandlerFactory,
+        backing_store_name: &str,
+        backing_store_params: &str,
+        options: RemoteCacheOptions,
+    ) -> Self {
+        Self::from_parts(
+            cache_handler_factory.memcache(),
+            Self::create_key_gen(
+                backing_store_name,
+                backing_store_params,
+                options.salt.as_deref(),
+                None,
+            ),
+            options,
+        )
+    }
+
+    /// Like `new_with_options`, but with keys in the namespace of `sitever` instead of the
+    /// default `MC_SITEVER`, or the one set with `MONONOKE_OVERRIDE_FILENODES_MC_SITEVER`, e.g. to
+    /// read values from before or after a sitever bump.
+    pub fn new_with_sitever(
+        cache_handler_factory: &CacheHandlerFactory,
+        backing_store_name: &str,
+        backing_store_params: &str,
+        options: RemoteCacheOptions,
+        sitever: u32,
+    ) -> Self {
+        Self::from_parts(
+            cache_handler_factory.memcache(),
+            Self::create_key_gen(
+                backing_store_name,
+                backing_store_params,
+                options.salt.as_deref(),
+                Some(sitever),
+            ),
+            options,
+        )
+    }
+
+    /// Build a cache on top of a pre-configured handler, e.g. one with a different connection
+    /// pool, instead of the one `CacheHandlerFactory` would produce.
+    pub fn with_handler(memcache: MemcacheHandler, keygen: KeyGen) -> Self {
+        Self::with_handler_and_options(memcache, keygen, RemoteCacheOptions::default())
+    }
+
+    /// Like `with_handler`, with `options`. Nothing here needs a `FacebookInit`, so this is how
+    /// the cache can be embedded with a handler built elsewhere, e.g. outside of Meta.
+    pub fn with_handler_and_options(
+        memcache: MemcacheHandler,
+        keygen: KeyGen,
+        options: RemoteCacheOptions,
+    ) -> Self {
+        Self::from_parts(memcache, keygen, options)
+    }
+
+    fn from_parts(memcache: MemcacheHandler, keygen: KeyGen, options: RemoteCacheOptions) -> Self {
+        let recent_fills = (options.recent_fills_capacity > 0)
+            .then(|| Arc::new(RecentFills::new(options.recent_fills_capacity)));
+        let absences = options
+            .negative_caching
+            .map(|_| Mutex::new(LruCache::new(NEGATIVE_CACHE_MAX_KEYS)));
+        let churn = options
+            .adaptive_ttl
+            .map(|adaptive| Mutex::new(LruCache::new(adaptive.max_paths)));
+        let recent_writes = (options.miss_classification_capacity > 0)
+            .then(|| RecentWrites::new(options.miss_classification_capacity));
+        let eviction_counts =
+            (options.miss_classification_capacity > 0).then(EvictionCounts::default);
+        let access_frequency = (options.hot_paths_capacity > 0)
+            .then(|| AccessFrequency::new(options.hot_paths_capacity));
+        let fill_permits = options
+            .max_concurrent_fills
+            .map(|max| Arc::new(Semaphore::new(max)));
+        let stale_filenodes = (options.stale_fallback_capacity > 0)
+            .then(|| Arc::new(StaleFilenodes::new(options.stale_fallback_capacity)));
+        let circuit_breaker = options.circuit_breaker.map(CircuitBreaker::new);
+        let options = Arc::new(options);
+
+        if let Some(interval) = options.canary_interval {
+            if !memcache.is_noop() {
+                spawn_canary(&memcache, &keygen, &options, interval);
+            }
+        }
+
+        Self {
+            memcache,
+            keygen,
+            legacy_keygen: None,
+            options,
+            recent_fills,
+            absences,
+            churn,
+            recent_writes,
+            access_frequency,
+            read_only: AtomicBool::new(false),
+            logger: Logger::root(slog::Discard, o!()),
+            events: None,
+            fill_counts: Arc::new(FillCounts::default()),
+            fill_permits,
+            fill_bytes_in_flight: Arc::new(AtomicUsize::new(0)),
+            fill_tracker: None,
+            format_reads: FormatReads::default(),
+            fill_rate: Mutex::new(SecondCounts::new()),
+            eviction_counts,
+            clock: Arc::new(unix_timestamp),
+            ttl_jitter: Arc::new(random_ttl_jitter),
+            kill_switch: &KILL_SWITCH,
+            disabled_repos: DisabledRepos::default(),
+            write_epoch: AtomicI64::new(NO_WRITE_EPOCH),
+            history_reads: Mutex::new(HashMap::new()),
+            history_refills: Arc::new(Mutex::new(HashSet::new())),
+            stale_filenodes,
+            circuit_breaker,
+        }
+    }
+
+    pub fn with_logger(self, logger: Logger) -> Self {
+        Self { logger, ..self }
+    }
+
+    /// Look for values missing from the cache under the keys of `keygen`, the key builder of the
+    /// previous key scheme, and copy the ones found to the current key, so that the hit rate
+    /// doesn't drop while the cache is warmed up again. Only changes of prefix or sitever can be
+    /// migrated this way: the keys are otherwise built the same way under both schemes, and
+    /// values written with another codever can't be read with the current code, so they count as
+    /// misses. As each miss then costs another get, this stops after `window`, which should be
+    /// about as long as values live, as there is nothing left to migrate after that.
+    pub fn with_legacy_keygen(self, keygen: KeyGen, window: Duration) -> Self {
+        Self {
+            legacy_keygen: Some(LegacyKeygen {
+                keygen,
+                until: Instant::now() + window,
+            }),
+            ..self
+        }
+    }
+
+    /// Keep track of the fills run in the background, so that they can be waited for with the
+    /// returned `FillTracker`. Caches without one don't keep track of their fills at all.
+    pub fn with_fill_tracker(self) -> (Self, FillTracker) {
+        let tracker = FillTracker::default();
+        let cache = Self {
+            fill_tracker: Some(tracker.clone()),
+            ..self
+        };
+        (cache, tracker)
+    }
+
+    /// Report each value written to the cache to `sender` and, if `reads` is set, each filenode
+    /// or history read from it. Reporting is best effort: if the channel is full, events are
+    /// dropped and counted as `event_dropped`.
+    pub fn with_events(self, sender: mpsc::Sender<CacheEvent>, reads: bool) -> Self {
+        Self {
+            events: Some(EventSink { sender, reads }),
+            ..self
+        }
+    }
+
+    #[cfg(test)]
+    fn with_clock(self, clock: impl Fn() -> i64 + Send + Sync + 'static) -> Self {
+        Self {
+            clock: Arc::new(clock),
+            ..self
+        }
+    }
+
+    #[cfg(test)]
+    fn with_kill_switch(self, kill_switch: &'static AtomicBool) -> Self {
+        Self {
+            kill_switch,
+            ..self
+        }
+    }
+
+    #[cfg(test)]
+    fn with_ttl_jitter(self, ttl_jitter: impl Fn() -> Duration + Send + Sync + 'static) -> Self {
+        Self {
+            ttl_jitter: Arc::new(ttl_jitter),
+            ..self
+        }
+    }
+
+    /// TTL the value under `key` was last written with, if this is a mock cache.
+    #[cfg(test)]
+    fn applied_ttl<V>(&self, key: &CacheKey<V>) -> Option<Duration> {
+        match &self.memcache {
+            MemcacheHandler::Mock(store) => store.ttl(&self.keygen.key(&key.key)),
+            _ => None,
+        }
+    }
+
+    pub fn new_noop() -> Self {
+        Self::new(&CacheHandlerFactory::Noop, "newfilenodes", "")
+    }
+
+    #[cfg(test)]
+    pub fn new_mock() -> Self {
+        Self::new(&CacheHandlerFactory::Mocked, "newfilenodes", "test")
+    }
+
+    #[cfg(test)]
+    pub fn new_mock_with_options(options: RemoteCacheOptions) -> Self {
+        Self::new_with_options(
+            &CacheHandlerFactory::Mocked,
+            "newfilenodes",
+            "test",
+            options,
+        )
+    }
+
+    fn create_key_gen(
+        backing_store_name: &str,
+        backing_store_params: &str,
+        salt: Option<&str>,
+        sitever: Option<u32>,
+    ) -> KeyGen {
+        let mut key_prefix = format!(
+            "scm.mononoke.filenodes.{}.{}",
+            backing_store_name, backing_store_params,
+        );
+        if let Some(salt) = salt {
+            key_prefix.push_str(".salt.");
+            key_prefix.push_str(&escape_key_segment(salt.to_owned()));
+        }
+
+        let mc_sitever = sitever.unwrap_or_else(|| match std::env::var(SITEVER_OVERRIDE_VAR) {
+            Ok(v) => v.parse().unwrap_or(MC_SITEVER as u32),
+            Err(_) => MC_SITEVER as u32,
+        });
+
+        KeyGen::new(key_prefix, MC_CODEVER as u32, mc_sitever)
+    }
+
+    /// Namespace all the keys of this cache are in: the key prefix, followed by the code and site
+    /// versions. Hosts with different namespaces never read each other's values, so comparing
+    /// them is a quick way to rule out version skew, e.g. from a stray sitever override.
+    pub fn key_namespace(&self) -> String {
+        self.keygen.key("").trim_end_matches('.').to_string()
+    }
+
+    /// Key of a filenode, with as much of the path hash as `path_hash_key_bytes` keeps.
+    pub(crate) fn filenode_cache_key(
+        &self,
+        repo_id: RepositoryId,
+        pwh: &PathWithHash<'_>,
+        filenode: &HgFileNodeId,
+    ) -> CacheKey<FilenodeInfo> {
+        filenode_cache_key_with_encoding(repo_id, pwh, filenode, self.options.path_hash_encoding())
+    }
+
+    /// Key of a history, with as much of the path hash as `path_hash_key_bytes` keeps.
+    pub(crate) fn history_cache_key(
+        &self,
+        repo_id: RepositoryId,
+        pwh: &PathWithHash<'_>,
+        limit: Option<u64>,
+    ) -> CacheKey<FilenodeRange> {
+        history_cache_key_with_encoding(repo_id, pwh, limit, self.options.path_hash_encoding())
+    }
+
+    /// Compute the key of a filenode once, for callers doing several operations on it with
+    /// `get_filenode_by_key` and `fill_filenode_by_key`.
+    pub fn filenode_key(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+        filenode_id: HgFileNodeId,
+    ) -> FilenodeCacheKey {
+        let pwh = PathWithHash::from_repo_path(path);
+        let key = self.filenode_cache_key(repo_id, &pwh, &filenode_id);
+        let mc_key = get_mc_key(&self.keygen, &key.key).ok();
+        FilenodeCacheKey { key, mc_key }
+    }
+
+    // TODO: Can we optimize to reuse the existing PathWithHash we got?
+    pub async fn get_filenode(&self, key: &CacheKey<FilenodeInfo>) -> Option<FilenodeInfo> {
+        self.read_filenode(key).await.hit()
+    }
+
+    /// Like `get_filenode`, but tells corrupt values and failed reads apart from misses.
+    pub async fn read_filenode(&self, key: &CacheKey<FilenodeInfo>) -> CacheRead<FilenodeInfo> {
+        match get_mc_key(&self.keygen, &key.key) {
+            Ok(mc_key) => self.get_filenode_at(key, mc_key, None, None).await,
+            Err(_) => CacheRead::Miss,
+        }
+    }
+
+    /// Like `get_filenode`, but returns an error rather than `None` if memcache can't be read or
+    /// the filenode can't be deserialized.
+    pub async fn try_get_filenode(
+        &self,
         key: &CacheKey<FilenodeInfo>,
-    ) -> Result<FilenodeInfo, Error> {
-        let r = time::timeout(Duration::from_millis(TIMEOUT_MS), async {
-            loop {
-                match cache.get_filenode(key).await {
-                    Some(f) => {
-                        break f;
+    ) -> Result<Option<FilenodeInfo>, CacheError> {
+        self.read_filenode(key).await.into_result()
+    }
+
+    /// Like `get_filenode`, but also counts the read in `stats`.
+    pub async fn get_filenode_with_stats(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        stats: &CacheStatsAccumulator,
+    ) -> Option<FilenodeInfo> {
+        let mc_key = get_mc_key(&self.keygen, &key.key).ok()?;
+        self.get_filenode_at(key, mc_key, Some(stats), None)
+            .await
+            .hit()
+    }
+
+    /// Like `get_filenode`, but if `bypass` is set, returns `None` without reading from memcache,
+    /// for callers that know the cache won't help, e.g. one-off scans.
+    pub async fn get_filenode_with_bypass(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        bypass: bool,
+    ) -> Option<FilenodeInfo> {
+        if bypass {
+            STATS::bypassed.add_value(1);
+            return None;
+        }
+        self.get_filenode(key).await
+    }
+
+    /// Like `get_filenode`, but with a key computed by `filenode_key`.
+    pub async fn get_filenode_by_key(&self, key: &FilenodeCacheKey) -> Option<FilenodeInfo> {
+        let mc_key = key.mc_key.clone()?;
+        self.get_filenode_at(&key.key, mc_key, None, None)
+            .await
+            .hit()
+    }
+
+    /// Like `get_filenode`, but only returns the cached filenode if it was written at or after
+    /// `epoch`, see `set_write_epoch`. Filenodes that don't record an epoch were written before
+    /// all of them. Older filenodes are counted as `point_filenode_before_epoch` and missed.
+    pub async fn get_filenode_since(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+        filenode_id: HgFileNodeId,
+        epoch: i64,
+    ) -> Option<FilenodeInfo> {
+        let pwh = PathWithHash::from_repo_path(path);
+        let key = self.filenode_cache_key(repo_id, &pwh, &filenode_id);
+        let mc_key = get_mc_key(&self.keygen, &key.key).ok()?;
+        self.get_filenode_at(&key, mc_key, None, Some(epoch))
+            .await
+            .hit()
+    }
+
+    /// Like `get_filenode_if`, accepting the cached filenode only if its parents are
+    /// `expected_parents`, for consistency audits of the DAG. Cached filenodes with other parents
+    /// are stale or corrupt, and are also counted as `point_filenode_parent_mismatch`.
+    pub async fn get_filenode_validated(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+        filenode_id: HgFileNodeId,
+        expected_parents: (Option<HgFileNodeId>, Option<HgFileNodeId>),
+    ) -> Option<FilenodeInfo> {
+        self.get_filenode_if(repo_id, path, filenode_id, |info| {
+            let matches = (info.p1, info.p2) == expected_parents;
+            if !matches {
+                STATS::point_filenode_parent_mismatch.add_value(1);
+            }
+            matches
+        })
+        .await
+    }
+
+    /// Like `get_filenode`, but only returns the cached filenode if `pred` accepts it, e.g. to
+    /// reject filenodes whose linknode is known to be bogus during an audit. Cached filenodes that
+    /// it rejects are counted as `point_filenode_rejected` and missed.
+    pub async fn get_filenode_if(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+        filenode_id: HgFileNodeId,
+        pred: impl Fn(&FilenodeInfo) -> bool,
+    ) -> Option<FilenodeInfo> {
+        let pwh = PathWithHash::from_repo_path(path);
+        let key = self.filenode_cache_key(repo_id, &pwh, &filenode_id);
+        let info = self.get_filenode(&key).await?;
+
+        if !pred(&info) {
+            STATS::point_filenode_rejected.add_value(1);
+            return None;
+        }
+
+        Some(info)
+    }
+
+    async fn get_filenode_at(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        mc_key: String,
+        stats: Option<&CacheStatsAccumulator>,
+        since: Option<i64>,
+    ) -> CacheRead<FilenodeInfo> {
+        let now = Instant::now();
+
+        let ctx = self.read_context(stats);
+        let ret =
+            get_single_filenode_from_memcache(self.repo_memcache(key.repo_id), mc_key, key, ctx)
+                .await;
+        self.finish_filenode_read(key, ret, ctx, since, now).await
+    }
+
+    /// What to return for a filenode read from memcache as `ret`: it is migrated from the legacy
+    /// keys if it was missed, served from `stale_filenodes` if memcache couldn't be read, and
+    /// checked against `since` and its TTLs.
+    async fn finish_filenode_read(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        mut ret: CacheRead<CachedFilenode>,
+        ctx: ReadContext<'_>,
+        since: Option<i64>,
+        now: Instant,
+    ) -> CacheRead<FilenodeInfo> {
+        if let (CacheRead::Miss, Some(legacy_keygen)) = (&ret, self.legacy_keygen()) {
+            ret = self.migrate_filenode(legacy_keygen, key, ctx).await;
+        }
+
+        if ctx.sample.is_some() {
+            let elapsed = now.elapsed().as_micros_unchecked() as i64;
+            STATS::get_latency.add_value(elapsed);
+        }
+
+        let cached = match ret {
+            CacheRead::Hit(cached) => cached,
+            CacheRead::Miss => return CacheRead::Miss,
+            CacheRead::Corrupt => {
+                self.evict_corrupt(key);
+                return CacheRead::Corrupt;
+            }
+            CacheRead::Failed => return self.serve_stale_filenode(key, since),
+        };
+
+        self.check_filenode(key, cached, since)
+    }
+
+    /// `cached` if it can be served: written at epoch `since` or later if set, and not past its
+    /// hard TTL. It is refreshed if it is past its soft TTL.
+    fn check_filenode(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        cached: CachedFilenode,
+        since: Option<i64>,
+    ) -> CacheRead<FilenodeInfo> {
+        if let Some(since) = since {
+            if cached.epoch.is_none_or(|epoch| epoch < since) {
+                STATS::point_filenode_before_epoch.add_value(1);
+                return CacheRead::Miss;
+            }
+        }
+
+        match self.freshness(&cached) {
+            Freshness::Fresh => CacheRead::Hit(cached.info),
+            Freshness::Stale => {
+                STATS::point_filenode_stale.add_value(1);
+                self.refill_filenode(key, &cached);
+                CacheRead::Hit(cached.info)
+            }
+            Freshness::Expired => {
+                STATS::point_filenode_expired.add_value(1);
+                CacheRead::Miss
+            }
+        }
+    }
+
+    /// Delete the value under `key`, which couldn't be read, in the background if `evict_corrupt`
+    /// is set, so that the read doesn't wait for it. For a chunked history, only the root is
+    /// deleted, which is enough for it to be missed.
+    fn evict_corrupt<V>(&self, key: &CacheKey<V>) {
+        if !self.options.evict_corrupt || !self.should_fill() {
+            return;
+        }
+        if let Ok(mc_key) = get_mc_key(&self.keygen, &key.key) {
+            let memcache = self.repo_memcache(key.repo_id).clone();
+            spawn_fill(self.spawner(), 0, async move {
+                if memcache.del(mc_key).await.is_ok() {
+                    STATS::corrupt_evicted.add_value(1);
+                }
+            });
+        }
+    }
+
+    /// The filenode last read from or written to memcache under `key`, if memcache can't be read
+    /// but it is kept in `stale_filenodes`, and it passes the same checks as if it had been read.
+    /// It is read again in the background, so that it is kept up to date.
+    fn serve_stale_filenode(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        since: Option<i64>,
+    ) -> CacheRead<FilenodeInfo> {
+        let stale_filenodes = match &self.stale_filenodes {
+            Some(stale_filenodes) => stale_filenodes.clone(),
+            None => return CacheRead::Failed,
+        };
+        let cached = match stale_filenodes.get(&key.key) {
+            Some(serialized) => match decode_filenode(&serialized, key.repo_id, &self.options) {
+                CacheRead::Hit((cached, ..)) => cached,
+                _ => return CacheRead::Failed,
+            },
+            None => return CacheRead::Failed,
+        };
+        let info = match self.check_filenode(key, cached, since) {
+            CacheRead::Hit(info) => info,
+            _ => return CacheRead::Failed,
+        };
+        STATS::served_stale_on_error.add_value(1);
+
+        if let Ok(mc_key) = get_mc_key(&self.keygen, &key.key) {
+            let memcache = self.repo_memcache(key.repo_id).clone();
+            let options = self.options.clone();
+            let key = key.clone();
+            spawn_fill(self.spawner(), 0, async move {
+                match get_with_retry(&memcache, mc_key, options.read_retry).await {
+                    Ok(Some(serialized)) => {
+                        match decode_filenode(&serialized, key.repo_id, &options) {
+                            CacheRead::Hit(..) => stale_filenodes.record(&key.key, &serialized),
+                            _ => stale_filenodes.remove(&key.key),
+                        }
                     }
-                    None => {}
+                    Ok(None) => stale_filenodes.remove(&key.key),
+                    Err(_) => {}
+                }
+            });
+        }
+
+        CacheRead::Hit(info)
+    }
+
+    /// Key builder of the previous key scheme, while values are still migrated from it.
+    fn legacy_keygen(&self) -> Option<&KeyGen> {
+        self.legacy_keygen
+            .as_ref()
+            .filter(|legacy| Instant::now() < legacy.until)
+            .map(|legacy| &legacy.keygen)
+    }
+
+    /// Read the filenode under `key` with the previous key scheme, and if it's there, fill it
+    /// under the current one, keeping the time it was written.
+    async fn migrate_filenode(
+        &self,
+        legacy_keygen: &KeyGen,
+        key: &CacheKey<FilenodeInfo>,
+        ctx: ReadContext<'_>,
+    ) -> CacheRead<CachedFilenode> {
+        let legacy_mc_key = match get_mc_key(legacy_keygen, &key.key) {
+            Ok(legacy_mc_key) => legacy_mc_key,
+            Err(_) => return CacheRead::Miss,
+        };
+        let ctx = ctx.for_legacy_read();
+        match get_single_filenode_from_memcache(
+            self.repo_memcache(key.repo_id),
+            legacy_mc_key,
+            key,
+            ctx,
+        )
+        .await
+        {
+            CacheRead::Hit(cached) => {
+                STATS::legacy_key_migrated.add_value(1);
+                self.refill_filenode(key, &cached);
+                CacheRead::Hit(cached)
+            }
+            CacheRead::Miss | CacheRead::Corrupt | CacheRead::Failed => CacheRead::Miss,
+        }
+    }
+
+    fn freshness(&self, cached: &CachedFilenode) -> Freshness {
+        let written_at = match cached.written_at {
+            Some(written_at) => written_at,
+            None => return Freshness::Fresh,
+        };
+        let age = Duration::from_secs((self.clock)().saturating_sub(written_at).max(0) as u64);
+
+        if self.options.hard_ttl.is_some_and(|ttl| age >= ttl) {
+            Freshness::Expired
+        } else if cached.soft_ttl.is_some_and(|ttl| age >= ttl) {
+            Freshness::Stale
+        } else {
+            Freshness::Fresh
+        }
+    }
+
+    fn cached_filenode(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        filenode: FilenodeInfo,
+        epoch: Option<i64>,
+    ) -> thrift::CachedFilenodeInfo {
+        thrift::CachedFilenodeInfo {
+            repo_id: key.repo_id.id(),
+            info: filenode.into_thrift(),
+            written_at: Some((self.clock)()),
+            soft_ttl_secs: self.options.soft_ttl.map(|ttl| ttl.as_secs() as i64),
+            epoch,
+        }
+    }
+
+    /// `cached` as written back when it is refreshed, with the epoch and time it was first written
+    /// at, as it is no more recent than it was then, so that the hard TTL still bounds how long it
+    /// is served. Its soft TTL is extended, so that it is next refreshed a soft TTL from now.
+    fn refreshed_filenode(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        cached: &CachedFilenode,
+    ) -> thrift::CachedFilenodeInfo {
+        let now = (self.clock)();
+        let written_at = cached.written_at.unwrap_or(now);
+        let age = now.saturating_sub(written_at).max(0);
+        thrift::CachedFilenodeInfo {
+            repo_id: key.repo_id.id(),
+            info: cached.info.clone().into_thrift(),
+            written_at: Some(written_at),
+            soft_ttl_secs: self.options.soft_ttl.map(|ttl| ttl.as_secs() as i64 + age),
+            epoch: cached.epoch,
+        }
+    }
+
+    /// Set the epoch recorded in the filenodes written from now on, so that readers can use
+    /// `get_filenode_since` to skip the ones written before, e.g. to invalidate them all after a
+    /// known invalidation point without deleting any key. Epochs should only be advanced.
+    pub fn set_write_epoch(&self, epoch: i64) {
+        self.write_epoch.store(epoch, Ordering::Relaxed);
+    }
+
+    /// The epoch to record in the filenodes written, if one was set. Until then, filenodes are
+    /// written without one, like they were before epochs were recorded.
+    fn write_epoch(&self) -> Option<i64> {
+        let epoch = self.write_epoch.load(Ordering::Relaxed);
+        (epoch != NO_WRITE_EPOCH).then_some(epoch)
+    }
+
+    // TODO: Need to use the same CacheKey here.
+    pub fn fill_filenode(&self, key: &CacheKey<FilenodeInfo>, filenode: FilenodeInfo) {
+        self.record_attempt();
+        if self.should_fill() {
+            if let Ok(mc_key) = get_mc_key(&self.keygen, &key.key) {
+                self.fill_filenode_at(key, mc_key, filenode, None, None);
+            }
+        }
+    }
+
+    /// Write back a filenode that was read from the cache, see `refreshed_filenode`.
+    fn refill_filenode(&self, key: &CacheKey<FilenodeInfo>, cached: &CachedFilenode) {
+        self.record_attempt();
+        if self.should_fill() {
+            if let Ok(mc_key) = get_mc_key(&self.keygen, &key.key) {
+                self.fill_filenode_at(key, mc_key, cached.info.clone(), Some(cached), None);
+            }
+        }
+    }
+
+    /// Like `fill_filenode`, but returns a handle that can be waited on to find out when the fill
+    /// landed, e.g. to read the filenode back right after, without polling.
+    pub fn fill_filenode_with_handle(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        filenode: FilenodeInfo,
+    ) -> FillHandle {
+        self.record_attempt();
+        if !self.should_fill() {
+            return FillHandle { written: None };
+        }
+        let mc_key = match get_mc_key(&self.keygen, &key.key) {
+            Ok(mc_key) => mc_key,
+            Err(_) => return FillHandle { written: None },
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        self.fill_filenode_at(key, mc_key, filenode, None, Some(sender));
+        FillHandle {
+            written: Some(receiver),
+        }
+    }
+
+    /// Like `fill_filenode`, but if `bypass` is set, writes nothing, so that values read once
+    /// don't take the place of ones that are read often. Bypassed fills aren't counted in
+    /// `fill_stats`.
+    pub fn fill_filenode_with_bypass(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        filenode: FilenodeInfo,
+        bypass: bool,
+    ) {
+        if bypass {
+            STATS::bypassed.add_value(1);
+            return;
+        }
+        self.fill_filenode(key, filenode)
+    }
+
+    /// Like `fill_filenode`, but with a key computed by `filenode_key`.
+    pub fn fill_filenode_by_key(&self, key: &FilenodeCacheKey, filenode: FilenodeInfo) {
+        self.record_attempt();
+        if self.should_fill() {
+            if let Some(mc_key) = &key.mc_key {
+                self.fill_filenode_at(&key.key, mc_key.clone(), filenode, None, None);
+            }
+        }
+    }
+
+    /// Write `filenode`, as a refresh of the value it was read from if `refreshed` is set.
+    fn fill_filenode_at(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        mc_key: String,
+        filenode: FilenodeInfo,
+        refreshed: Option<&CachedFilenode>,
+        handle: Option<oneshot::Sender<()>>,
+    ) {
+        self.record_fill(&key.key);
+        let recent_fill = self.recent_fill(key, || FilledValue::Filenode(filenode.clone()));
+        let value = match refreshed {
+            Some(cached) => self.refreshed_filenode(key, cached),
+            None => self.cached_filenode(key, filenode, self.write_epoch()),
+        };
+        let serialized = serialize_filenode(&value, self.options.filenode_checksum);
+        // Kept before the write is even scheduled, so that it can be served if the write fails.
+        if let Some(stale_filenodes) = &self.stale_filenodes {
+            stale_filenodes.record(&key.key, &serialized);
+        }
+        let completion = FillCompletion {
+            handle,
+            recent_fill,
+            ..self.fill_completion(key, CacheEventKind::FilenodeFill)
+        };
+        schedule_fill_filenode(
+            self.spawner(),
+            self.repo_memcache(key.repo_id),
+            mc_key,
+            serialized,
+            &self.options,
+            self.options.ttl_overrides.get(&key.repo_id).copied(),
+            completion,
+        );
+    }
+
+    /// Look up filenodes for many paths at once. Each path is hashed once for all of its filenodes,
+    /// and the filenodes are read with a get each, issued concurrently. Only the filenodes that
+    /// were found are returned, grouped by path.
+    pub async fn get_filenodes_grouped(
+        &self,
+        repo_id: RepositoryId,
+        by_path: &[(&RepoPath, Vec<HgFileNodeId>)],
+    ) -> HashMap<RepoPath, HashMap<HgFileNodeId, FilenodeInfo>> {
+        self.get_filenodes_grouped_with(repo_id, by_path, PathWithHash::from_repo_path)
+            .await
+    }
+
+    async fn get_filenodes_grouped_with<'a>(
+        &self,
+        repo_id: RepositoryId,
+        by_path: &[(&'a RepoPath, Vec<HgFileNodeId>)],
+        mut hash_path: impl FnMut(&'a RepoPath) -> PathWithHash<'a>,
+    ) -> HashMap<RepoPath, HashMap<HgFileNodeId, FilenodeInfo>> {
+        let keys = by_path
+            .iter()
+            .flat_map(|(path, filenodes)| {
+                let pwh = hash_path(path);
+                filenodes
+                    .iter()
+                    .map(|filenode| {
+                        (
+                            *path,
+                            *filenode,
+                            self.filenode_cache_key(repo_id, &pwh, filenode),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let found = join_all(keys.iter().map(|(path, filenode, key)| async move {
+            let info = self.get_filenode(key).await?;
+            Some((*path, *filenode, info))
+        }))
+        .await;
+
+        let mut grouped: HashMap<RepoPath, HashMap<HgFileNodeId, FilenodeInfo>> = HashMap::new();
+        for (path, filenode, info) in found.into_iter().flatten() {
+            grouped
+                .entry(path.clone())
+                .or_default()
+                .insert(filenode, info);
+        }
+        grouped
+    }
+
+    /// Like `fill_filenode`, but if `content_index` is enabled, also store the filenode under its
+    /// content id. This is a secondary index: it doubles the number of writes, and if the same
+    /// content is filled at several paths, the index points to whichever was filled last.
+    pub fn fill_filenode_with_content(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        filenode: FilenodeInfo,
+        content_id: &ContentId,
+    ) {
+        if self.options.content_index {
+            self.record_attempt();
+        }
+        if self.options.content_index && self.should_fill() {
+            let content_key = filenode_content_cache_key(key.repo_id, content_id);
+            if let Ok(mc_key) = get_mc_key(&self.keygen, &content_key.key) {
+                self.record_write(&content_key.key);
+                let value =
+                    self.cached_filenode(&content_key, filenode.clone(), self.write_epoch());
+                let serialized = serialize_filenode(&value, self.options.filenode_checksum);
+                let completion = FillCompletion {
+                    recent_fill: self
+                        .recent_fill(&content_key, || FilledValue::Filenode(filenode.clone())),
+                    ..self.fill_completion(&content_key, CacheEventKind::FilenodeFill)
+                };
+                schedule_fill_filenode(
+                    self.spawner(),
+                    self.repo_memcache(key.repo_id),
+                    mc_key,
+                    serialized,
+                    &self.options,
+                    self.options.ttl_overrides.get(&key.repo_id).copied(),
+                    completion,
+                );
+            }
+        }
+
+        self.fill_filenode(key, filenode);
+    }
+
+    /// Look up a filenode in the secondary index populated by `fill_filenode_with_content`.
+    /// Always misses if `content_index` is disabled.
+    pub async fn get_filenode_by_content(
+        &self,
+        repo_id: RepositoryId,
+        content_id: &ContentId,
+    ) -> Option<FilenodeInfo> {
+        if !self.options.content_index {
+            return None;
+        }
+
+        self.get_filenode(&filenode_content_cache_key(repo_id, content_id))
+            .await
+    }
+
+    /// Read-through helper: returns the cached filenode if there is one, and otherwise calls
+    /// `fill` to fetch it from the backing store and schedules a fill of the cache with the
+    /// result. The time spent in `fill` is recorded separately from the cache latency.
+    pub async fn get_or_fill_filenode<F, Fut>(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        fill: F,
+    ) -> Result<Option<FilenodeInfo>, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<FilenodeInfo>, Error>>,
+    {
+        let (_backfill_latency, ret) = self.get_or_fill_filenode_timed(key, fill).await;
+        ret
+    }
+
+    /// Same as `get_or_fill_filenode`, but also returns how long the backing store took, if it
+    /// was called at all.
+    async fn get_or_fill_filenode_timed<F, Fut>(
+        &self,
+        key: &CacheKey<FilenodeInfo>,
+        fill: F,
+    ) -> (Option<Duration>, Result<Option<FilenodeInfo>, Error>)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Option<FilenodeInfo>, Error>>,
+    {
+        if let Some(filenode) = self.get_filenode(key).await {
+            return (None, Ok(Some(filenode)));
+        }
+
+        if self.is_known_absent(&key.key) {
+            STATS::negative_hit.add_value(1);
+            return (None, Ok(None));
+        }
+
+        let now = Instant::now();
+
+        let ret = fill().await;
+
+        let elapsed = now.elapsed();
+        STATS::backfill_latency.add_value(elapsed.as_micros_unchecked() as i64);
+
+        match &ret {
+            Ok(Some(filenode)) => {
+                self.record_backing_result(&key.key, true);
+                self.fill_filenode(key, filenode.clone());
+            }
+            Ok(None) => self.record_backing_result(&key.key, false),
+            Err(_) => {}
+        }
+
+        (Some(elapsed), ret)
+    }
+
+    pub async fn get_history(&self, key: &CacheKey<FilenodeRange>) -> Option<FilenodeRange> {
+        self.read_history(key).await.hit()
+    }
+
+    /// Like `get_history`, but tells corrupt values and failed reads apart from misses.
+    pub async fn read_history(&self, key: &CacheKey<FilenodeRange>) -> CacheRead<FilenodeRange> {
+        if !self.options.coalesce_history_reads {
+            return self.read_history_alone(key).await;
+        }
+        let root_key = match get_mc_key(&self.keygen, &key.key) {
+            Ok(root_key) => root_key,
+            Err(_) => return CacheRead::Miss,
+        };
+
+        let waiting = {
+            let mut reads = self.history_reads.lock().expect("poisoned lock");
+            match reads.get_mut(&root_key) {
+                Some(waiters) => {
+                    let (sender, receiver) = oneshot::channel();
+                    waiters.push(sender);
+                    Some(receiver)
+                }
+                None => {
+                    reads.insert(root_key.clone(), Vec::new());
+                    None
+                }
+            }
+        };
+        if let Some(waiting) = waiting {
+            STATS::gaf_read_coalesced.add_value(1);
+            // If the read we waited for was cancelled, read the history ourselves.
+            return match waiting.await {
+                Ok(ret) => ret,
+                Err(_) => self.read_history_alone(key).await,
+            };
+        }
+
+        let in_flight = InFlightHistoryRead {
+            reads: &self.history_reads,
+            root_key: Some(root_key),
+        };
+        let ret = self.read_history_alone(key).await;
+        for waiter in in_flight.finish() {
+            let _ = waiter.send(ret.clone());
+        }
+        ret
+    }
+
+    /// Like `read_history`, but never shares the read with concurrent calls.
+    async fn read_history_alone(&self, key: &CacheKey<FilenodeRange>) -> CacheRead<FilenodeRange> {
+        match self.get_cached_history(key, None, &mut Vec::new()).await {
+            CacheRead::Hit(history) => CacheRead::Hit(history.range),
+            CacheRead::Miss => CacheRead::Miss,
+            CacheRead::Corrupt => CacheRead::Corrupt,
+            CacheRead::Failed => CacheRead::Failed,
+        }
+    }
+
+    /// Like `get_history`, but returns an error rather than `None` if memcache can't be read or
+    /// the history can't be deserialized, e.g. for callers that would rather not refetch from the
+    /// backing store when the pool is down.
+    pub async fn try_get_history(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+    ) -> Result<Option<FilenodeRange>, CacheError> {
+        self.read_history(key).await.into_result()
+    }
+
+    /// Like `get_history`, but also returns how the history was stored.
+    pub async fn get_history_with_layout(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+    ) -> (Option<FilenodeRange>, HistoryLayout) {
+        match self
+            .get_cached_history(key, None, &mut Vec::new())
+            .await
+            .hit()
+        {
+            Some(history) => (Some(history.range), history.layout),
+            None => (None, HistoryLayout::Missed),
+        }
+    }
+
+    /// Like `get_history`, but also counts the read in `stats`.
+    pub async fn get_history_with_stats(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+        stats: &CacheStatsAccumulator,
+    ) -> Option<FilenodeRange> {
+        let history = self
+            .get_cached_history(key, Some(stats), &mut Vec::new())
+            .await
+            .hit()?;
+        Some(history.range)
+    }
+
+    /// Like `get_history`, but if `bypass` is set, returns `None` without reading from memcache.
+    /// See `get_filenode_with_bypass`.
+    pub async fn get_history_with_bypass(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+        bypass: bool,
+    ) -> Option<FilenodeRange> {
+        if bypass {
+            STATS::bypassed.add_value(1);
+            return None;
+        }
+        self.get_history(key).await
+    }
+
+    /// Like `get_history`, but reassembles chunked histories in `scratch` rather than in a newly
+    /// allocated buffer. The caller keeps ownership of `scratch`: it is cleared before use, and
+    /// its contents afterwards are unspecified, but its capacity is kept, so reusing the same
+    /// buffer (e.g. one per worker, or one taken from a pool) for many reads saves an allocation
+    /// per chunked history once it has grown to fit the largest one. Inline and streamed
+    /// histories don't need reassembling and leave it untouched.
+    pub async fn get_history_with_scratch(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+        scratch: &mut Vec<u8>,
+    ) -> Option<FilenodeRange> {
+        let history = self.get_cached_history(key, None, scratch).await.hit()?;
+        Some(history.range)
+    }
+
+    async fn get_cached_history(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+        stats: Option<&CacheStatsAccumulator>,
+        scratch: &mut Vec<u8>,
+    ) -> CacheRead<CachedHistory> {
+        let now = Instant::now();
+
+        let ctx = self.read_context(stats);
+        let mut ret = get_history_from_memcache(
+            &self.logger,
+            self.repo_memcache(key.repo_id),
+            &self.keygen,
+            key,
+            ctx,
+            scratch,
+        )
+        .await;
+        if let (CacheRead::Miss, Some(legacy_keygen)) = (&ret, self.legacy_keygen()) {
+            ret = self.migrate_history(legacy_keygen, key, ctx, scratch).await;
+        }
+
+        if ctx.sample.is_some() {
+            let elapsed = now.elapsed().as_micros_unchecked() as i64;
+            STATS::get_history.add_value(elapsed);
+        }
+
+        let history = match ret {
+            CacheRead::Hit(history) => history,
+            CacheRead::Miss => return CacheRead::Miss,
+            CacheRead::Corrupt => {
+                self.evict_corrupt(key);
+                return CacheRead::Corrupt;
+            }
+            CacheRead::Failed => return CacheRead::Failed,
+        };
+
+        if let (Some(threshold), Some(ttl_remaining)) =
+            (self.options.refill_ttl_threshold, history.ttl_remaining)
+        {
+            if ttl_remaining < threshold {
+                STATS::gaf_refill.add_value(1);
+                self.refill_history(key, history.range.clone());
+                return CacheRead::Hit(history);
+            }
+        }
+
+        if self.options.rechunk_on_read && history.stale_chunk_size {
+            STATS::gaf_rechunk.add_value(1);
+            self.refill_history(key, history.range.clone());
+        }
+
+        CacheRead::Hit(history)
+    }
+
+    /// Write a history that was just read again, unless it is already being written again after
+    /// another read, so that a history read by many at once is written once.
+    fn refill_history(&self, key: &CacheKey<FilenodeRange>, filenodes: FilenodeRange) {
+        let refill = match PendingRefill::start(&self.history_refills, &key.key) {
+            Some(refill) => refill,
+            None => {
+                STATS::gaf_refill_coalesced.add_value(1);
+                return;
+            }
+        };
+        if self.start_history_fill(key, &filenodes) {
+            self.schedule_fill_history(key, filenodes, Some(refill));
+        }
+    }
+
+    /// Like `migrate_filenode`, but for a history.
+    async fn migrate_history(
+        &self,
+        legacy_keygen: &KeyGen,
+        key: &CacheKey<FilenodeRange>,
+        ctx: ReadContext<'_>,
+        scratch: &mut Vec<u8>,
+    ) -> CacheRead<CachedHistory> {
+        let ctx = ctx.for_legacy_read();
+        let ret = get_history_from_memcache(
+            &self.logger,
+            self.repo_memcache(key.repo_id),
+            legacy_keygen,
+            key,
+            ctx,
+            scratch,
+        )
+        .await;
+        match ret {
+            CacheRead::Hit(history) => {
+                STATS::legacy_key_migrated.add_value(1);
+                self.fill_history(key, history.range.clone());
+                CacheRead::Hit(history)
+            }
+            CacheRead::Miss | CacheRead::Corrupt | CacheRead::Failed => CacheRead::Miss,
+        }
+    }
+
+    /// Like `get_history` for the full histories of many paths, read concurrently. Results are in
+    /// the order of `paths`.
+    pub async fn get_histories(
+        &self,
+        repo_id: RepositoryId,
+        paths: &[RepoPath],
+    ) -> Vec<Option<FilenodeRange>> {
+        let keys: Vec<_> = paths
+            .iter()
+            .map(|path| self.history_cache_key(repo_id, &PathWithHash::from_repo_path(path), None))
+            .collect();
+        join_all(keys.iter().map(|key| self.get_history(key))).await
+    }
+
+    /// Like `get_history`, but returns entries in the opposite of the stored order.
+    ///
+    /// Histories are stored in the order they were passed to `fill_history`, which for the
+    /// filenodes reader is the order SQL returned the rows in. The chunks of a history written by
+    /// `fill_history_stream` are separately serialized parts of it, so they are read from the last
+    /// to the first, and the entries of each are reversed. The chunks of other chunked histories
+    /// are byte ranges of a single serialized list, so those are still reassembled before the
+    /// entries can be reversed.
+    pub async fn get_history_reversed(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+    ) -> Option<FilenodeRange> {
+        let root = match self.get_history_root(key).await {
+            Some((_, root)) => root,
+            // Legacy histories are migrated by get_history.
+            None if self.legacy_keygen().is_some() => {
+                return self.get_history(key).await.map(reverse_history);
+            }
+            None => return None,
+        };
+
+        let lenient = self.options.lenient_conversion;
+        match root {
+            HistoryRoot::Range { range, .. } => Some(reverse_history(range)),
+            HistoryRoot::Chunks {
+                mut pointers,
+                generation,
+                segmented: true,
+                compressed,
+                ..
+            } => {
+                pointers.reverse();
+                let chunks = self
+                    .read_chunks_of_root(key, pointers, generation, compressed)
+                    .await?;
+                let mut filenodes = Vec::new();
+                for chunk in &chunks {
+                    match deserialize_history_blob(chunk, lenient)? {
+                        FilenodeRange::Filenodes(part) => filenodes.extend(part.into_iter().rev()),
+                        FilenodeRange::TooBig => {
+                            STATS::gaf_reassembled_deserialize_err.add_value(1);
+                            return None;
+                        }
+                    }
+                }
+                Some(FilenodeRange::Filenodes(filenodes))
+            }
+            HistoryRoot::Chunks {
+                pointers,
+                generation,
+                compressed,
+                ..
+            } => {
+                let chunks = self
+                    .read_chunks_of_root(key, pointers, generation, compressed)
+                    .await?;
+                deserialize_history_chunks(&chunks, false, lenient, &mut Vec::new())
+                    .map(reverse_history)
+            }
+        }
+    }
+
+    /// The entries of the full history of `path` for which `pred` is true, in the stored order,
+    /// or `None` if the history isn't cached or was too big to be. The history is read like
+    /// `get_history`, so every entry is deserialized and given to `pred`, even if only the first
+    /// match is wanted. Entries that don't match are dropped in place, without another copy.
+    pub async fn get_history_filtered(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+        pred: impl Fn(&FilenodeInfo) -> bool,
+    ) -> Option<Vec<FilenodeInfo>> {
+        let key = self.history_cache_key(repo_id, &PathWithHash::from_repo_path(path), None);
+        match self.get_history(&key).await? {
+            FilenodeRange::Filenodes(mut filenodes) => {
+                filenodes.retain(|filenode| pred(filenode));
+                Some(filenodes)
+            }
+            FilenodeRange::TooBig => None,
+        }
+    }
+
+    /// The entries of the full history of `path`, in the stored order, for callers that go
+    /// through them once, e.g. to `find` one or `take` the first few. `None` if the history isn't
+    /// cached or was too big to be.
+    ///
+    /// The chunks of a history written by `fill_history_stream` are separately serialized parts
+    /// of it, so they are read and deserialized one at a time, as the stream is polled: a caller
+    /// that stops early doesn't read the rest. A chunk that can't be read then ends the stream
+    /// with an error. Other histories are read whole, as their chunks can't be decoded
+    /// separately.
+    pub async fn get_history_stream(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+    ) -> Option<BoxStream<'_, Result<FilenodeInfo, Error>>> {
+        let key = self.history_cache_key(repo_id, &PathWithHash::from_repo_path(path), None);
+        let whole = |range| match range {
+            FilenodeRange::Filenodes(filenodes) => {
+                Some(stream::iter(filenodes.into_iter().map(Ok)).boxed())
+            }
+            FilenodeRange::TooBig => None,
+        };
+        let root = match self.get_history_root(&key).await {
+            Some((_, root)) => root,
+            // Legacy histories are migrated by get_history.
+            None if self.legacy_keygen().is_some() => return whole(self.get_history(&key).await?),
+            None => return None,
+        };
+
+        let (pointers, generation, compressed) = match root {
+            HistoryRoot::Range { range, .. } => return whole(range),
+            HistoryRoot::Chunks {
+                pointers,
+                generation,
+                size,
+                segmented: true,
+                compressed,
+                ..
+            } => {
+                if pointers.len() > self.options.max_history_pointers() {
+                    STATS::gaf_too_many_pointers.add_value(1);
+                    return None;
+                }
+                if size.is_some_and(|size| self.options.value_too_big(size)) {
+                    return None;
+                }
+                (pointers, generation, compressed)
+            }
+            HistoryRoot::Chunks {
+                pointers,
+                generation,
+                compressed,
+                ..
+            } => {
+                let chunks = self
+                    .read_chunks_of_root(&key, pointers, generation, compressed)
+                    .await?;
+                let lenient = self.options.lenient_conversion;
+                let range = deserialize_history_chunks(&chunks, false, lenient, &mut Vec::new())?;
+                return whole(range);
+            }
+        };
+
+        let parts = stream::unfold(
+            (key, pointers.into_iter()),
+            move |(key, mut pointers)| async move {
+                let pointer = pointers.next()?;
+                let part = self
+                    .read_history_part(&key, pointer, generation, compressed)
+                    .await;
+                if part.is_err() {
+                    pointers = Vec::new().into_iter();
+                }
+                Some((part, (key, pointers)))
+            },
+        );
+        let entries = parts.flat_map(|part| match part {
+            Ok(part) => stream::iter(part.into_iter().map(Ok)).left_stream(),
+            Err(e) => stream::once(async { Err(e) }).right_stream(),
+        });
+        Some(entries.boxed())
+    }
+
+    /// The entries in the chunk under `pointer` of a history written by `fill_history_stream`.
+    async fn read_history_part(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+        pointer: Pointer,
+        generation: Option<i64>,
+        compressed: bool,
+    ) -> Result<Vec<FilenodeInfo>, Error> {
+        let memcache = self.repo_memcache(key.repo_id);
+        let keygen = &self.keygen;
+        let pointers = vec![pointer];
+        let read = read_history_chunks(memcache, keygen, key, pointers, generation, compressed);
+        let chunk = match read.await {
+            Ok(mut chunks) => chunks.pop().expect("a chunk was read for the pointer"),
+            Err(e) => {
+                chunk_read_failed::<()>(&self.logger, keygen, key, &self.options, e.clone());
+                return Err(e.into());
+            }
+        };
+        match deserialize_history_blob(&chunk, self.options.lenient_conversion) {
+            Some(FilenodeRange::Filenodes(part)) => Ok(part),
+            Some(FilenodeRange::TooBig) => {
+                STATS::gaf_reassembled_deserialize_err.add_value(1);
+                Err(ChunkReadError::Malformed(pointer).into())
+            }
+            None => Err(ChunkReadError::Malformed(pointer).into()),
+        }
+    }
+
+    /// Whether the full history of `path` has an entry with `linknode`, or `None` if the history
+    /// isn't cached or was too big to be. If the history is chunked and its root points to a
+    /// filter of its linknodes, histories without `linknode` are mostly told apart from the root
+    /// and the filter alone, and counted as `gaf_linknode_filter_negative`. Otherwise, the chunks
+    /// the root points to are read, without reading the root again.
+    pub async fn history_contains_linknode(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+        linknode: HgChangesetId,
+    ) -> Option<bool> {
+        let key = self.history_cache_key(repo_id, &PathWithHash::from_repo_path(path), None);
+        let contains = |range: FilenodeRange| match range {
+            FilenodeRange::Filenodes(filenodes) => Some(
+                filenodes
+                    .iter()
+                    .any(|filenode| filenode.linknode == linknode),
+            ),
+            FilenodeRange::TooBig => None,
+        };
+
+        let (pointers, generation, segmented, compressed, linknode_filter) =
+            match self.get_history_root(&key).await?.1 {
+                HistoryRoot::Range { range, .. } => return contains(range),
+                HistoryRoot::Chunks {
+                    pointers,
+                    generation,
+                    segmented,
+                    compressed,
+                    linknode_filter,
+                    ..
+                } => (pointers, generation, segmented, compressed, linknode_filter),
+            };
+        if let Some(pointer) = linknode_filter {
+            if let Some(filter) = self.get_linknode_filter(&key, pointer).await {
+                if !linknode_filter_contains(&filter, &linknode) {
+                    STATS::gaf_linknode_filter_negative.add_value(1);
+                    return Some(false);
+                }
+            }
+        }
+
+        let chunks = self
+            .read_chunks_of_root(&key, pointers, generation, compressed)
+            .await?;
+        let lenient = self.options.lenient_conversion;
+        let range = deserialize_history_chunks(&chunks, segmented, lenient, &mut Vec::new())?;
+        contains(range)
+    }
+
+    /// The filter of the linknodes of the history under `key` stored under `pointer`, if it can
+    /// be read.
+    async fn get_linknode_filter(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+        pointer: Pointer,
+    ) -> Option<Bytes> {
+        let filter_key = get_mc_key_for_filenodes_list_chunk(&self.keygen, key, pointer).ok()?;
+        get_with_retry(
+            self.repo_memcache(key.repo_id),
+            filter_key,
+            self.options.read_retry,
+        )
+        .await
+        .ok()
+        .flatten()
+    }
+
+    /// Like `get_history`, but with runs of identical consecutive entries collapsed into one, for
+    /// callers that only care about distinct versions. Only adjacent duplicates are removed: an
+    /// entry equal to an earlier, non-adjacent one is kept.
+    pub async fn get_history_distinct(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+    ) -> Option<FilenodeRange> {
+        match self.get_history(key).await? {
+            FilenodeRange::Filenodes(mut filenodes) => {
+                filenodes.dedup();
+                Some(FilenodeRange::Filenodes(filenodes))
+            }
+            FilenodeRange::TooBig => Some(FilenodeRange::TooBig),
+        }
+    }
+
+    /// The value stored for a filenode, as is, for tools that want to inspect or forward it
+    /// without deserializing it. It is `FILENODE_TAG` followed by a serialized
+    /// `thrift::CachedFilenodeInfo`, and by a checksum if `filenode_checksum` is set.
+    pub async fn get_filenode_raw(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+        filenode_id: HgFileNodeId,
+    ) -> Option<Bytes> {
+        let pwh = PathWithHash::from_repo_path(path);
+        let key = self.filenode_cache_key(repo_id, &pwh, &filenode_id);
+        let mc_key = get_mc_key(&self.keygen, &key.key).ok()?;
+        let memcache = self.repo_memcache(key.repo_id);
+        let result = get_with_retry(memcache, mc_key, self.options.read_retry).await;
+        self.read_context(None)
+            .record_read_result(memcache, &result);
+        let serialized = result.ok().flatten()?;
+
+        // Another repo's filenode is never handed out, even as is.
+        decode_filenode(&serialized, key.repo_id, &self.options).hit()?;
+        Some(serialized)
+    }
+
+    /// Like `get_filenode_raw`, but for a history. If the history was stored as a single value,
+    /// that value is returned, which is `HISTORY_TAG` followed by a serialized
+    /// `thrift::CachedFilenodeInfoList`. If it was split into chunks, the chunks are reassembled,
+    /// which gives a serialized `thrift::FilenodeInfoList`. Chunks written by
+    /// `fill_history_stream` can't be reassembled as they are, so they are deserialized and the
+    /// joined history is serialized again.
+    pub async fn get_history_raw(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+        limit: Option<u64>,
+    ) -> Option<Bytes> {
+        let pwh = PathWithHash::from_repo_path(path);
+        let key = self.history_cache_key(repo_id, &pwh, limit);
+
+        // The root has to be read to find out whether the history was chunked.
+        let (serialized, root) = self.read_history_root(&key).await?;
+        match root {
+            HistoryRoot::Range { .. } => Some(serialized),
+            HistoryRoot::Chunks {
+                pointers,
+                generation,
+                segmented,
+                compressed,
+                ..
+            } => {
+                let chunks = read_history_chunks(
+                    self.repo_memcache(key.repo_id),
+                    &self.keygen,
+                    &key,
+                    pointers,
+                    generation,
+                    compressed,
+                )
+                .await
+                .ok()?;
+                if !segmented {
+                    return Some(Bytes::from(chunks.concat()));
+                }
+                let range = deserialize_history_chunks(
+                    &chunks,
+                    true,
+                    self.options.lenient_conversion,
+                    &mut Vec::new(),
+                )?;
+                Some(compact_protocol::serialize(&history_into_thrift(range)))
+            }
+        }
+    }
+
+    /// Number of filenodes in the full history of `path`, and how the history is stored. If the
+    /// history was chunked, the chunks are only read if the root doesn't record the count.
+    pub async fn history_summary(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+    ) -> Option<HistorySummary> {
+        let pwh = PathWithHash::from_repo_path(path);
+        let key = self.history_cache_key(repo_id, &pwh, None);
+        let (root_bytes, root) = self.get_history_root(&key).await?;
+
+        match root {
+            HistoryRoot::Range { range, .. } => Some(HistorySummary::new(
+                &range,
+                HistoryLayout::Inline,
+                root_bytes,
+            )),
+            HistoryRoot::Chunks {
+                pointers,
+                generation,
+                entry_count,
+                size,
+                segmented,
+                compressed,
+                ..
+            } => {
+                let layout = HistoryLayout::Chunked {
+                    chunks: pointers.len(),
+                };
+                if let (Some(entry_count), Some(size)) = (entry_count, size) {
+                    return Some(HistorySummary {
+                        entry_count: Some(entry_count),
+                        layout,
+                        approx_bytes: size,
+                    });
+                }
+
+                if pointers.len() > self.options.max_history_pointers() {
+                    STATS::gaf_too_many_pointers.add_value(1);
+                    return None;
+                }
+                let chunks = read_history_chunks(
+                    self.repo_memcache(key.repo_id),
+                    &self.keygen,
+                    &key,
+                    pointers,
+                    generation,
+                    compressed,
+                )
+                .await
+                .ok()?;
+                let range = deserialize_history_chunks(
+                    &chunks,
+                    segmented,
+                    self.options.lenient_conversion,
+                    &mut Vec::new(),
+                )?;
+                let bytes = chunks.iter().map(Vec::len).sum();
+                Some(HistorySummary::new(&range, layout, bytes))
+            }
+        }
+    }
+
+    /// Describe how the full history of `path` is stored, for admin tooling, e.g.
+    /// `inline: 3 entries, 412 bytes` or `chunked: 5 pointers [12, -44, 7, 90, ...], root TTL ~7h`.
+    /// Only the root is read.
+    pub async fn debug_history_layout(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+    ) -> Option<String> {
+        let pwh = PathWithHash::from_repo_path(path);
+        let key = self.history_cache_key(repo_id, &pwh, None);
+        let (root_bytes, root) = self.get_history_root(&key).await?;
+
+        let description = match root {
+            HistoryRoot::Range {
+                range: FilenodeRange::Filenodes(filenodes),
+                ..
+            } => {
+                format!("inline: {} entries, {} bytes", filenodes.len(), root_bytes)
+            }
+            HistoryRoot::Range {
+                range: FilenodeRange::TooBig,
+                ..
+            } => {
+                format!("inline: too big, {} bytes", root_bytes)
+            }
+            HistoryRoot::Chunks {
+                pointers,
+                expires_at,
+                ..
+            } => {
+                let mut shown = pointers
+                    .iter()
+                    .take(DEBUG_POINTERS_SHOWN)
+                    .map(|pointer| pointer.to_string())
+                    .collect::<Vec<_>>();
+                if pointers.len() > DEBUG_POINTERS_SHOWN {
+                    shown.push("...".to_string());
+                }
+                let root_ttl = match expires_at {
+                    Some(expires_at) => format!("~{}", approx_duration(ttl_remaining(expires_at))),
+                    None => "unknown".to_string(),
+                };
+                format!(
+                    "chunked: {} pointers [{}], root TTL {}",
+                    pointers.len(),
+                    shown.join(", "),
+                    root_ttl
+                )
+            }
+        };
+
+        Some(description)
+    }
+
+    /// Read the full history of `path` the way `get_history` does, recording every step, for
+    /// admin tooling debugging why a read hits or misses. Unlike `get_history`, all the chunks
+    /// are fetched even if some can't be read, one at a time.
+    pub async fn explain_get_history(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+    ) -> HistoryExplain {
+        let pwh = PathWithHash::from_repo_path(path);
+        let key = self.history_cache_key(repo_id, &pwh, None);
+        let mut explain = HistoryExplain {
+            key: key.key.clone(),
+            root_bytes: None,
+            layout: HistoryLayout::Missed,
+            chunks: Vec::new(),
+            errors: Vec::new(),
+            outcome: CacheRead::Miss,
+        };
+
+        let mc_key = match get_mc_key(&self.keygen, &key.key) {
+            Ok(mc_key) => mc_key,
+            Err(e) => {
+                explain.errors.push(e.to_string());
+                return explain;
+            }
+        };
+        let serialized = match self.repo_memcache(key.repo_id).get(mc_key).await {
+            Ok(Some(serialized)) => serialized,
+            Ok(None) => return explain,
+            Err(e) => {
+                explain
+                    .errors
+                    .push(format!("Root could not be fetched: {}", e));
+                explain.outcome = CacheRead::Failed;
+                return explain;
+            }
+        };
+        explain.root_bytes = Some(serialized.len());
+
+        let (root_repo_id, root) =
+            match deserialize_history_root(&serialized, self.options.lenient_conversion) {
+                Some(root) => root,
+                None => {
+                    explain
+                        .errors
+                        .push("Root could not be deserialized".to_string());
+                    explain.outcome = CacheRead::Corrupt;
+                    return explain;
+                }
+            };
+        if root_repo_id != repo_id {
+            explain.errors.push(format!(
+                "Root belongs to repo {}, not {}",
+                root_repo_id, repo_id
+            ));
+            return explain;
+        }
+
+        let (pointers, generation, segmented, compressed) = match root {
+            HistoryRoot::Range { range, .. } => {
+                explain.layout = HistoryLayout::Inline;
+                explain.outcome = CacheRead::Hit(range);
+                return explain;
+            }
+            HistoryRoot::Chunks {
+                pointers,
+                generation,
+                segmented,
+                compressed,
+                ..
+            } => (pointers, generation, segmented, compressed),
+        };
+        explain.layout = HistoryLayout::Chunked {
+            chunks: pointers.len(),
+        };
+        if pointers.len() > self.options.max_history_pointers() {
+            explain.errors.push(format!(
+                "Root has {} pointers, more than the maximum of {}",
+                pointers.len(),
+                self.options.max_history_pointers()
+            ));
+            return explain;
+        }
+
+        let mut chunks = Vec::with_capacity(pointers.len());
+        let mut failed = None;
+        for pointer in pointers {
+            let res = match get_mc_key_for_filenodes_list_chunk(&self.keygen, &key, pointer) {
+                Ok(chunk_key) => match self.repo_memcache(key.repo_id).get(chunk_key).await {
+                    Ok(chunk) => check_chunk(chunk.as_deref(), pointer, generation, compressed),
+                    Err(_) => Err(ChunkReadError::Failed(pointer)),
+                },
+                Err(_) => Err(ChunkReadError::Failed(pointer)),
+            };
+            match res {
+                Ok(chunk) => {
+                    explain.chunks.push(ChunkExplain {
+                        pointer,
+                        bytes: Some(chunk.len()),
+                    });
+                    chunks.push(chunk);
+                }
+                Err(e) => {
+                    explain.chunks.push(ChunkExplain {
+                        pointer,
+                        bytes: None,
+                    });
+                    explain.errors.push(e.to_string());
+                    failed.get_or_insert(match e {
+                        ChunkReadError::Malformed(_) => CacheRead::Corrupt,
+                        ChunkReadError::Failed(_) => CacheRead::Failed,
+                        _ => CacheRead::Miss,
+                    });
+                }
+            }
+        }
+        if let Some(outcome) = failed {
+            explain.outcome = outcome;
+            return explain;
+        }
+
+        explain.outcome = match deserialize_history_chunks(
+            &chunks,
+            segmented,
+            self.options.lenient_conversion,
+            &mut Vec::new(),
+        ) {
+            Some(range) => CacheRead::Hit(range),
+            None => {
+                explain
+                    .errors
+                    .push("Chunks could not be deserialized".to_string());
+                CacheRead::Corrupt
+            }
+        };
+        explain
+    }
+
+    /// The root of a history along with its size, if it is cached for the repo of `key`.
+    async fn get_history_root(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+    ) -> Option<(usize, HistoryRoot)> {
+        let (serialized, root) = self.read_history_root(key).await?;
+        Some((serialized.len(), root))
+    }
+
+    /// Like `get_history_root`, but with the root as it was stored.
+    async fn read_history_root(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+    ) -> Option<(Bytes, HistoryRoot)> {
+        let mc_key = get_mc_key(&self.keygen, &key.key).ok()?;
+        let memcache = self.repo_memcache(key.repo_id);
+        let result = get_with_retry(memcache, mc_key, self.options.read_retry).await;
+        self.read_context(None)
+            .record_read_result(memcache, &result);
+        let serialized = result.ok().flatten()?;
+
+        let (repo_id, root) =
+            deserialize_history_root(&serialized, self.options.lenient_conversion)?;
+        if repo_id != key.repo_id {
+            STATS::repo_mismatch.add_value(1);
+            return None;
+        }
+
+        Some((serialized, root))
+    }
+
+    /// The chunks `pointers` of a root read with `get_history_root`, in the order of `pointers`,
+    /// or `None` if any of them can't be read or the root points to too many.
+    async fn read_chunks_of_root(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+        pointers: Vec<Pointer>,
+        generation: Option<i64>,
+        compressed: bool,
+    ) -> Option<Vec<Vec<u8>>> {
+        if pointers.len() > self.options.max_history_pointers() {
+            STATS::gaf_too_many_pointers.add_value(1);
+            return None;
+        }
+
+        let memcache = self.repo_memcache(key.repo_id);
+        let chunks = match read_history_chunks(
+            memcache,
+            &self.keygen,
+            key,
+            pointers,
+            generation,
+            compressed,
+        )
+        .await
+        {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                chunk_read_failed::<()>(&self.logger, &self.keygen, key, &self.options, e);
+                return None;
+            }
+        };
+        let bytes = chunks.iter().map(Vec::len).sum();
+        if self.options.value_too_big(bytes) {
+            return None;
+        }
+        Some(chunks)
+    }
+
+    // TODO: Take ownership of key
+    pub fn fill_history(&self, key: &CacheKey<FilenodeRange>, filenodes: FilenodeRange) {
+        if self.start_history_fill(key, &filenodes) {
+            self.schedule_fill_history(key, filenodes, None);
+        }
+    }
+
+    /// Like `fill_history`, but if `bypass` is set, writes nothing. See
+    /// `fill_filenode_with_bypass`.
+    pub fn fill_history_with_bypass(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+        filenodes: FilenodeRange,
+        bypass: bool,
+    ) {
+        if bypass {
+            STATS::bypassed.add_value(1);
+            return;
+        }
+        self.fill_history(key, filenodes)
+    }
+
+    /// Read the full history of `path` from the backing store with `fill`, ignoring any cached
+    /// value, and write it to the cache before returning it. This is for callers that know the
+    /// cached history is stale, e.g. right after writing filenodes for `path`: unlike
+    /// `fill_history`, the write is done in place, so reads made once this returns see the fresh
+    /// history. Failing to write it isn't an error, as the history was still read.
+    pub async fn refresh_history<F, Fut>(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+        fill: F,
+    ) -> Result<FilenodeRange, Error>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<FilenodeRange, Error>>,
+    {
+        let pwh = PathWithHash::from_repo_path(path);
+        let key = self.history_cache_key(repo_id, &pwh, None);
+
+        let now = Instant::now();
+        let filenodes = fill().await?;
+        STATS::backfill_latency.add_value(now.elapsed().as_micros_unchecked() as i64);
+
+        if self.start_history_fill(&key, &filenodes) {
+            let ttls = self.history_ttls(self.history_ttl(&key));
+            let completion = FillCompletion {
+                recent_fill: self.recent_fill(&key, || FilledValue::History(filenodes.clone())),
+                ..self.fill_completion(&key, CacheEventKind::HistoryFill)
+            };
+            let res = fill_history(
+                &self.logger,
+                self.repo_memcache(key.repo_id),
+                &self.keygen,
+                &key,
+                serialize_history(filenodes.clone(), &self.options),
+                &self.options,
+                ttls,
+            )
+            .await;
+            if let Ok(bytes) = res {
+                completion.written(bytes);
+            }
+        }
+
+        Ok(filenodes)
+    }
+
+    /// Warm the cache in the background with the full histories of the paths from `entries`,
+    /// each fetched from the backing store with its closure, e.g. to fill the histories of hot
+    /// paths after a deploy. Histories are written in place, as by `refresh_history`, before the
+    /// next one is fetched, so a slow cache slows the warmer down rather than letting fills pile
+    /// up, and at most `fills_per_sec` are written a second. Entries are skipped while the cache
+    /// is read-only. Fetch errors are logged and counted as `warm_fetch_err`. Must be called
+    /// from within a tokio runtime.
+    pub fn warm<S, F, Fut>(self: &Arc<Self>, entries: S, fills_per_sec: NonZeroU32) -> Warmer
+    where
+        S: Stream<Item = (RepositoryId, RepoPath, F)> + Send + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<FilenodeRange, Error>> + Send + 'static,
+    {
+        let cache = self.clone();
+        let warmed = Arc::new(AtomicU64::new(0));
+        let progress = warmed.clone();
+
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1) / fills_per_sec.get());
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+            futures::pin_mut!(entries);
+            while let Some((repo_id, path, fetch)) = entries.next().await {
+                interval.tick().await;
+                if cache.read_only.load(Ordering::Relaxed) {
+                    STATS::fill_suppressed.add_value(1);
+                    continue;
+                }
+                match cache.refresh_history(repo_id, &path, fetch).await {
+                    Ok(_) => {
+                        STATS::warm_progress.add_value(1);
+                        progress.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        STATS::warm_fetch_err.add_value(1);
+                        warn!(
+                            cache.logger,
+                            "Failed to fetch history to warm the cache: {}", e;
+                            "path" => path.to_string()
+                        );
+                    }
+                }
+            }
+        });
+
+        Warmer { warmed, handle }
+    }
+
+    /// Count an attempt to fill the history under `key`, and whether it should go ahead.
+    fn start_history_fill(&self, key: &CacheKey<FilenodeRange>, filenodes: &FilenodeRange) -> bool {
+        self.record_attempt();
+        if !self.should_fill() {
+            return false;
+        }
+        if let FilenodeRange::Filenodes(filenodes) = filenodes {
+            if filenodes.len() < self.options.min_history_entries {
+                STATS::gaf_below_min_skipped.add_value(1);
+                return false;
+            }
+        }
+
+        self.record_fill(&key.key);
+        true
+    }
+
+    /// Write `filenodes` in the background. If the write is a `refill`, it is done once the write
+    /// is.
+    fn schedule_fill_history(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+        filenodes: FilenodeRange,
+        refill: Option<PendingRefill>,
+    ) {
+        let logger = self.logger.clone();
+        let memcache = self.repo_memcache(key.repo_id).clone();
+        let keygen = self.keygen.clone();
+        let key = key.clone();
+        let options = self.options.clone();
+        let ttls = self.history_ttls(self.history_ttl(&key));
+        let completion = FillCompletion {
+            recent_fill: self.recent_fill(&key, || FilledValue::History(filenodes.clone())),
+            ..self.fill_completion(&key, CacheEventKind::HistoryFill)
+        };
+        let history = serialize_history(filenodes, &options);
+        let bytes = history.serialized.len();
+
+        let fut = async move {
+            let res =
+                fill_history(&logger, &memcache, &keygen, &key, history, &options, ttls).await;
+            if let Ok(bytes) = res {
+                completion.written(bytes);
+            }
+            drop(refill);
+        };
+
+        spawn_fill(self.spawner(), bytes, fut);
+    }
+
+    /// TTLs to write a history with, given its TTL before the random extension of its root.
+    fn history_ttls(&self, ttl: Duration) -> HistoryTtls {
+        HistoryTtls {
+            root: ttl + (self.ttl_jitter)(),
+            // Chunks get the largest extension, so that they always outlive the root.
+            chunks: ttl + Duration::from_secs(TTL_SEC_RAND),
+        }
+    }
+
+    /// TTL to write the history under `key` with, before the random extension of its root. With
+    /// `adaptive_ttl`, this counts as a write of the history.
+    fn history_ttl(&self, key: &CacheKey<FilenodeRange>) -> Duration {
+        let (churn, adaptive) = match (&self.churn, &self.options.adaptive_ttl) {
+            (Some(churn), Some(adaptive))
+                if !self.options.ttl_overrides.contains_key(&key.repo_id) =>
+            {
+                (churn, adaptive)
+            }
+            _ => return self.options.history_ttl(key.repo_id),
+        };
+
+        let now = Instant::now();
+        let mut churn = churn.lock().expect("poisoned lock");
+        let writes = match churn.get_mut(&key.key) {
+            Some(writes) if writes.is_recent(now, adaptive.max_ttl) => {
+                writes.writes += 1;
+                writes.writes
+            }
+            _ => {
+                churn.put(
+                    key.key.clone(),
+                    Churn {
+                        since: now,
+                        writes: 1,
+                    },
+                );
+                1
+            }
+        };
+
+        (adaptive.max_ttl / writes).clamp(adaptive.min_ttl, adaptive.max_ttl)
+    }
+}
+
+impl RemoteCache {
+    /// Write the full history of `path` from a stream, e.g. one paging through the database, so
+    /// that the history is never held in memory in full. Filenodes are buffered until they fill a
+    /// chunk, which is written before more are read, and the root is written once the stream
+    /// ends. Chunks are only written once the history is known to have `min_history_entries`, so
+    /// that a history that is skipped as too short leaves no chunks behind. Unlike
+    /// `fill_history`, this writes in place rather than in the background, and always splits the
+    /// history into chunks, even if it is short.
+    pub async fn fill_history_stream(
+        &self,
+        repo_id: RepositoryId,
+        path: &RepoPath,
+        filenodes: impl Stream<Item = FilenodeInfo>,
+    ) -> Result<(), Error> {
+        self.record_attempt();
+        if !self.should_fill() {
+            return Ok(());
+        }
+
+        let pwh = PathWithHash::from_repo_path(path);
+        let key = self.history_cache_key(repo_id, &pwh, None);
+        let root_key = get_mc_key(&self.keygen, &key.key)?;
+        STATS::list_key_bytes.add_value(root_key.len() as i64);
+        let options = &self.options;
+        let max_pointers = options.max_history_pointers();
+
+        let (previous_generation, replaced_pointers) = get_chunks_in_memcache(
+            self.repo_memcache(key.repo_id),
+            &key,
+            &root_key,
+            max_pointers,
+        )
+        .await;
+        let generation = previous_generation.map_or(0, |generation| generation.wrapping_add(1));
+
+        let ttls = self.history_ttls(self.history_ttl(&key));
+        let chunk_size = options.preferred_chunk_size();
+        let segment_size = chunk_data_size(chunk_size).saturating_sub(list_envelope_bytes());
+
+        let mut pointers = Vec::new();
+        let mut pointers_iter = PointersIter::new();
+        let mut entry_count = 0;
+        let mut size = 0;
+        let mut buffer = Vec::new();
+        let mut buffered_size = 0;
+        // Full segments held back until there are `min_history_entries`.
+        let mut held = Vec::new();
+
+        futures::pin_mut!(filenodes);
+        loop {
+            let next = filenodes.next().await;
+            let next = next.map(|filenode| {
+                let filenode = filenode.into_thrift();
+                let filenode_size = compact_protocol::serialize(&filenode).len();
+                (filenode, filenode_size)
+            });
+
+            let full = match &next {
+                Some((_, filenode_size)) => buffered_size + filenode_size > segment_size,
+                None => true,
+            };
+            if full && !buffer.is_empty() {
+                held.push(std::mem::take(&mut buffer));
+                buffered_size = 0;
+            }
+            if entry_count >= options.min_history_entries {
+                for part in held.drain(..) {
+                    if pointers.len() == max_pointers {
+                        return Err(TooManyChunks(max_pointers).into());
+                    }
+                    let (pointer, bytes) = self
+                        .write_segment(&key, part, generation, ttls.chunks, &mut pointers_iter)
+                        .await?;
+                    pointers.push(pointer);
+                    size += bytes;
+                }
+            }
+
+            match next {
+                Some((filenode, filenode_size)) => {
+                    entry_count += 1;
+                    buffered_size += filenode_size;
+                    buffer.push(filenode);
+                }
+                None => break,
+            }
+        }
+
+        if entry_count < options.min_history_entries {
+            STATS::gaf_below_min_skipped.add_value(1);
+            return Ok(());
+        }
+
+        let root_ttl = ttls.root;
+        let root = thrift::CachedFilenodeInfoList {
+            repo_id: repo_id.id(),
+            list: thrift::FilenodeInfoList::Chunks(thrift::FilenodeInfoChunks {
+                pointers,
+                expires_at: unix_timestamp() + root_ttl.as_secs() as i64,
+                generation,
+                entry_count: Some(entry_count as i64),
+                size: Some(size as i64),
+                segmented: Some(true),
+                compressed: options.chunk_compression_level.map(|_| true),
+                chunk_size: Some(chunk_size as i64),
+                linknode_filter_pointer: None,
+            }),
+        };
+        let root = record_serialize(|| compact_protocol::serialize(&root));
+        let root = with_kind_tag(HISTORY_TAG, &root);
+
+        self.record_fill(&key.key);
+        self.repo_memcache(key.repo_id)
+            .set_with_ttl(root_key.clone(), root.clone(), root_ttl)
+            .await?;
+        self.fill_completion(&key, CacheEventKind::HistoryFill)
+            .written(size);
+        if options.sample_fill_verify() {
+            verify_fill(
+                &self.logger,
+                self.repo_memcache(key.repo_id),
+                root_key,
+                &root,
+            )
+            .await;
+        }
+
+        if options.delete_replaced_chunks {
+            let delete_chunks_fut = replaced_pointers.into_iter().map(|pointer| {
+                let chunk_key = get_mc_key_for_filenodes_list_chunk(&self.keygen, &key, pointer);
+                async move {
+                    if let Ok(chunk_key) = chunk_key {
+                        let _ = self.repo_memcache(key.repo_id).del(chunk_key).await;
+                    }
+                }
+            });
+            join_all(delete_chunks_fut).await;
+        }
+
+        Ok(())
+    }
+
+    /// Write part of a history as a chunk of its own, returning the chunk's pointer and the size
+    /// the part was serialized to.
+    async fn write_segment(
+        &self,
+        key: &CacheKey<FilenodeRange>,
+        filenodes: Vec<thrift::FilenodeInfo>,
+        generation: i64,
+        ttl: Duration,
+        pointers: &mut PointersIter,
+    ) -> Result<(Pointer, usize), Error> {
+        let pointer = pointers.next().expect("pointers are infinite");
+        let chunk_key = get_mc_key_for_filenodes_list_chunk(&self.keygen, key, pointer)?;
+        STATS::chunk_key_bytes.add_value(chunk_key.len() as i64);
+
+        let data = record_serialize(|| {
+            compact_protocol::serialize(&thrift::FilenodeInfoList::Data(filenodes))
+        });
+        let bytes = data.len();
+        STATS::gaf_compact_bytes.add_value(bytes as i64);
+        let (data, compressed) = compress_chunk(
+            data.to_vec(),
+            self.options.chunk_compression_level,
+            self.options.compress_min_bytes,
+        )?;
+        let chunk = thrift::FilenodeInfoChunk {
+            generation,
+            data,
+            compressed,
+        };
+        let chunk = record_serialize(|| compact_protocol::serialize(&chunk));
+
+        self.repo_memcache(key.repo_id)
+            .set_with_ttl(chunk_key, chunk, ttl)
+            .await?;
+        Ok((pointer, bytes))
+    }
+
+    /// Make every cache in the process behave like a no-op one (or stop doing so), e.g. to take
+    /// memcache out of service during an incident without a redeploy. Unlike `set_read_only`,
+    /// this also stops reads. Each memcache operation skipped is counted as `cache_killed`.
+    pub fn set_kill_switch(killed: bool) {
+        KILL_SWITCH.store(killed, Ordering::Relaxed);
+    }
+
+    /// The memcache to read from and write to, which is a no-op one while the kill switch is set,
+    /// or while the circuit breaker is open.
+    fn memcache(&self) -> &MemcacheHandler {
+        if self.kill_switch.load(Ordering::Relaxed) {
+            STATS::cache_killed.add_value(1);
+            return &KILLED_MEMCACHE;
+        }
+        if self
+            .circuit_breaker
+            .as_ref()
+            .is_some_and(|breaker| !breaker.allow(tokio::time::Instant::now()))
+        {
+            STATS::circuit_skipped.add_value(1);
+            return &KILLED_MEMCACHE;
+        }
+        &self.memcache
+    }
+
+    /// Make the cache behave like a no-op one for `repo_id` (or stop doing so), e.g. to take
+    /// memcache out of service for a single repo during an incident, while other repos are still
+    /// cached. Each memcache operation skipped is counted as `repo_disabled`, and reads of a
+    /// disabled repo are counted as `repo_disabled_read` rather than as misses.
+    pub fn set_repo_disabled(&self, repo_id: RepositoryId, disabled: bool) {
+        self.disabled_repos.set(repo_id, disabled);
+    }
+
+    /// Like `memcache`, but a no-op one if `repo_id` is disabled.
+    fn repo_memcache(&self, repo_id: RepositoryId) -> &MemcacheHandler {
+        if self.disabled_repos.contains(repo_id) {
+            STATS::repo_disabled.add_value(1);
+            return &KILLED_MEMCACHE;
+        }
+        self.memcache()
+    }
+
+    /// Stop (or resume) writing to the cache. Reads are unaffected, so this can be used to freeze
+    /// the contents of the cache without taking it out of service.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+
+    pub fn config_summary(&self) -> CacheConfigSummary {
+        if self.memcache.is_noop() {
+            return CacheConfigSummary::Noop;
+        }
+
+        let options = &self.options;
+        CacheConfigSummary::Memcache {
+            key_prefix: self.keygen.key(""),
+            ttl_secs: TTL_SEC,
+            ttl_jitter_secs: TTL_SEC_RAND,
+            chunk_size: options.chunk_size(),
+            preferred_chunk_size: options.preferred_chunk_size(),
+            refill_ttl_threshold_secs: options.refill_ttl_threshold.map(|ttl| ttl.as_secs()),
+            rechunk_on_read: options.rechunk_on_read,
+            recent_fills_capacity: options.recent_fills_capacity,
+            content_index: options.content_index,
+            path_depth_stats: options.path_depth_stats,
+            delete_replaced_chunks: options.delete_replaced_chunks,
+            soft_ttl_secs: options.soft_ttl.map(|ttl| ttl.as_secs()),
+            hard_ttl_secs: options.hard_ttl.map(|ttl| ttl.as_secs()),
+            lenient_conversion: options.lenient_conversion,
+            canary_interval_secs: options.canary_interval.map(|interval| interval.as_secs()),
+            negative_caching: options.negative_caching,
+            adaptive_ttl: options.adaptive_ttl,
+            read_retry: options.read_retry,
+            miss_classification_capacity: options.miss_classification_capacity,
+            hot_paths_capacity: options.hot_paths_capacity,
+            max_history_pointers: options.max_history_pointers(),
+            max_value_bytes: options.max_value_bytes,
+            chunk_compression_level: options.chunk_compression_level,
+            fill_verify_sample_rate: options.fill_verify_sample_rate,
+            stat_sample_rate: options.stat_sample_rate,
+            max_concurrent_fills: options.max_concurrent_fills,
+            coalesce_history_reads: options.coalesce_history_reads,
+            stale_fallback_capacity: options.stale_fallback_capacity,
+            evict_corrupt: options.evict_corrupt,
+            circuit_breaker: options.circuit_breaker,
+            compress_min_bytes: options.compress_min_bytes,
+            history_error_log_sample_rate: options.history_error_log_sample_rate,
+            fill_bytes_budget: options.fill_bytes_budget,
+            intern_history_paths: options.intern_history_paths,
+            filenode_checksum: options.filenode_checksum,
+            min_history_entries: options.min_history_entries,
+            ttl_overrides_secs: options
+                .ttl_overrides
+                .iter()
+                .map(|(repo_id, ttl)| (repo_id.id(), ttl.as_secs()))
+                .collect(),
+            salt: options.salt.clone(),
+            path_hash_key_bytes: options.path_hash_encoding().key_bytes,
+            read_only: self.read_only.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Write a canary value and read it back, returning whether the same value was read. The
+    /// result is also recorded in the `canary_ok` and `canary_fail` stats. The value is written
+    /// under a key of its own to this process, with a short TTL.
+    pub async fn check_canary(&self) -> bool {
+        check_canary(self.memcache(), &self.keygen).await
+    }
+
+    /// Write a canary filenode and history in place, and read them back through the same code
+    /// as `get_filenode` and `get_history`, for readiness probes and admin health checks. Unlike
+    /// `check_canary`, this exercises serialization and chunking, and values are written even if
+    /// the cache is read-only, as the canary keys are reserved. Like the canary, the values are
+    /// written under keys of their own to this process, with a short TTL.
+    pub async fn self_check(&self) -> SelfCheckResult {
+        let now = Instant::now();
+        let filenode = self_check_filenode();
+
+        let filenode_key = self_check_key("filenode");
+        let filenode_ok = match get_mc_key(&self.keygen, &filenode_key.key) {
+            Ok(mc_key) => {
+                let value =
+                    self.cached_filenode(&filenode_key, filenode.clone(), self.write_epoch());
+                let serialized = serialize_filenode(&value, self.options.filenode_checksum);
+                let written =
+                    write_filenode(self.memcache(), mc_key, serialized, Some(CANARY_TTL)).await;
+                written.is_ok() && self.get_filenode(&filenode_key).await == Some(filenode.clone())
+            }
+            Err(_) => false,
+        };
+
+        let history_key = self_check_key("history");
+        let history = FilenodeRange::Filenodes(vec![filenode]);
+        let written = fill_history(
+            &self.logger,
+            self.memcache(),
+            &self.keygen,
+            &history_key,
+            serialize_history(history.clone(), &self.options),
+            &self.options,
+            HistoryTtls {
+                root: CANARY_TTL,
+                // Chunks are written first, so they need a longer TTL to outlive the root.
+                chunks: CANARY_TTL * 2,
+            },
+        )
+        .await;
+        let history_ok = written.is_ok() && self.get_history(&history_key).await == Some(history);
+
+        SelfCheckResult {
+            filenode_ok,
+            history_ok,
+            latency: now.elapsed(),
+        }
+    }
+
+    fn should_fill(&self) -> bool {
+        // Avoid wasting time spawning a fill operation if the memcache is a no-op
+        if self.memcache().is_noop() {
+            return false;
+        }
+
+        if self.read_only.load(Ordering::Relaxed) {
+            STATS::fill_suppressed.add_value(1);
+            return false;
+        }
+
+        true
+    }
+
+    fn spawner(&self) -> FillSpawner<'_> {
+        FillSpawner {
+            logger: &self.logger,
+            tracker: self.fill_tracker.as_ref(),
+            counts: &self.fill_counts,
+            permits: self.fill_permits.as_ref(),
+            bytes_in_flight: &self.fill_bytes_in_flight,
+            bytes_budget: self.options.fill_bytes_budget,
+        }
+    }
+
+    fn read_context<'a>(&'a self, stats: Option<&'a CacheStatsAccumulator>) -> ReadContext<'a> {
+        ReadContext {
+            options: &self.options,
+            sample: self.options.stat_sample(),
+            recent_writes: self.recent_writes.as_ref(),
+            access_frequency: self.access_frequency.as_ref(),
+            events: self.events.as_ref().filter(|events| events.reads),
+            stats,
+            format_reads: &self.format_reads,
+            circuit_breaker: self.circuit_breaker.as_ref(),
+            eviction_counts: self.eviction_counts.as_ref(),
+            clock: &*self.clock,
+            disabled_repos: &self.disabled_repos,
+            stale_filenodes: self.stale_filenodes.as_deref(),
+        }
+    }
+
+    fn fill_completion<V>(&self, key: &CacheKey<V>, kind: CacheEventKind) -> FillCompletion {
+        let event = self.events.as_ref().map(|sink| PendingEvent {
+            sink: sink.clone(),
+            event: CacheEvent {
+                repo_id: key.repo_id,
+                key: key.key.clone(),
+                bytes: 0,
+                kind,
+            },
+        });
+        FillCompletion {
+            counts: self.fill_counts.clone(),
+            event,
+            handle: None,
+            recent_fill: None,
+        }
+    }
+
+    /// What to remember of a fill of `key` once it was written, if `recent_fills_capacity` is set.
+    /// `value` is only called if it is.
+    fn recent_fill<V>(
+        &self,
+        key: &CacheKey<V>,
+        value: impl FnOnce() -> FilledValue,
+    ) -> Option<(Arc<RecentFills>, String, FilledValue)> {
+        let recent_fills = self.recent_fills.as_ref()?;
+        Some((recent_fills.clone(), key.key.clone(), value()))
+    }
+
+    fn record_attempt(&self) {
+        STATS::fill_attempted.add_value(1);
+        self.fill_counts.attempted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Fills attempted and written since the cache was created. Fills are written in the
+    /// background, so the ones still in flight count as skipped.
+    pub fn fill_stats(&self) -> FillStats {
+        FillStats {
+            attempted: self.fill_counts.attempted.load(Ordering::Relaxed),
+            written: self.fill_counts.written.load(Ordering::Relaxed),
+            started: self.fill_counts.started.load(Ordering::Relaxed),
+            spawn_lag: Duration::from_micros(self.fill_counts.spawn_lag_us.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Number of successful reads of each format since the cache was created.
+    pub fn format_reads(&self) -> HashMap<ValueFormat, u64> {
+        ValueFormat::ALL
+            .into_iter()
+            .filter_map(|format| {
+                let reads = self.format_reads.0[format as usize].load(Ordering::Relaxed);
+                (reads > 0).then_some((format, reads))
+            })
+            .collect()
+    }
+
+    /// Fills started per second, averaged over the last minute, which tells a fill storm (e.g.
+    /// after the pool was flushed) from the steady state. Also sets the `fill_rate_per_sec`
+    /// gauge, which is otherwise only updated when a fill starts.
+    pub fn fill_rate_per_sec(&self) -> f64 {
+        let mut fill_rate = self.fill_rate.lock().expect("poisoned lock");
+        let rate = fill_rate.rate((self.clock)());
+        STATS::fill_rate_per_sec.set_value(rate.round() as i64);
+        rate
+    }
+
+    /// Share of the reads of the last minute that missed a key written recently, which must have
+    /// been evicted or expired since, between 0 and 1. As values are written with long TTLs, most
+    /// of these are evictions, so a high value suggests the pool is too small for the working set.
+    /// Misses of keys that were never written aren't counted as evicted, as filling them is how
+    /// the cache warms up. Always 0 unless `miss_classification_capacity` is set, and limited to
+    /// the keys it remembers.
+    pub fn estimated_eviction_pressure(&self) -> f64 {
+        match &self.eviction_counts {
+            Some(counts) => counts.pressure((self.clock)()),
+            None => 0.0,
+        }
+    }
+
+    fn record_fill(&self, key: &str) {
+        {
+            let mut fill_rate = self.fill_rate.lock().expect("poisoned lock");
+            let now = (self.clock)();
+            fill_rate.record(now);
+            STATS::fill_rate_per_sec.set_value(fill_rate.rate(now).round() as i64);
+        }
+        self.record_write(key);
+    }
+
+    fn record_write(&self, key: &str) {
+        if let Some(recent_writes) = &self.recent_writes {
+            recent_writes.insert(key);
+        }
+    }
+
+    fn is_known_absent(&self, key: &str) -> bool {
+        let absences = match &self.absences {
+            Some(absences) => absences,
+            None => return false,
+        };
+
+        let mut absences = absences.lock().expect("poisoned lock");
+        match absences.peek(key).and_then(|absence| absence.absent_until) {
+            Some(absent_until) if absent_until > Instant::now() => true,
+            Some(_) => {
+                absences.pop(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Record whether the backing store found a filenode, caching its absence if it has now been
+    /// missed too many times.
+    fn record_backing_result(&self, key: &str, found: bool) {
+        let (absences, options) = match (&self.absences, &self.options.negative_caching) {
+            (Some(absences), Some(options)) => (absences, options),
+            _ => return,
+        };
+
+        let mut absences = absences.lock().expect("poisoned lock");
+        if found {
+            absences.pop(key);
+            return;
+        }
+
+        let now = Instant::now();
+        if !absences.contains(key) {
+            absences.put(key.to_owned(), Absence::default());
+        }
+        let absence = match absences.get_mut(key) {
+            Some(absence) => absence,
+            // Only if no keys can be tracked at all.
+            None => return,
+        };
+        while absence
+            .misses
+            .front()
+            .is_some_and(|miss| now.duration_since(*miss) > options.window)
+        {
+            absence.misses.pop_front();
+        }
+        absence.misses.push_back(now);
+
+        if absence.misses.len() >= options.miss_threshold {
+            let jitter = options.ttl.mul_f64(thread_rng().gen_range(0.0..0.5));
+            absence.absent_until = Some(now + options.ttl + jitter);
+            absence.misses.clear();
+            STATS::negative_cached.add_value(1);
+        }
+    }
+
+    /// The `top_n` most read paths, by encoded path hash, along with approximately how many
+    /// times each was read, most read first. Empty unless `hot_paths_capacity` is set, and never
+    /// more than that many paths.
+    pub fn hot_paths(&self, top_n: usize) -> Vec<(String, u64)> {
+        match &self.access_frequency {
+            Some(access_frequency) => access_frequency.hottest(top_n),
+            None => Vec::new(),
+        }
+    }
+
+    /// Keys and values of the most recent fills that were written, oldest first, up to
+    /// `recent_fills_capacity` of them. The keys encode the repo id, path hash and (for single
+    /// filenodes) filenode id of each fill, so that the values can be written under the same keys
+    /// elsewhere.
+    pub fn recent_fills(&self) -> Vec<(String, FilledValue)> {
+        match &self.recent_fills {
+            Some(recent_fills) => recent_fills.snapshot(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Value of a fill, see `RemoteCache::recent_fills`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilledValue {
+    Filenode(FilenodeInfo),
+    History(FilenodeRange),
+}
+
+/// Most recent fills that were written, up to `capacity` of them.
+struct RecentFills {
+    capacity: usize,
+    fills: Mutex<VecDeque<(String, FilledValue)>>,
+}
+
+impl RecentFills {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            fills: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    fn record(&self, key: String, value: FilledValue) {
+        let mut fills = self.fills.lock().expect("poisoned lock");
+        if fills.len() >= self.capacity {
+            fills.pop_front();
+        }
+        fills.push_back((key, value));
+    }
+
+    fn snapshot(&self) -> Vec<(String, FilledValue)> {
+        let fills = self.fills.lock().expect("poisoned lock");
+        fills.iter().cloned().collect()
+    }
+}
+
+/// Result of reading a value from a cache.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheRead<V> {
+    Hit(V),
+    /// Not cached, or cached but not usable, e.g. because it expired.
+    Miss,
+    /// Cached, but not readable, e.g. because it is corrupt.
+    Corrupt,
+    /// Not known, because the cache could not be reached.
+    Failed,
+}
+
+impl<V> CacheRead<V> {
+    pub fn hit(self) -> Option<V> {
+        match self {
+            CacheRead::Hit(value) => Some(value),
+            CacheRead::Miss | CacheRead::Corrupt | CacheRead::Failed => None,
+        }
+    }
+
+    /// The value if there is one, or why the cache couldn't tell if there is.
+    pub fn into_result(self) -> Result<Option<V>, CacheError> {
+        match self {
+            CacheRead::Hit(value) => Ok(Some(value)),
+            CacheRead::Miss => Ok(None),
+            CacheRead::Corrupt => Err(CacheError::Deserialize),
+            CacheRead::Failed => Err(CacheError::Internal),
+        }
+    }
+}
+
+/// Why a read from a cache failed, returned by `RemoteCache::try_get_filenode` and
+/// `RemoteCache::try_get_history`.
+#[derive(Clone, Copy, Debug, DeriveError, PartialEq, Eq)]
+pub enum CacheError {
+    /// Memcache returned an error, e.g. because it couldn't be reached or timed out.
+    #[error("Memcache could not be read")]
+    Internal,
+    #[error("The cached value could not be deserialized")]
+    Deserialize,
+}
+
+impl<V> From<Option<V>> for CacheRead<V> {
+    fn from(value: Option<V>) -> Self {
+        match value {
+            Some(value) => CacheRead::Hit(value),
+            None => CacheRead::Miss,
+        }
+    }
+}
+
+/// Interface of `RemoteCache`, so that callers can be given a different implementation in tests.
+#[async_trait]
+pub trait FilenodeCache: Send + Sync {
+    async fn get_filenode(&self, key: &CacheKey<FilenodeInfo>) -> Option<FilenodeInfo>;
+
+    /// Like `get_filenode`, but tells corrupt values apart from misses, for caches that can.
+    async fn read_filenode(&self, key: &CacheKey<FilenodeInfo>) -> CacheRead<FilenodeInfo> {
+        self.get_filenode(key).await.into()
+    }
+
+    fn fill_filenode(&self, key: &CacheKey<FilenodeInfo>, filenode: FilenodeInfo);
+
+    async fn get_history(&self, key: &CacheKey<FilenodeRange>) -> Option<FilenodeRange>;
+
+    /// Like `get_history`, but tells corrupt values apart from misses, for caches that can.
+    async fn read_history(&self, key: &CacheKey<FilenodeRange>) -> CacheRead<FilenodeRange> {
+        self.get_history(key).await.into()
+    }
+
+    fn fill_history(&self, key: &CacheKey<FilenodeRange>, filenodes: FilenodeRange);
+}
+
+#[async_trait]
+impl FilenodeCache for RemoteCache {
+    async fn get_filenode(&self, key: &CacheKey<FilenodeInfo>) -> Option<FilenodeInfo> {
+        RemoteCache::get_filenode(self, key).await
+    }
+
+    async fn read_filenode(&self, key: &CacheKey<FilenodeInfo>) -> CacheRead<FilenodeInfo> {
+        RemoteCache::read_filenode(self, key).await
+    }
+
+    fn fill_filenode(&self, key: &CacheKey<FilenodeInfo>, filenode: FilenodeInfo) {
+        RemoteCache::fill_filenode(self, key, filenode)
+    }
+
+    async fn get_history(&self, key: &CacheKey<FilenodeRange>) -> Option<FilenodeRange> {
+        RemoteCache::get_history(self, key).await
+    }
+
+    async fn read_history(&self, key: &CacheKey<FilenodeRange>) -> CacheRead<FilenodeRange> {
+        RemoteCache::read_history(self, key).await
+    }
+
+    fn fill_history(&self, key: &CacheKey<FilenodeRange>, filenodes: FilenodeRange) {
+        RemoteCache::fill_history(self, key, filenodes)
+    }
+}
+
+/// Caches tried in order, e.g. a fast local cache and then memcache. Reads return the first hit,
+/// and by default fill the caches that missed with it, and repair the caches that held a corrupt
+/// value with it. Fills go to all the caches.
+pub struct FallbackCache {
+    layers: Vec<Arc<dyn FilenodeCache>>,
+    backfill: bool,
+    repair: bool,
+}
+
+impl FallbackCache {
+    pub fn new(layers: Vec<Arc<dyn FilenodeCache>>) -> Self {
+        Self {
+            layers,
+            backfill: true,
+            repair: true,
+        }
+    }
+
+    /// Whether a hit from a cache fills the caches before it that missed.
+    pub fn with_backfill(self, backfill: bool) -> Self {
+        Self { backfill, ..self }
+    }
+
+    /// Whether a hit from a cache fills the caches before it that held a corrupt value.
+    pub fn with_repair(self, repair: bool) -> Self {
+        Self { repair, ..self }
+    }
+
+    /// The caches before the one at `index` that should be filled with the value it hit, given
+    /// the indices of the caches that held a corrupt value.
+    fn to_refill(&self, index: usize, corrupt: &[usize]) -> Vec<&Arc<dyn FilenodeCache>> {
+        if !corrupt.is_empty() {
+            STATS::deserialize_err_fallback.add_value(1);
+        }
+        self.layers[..index]
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| {
+                if corrupt.contains(index) {
+                    self.repair
+                } else {
+                    self.backfill
+                }
+            })
+            .map(|(_, layer)| layer)
+            .collect()
+    }
+}
+
+#[async_trait]
+impl FilenodeCache for FallbackCache {
+    async fn get_filenode(&self, key: &CacheKey<FilenodeInfo>) -> Option<FilenodeInfo> {
+        self.read_filenode(key).await.hit()
+    }
+
+    async fn read_filenode(&self, key: &CacheKey<FilenodeInfo>) -> CacheRead<FilenodeInfo> {
+        let mut corrupt = Vec::new();
+        for (index, layer) in self.layers.iter().enumerate() {
+            match layer.read_filenode(key).await {
+                CacheRead::Hit(filenode) => {
+                    for layer in self.to_refill(index, &corrupt) {
+                        layer.fill_filenode(key, filenode.clone());
+                    }
+                    return CacheRead::Hit(filenode);
+                }
+                CacheRead::Miss | CacheRead::Failed => {}
+                CacheRead::Corrupt => corrupt.push(index),
+            }
+        }
+        if corrupt.is_empty() {
+            CacheRead::Miss
+        } else {
+            CacheRead::Corrupt
+        }
+    }
+
+    fn fill_filenode(&self, key: &CacheKey<FilenodeInfo>, filenode: FilenodeInfo) {
+        for layer in &self.layers {
+            layer.fill_filenode(key, filenode.clone());
+        }
+    }
+
+    async fn get_history(&self, key: &CacheKey<FilenodeRange>) -> Option<FilenodeRange> {
+        self.read_history(key).await.hit()
+    }
+
+    async fn read_history(&self, key: &CacheKey<FilenodeRange>) -> CacheRead<FilenodeRange> {
+        let mut corrupt = Vec::new();
+        for (index, layer) in self.layers.iter().enumerate() {
+            match layer.read_history(key).await {
+                CacheRead::Hit(history) => {
+                    for layer in self.to_refill(index, &corrupt) {
+                        layer.fill_history(key, history.clone());
+                    }
+                    return CacheRead::Hit(history);
+                }
+                CacheRead::Miss | CacheRead::Failed => {}
+                CacheRead::Corrupt => corrupt.push(index),
+            }
+        }
+        if corrupt.is_empty() {
+            CacheRead::Miss
+        } else {
+            CacheRead::Corrupt
+        }
+    }
+
+    fn fill_history(&self, key: &CacheKey<FilenodeRange>, filenodes: FilenodeRange) {
+        for layer in &self.layers {
+            layer.fill_history(key, filenodes.clone());
+        }
+    }
+}
+
+type Pointer = i64;
+
+// Memcache would reject keys that are too long, and that would look like an ordinary miss or
+// failed write, so catch those before they're sent.
+fn get_mc_key(keygen: &KeyGen, key: &str) -> Result<String, KeyTooLong> {
+    let mc_key = keygen.key(key);
+    if mc_key.len() > MEMCACHE_KEY_MAX_LEN {
+        STATS::key_too_long.add_value(1);
+        return Err(KeyTooLong(mc_key.len()));
+    }
+    Ok(mc_key)
+}
+
+fn get_mc_key_for_filenodes_list_chunk(
+    keygen: &KeyGen,
+    key: &CacheKey<FilenodeRange>,
+    pointer: Pointer,
+) -> Result<String, KeyTooLong> {
+    get_mc_key(keygen, &format!("{}.{}", key.key, pointer))
+}
+
+/// What the read helpers need from the `RemoteCache` they read for.
+#[derive(Clone, Copy)]
+struct ReadContext<'a> {
+    options: &'a RemoteCacheOptions,
+    /// What to add to the hot stats of this read, if it is sampled by `stat_sample_rate`. Drawn
+    /// once per read, which also skips the bookkeeping that takes locks when it isn't sampled.
+    sample: Option<i64>,
+    recent_writes: Option<&'a RecentWrites>,
+    access_frequency: Option<&'a AccessFrequency>,
+    /// Where reads are reported, if they are.
+    events: Option<&'a EventSink>,
+    /// Stats of the request the read is for, if they are collected.
+    stats: Option<&'a CacheStatsAccumulator>,
+    format_reads: &'a FormatReads,
+    /// Told whether each read reached memcache, if `circuit_breaker` is set.
+    circuit_breaker: Option<&'a CircuitBreaker>,
+    eviction_counts: Option<&'a EvictionCounts>,
+    clock: &'a (dyn Fn() -> i64 + Send + Sync),
+    /// Repos whose reads were skipped, and so aren't misses.
+    disabled_repos: &'a DisabledRepos,
+    /// Where filenodes read are kept, if `stale_fallback_capacity` is set.
+    stale_filenodes: Option<&'a StaleFilenodes>,
+}
+
+impl ReadContext<'_> {
+    /// Context to read a value that was missing under the current key scheme with the previous
+    /// one. The miss was already recorded, so the outcome of the read isn't.
+    fn for_legacy_read(self) -> Self {
+        Self {
+            recent_writes: None,
+            access_frequency: None,
+            events: None,
+            stats: None,
+            eviction_counts: None,
+            stale_filenodes: None,
+            ..self
+        }
+    }
+
+    fn record_hit<V>(&self, key: &CacheKey<V>, bytes: usize, format: ValueFormat) {
+        if let Some(weight) = self.sample {
+            self.record_access(key, weight);
+            self.record_eviction(false);
+        }
+        STATS::format_read.add_value(1, (format.tag(),));
+        self.format_reads.0[format as usize].fetch_add(1, Ordering::Relaxed);
+        if let Some(stats) = self.stats {
+            stats.hits.fetch_add(1, Ordering::Relaxed);
+            stats.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        self.send_event(key, bytes, CacheEventKind::Hit);
+    }
+
+    /// Whether the read of `key` was skipped because its repo is disabled, in which case it is
+    /// counted as such rather than as a miss.
+    fn skipped_disabled_repo<V>(&self, key: &CacheKey<V>) -> bool {
+        if !self.disabled_repos.contains(key.repo_id) {
+            return false;
+        }
+        STATS::repo_disabled_read.add_value(1);
+        true
+    }
+
+    fn record_miss<V>(&self, key: &CacheKey<V>) {
+        if let Some(weight) = self.sample {
+            self.record_access(key, weight);
+            match self
+                .recent_writes
+                .map(|recent_writes| recent_writes.classify_miss(&key.key))
+            {
+                Some(MissKind::Cold) => {
+                    STATS::cold_miss.add_value(weight);
+                    self.record_eviction(false);
+                }
+                Some(MissKind::Evicted) => {
+                    STATS::evicted_miss.add_value(weight);
+                    self.record_eviction(true);
+                }
+                None => {}
+            }
+        }
+        if let Some(stats) = self.stats {
+            stats.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        self.send_event(key, 0, CacheEventKind::Miss);
+    }
+
+    fn record_eviction(&self, evicted: bool) {
+        if let Some(counts) = self.eviction_counts {
+            counts.record((self.clock)(), evicted);
+        }
+    }
+
+    fn record_access<V>(&self, key: &CacheKey<V>, weight: i64) {
+        if let (Some(access_frequency), Some(path_hash)) = (self.access_frequency, key.path_hash())
+        {
+            access_frequency.record(path_hash, weight as u64);
+        }
+    }
+
+    /// Record whether a read from `memcache` reached it, whatever it found. Reads from a no-op
+    /// memcache, e.g. while the breaker is open, say nothing about it, and aren't recorded.
+    fn record_read_result<T>(&self, memcache: &MemcacheHandler, result: &Result<T, Error>) {
+        if let (Some(breaker), false) = (self.circuit_breaker, memcache.is_noop()) {
+            breaker.record(result.is_ok(), tokio::time::Instant::now());
+        }
+    }
+
+    fn send_event<V>(&self, key: &CacheKey<V>, bytes: usize, kind: CacheEventKind) {
+        if let Some(events) = self.events {
+            events.send(CacheEvent {
+                repo_id: key.repo_id,
+                key: key.key.clone(),
+                bytes,
+                kind,
+            });
+        }
+    }
+}
+
+async fn get_single_filenode_from_memcache(
+    memcache: &MemcacheHandler,
+    mc_key: String,
+    key: &CacheKey<FilenodeInfo>,
+    ctx: ReadContext<'_>,
+) -> CacheRead<CachedFilenode> {
+    let options = ctx.options;
+    let result = get_with_retry(memcache, mc_key, options.read_retry).await;
+    ctx.record_read_result(memcache, &result);
+    let serialized = match result {
+        Ok(Some(serialized)) => serialized,
+        Ok(None) if ctx.skipped_disabled_repo(key) => return CacheRead::Miss,
+        Ok(None) => {
+            if let Some(weight) = ctx.sample {
+                STATS::point_filenode_miss.add_value(weight);
+            }
+            ctx.record_miss(key);
+            return CacheRead::Miss;
+        }
+        Err(_) => {
+            STATS::point_filenode_internal_err.add_value(1);
+            return CacheRead::Failed;
+        }
+    };
+
+    let (cached, bytes, format) = match decode_filenode(&serialized, key.repo_id, options) {
+        CacheRead::Hit(decoded) => decoded,
+        CacheRead::Miss => return CacheRead::Miss,
+        CacheRead::Corrupt => return CacheRead::Corrupt,
+        CacheRead::Failed => return CacheRead::Failed,
+    };
+    if let Some(stale_filenodes) = ctx.stale_filenodes {
+        stale_filenodes.record(&key.key, &serialized);
+    }
+
+    if let Some(weight) = ctx.sample {
+        STATS::point_filenode_hit.add_value(weight);
+    }
+    ctx.record_hit(key, bytes, format);
+
+    CacheRead::Hit(cached)
+}
+
+/// The filenode of `repo_id` a value read from memcache holds, along with the size of its
+/// serialized form and its format.
+fn decode_filenode(
+    serialized: &[u8],
+    repo_id: RepositoryId,
+    options: &RemoteCacheOptions,
+) -> CacheRead<(CachedFilenode, usize, ValueFormat)> {
+    if options.value_too_big(serialized.len()) {
+        return CacheRead::Miss;
+    }
+
+    let (serialized, format) = if options.filenode_checksum {
+        match strip_checksum(serialized) {
+            Some(serialized) => (serialized, ValueFormat::CompactChecksummed),
+            None => {
+                STATS::point_filenode_checksum_err.add_value(1);
+                return CacheRead::Corrupt;
+            }
+        }
+    } else {
+        (serialized, ValueFormat::Compact)
+    };
+
+    let cached = match deserialize_filenode(serialized, options.lenient_conversion) {
+        Some(cached) => cached,
+        None => return CacheRead::Corrupt,
+    };
+
+    // Only a bug in key construction could get us here, so treat it as a miss rather than
+    // serving another repo's filenode.
+    if cached.repo_id != repo_id {
+        STATS::repo_mismatch.add_value(1);
+        return CacheRead::Miss;
+    }
+
+    CacheRead::Hit((cached, serialized.len(), format))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MissKind {
+    /// The key wasn't written recently.
+    Cold,
+    /// The key was written recently, so it must have been evicted or expired since.
+    Evicted,
+}
+
+/// Filenodes most recently read from or written to memcache, as serialized there, by cache key.
+/// Values are shared with the reads and writes they come from rather than copied, and decoded
+/// only if they are served. The least recently used are dropped first.
+struct StaleFilenodes {
+    filenodes: Mutex<LruCache<String, Bytes>>,
+}
+
+impl StaleFilenodes {
+    fn new(capacity: usize) -> Self {
+        Self {
+            filenodes: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Keep `serialized` as the value under `key`. A key that is already kept isn't copied again.
+    fn record(&self, key: &str, serialized: &Bytes) {
+        let mut filenodes = self.filenodes.lock().expect("poisoned lock");
+        match filenodes.get_mut(key) {
+            Some(kept) => *kept = serialized.clone(),
+            None => {
+                filenodes.put(key.to_owned(), serialized.clone());
+            }
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Bytes> {
+        let mut filenodes = self.filenodes.lock().expect("poisoned lock");
+        filenodes.get(key).cloned()
+    }
+
+    fn remove(&self, key: &str) {
+        self.filenodes.lock().expect("poisoned lock").pop(key);
+    }
+}
+
+/// Keys written recently, remembered in two bloom filters: keys are added to the newer one, and
+/// once it has had `capacity` keys added, the older one is discarded. Each key is therefore
+/// remembered for at least `capacity` writes, using a bounded amount of memory.
+struct RecentWrites {
+    capacity: usize,
+    filters: Mutex<(BloomFilter, BloomFilter)>,
+}
+
+impl RecentWrites {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            filters: Mutex::new((BloomFilter::new(capacity), BloomFilter::new(capacity))),
+        }
+    }
+
+    fn insert(&self, key: &str) {
+        let mut filters = self.filters.lock().expect("poisoned lock");
+        let (older, newer) = &mut *filters;
+        if newer.inserted >= self.capacity {
+            *older = std::mem::replace(newer, BloomFilter::new(self.capacity));
+        }
+        newer.insert(key);
+    }
+
+    fn classify_miss(&self, key: &str) -> MissKind {
+        let filters = self.filters.lock().expect("poisoned lock");
+        let (older, newer) = &*filters;
+        if newer.contains(key) || older.contains(key) {
+            MissKind::Evicted
+        } else {
+            MissKind::Cold
+        }
+    }
+}
+
+/// Repos for which the cache behaves like a no-op one. Checked on every memcache operation, so
+/// the set is only looked at while some repo is disabled, which is rare.
+#[derive(Default)]
+struct DisabledRepos {
+    any: AtomicBool,
+    repos: RwLock<HashSet<RepositoryId>>,
+}
+
+impl DisabledRepos {
+    fn set(&self, repo_id: RepositoryId, disabled: bool) {
+        let mut repos = self.repos.write().expect("poisoned lock");
+        if disabled {
+            repos.insert(repo_id);
+        } else {
+            repos.remove(&repo_id);
+        }
+        self.any.store(!repos.is_empty(), Ordering::Relaxed);
+    }
+
+    fn contains(&self, repo_id: RepositoryId) -> bool {
+        self.any.load(Ordering::Relaxed)
+            && self.repos.read().expect("poisoned lock").contains(&repo_id)
+    }
+}
+
+/// Approximate number of reads of each path, and the paths read the most.
+struct AccessFrequency {
+    capacity: usize,
+    inner: Mutex<(CountMinSketch, HotPaths)>,
+}
+
+impl AccessFrequency {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new((CountMinSketch::new(), HotPaths::default())),
+        }
+    }
+
+    /// Count `weight` more reads of `path_hash`.
+    fn record(&self, path_hash: &str, weight: u64) {
+        let mut inner = self.inner.lock().expect("poisoned lock");
+        let (sketch, hottest) = &mut *inner;
+        let count = sketch.add(path_hash, weight);
+
+        if let Some(&slot) = hottest.slots.get(path_hash) {
+            hottest.set_count(slot, count);
+        } else if hottest.paths.len() < self.capacity {
+            hottest.push(path_hash, count);
+        } else if let Some(&(coldest_count, slot)) = hottest.by_count.first() {
+            if coldest_count < count {
+                hottest.replace(slot, path_hash, count);
+            }
+        }
+    }
+
+    fn hottest(&self, top_n: usize) -> Vec<(String, u64)> {
+        let inner = self.inner.lock().expect("poisoned lock");
+        let hottest = &inner.1;
+        hottest
+            .by_count
+            .iter()
+            .rev()
+            .take(top_n)
+            .map(|&(_, slot)| hottest.paths[slot].clone())
+            .collect()
+    }
+}
+
+/// The paths read the most so far, kept ordered by their number of reads so that the least read
+/// of them can be found without going through all of them.
+#[derive(Default)]
+struct HotPaths {
+    /// Each path and its number of reads.
+    paths: Vec<(String, u64)>,
+    /// Where each path is in `paths`.
+    slots: HashMap<String, usize>,
+    /// Number of reads and slot of each path, least read first.
+    by_count: BTreeSet<(u64, usize)>,
+}
+
+impl HotPaths {
+    fn push(&mut self, path_hash: &str, count: u64) {
+        let slot = self.paths.len();
+        self.paths.push((path_hash.to_string(), count));
+        self.slots.insert(path_hash.to_string(), slot);
+        self.by_count.insert((count, slot));
+    }
+
+    fn set_count(&mut self, slot: usize, count: u64) {
+        let old_count = std::mem::replace(&mut self.paths[slot].1, count);
+        self.by_count.remove(&(old_count, slot));
+        self.by_count.insert((count, slot));
+    }
+
+    /// Replace the path in `slot` with `path_hash`.
+    fn replace(&mut self, slot: usize, path_hash: &str, count: u64) {
+        let (old_path_hash, old_count) =
+            std::mem::replace(&mut self.paths[slot], (path_hash.to_string(), count));
+        self.slots.remove(&old_path_hash);
+        self.slots.insert(path_hash.to_string(), slot);
+        self.by_count.remove(&(old_count, slot));
+        self.by_count.insert((count, slot));
+    }
+}
+
+struct CountMinSketch {
+    counts: Vec<u64>,
+}
+
+impl CountMinSketch {
+    fn new() -> Self {
+        Self {
+            counts: vec![0; ACCESS_SKETCH_WIDTH * ACCESS_SKETCH_DEPTH as usize],
+        }
+    }
+
+    /// Count `count` more occurrences of `key`, returning the estimated number of occurrences.
+    fn add(&mut self, key: &str, count: u64) -> u64 {
+        let mut estimate = u64::MAX;
+        for row in 0..ACCESS_SKETCH_DEPTH {
+            let mut hasher = DefaultHasher::new();
+            row.hash(&mut hasher);
+            key.hash(&mut hasher);
+            let column = (hasher.finish() % ACCESS_SKETCH_WIDTH as u64) as usize;
+            let total = &mut self.counts[row as usize * ACCESS_SKETCH_WIDTH + column];
+            *total += count;
+            estimate = estimate.min(*total);
+        }
+        estimate
+    }
+}
+
+struct BloomFilter {
+    bits: Vec<u64>,
+    inserted: usize,
+}
+
+impl BloomFilter {
+    fn new(capacity: usize) -> Self {
+        Self {
+            bits: vec![0; (capacity * BLOOM_BITS_PER_KEY).div_ceil(64)],
+            inserted: 0,
+        }
+    }
+
+    fn bit_indexes<'a>(&self, key: &'a str) -> impl Iterator<Item = usize> + 'a {
+        let len = self.bits.len() * 64;
+        (0..BLOOM_HASHES).map(move |seed| {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            key.hash(&mut hasher);
+            (hasher.finish() % len as u64) as usize
+        })
+    }
+
+    fn insert(&mut self, key: &str) {
+        for index in self.bit_indexes(key).collect::<Vec<_>>() {
+            self.bits[index / 64] |= 1 << (index % 64);
+        }
+        self.inserted += 1;
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.bit_indexes(key)
+            .all(|index| self.bits[index / 64] & (1 << (index % 64)) != 0)
+    }
+}
+
+/// Events, e.g. fills started, in each of the last `FILL_RATE_WINDOW_SECS` seconds, in a ring
+/// indexed by Unix timestamp.
+struct SecondCounts {
+    buckets: [u64; FILL_RATE_WINDOW_SECS],
+    /// Latest second counted in `buckets`.
+    latest: i64,
+}
+
+impl SecondCounts {
+    fn new() -> Self {
+        Self {
+            buckets: [0; FILL_RATE_WINDOW_SECS],
+            latest: 0,
+        }
+    }
+
+    fn bucket(second: i64) -> usize {
+        second.rem_euclid(FILL_RATE_WINDOW_SECS as i64) as usize
+    }
+
+    /// Clear the buckets of the seconds that are no longer in the window at `now`. If the clock
+    /// went back, the events keep being counted in the latest second.
+    fn advance(&mut self, now: i64) -> i64 {
+        if now <= self.latest {
+            return self.latest;
+        }
+        let elapsed = (now - self.latest).min(FILL_RATE_WINDOW_SECS as i64);
+        for second in now - elapsed + 1..=now {
+            self.buckets[Self::bucket(second)] = 0;
+        }
+        self.latest = now;
+        now
+    }
+
+    fn record(&mut self, now: i64) {
+        self.add(now, 1);
+    }
+
+    fn add(&mut self, now: i64, count: u64) {
+        let now = self.advance(now);
+        self.buckets[Self::bucket(now)] += count;
+    }
+
+    fn count(&mut self, now: i64) -> u64 {
+        self.advance(now);
+        self.buckets.iter().sum()
+    }
+
+    fn rate(&mut self, now: i64) -> f64 {
+        self.count(now) as f64 / FILL_RATE_WINDOW_SECS as f64
+    }
+}
+
+/// Reads, and the misses among them of keys written recently, see
+/// `RemoteCache::estimated_eviction_pressure`. As every read is counted, those of the latest
+/// second are counted in atomics, and only moved to the window under its lock by the first read
+/// of a later second.
+struct EvictionCounts {
+    /// Second the reads in `reads` and `evicted` happened in.
+    second: AtomicI64,
+    reads: AtomicU64,
+    evicted: AtomicU64,
+    window: Mutex<EvictionWindow>,
+}
+
+/// Reads, and the misses among them of keys written recently, of the seconds before the latest.
+struct EvictionWindow {
+    reads: SecondCounts,
+    evicted: SecondCounts,
+}
+
+impl Default for EvictionCounts {
+    fn default() -> Self {
+        Self {
+            second: AtomicI64::new(0),
+            reads: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+            window: Mutex::new(EvictionWindow {
+                reads: SecondCounts::new(),
+                evicted: SecondCounts::new(),
+            }),
+        }
+    }
+}
+
+impl EvictionCounts {
+    fn record(&self, now: i64, evicted: bool) {
+        let second = self.second.load(Ordering::Acquire);
+        // Reads counted by other threads while the latest second is moved to the window may be
+        // counted in either second.
+        if now > second
+            && self
+                .second
+                .compare_exchange(second, now, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            let reads = self.reads.swap(0, Ordering::AcqRel);
+            let evicted = self.evicted.swap(0, Ordering::AcqRel);
+            let mut window = self.window.lock().expect("poisoned lock");
+            window.reads.add(second, reads);
+            window.evicted.add(second, evicted);
+        }
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        if evicted {
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn pressure(&self, now: i64) -> f64 {
+        let mut window = self.window.lock().expect("poisoned lock");
+        let mut reads = window.reads.count(now);
+        let mut evicted = window.evicted.count(now);
+        if now - self.second.load(Ordering::Acquire) < FILL_RATE_WINDOW_SECS as i64 {
+            reads += self.reads.load(Ordering::Relaxed);
+            evicted += self.evicted.load(Ordering::Relaxed);
+        }
+        match reads {
+            0 => 0.0,
+            reads => evicted as f64 / reads as f64,
+        }
+    }
+}
+
+/// Key builder of the previous key scheme, see `RemoteCache::with_legacy_keygen`.
+struct LegacyKeygen {
+    keygen: KeyGen,
+    /// When to stop migrating values.
+    until: Instant,
+}
+
+/// TTLs a history is written with.
+#[derive(Clone, Copy)]
+struct HistoryTtls {
+    root: Duration,
+    chunks: Duration,
+}
+
+/// Writes of a history since `since`.
+struct Churn {
+    since: Instant,
+    writes: u32,
+}
+
+impl Churn {
+    fn is_recent(&self, now: Instant, window: Duration) -> bool {
+        now.duration_since(self.since) <= window
+    }
+}
+
+/// Recent backing store misses for a key, and until when it's considered absent if they were
+/// frequent enough.
+#[derive(Default)]
+struct Absence {
+    misses: VecDeque<Instant>,
+    absent_until: Option<Instant>,
+}
+
+/// Consecutive read failures, and the cooldown they started, see `CircuitBreakerOptions`. It is
+/// checked by every memcache operation, so it is kept in atomics rather than behind a lock. Times
+/// are in microseconds since `base`, plus one, so that 0 stands for none.
+struct CircuitBreaker {
+    options: CircuitBreakerOptions,
+    base: tokio::time::Instant,
+    /// Failures since the last success, and when the first of them happened.
+    errors: AtomicUsize,
+    first_error: AtomicU64,
+    /// End of the current cooldown, or 0 if the breaker is closed. Once it is over, it is pushed
+    /// back by another cooldown as the probe is let through, and cleared when the probe succeeds.
+    open_until: AtomicU64,
+    /// Whether a probe was let through, and hasn't reported yet.
+    probing: AtomicBool,
+}
+
+impl CircuitBreaker {
+    fn new(options: CircuitBreakerOptions) -> Self {
+        Self {
+            options,
+            base: tokio::time::Instant::now(),
+            errors: AtomicUsize::new(0),
+            first_error: AtomicU64::new(0),
+            open_until: AtomicU64::new(0),
+            probing: AtomicBool::new(false),
+        }
+    }
+
+    fn time(&self, now: tokio::time::Instant) -> u64 {
+        now.saturating_duration_since(self.base).as_micros() as u64 + 1
+    }
+
+    /// Whether a memcache operation can go ahead at `now`: always while the breaker is closed,
+    /// never during a cooldown, and only for the one probe once it is over.
+    fn allow(&self, now: tokio::time::Instant) -> bool {
+        let open_until = self.open_until.load(Ordering::Acquire);
+        if open_until == 0 {
+            return true;
+        }
+        let now = self.time(now);
+        if now < open_until {
+            return false;
+        }
+        let next = now + self.options.cooldown.as_micros() as u64;
+        let probe = self
+            .open_until
+            .compare_exchange(open_until, next, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+        if probe {
+            self.probing.store(true, Ordering::Release);
+        }
+        probe
+    }
+
+    fn record(&self, ok: bool, now: tokio::time::Instant) {
+        let now = self.time(now);
+        if self.open_until.load(Ordering::Acquire) != 0 {
+            // Other reads completing while the breaker is open were started before it opened, so
+            // they say nothing about whether memcache has recovered.
+            if !self.probing.swap(false, Ordering::AcqRel) {
+                return;
+            }
+            if ok {
+                self.errors.store(0, Ordering::Release);
+                self.first_error.store(0, Ordering::Release);
+                self.open_until.store(0, Ordering::Release);
+                STATS::circuit_closed.add_value(1);
+            } else {
+                let open_until = now + self.options.cooldown.as_micros() as u64;
+                self.open_until.store(open_until, Ordering::Release);
+                STATS::circuit_open.add_value(1);
+            }
+            return;
+        }
+
+        if ok {
+            self.errors.store(0, Ordering::Release);
+            self.first_error.store(0, Ordering::Release);
+            return;
+        }
+        let first_error = self.first_error.load(Ordering::Acquire);
+        if first_error == 0
+            || now.saturating_sub(first_error) > self.options.window.as_micros() as u64
+        {
+            self.errors.store(0, Ordering::Release);
+            self.first_error.store(now, Ordering::Release);
+        }
+        let errors = self.errors.fetch_add(1, Ordering::AcqRel) + 1;
+        let open_until = now + self.options.cooldown.as_micros() as u64;
+        if errors >= self.options.errors
+            && self
+                .open_until
+                .compare_exchange(0, open_until, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            STATS::circuit_open.add_value(1);
+        }
+    }
+}
+
+/// Get a value from memcache, retrying failures according to `retry`. If the last attempt fails,
+/// its error is returned.
+async fn get_with_retry(
+    memcache: &MemcacheHandler,
+    key: String,
+    retry: Option<ReadRetryPolicy>,
+) -> Result<Option<Bytes>, Error> {
+    with_retry(retry, || memcache.get(key.clone())).await
+}
+
+/// Run `read`, running it again when it fails according to `retry`.
+async fn with_retry<T, Fut>(
+    retry: Option<ReadRetryPolicy>,
+    mut read: impl FnMut() -> Fut,
+) -> Result<T, Error>
+where
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let (retries, mut backoff) = match retry {
+        Some(retry) => (retry.retries, retry.backoff),
+        None => (0, Duration::ZERO),
+    };
+
+    let mut attempt = 0;
+    loop {
+        match read().await {
+            Err(_) if attempt < retries => {
+                STATS::read_retry.add_value(1);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// A filenode read from memcache, along with what was recorded about it when it was written.
+struct CachedFilenode {
+    repo_id: RepositoryId,
+    info: FilenodeInfo,
+    /// Unix timestamp at which the filenode was written. Not recorded by older values.
+    written_at: Option<i64>,
+    soft_ttl: Option<Duration>,
+    /// Epoch at which the filenode was written. Not recorded by older values.
+    epoch: Option<i64>,
+}
+
+enum Freshness {
+    Fresh,
+    /// Past its soft TTL: served, but refreshed.
+    Stale,
+    /// Past the hard TTL: not served.
+    Expired,
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut hasher = XxHash32::with_seed(0);
+    hasher.write(data);
+    (hasher.finish() as u32).to_be_bytes()
+}
+
+fn with_checksum(data: &[u8]) -> Bytes {
+    let mut value = Vec::with_capacity(data.len() + CHECKSUM_LEN);
+    value.extend_from_slice(data);
+    value.extend_from_slice(&checksum(data));
+    Bytes::from(value)
+}
+
+/// The data a value written by `with_checksum` was created from, if its checksum matches.
+fn strip_checksum(value: &[u8]) -> Option<&[u8]> {
+    let data_len = value.len().checked_sub(CHECKSUM_LEN)?;
+    let (data, sum) = value.split_at(data_len);
+    (sum == checksum(data)).then_some(data)
+}
+
+fn with_kind_tag(tag: u8, data: &[u8]) -> Bytes {
+    let mut value = Vec::with_capacity(data.len() + 1);
+    value.push(tag);
+    value.extend_from_slice(data);
+    Bytes::from(value)
+}
+
+/// The data a value written by `with_kind_tag` was created from, if it has the expected tag.
+fn strip_kind_tag(tag: u8, value: &[u8]) -> Option<&[u8]> {
+    match value.split_first() {
+        Some((first, data)) if *first == tag => Some(data),
+        _ => None,
+    }
+}
+
+fn deserialize_filenode(value: &[u8], lenient: bool) -> Option<CachedFilenode> {
+    let serialized = match strip_kind_tag(FILENODE_TAG, value) {
+        Some(serialized) => serialized,
+        None => {
+            STATS::point_filenode_kind_mismatch.add_value(1);
+            return None;
+        }
+    };
+
+    let res = record_deserialize(|| compact_protocol::deserialize(serialized));
+    let thrift: thrift::CachedFilenodeInfo = match res {
+        Ok(thrift) => thrift,
+        Err(_) => {
+            STATS::point_filenode_deserialize_err.add_value(1);
+            return None;
+        }
+    };
+
+    match filenode_from_thrift(thrift.info, lenient) {
+        Ok(info) => Some(CachedFilenode {
+            repo_id: RepositoryId::new(thrift.repo_id),
+            info,
+            written_at: thrift.written_at,
+            soft_ttl: thrift
+                .soft_ttl_secs
+                .map(|secs| Duration::from_secs(secs.max(0) as u64)),
+            epoch: thrift.epoch,
+        }),
+        Err(_) => {
+            STATS::point_filenode_deserialize_err.add_value(1);
+            None
+        }
+    }
+}
+
+/// The value stored under a history key: either the history itself, or pointers to the chunks
+/// its serialized form was split into.
+enum HistoryRoot {
+    Range {
+        range: FilenodeRange,
+        /// Whether the copy source paths of the history are stored once, see
+        /// `intern_history_paths`.
+        interned: bool,
+    },
+    Chunks {
+        pointers: Vec<Pointer>,
+        /// Unix timestamp at which the root expires. Not recorded by older roots.
+        expires_at: Option<i64>,
+        /// Generation the chunks must belong to. Older roots point to chunks that don't record
+        /// their generation.
+        generation: Option<i64>,
+        /// Number of filenodes in the history. Not recorded by older roots.
+        entry_count: Option<usize>,
+        /// Size of the serialized history split into the chunks. Not recorded by older roots.
+        size: Option<usize>,
+        /// Whether each chunk is a separately serialized part of the history, rather than a slice
+        /// of the serialized history.
+        segmented: bool,
+        /// Whether the data of each chunk is separately compressed with zstd.
+        compressed: bool,
+        /// Pointer to the Bloom filter of the linknodes of the history, stored like a chunk. Not
+        /// recorded by older roots, nor by roots written by `fill_history_stream`.
+        linknode_filter: Option<Pointer>,
+        /// Size the history was split into chunks of. Not recorded by older roots.
+        chunk_size: Option<usize>,
+    },
+}
+
+/// How a history read from memcache was stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryLayout {
+    /// Stored as a single value.
+    Inline,
+    /// Split into this many chunks.
+    Chunked { chunks: usize },
+    /// Not found, or could not be read.
+    Missed,
+}
+
+/// Encoding of a value read from memcache, reported by the `format_read` stat so that the rollout
+/// of new encodings can be followed. Values are all compact thrift, and differ in how they are
+/// framed and in whether the chunks of a history are compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ValueFormat {
+    /// A single serialized value.
+    Compact,
+    /// A single serialized filenode followed by a checksum, see `filenode_checksum`.
+    CompactChecksummed,
+    /// A serialized history split into chunks.
+    CompactChunked,
+    /// Chunks that are each a serialized part of a history, written by `fill_history_stream`.
+    CompactSegmented,
+    /// Like `CompactChunked`, with each chunk compressed, see `chunk_compression_level`.
+    CompactChunkedZstd,
+    /// Like `CompactSegmented`, with each chunk compressed.
+    CompactSegmentedZstd,
+    /// A single serialized history with its copy source paths stored once, see
+    /// `intern_history_paths`. Chunked histories are counted as their chunked format whether or
+    /// not their paths are.
+    CompactInterned,
+}
+
+impl ValueFormat {
+    const ALL: [ValueFormat; 7] = [
+        ValueFormat::Compact,
+        ValueFormat::CompactChecksummed,
+        ValueFormat::CompactChunked,
+        ValueFormat::CompactSegmented,
+        ValueFormat::CompactChunkedZstd,
+        ValueFormat::CompactSegmentedZstd,
+        ValueFormat::CompactInterned,
+    ];
+
+    /// Dimension of the `format_read` stat for this format.
+    pub fn tag(self) -> &'static str {
+        match self {
+            ValueFormat::Compact => "compact.uncompressed",
+            ValueFormat::CompactChecksummed => "compact_checksummed.uncompressed",
+            ValueFormat::CompactChunked => "compact_chunked.uncompressed",
+            ValueFormat::CompactSegmented => "compact_segmented.uncompressed",
+            ValueFormat::CompactChunkedZstd => "compact_chunked.zstd",
+            ValueFormat::CompactSegmentedZstd => "compact_segmented.zstd",
+            ValueFormat::CompactInterned => "compact_interned.uncompressed",
+        }
+    }
+
+    fn inline(interned: bool) -> Self {
+        if interned {
+            ValueFormat::CompactInterned
+        } else {
+            ValueFormat::Compact
+        }
+    }
+
+    fn chunked(segmented: bool, compressed: bool) -> Self {
+        match (segmented, compressed) {
+            (false, false) => ValueFormat::CompactChunked,
+            (true, false) => ValueFormat::CompactSegmented,
+            (false, true) => ValueFormat::CompactChunkedZstd,
+            (true, true) => ValueFormat::CompactSegmentedZstd,
+        }
+    }
+}
+
+/// Successful reads of each format, indexed by format, see `RemoteCache::format_reads`.
+#[derive(Default)]
+struct FormatReads([AtomicU64; ValueFormat::ALL.len()]);
+
+/// Outcome of `RemoteCache::self_check`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct SelfCheckResult {
+    /// Whether the canary filenode was read back as it was written.
+    pub filenode_ok: bool,
+    /// Whether the canary history was read back as it was written.
+    pub history_ok: bool,
+    /// Time taken to write and read back both values.
+    pub latency: Duration,
+}
+
+impl SelfCheckResult {
+    pub fn ok(&self) -> bool {
+        self.filenode_ok && self.history_ok
+    }
+}
+
+/// Number of filenodes in a history stored in memcache, and how it is stored. Returned by
+/// `RemoteCache::history_summary`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistorySummary {
+    /// Number of filenodes in the history, or `None` if it was too big to be stored.
+    pub entry_count: Option<usize>,
+    pub layout: HistoryLayout,
+    /// Size of the serialized history. For a chunked history this excludes the root.
+    pub approx_bytes: usize,
+}
+
+impl HistorySummary {
+    fn new(range: &FilenodeRange, layout: HistoryLayout, approx_bytes: usize) -> Self {
+        let entry_count = match range {
+            FilenodeRange::Filenodes(filenodes) => Some(filenodes.len()),
+            FilenodeRange::TooBig => None,
+        };
+        Self {
+            entry_count,
+            layout,
+            approx_bytes,
+        }
+    }
+}
+
+/// Every step of a read of a history, returned by `RemoteCache::explain_get_history`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryExplain {
+    /// Key of the root, without the memcache prefix.
+    pub key: String,
+    /// Size of the root, if it was found.
+    pub root_bytes: Option<usize>,
+    /// How the history is stored, or `Missed` if the root wasn't found or couldn't be read.
+    pub layout: HistoryLayout,
+    /// The chunks that were fetched, in order, if the history is chunked.
+    pub chunks: Vec<ChunkExplain>,
+    /// Why the read failed, if it did, e.g. `Chunk 12 is missing`.
+    pub errors: Vec<String>,
+    /// What `get_history` would have returned.
+    pub outcome: CacheRead<FilenodeRange>,
+}
+
+/// A chunk fetched by `RemoteCache::explain_get_history`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkExplain {
+    pub pointer: i64,
+    /// Size of the data of the chunk, or `None` if it couldn't be read.
+    pub bytes: Option<usize>,
+}
+
+/// A history read in flight, if `coalesce_history_reads` is set. Calls waiting for it are told
+/// when it is dropped, even if the read is cancelled, so that they can read the history
+/// themselves.
+struct InFlightHistoryRead<'a> {
+    reads: &'a Mutex<HashMap<String, Vec<oneshot::Sender<CacheRead<FilenodeRange>>>>>,
+    root_key: Option<String>,
+}
+
+impl InFlightHistoryRead<'_> {
+    /// Mark the read as done, and return the calls waiting for its result.
+    fn finish(mut self) -> Vec<oneshot::Sender<CacheRead<FilenodeRange>>> {
+        self.take_waiters()
+    }
+
+    fn take_waiters(&mut self) -> Vec<oneshot::Sender<CacheRead<FilenodeRange>>> {
+        let root_key = match self.root_key.take() {
+            Some(root_key) => root_key,
+            None => return Vec::new(),
+        };
+        let mut reads = self.reads.lock().expect("poisoned lock");
+        reads.remove(&root_key).unwrap_or_default()
+    }
+}
+
+impl Drop for InFlightHistoryRead<'_> {
+    fn drop(&mut self) {
+        self.take_waiters();
+    }
+}
+
+/// A history being written again after a read, see `RemoteCache::refill_history`. Until this is
+/// dropped, other reads don't write it again.
+struct PendingRefill {
+    refills: Arc<Mutex<HashSet<String>>>,
+    key: String,
+}
+
+impl PendingRefill {
+    /// Start writing the history under `key` again, unless it already is.
+    fn start(refills: &Arc<Mutex<HashSet<String>>>, key: &str) -> Option<Self> {
+        if !refills
+            .lock()
+            .expect("poisoned lock")
+            .insert(key.to_owned())
+        {
+            return None;
+        }
+        Some(Self {
+            refills: refills.clone(),
+            key: key.to_owned(),
+        })
+    }
+}
+
+impl Drop for PendingRefill {
+    fn drop(&mut self) {
+        self.refills
+            .lock()
+            .expect("poisoned lock")
+            .remove(&self.key);
+    }
+}
+
+/// A history read from memcache, along with details of how it was stored.
+struct CachedHistory {
+    range: FilenodeRange,
+    layout: HistoryLayout,
+    /// How long the root has left to live, if the history was chunked and the root records it.
+    ttl_remaining: Option<Duration>,
+    /// Whether the history was chunked, but not at the size it would be now, if the root records
+    /// the sizes needed to tell.
+    stale_chunk_size: bool,
+    timings: HistoryTimings,
+}
+
+/// Where the time reading a history from memcache went, also recorded in the
+/// `get_history_*_us` histograms, which break down the `get_history` one.
+#[derive(Clone, Copy, Debug, Default)]
+struct HistoryTimings {
+    root_read: Duration,
+    /// Reading the chunks, if the history is chunked.
+    chunk_read: Duration,
+    /// Deserializing the root, and the chunks if the history is chunked.
+    deserialize: Duration,
+}
+
+async fn get_history_from_memcache(
+    logger: &Logger,
+    memcache: &MemcacheHandler,
+    keygen: &KeyGen,
+    key: &CacheKey<FilenodeRange>,
+    ctx: ReadContext<'_>,
+    scratch: &mut Vec<u8>,
+) -> CacheRead<CachedHistory> {
+    let options = ctx.options;
+    let root_key = match get_mc_key(keygen, &key.key) {
+        Ok(root_key) => root_key,
+        Err(_) => return CacheRead::Miss,
+    };
+
+    let mut timings = HistoryTimings::default();
+    let now = Instant::now();
+    let root = get_with_retry(memcache, root_key, options.read_retry).await;
+    timings.root_read = now.elapsed();
+    ctx.record_read_result(memcache, &root);
+    STATS::get_history_root_read_us.add_value(timings.root_read.as_micros_unchecked() as i64);
+
+    let serialized = match root {
+        Ok(Some(serialized)) => serialized,
+        Ok(None) if ctx.skipped_disabled_repo(key) => return CacheRead::Miss,
+        Ok(None) => {
+            if let Some(weight) = ctx.sample {
+                STATS::gaf_miss.add_value(weight);
+            }
+            ctx.record_miss(key);
+            return CacheRead::Miss;
+        }
+        Err(e) => {
+            STATS::gaf_internal_err.add_value(1);
+            if options.sample_history_error_log() {
+                warn!(
+                    logger,
+                    "Failed to read history root from memcache: {}", e;
+                    "key" => &key.key,
+                    "root_key" => get_mc_key(keygen, &key.key).unwrap_or_default(),
+                );
+            }
+            return CacheRead::Failed;
+        }
+    };
+
+    if options.value_too_big(serialized.len()) {
+        return CacheRead::Miss;
+    }
+
+    let (root, elapsed) =
+        timed(|| deserialize_history_root(&serialized, options.lenient_conversion));
+    timings.deserialize = elapsed;
+    let (repo_id, root) = match root {
+        Some(root) => root,
+        None => return CacheRead::Corrupt,
+    };
+
+    // See get_single_filenode_from_memcache
+    if repo_id != key.repo_id {
+        STATS::repo_mismatch.add_value(1);
+        return CacheRead::Miss;
+    }
+
+    let mut bytes = serialized.len();
+    let format;
+    let history = match root {
+        HistoryRoot::Range { range, interned } => {
+            format = ValueFormat::inline(interned);
+            CachedHistory {
+                range,
+                layout: HistoryLayout::Inline,
+                ttl_remaining: None,
+                stale_chunk_size: false,
+                timings,
+            }
+        }
+        HistoryRoot::Chunks {
+            pointers,
+            expires_at,
+            generation,
+            size,
+            segmented,
+            compressed,
+            chunk_size,
+            ..
+        } => {
+            STATS::gaf_pointers.add_value(1);
+
+            if pointers.len() > options.max_history_pointers() {
+                STATS::gaf_too_many_pointers.add_value(1);
+                return CacheRead::Corrupt;
+            }
+
+            let chunks = pointers.len();
+            let now = Instant::now();
+            let read_chunks_fut =
+                read_history_chunks(memcache, keygen, key, pointers, generation, compressed);
+            let read_chunks = read_chunks_fut.await;
+            timings.chunk_read = now.elapsed();
+            STATS::get_history_chunk_read_us
+                .add_value(timings.chunk_read.as_micros_unchecked() as i64);
+            let read_chunks = match read_chunks {
+                Ok(read_chunks) => read_chunks,
+                Err(e) => return chunk_read_failed(logger, keygen, key, options, e),
+            };
+
+            bytes = read_chunks.iter().map(Vec::len).sum();
+            if options.value_too_big(bytes) {
+                return CacheRead::Miss;
+            }
+            format = ValueFormat::chunked(segmented, compressed);
+            let lenient = options.lenient_conversion;
+            let (range, elapsed) =
+                timed(|| deserialize_history_chunks(&read_chunks, segmented, lenient, scratch));
+            timings.deserialize += elapsed;
+            match range {
+                Some(range) => CachedHistory {
+                    range,
+                    layout: HistoryLayout::Chunked { chunks },
+                    ttl_remaining: expires_at.map(ttl_remaining),
+                    // Chunks are sized for the serialized history, as recorded in the root.
+                    stale_chunk_size: chunk_size.is_some_and(|chunk_size| {
+                        chunk_size != target_chunk_size(size.unwrap_or(bytes), options).0
+                    }),
+                    timings,
+                },
+                None => return CacheRead::Corrupt,
+            }
+        }
+    };
+    STATS::get_history_deserialize_us
+        .add_value(history.timings.deserialize.as_micros_unchecked() as i64);
+
+    if let Some(weight) = ctx.sample {
+        STATS::gaf_hit.add_value(weight);
+    }
+    ctx.record_hit(key, bytes, format);
+
+    CacheRead::Hit(history)
+}
+
+/// Fetch the chunks of a chunked history, in order, decompressed if they are `compressed`. If the
+/// root records a generation, all the chunks must belong to it, so that a history being replaced
+/// is never read as a mix of the old and new chunks.
+async fn read_history_chunks(
+    memcache: &MemcacheHandler,
+    keygen: &KeyGen,
+    key: &CacheKey<FilenodeRange>,
+    pointers: Vec<Pointer>,
+    generation: Option<i64>,
+    compressed: bool,
+) -> Result<Vec<Vec<u8>>, ChunkReadError> {
+    let read_chunks_fut = pointers.into_iter().map(move |pointer| {
+        let chunk_key = get_mc_key_for_filenodes_list_chunk(keygen, key, pointer);
+
+        async move {
+            let chunk_key = chunk_key.map_err(|_| ChunkReadError::Failed(pointer))?;
+            match memcache.get(chunk_key).await {
+                Ok(chunk) => check_chunk(chunk.as_deref(), pointer, generation, compressed),
+                Err(_) => Err(ChunkReadError::Failed(pointer)),
+            }
+        }
+    });
+
+    try_join_all(read_chunks_fut).await
+}
+
+/// The data of a chunk read from memcache, unwrapped if the root records a generation. Only roots
+/// that record a generation can point to `compressed` chunks.
+fn check_chunk(
+    chunk: Option<&[u8]>,
+    pointer: Pointer,
+    generation: Option<i64>,
+    compressed: bool,
+) -> Result<Vec<u8>, ChunkReadError> {
+    match chunk {
+        Some([]) => Err(ChunkReadError::Empty(pointer)),
+        Some(chunk) => match generation {
+            Some(generation) => unwrap_chunk(chunk, pointer, generation, compressed),
+            None => Ok(chunk.to_vec()),
+        },
+        None => Err(ChunkReadError::Missing(pointer)),
+    }
+}
+
+/// Record a failure to read the chunks of the history under `key`.
+fn chunk_read_failed<V>(
+    logger: &Logger,
+    keygen: &KeyGen,
+    key: &CacheKey<FilenodeRange>,
+    options: &RemoteCacheOptions,
+    e: ChunkReadError,
+) -> CacheRead<V> {
+    match e {
+        ChunkReadError::Missing(_) => STATS::gaf_chunk_missing.add_value(1),
+        ChunkReadError::Empty(_) => STATS::gaf_chunk_empty.add_value(1),
+        ChunkReadError::Failed(_) => STATS::gaf_pointers_err.add_value(1),
+        ChunkReadError::Malformed(_) => STATS::gaf_deserialize_err.add_value(1),
+        ChunkReadError::GenerationMismatch(_) => STATS::gaf_chunk_generation_mismatch.add_value(1),
+    }
+    if options.sample_history_error_log() {
+        warn!(
+            logger,
+            "Failed to read history from memcache: {}", e;
+            "key" => &key.key,
+            "root_key" => get_mc_key(keygen, &key.key).unwrap_or_default(),
+            "chunk_key" => get_mc_key_for_filenodes_list_chunk(keygen, key, e.pointer())
+                .unwrap_or_default(),
+        );
+    }
+    match e {
+        ChunkReadError::Malformed(_) => CacheRead::Corrupt,
+        ChunkReadError::Failed(_) => CacheRead::Failed,
+        _ => CacheRead::Miss,
+    }
+}
+
+fn unwrap_chunk(
+    serialized: &[u8],
+    pointer: Pointer,
+    generation: i64,
+    compressed: bool,
+) -> Result<Vec<u8>, ChunkReadError> {
+    let res = record_deserialize(|| compact_protocol::deserialize(serialized));
+    let chunk: thrift::FilenodeInfoChunk = res.map_err(|_| ChunkReadError::Malformed(pointer))?;
+
+    if chunk.generation != generation {
+        return Err(ChunkReadError::GenerationMismatch(pointer));
+    }
+
+    if chunk.data.is_empty() {
+        return Err(ChunkReadError::Empty(pointer));
+    }
+
+    if chunk.compressed.unwrap_or(compressed) {
+        return zstd::stream::decode_all(&chunk.data[..])
+            .map_err(|_| ChunkReadError::Malformed(pointer));
+    }
+
+    Ok(chunk.data)
+}
+
+/// Bloom filter of the linknodes of `list`, or `None` if it isn't a list of filenodes or a
+/// linknode can't be read, so that a filter never misses a linknode of its list. Unlike
+/// `BloomFilter`, this is stored, so it hashes with xxHash rather than with the standard library's
+/// hasher, which may change between releases.
+fn build_linknode_filter(list: &thrift::FilenodeInfoList) -> Option<Vec<u8>> {
+    let list: Vec<_> = match list {
+        thrift::FilenodeInfoList::Data(list) => list.iter().collect(),
+        thrift::FilenodeInfoList::Interned(list) => {
+            list.infos.iter().map(|item| &item.info).collect()
+        }
+        _ => return None,
+    };
+    let bits = (list.len() * BLOOM_BITS_PER_KEY).max(64);
+    let mut filter = vec![0u8; bits.div_ceil(8)];
+    for info in list {
+        let linknode = HgNodeHash::from_thrift(info.linknode.clone()).ok()?;
+        for index in linknode_filter_indexes(&filter, linknode.as_bytes()) {
+            filter[index / 8] |= 1 << (index % 8);
+        }
+    }
+    Some(filter)
+}
+
+/// Whether the list `filter` was built for may contain `linknode`. False positives are possible,
+/// false negatives are not. An empty filter is never built, so one that was read back empty may
+/// contain anything.
+fn linknode_filter_contains(filter: &[u8], linknode: &HgChangesetId) -> bool {
+    filter.is_empty()
+        || linknode_filter_indexes(filter, linknode.as_bytes())
+            .all(|index| filter[index / 8] & (1 << (index % 8)) != 0)
+}
+
+fn linknode_filter_indexes<'a>(
+    filter: &[u8],
+    linknode: &'a [u8],
+) -> impl Iterator<Item = usize> + 'a {
+    let len = filter.len() as u64 * 8;
+    (0..BLOOM_HASHES as u32).map(move |seed| {
+        let mut hasher = XxHash32::with_seed(seed);
+        hasher.write(linknode);
+        (hasher.finish() % len) as usize
+    })
+}
+
+/// Compress the data of a chunk if a `level` is set, the data is at least `min_bytes` long, and
+/// compressing it makes it smaller, see `chunk_compression_level`. Also returns whether the data
+/// was compressed, to be recorded in the chunk, or `None` if compression is disabled.
+fn compress_chunk(
+    data: Vec<u8>,
+    level: Option<i32>,
+    min_bytes: usize,
+) -> Result<(Vec<u8>, Option<bool>), Error> {
+    let level = match level {
+        Some(level) => level,
+        None => return Ok((data, None)),
+    };
+    if data.len() < min_bytes {
+        STATS::chunk_compression_below_min.add_value(1);
+        return Ok((data, Some(false)));
+    }
+    let compressed = zstd::bulk::compress(&data, level)?;
+    if compressed.len() < data.len() {
+        Ok((compressed, Some(true)))
+    } else {
+        STATS::chunk_compression_skipped.add_value(1);
+        Ok((data, Some(false)))
+    }
+}
+
+/// Convert a cached filenode, falling back to the lenient conversion if the strict one fails and
+/// `lenient` is set.
+fn filenode_from_thrift(info: thrift::FilenodeInfo, lenient: bool) -> Result<FilenodeInfo, Error> {
+    if !lenient {
+        return FilenodeInfo::from_thrift(info);
+    }
+
+    FilenodeInfo::from_thrift(info.clone()).or_else(|_| {
+        let info = FilenodeInfo::from_thrift_lenient(info)?;
+        STATS::lenient_conversion.add_value(1);
+        Ok(info)
+    })
+}
+
+// helper function for deserializing list of thrift FilenodeInfo into rust structure with proper
+// error returned
+fn deserialize_list(list: Vec<thrift::FilenodeInfo>, lenient: bool) -> Option<Vec<FilenodeInfo>> {
+    let res: Result<Vec<_>, _> = list
+        .into_iter()
+        .map(|info| filenode_from_thrift(info, lenient))
+        .collect();
+    if res.is_err() {
+        STATS::gaf_deserialize_err.add_value(1);
+    }
+    res.ok()
+}
+
+/// Like `deserialize_list`, for a list written by `history_into_thrift_interned`.
+fn deserialize_interned_list(
+    list: thrift::InternedFilenodeInfoList,
+    lenient: bool,
+) -> Option<Vec<FilenodeInfo>> {
+    let res = (|| {
+        let paths = list
+            .copyfrom_paths
+            .into_iter()
+            .map(RepoPath::from_thrift)
+            .collect::<Result<Vec<_>, _>>()?;
+        list.infos
+            .into_iter()
+            .map(|item| {
+                let mut info = filenode_from_thrift(item.info, lenient)?;
+                if let (Some(path), Some(filenode)) = (item.copyfrom_path, item.copyfrom_filenode) {
+                    let path = paths.get(path as usize).ok_or_else(|| {
+                        Error::msg(format!("Copy source path {} is missing", path))
+                    })?;
+                    let filenode = HgFileNodeId::new(HgNodeHash::from_thrift(filenode)?);
+                    info.copyfrom = Some((path.clone(), filenode));
+                }
+                Ok(info)
+            })
+            .collect::<Result<Vec<_>, Error>>()
+    })();
+    if res.is_err() {
+        STATS::gaf_deserialize_err.add_value(1);
+    }
+    res.ok()
+}
+
+fn deserialize_history_root(value: &[u8], lenient: bool) -> Option<(RepositoryId, HistoryRoot)> {
+    let serialized = match strip_kind_tag(HISTORY_TAG, value) {
+        Some(serialized) => serialized,
+        None => {
+            STATS::gaf_kind_mismatch.add_value(1);
+            return None;
+        }
+    };
+
+    let res = record_deserialize(|| compact_protocol::deserialize(serialized));
+    let thrift: thrift::CachedFilenodeInfoList = match res {
+        Ok(thrift) => thrift,
+        Err(_) => {
+            STATS::gaf_deserialize_err.add_value(1);
+            return None;
+        }
+    };
+
+    let root = match thrift.list {
+        thrift::FilenodeInfoList::UnknownField(_) => {
+            STATS::gaf_deserialize_err.add_value(1);
+            None
+        }
+        thrift::FilenodeInfoList::Data(list) => {
+            deserialize_list(list, lenient).map(|list| HistoryRoot::Range {
+                range: FilenodeRange::Filenodes(list),
+                interned: false,
+            })
+        }
+        thrift::FilenodeInfoList::Interned(list) => {
+            deserialize_interned_list(list, lenient).map(|list| HistoryRoot::Range {
+                range: FilenodeRange::Filenodes(list),
+                interned: true,
+            })
+        }
+        thrift::FilenodeInfoList::Pointers(pointers) => Some(HistoryRoot::Chunks {
+            pointers,
+            expires_at: None,
+            generation: None,
+            entry_count: None,
+            size: None,
+            segmented: false,
+            compressed: false,
+            linknode_filter: None,
+            chunk_size: None,
+        }),
+        thrift::FilenodeInfoList::Chunks(chunks) => Some(HistoryRoot::Chunks {
+            pointers: chunks.pointers,
+            expires_at: Some(chunks.expires_at),
+            generation: Some(chunks.generation),
+            entry_count: chunks.entry_count.map(|count| count as usize),
+            size: chunks.size.map(|size| size as usize),
+            segmented: chunks.segmented.unwrap_or(false),
+            compressed: chunks.compressed.unwrap_or(false),
+            linknode_filter: chunks.linknode_filter_pointer,
+            chunk_size: chunks.chunk_size.map(|size| size as usize),
+        }),
+        thrift::FilenodeInfoList::TooBig(_) => Some(HistoryRoot::Range {
+            range: FilenodeRange::TooBig,
+            interned: false,
+        }),
+    }?;
+
+    Some((RepositoryId::new(thrift.repo_id), root))
+}
+
+/// Deserialize a history reassembled from its chunks.
+fn deserialize_history_blob(blob: &[u8], lenient: bool) -> Option<FilenodeRange> {
+    match record_deserialize(|| compact_protocol::deserialize(blob)) {
+        Ok(thrift::FilenodeInfoList::Data(list)) => {
+            deserialize_list(list, lenient).map(FilenodeRange::Filenodes)
+        }
+        Ok(thrift::FilenodeInfoList::Interned(list)) => {
+            deserialize_interned_list(list, lenient).map(FilenodeRange::Filenodes)
+        }
+        Ok(thrift::FilenodeInfoList::TooBig(_)) => Some(FilenodeRange::TooBig),
+        _ => {
+            STATS::gaf_reassembled_deserialize_err.add_value(1);
+            None
+        }
+    }
+}
+
+/// `range` with its entries in the opposite order.
+fn reverse_history(range: FilenodeRange) -> FilenodeRange {
+    match range {
+        FilenodeRange::Filenodes(mut filenodes) => {
+            filenodes.reverse();
+            FilenodeRange::Filenodes(filenodes)
+        }
+        FilenodeRange::TooBig => FilenodeRange::TooBig,
+    }
+}
+
+/// Deserialize a history from its chunks, either by reassembling them in `scratch`, or if they
+/// are `segmented`, by deserializing each of them and joining the parts.
+fn deserialize_history_chunks(
+    chunks: &[Vec<u8>],
+    segmented: bool,
+    lenient: bool,
+    scratch: &mut Vec<u8>,
+) -> Option<FilenodeRange> {
+    if !segmented {
+        scratch.clear();
+        for chunk in chunks {
+            scratch.extend_from_slice(chunk);
+        }
+        return deserialize_history_blob(scratch, lenient);
+    }
+
+    let mut filenodes = Vec::new();
+    for chunk in chunks {
+        match deserialize_history_blob(chunk, lenient)? {
+            FilenodeRange::Filenodes(part) => filenodes.extend(part),
+            FilenodeRange::TooBig => {
+                STATS::gaf_reassembled_deserialize_err.add_value(1);
+                return None;
+            }
+        }
+    }
+    Some(FilenodeRange::Filenodes(filenodes))
+}
+
+/// Fuzzing entry point running the same decoding as `get_filenode` on arbitrary bytes.
+#[cfg(any(test, fuzzing))]
+pub fn try_deserialize_filenode(bytes: &[u8]) -> Option<FilenodeInfo> {
+    deserialize_filenode(bytes, false).map(|cached| cached.info)
+}
+
+/// Fuzzing entry point running the same decoding as `get_history` on arbitrary bytes. A root
+/// pointing to chunks can't be resolved without memcache, so it yields `None`.
+#[cfg(any(test, fuzzing))]
+pub fn try_deserialize_history(bytes: &[u8]) -> Option<FilenodeRange> {
+    match deserialize_history_root(bytes, false)?.1 {
+        HistoryRoot::Range { range, .. } => Some(range),
+        HistoryRoot::Chunks { .. } => None,
+    }
+}
+
+/// `rate` for one in `rate` calls, and `None` for the others, or always 1 if `rate` is 0 or 1, so
+/// that adding the result to a counter gives about the same total as adding 1 for every call.
+fn sample_weight(rate: u32) -> Option<i64> {
+    if rate <= 1 {
+        return Some(1);
+    }
+    thread_rng().gen_ratio(1, rate).then_some(i64::from(rate))
+}
+
+fn random_ttl_jitter() -> Duration {
+    ttl_jitter_from(&mut thread_rng())
+}
+
+fn ttl_jitter_from(rng: &mut impl Rng) -> Duration {
+    Duration::from_secs(rng.gen_range(0..TTL_SEC_RAND))
+}
+
+fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
+
+/// Time left before something expiring at the given Unix timestamp expires.
+fn ttl_remaining(expires_at: i64) -> Duration {
+    Duration::from_secs(expires_at.saturating_sub(unix_timestamp()).max(0) as u64)
+}
+
+/// `duration` in its largest whole unit, out of hours, minutes and seconds, e.g. `7h`.
+fn approx_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 60 * 60 {
+        format!("{}h", secs / (60 * 60))
+    } else if secs >= 60 {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Run `f`, returning its result along with how long it took.
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let now = Instant::now();
+    let ret = f();
+    (ret, now.elapsed())
+}
+
+// Time spent (de)serializing is recorded separately from memcache latency, so that it's possible
+// to tell whether slow requests are CPU or network bound.
+fn record_serialize<T>(f: impl FnOnce() -> T) -> T {
+    let (ret, elapsed) = timed(f);
+    STATS::serialize_us.add_value(elapsed.as_micros_unchecked() as i64);
+    #[cfg(test)]
+    SERDE_RECORDED.with(|recorded| recorded.set((recorded.get().0 + 1, recorded.get().1)));
+    ret
+}
+
+fn record_deserialize<T>(f: impl FnOnce() -> T) -> T {
+    let (ret, elapsed) = timed(f);
+    STATS::deserialize_us.add_value(elapsed.as_micros_unchecked() as i64);
+    #[cfg(test)]
+    SERDE_RECORDED.with(|recorded| recorded.set((recorded.get().0, recorded.get().1 + 1)));
+    ret
+}
+
+// Number of serializations and deserializations timed on this thread, as the stats can't be read
+// back. Tests run their fills on their own thread, so they only see their own.
+#[cfg(test)]
+thread_local! {
+    static SERDE_RECORDED: std::cell::Cell<(usize, usize)> = const { std::cell::Cell::new((0, 0)) };
+}
+
+/// Fills are best effort, so if there is no runtime to run them on (e.g. because `fill_*` was
+/// called from a synchronous shutdown path), drop them rather than panic.
+fn spawn_fill(
+    spawner: FillSpawner<'_>,
+    bytes: usize,
+    fut: impl Future<Output = ()> + Send + 'static,
+) {
+    let in_flight = spawner.bytes_in_flight.fetch_add(bytes, Ordering::Relaxed);
+    let fill_bytes = FillBytes {
+        in_flight: spawner.bytes_in_flight.clone(),
+        bytes,
+    };
+    if spawner
+        .bytes_budget
+        .is_some_and(|budget| in_flight >= budget)
+    {
+        STATS::fill_dropped_bytes_budget.add_value(1);
+        return;
+    }
+
+    let permit = match spawner
+        .permits
+        .map(|permits| permits.clone().try_acquire_owned())
+    {
+        Some(Ok(permit)) => Some(permit),
+        Some(Err(_)) => {
+            STATS::fill_dropped_concurrency.add_value(1);
+            return;
+        }
+        None => None,
+    };
+
+    match Handle::try_current() {
+        Ok(handle) => {
+            let counts = spawner.counts.clone();
+            let spawned = Instant::now();
+            let tracked = spawner.tracker.map(FillTracker::track);
+            handle.spawn(async move {
+                let lag_us = spawned.elapsed().as_micros() as u64;
+                STATS::fill_spawn_lag_us.add_value(lag_us as i64);
+                counts.started.fetch_add(1, Ordering::Relaxed);
+                counts.spawn_lag_us.fetch_add(lag_us, Ordering::Relaxed);
+                fut.await;
+                drop(permit);
+                drop(fill_bytes);
+                drop(tracked);
+            });
+        }
+        Err(_) => {
+            STATS::fill_no_runtime.add_value(1);
+            if thread_rng().gen_ratio(1, NO_RUNTIME_LOG_SAMPLE_RATE) {
+                warn!(
+                    spawner.logger,
+                    "No tokio runtime available, dropping filenodes cache fill"
+                );
+            }
+        }
+    }
+}
+
+async fn check_canary(memcache: &MemcacheHandler, keygen: &KeyGen) -> bool {
+    let key = keygen.key(&*CANARY_PREFIX);
+    // A fresh value each time, so that a value left over from a previous check doesn't count.
+    let value = Bytes::from(random::<u64>().to_string());
+
+    let ok = match memcache
+        .set_with_ttl(key.clone(), value.clone(), CANARY_TTL)
+        .await
+    {
+        Ok(()) => matches!(memcache.get(key).await, Ok(Some(read)) if read == value),
+        Err(_) => false,
+    };
+
+    if ok {
+        STATS::canary_ok.add_value(1);
+    } else {
+        STATS::canary_fail.add_value(1);
+    }
+
+    ok
+}
+
+/// Key of a value written by `RemoteCache::self_check` in this process. Keys of real values all
+/// start with the kind of value they are for, so they can't clash with these.
+fn self_check_key<V>(kind: &str) -> CacheKey<V> {
+    CacheKey {
+        key: format!("{}.self_check.{}", *CANARY_PREFIX, kind),
+        repo_id: RepositoryId::new(0),
+        path_depth: None,
+        path_hash: None,
+        value: PhantomData,
+    }
+}
+
+/// A fresh filenode for each `RemoteCache::self_check`, so that a value left over from a previous
+/// check doesn't count.
+fn self_check_filenode() -> FilenodeInfo {
+    let hash = HgNodeHash::from_bytes(&random::<[u8; 20]>()).expect("invalid hash length");
+    FilenodeInfo {
+        filenode: HgFileNodeId::new(hash),
+        p1: None,
+        p2: None,
+        copyfrom: None,
+        linknode: HgChangesetId::new(hash),
+    }
+}
+
+/// Run the canary every `interval` until the cache owning `options` is dropped.
+fn spawn_canary(
+    memcache: &MemcacheHandler,
+    keygen: &KeyGen,
+    options: &Arc<RemoteCacheOptions>,
+    interval: Duration,
+) {
+    let handle = match Handle::try_current() {
+        Ok(handle) => handle,
+        Err(_) => return,
+    };
+
+    let memcache = memcache.clone();
+    let keygen = keygen.clone();
+    let options: Weak<RemoteCacheOptions> = Arc::downgrade(options);
+
+    handle.spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if options.strong_count() == 0 {
+                break;
+            }
+            check_canary(&memcache, &keygen).await;
+        }
+    });
+}
+
+fn schedule_fill_filenode(
+    spawner: FillSpawner<'_>,
+    memcache: &MemcacheHandler,
+    mc_key: String,
+    serialized: Bytes,
+    options: &RemoteCacheOptions,
+    ttl: Option<Duration>,
+    completion: FillCompletion,
+) {
+    STATS::single_key_bytes.add_value(mc_key.len() as i64);
+
+    // Quite unlikely that single filenode will be bigger than MEMCACHE_VALUE_MAX_SIZE
+    // It's probably not even worth logging it
+    if serialized.len() < options.value_max_size() {
+        let logger = spawner.logger.clone();
+        let memcache = memcache.clone();
+        let bytes = serialized.len();
+        let verify = options.sample_fill_verify();
+        let fut = async move {
+            let written = write_filenode(&memcache, mc_key.clone(), serialized.clone(), ttl).await;
+            if written.is_ok() {
+                completion.written(bytes);
+                if verify {
+                    verify_fill(&logger, &memcache, mc_key, &serialized).await;
+                }
+            }
+        };
+
+        spawn_fill(spawner, bytes, fut);
+    }
+}
+
+fn serialize_filenode(value: &thrift::CachedFilenodeInfo, checksum: bool) -> Bytes {
+    let serialized = record_serialize(|| compact_protocol::serialize(value));
+    let serialized = with_kind_tag(FILENODE_TAG, &serialized);
+    if checksum {
+        with_checksum(&serialized)
+    } else {
+        serialized
+    }
+}
+
+async fn write_filenode(
+    memcache: &MemcacheHandler,
+    mc_key: String,
+    serialized: Bytes,
+    ttl: Option<Duration>,
+) -> Result<(), Error> {
+    match ttl {
+        Some(ttl) => memcache.set_with_ttl(mc_key, serialized, ttl).await,
+        None => memcache.set(mc_key, serialized).await,
+    }
+}
+
+/// Read back a value that was just written under `mc_key`, and count `fill_verify_fail` if it is
+/// missing or differs.
+async fn verify_fill(logger: &Logger, memcache: &MemcacheHandler, mc_key: String, written: &Bytes) {
+    let found = match memcache.get(mc_key.clone()).await {
+        Ok(Some(read)) if read == written => {
+            STATS::fill_verify_ok.add_value(1);
+            return;
+        }
+        Ok(Some(_)) => "a different value",
+        Ok(None) => "nothing",
+        Err(_) => "an error",
+    };
+    STATS::fill_verify_fail.add_value(1);
+    warn!(logger, "Read back {} right after filling memcache", found; "key" => mc_key);
+}
+
+fn path_depth_bucket(depth: usize) -> String {
+    if depth >= PATH_DEPTH_BUCKET_MAX {
+        format!("{}+", PATH_DEPTH_BUCKET_MAX)
+    } else {
+        depth.to_string()
+    }
+}
+
+fn history_into_thrift(filenodes: FilenodeRange) -> thrift::FilenodeInfoList {
+    match filenodes {
+        FilenodeRange::Filenodes(filenodes) => thrift::FilenodeInfoList::Data(
+            filenodes
+                .into_iter()
+                .map(|filenode_info| filenode_info.into_thrift())
+                .collect(),
+        ),
+        // Value in TooBig is ignored, so any value would work
+        FilenodeRange::TooBig => thrift::FilenodeInfoList::TooBig(0),
+    }
+}
+
+/// Like `history_into_thrift`, with the path of each copy source stored once, see
+/// `intern_history_paths`.
+fn history_into_thrift_interned(filenodes: FilenodeRange) -> thrift::FilenodeInfoList {
+    let filenodes = match filenodes {
+        FilenodeRange::Filenodes(filenodes) => filenodes,
+        FilenodeRange::TooBig => return history_into_thrift(FilenodeRange::TooBig),
+    };
+
+    let mut path_indexes = HashMap::new();
+    let mut copyfrom_paths = Vec::new();
+    let infos = filenodes
+        .into_iter()
+        .map(|mut filenode_info| {
+            let copyfrom = filenode_info.copyfrom.take();
+            let (copyfrom_path, copyfrom_filenode) = match copyfrom {
+                Some((path, filenode)) => {
+                    let index = *path_indexes.entry(path).or_insert_with_key(|path| {
+                        copyfrom_paths.push(path.clone().into_thrift());
+                        copyfrom_paths.len() as i32 - 1
+                    });
+                    (Some(index), Some(filenode.into_nodehash().into_thrift()))
+                }
+                None => (None, None),
+            };
+            thrift::InternedFilenodeInfo {
+                info: filenode_info.into_thrift(),
+                copyfrom_path,
+                copyfrom_filenode,
+            }
+        })
+        .collect();
+
+    thrift::FilenodeInfoList::Interned(thrift::InternedFilenodeInfoList {
+        copyfrom_paths,
+        infos,
+    })
+}
+
+/// Size of the chunks to split a history serialized to `len` bytes into.
+fn history_chunk_size(
+    logger: &Logger,
+    key: &CacheKey<FilenodeRange>,
+    len: usize,
+    options: &RemoteCacheOptions,
+) -> usize {
+    let (chunk_size, exceeded) = target_chunk_size(len, options);
+    if exceeded {
+        STATS::gaf_preferred_chunk_size_exceeded.add_value(1);
+        warn!(
+            logger,
+            "History of {} bytes needs more than {} chunks of the preferred size of {} bytes",
+            len,
+            options.max_history_pointers(),
+            options.preferred_chunk_size();
+            "key" => &key.key
+        );
+    }
+    chunk_size
+}
+
+/// Size of the chunks a history serialized to `len` bytes is split into, and whether that is
+/// bigger than the preferred size, as the history would need too many chunks of that size.
+fn target_chunk_size(len: usize, options: &RemoteCacheOptions) -> (usize, bool) {
+    let preferred = options.preferred_chunk_size();
+    let max_pointers = options.max_history_pointers();
+    if len.div_ceil(chunk_data_size(preferred)) <= max_pointers {
+        return (preferred, false);
+    }
+    let chunk_size =
+        (len.div_ceil(max_pointers) + CHUNK_ENVELOPE_BYTES).clamp(preferred, options.chunk_size());
+    (chunk_size, true)
+}
+
+/// Number of bytes of a history stored in each of its chunks, so that with the chunk's envelope
+/// they are no bigger than `chunk_size`.
+fn chunk_data_size(chunk_size: usize) -> usize {
+    chunk_size.saturating_sub(CHUNK_ENVELOPE_BYTES).max(1)
+}
+
+/// Most bytes `FilenodeInfoList::Data` adds around the filenodes it lists: what it adds around no
+/// filenodes, and up to 5 more for the length of the list as a varint.
+fn list_envelope_bytes() -> usize {
+    compact_protocol::serialize(&thrift::FilenodeInfoList::Data(Vec::new())).len() + 5
+}
+
+/// A history converted for a fill, along with what it serializes to.
+struct SerializedHistory {
+    list: thrift::FilenodeInfoList,
+    serialized: Bytes,
+    entry_count: Option<i64>,
+}
+
+/// Convert `filenodes` for a fill, interning their paths if `intern_history_paths` is set.
+fn serialize_history(filenodes: FilenodeRange, options: &RemoteCacheOptions) -> SerializedHistory {
+    let list = if options.intern_history_paths {
+        history_into_thrift_interned(filenodes)
+    } else {
+        history_into_thrift(filenodes)
+    };
+    let entry_count = match &list {
+        thrift::FilenodeInfoList::Data(list) => Some(list.len() as i64),
+        thrift::FilenodeInfoList::Interned(list) => Some(list.infos.len() as i64),
+        _ => None,
+    };
+    let serialized = record_serialize(|| compact_protocol::serialize(&list));
+    SerializedHistory {
+        list,
+        serialized,
+        entry_count,
+    }
+}
+
+/// Write a history, returning the size it was serialized to. A history too big to be stored in
+/// its root is split into chunks, after reading the root it replaces to find out the generation
+/// the new chunks belong to.
+async fn fill_history(
+    logger: &Logger,
+    memcache: &MemcacheHandler,
+    keygen: &KeyGen,
+    key: &CacheKey<FilenodeRange>,
+    history: SerializedHistory,
+    options: &RemoteCacheOptions,
+    ttls: HistoryTtls,
+) -> Result<usize, ()> {
+    let root_key = get_mc_key(keygen, &key.key).map_err(drop)?;
+    STATS::list_key_bytes.add_value(root_key.len() as i64);
+
+    let SerializedHistory {
+        list,
+        serialized,
+        entry_count,
+    } = history;
+
+    STATS::gaf_compact_bytes.add_value(serialized.len() as i64);
+    if let (true, Some(depth)) = (options.path_depth_stats, key.path_depth) {
+        STATS::gaf_compact_bytes_by_path_depth
+            .add_value(serialized.len() as i64, (path_depth_bucket(depth),));
+    }
+
+    let chunk_size = history_chunk_size(logger, key, serialized.len(), options);
+
+    let root_ttl = ttls.root;
+
+    let mut replaced_pointers = Vec::new();
+
+    let list = if serialized.len() + ROOT_ENVELOPE_BYTES <= chunk_size {
+        list
+    } else {
+        let (previous_generation, previous_pointers) =
+            get_chunks_in_memcache(memcache, key, &root_key, options.max_history_pointers()).await;
+        replaced_pointers = previous_pointers;
+        let generation = previous_generation.map_or(0, |generation| generation.wrapping_add(1));
+        let level = options.chunk_compression_level;
+        let min_bytes = options.compress_min_bytes;
+        let mut pointers_iter = PointersIter::new();
+
+        // Like the chunks, the filter has to fit in a value.
+        let linknode_filter_pointer = match build_linknode_filter(&list) {
+            Some(filter) if filter.len() <= chunk_size => {
+                let pointer = pointers_iter.next();
+                write_linknode_filter(memcache, keygen, key, pointer, filter, ttls.chunks).await
+            }
+            _ => None,
+        };
+
+        let write_chunks_fut = serialized
+            .chunks(chunk_data_size(chunk_size))
+            .map(Vec::from) // takes ownership
+            .zip(pointers_iter)
+            .map({
+                move |(chunk, pointer)| async move {
+                    let chunk_key =
+                        get_mc_key_for_filenodes_list_chunk(keygen, key, pointer).map_err(drop)?;
+                    STATS::chunk_key_bytes.add_value(chunk_key.len() as i64);
+                    let (data, compressed) =
+                        compress_chunk(chunk, level, min_bytes).map_err(drop)?;
+                    let chunk = thrift::FilenodeInfoChunk {
+                        generation,
+                        data,
+                        compressed,
+                    };
+                    let chunk = record_serialize(|| compact_protocol::serialize(&chunk));
+
+                    memcache
+                        .set_with_ttl(chunk_key, chunk, ttls.chunks)
+                        .await
+                        .map_err(drop)?;
+
+                    Ok(pointer)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let pointers = try_join_all(write_chunks_fut).await?;
+        thrift::FilenodeInfoList::Chunks(thrift::FilenodeInfoChunks {
+            pointers,
+            expires_at: unix_timestamp() + root_ttl.as_secs() as i64,
+            generation,
+            entry_count,
+            size: Some(serialized.len() as i64),
+            segmented: None,
+            compressed: level.map(|_| true),
+            chunk_size: Some(chunk_size as i64),
+            linknode_filter_pointer,
+        })
+    };
+
+    let root = thrift::CachedFilenodeInfoList {
+        repo_id: key.repo_id.id(),
+        list,
+    };
+    let root = record_serialize(|| compact_protocol::serialize(&root));
+    let root = with_kind_tag(HISTORY_TAG, &root);
+
+    memcache
+        .set_with_ttl(root_key.clone(), root.clone(), root_ttl)
+        .await
+        .map_err(drop)?;
+    if options.sample_fill_verify() {
+        verify_fill(logger, memcache, root_key, &root).await;
+    }
+
+    if options.delete_replaced_chunks {
+        let delete_chunks_fut = replaced_pointers.into_iter().map(|pointer| async move {
+            if let Ok(chunk_key) = get_mc_key_for_filenodes_list_chunk(keygen, key, pointer) {
+                let _ = memcache.del(chunk_key).await;
+            }
+        });
+        join_all(delete_chunks_fut).await;
+    }
+
+    Ok(serialized.len())
+}
+
+/// Write the linknode `filter` of the history under `key` like a chunk, returning where it was
+/// written, or `None` if it couldn't be, in which case the history is written without one.
+async fn write_linknode_filter(
+    memcache: &MemcacheHandler,
+    keygen: &KeyGen,
+    key: &CacheKey<FilenodeRange>,
+    pointer: Option<Pointer>,
+    filter: Vec<u8>,
+    ttl: Duration,
+) -> Option<Pointer> {
+    let pointer = pointer?;
+    let filter_key = get_mc_key_for_filenodes_list_chunk(keygen, key, pointer).ok()?;
+    memcache
+        .set_with_ttl(filter_key, Bytes::from(filter), ttl)
+        .await
+        .ok()?;
+    Some(pointer)
+}
+
+/// Generation and pointers of the chunked history currently stored under `root_key`, if any,
+/// including that of its linknode filter. If there are more than `max_pointers` pointers, none are
+/// returned.
+async fn get_chunks_in_memcache(
+    memcache: &MemcacheHandler,
+    key: &CacheKey<FilenodeRange>,
+    root_key: &str,
+    max_pointers: usize,
+) -> (Option<i64>, Vec<Pointer>) {
+    let serialized = match memcache.get(root_key.to_owned()).await {
+        Ok(Some(serialized)) => serialized,
+        _ => return (None, Vec::new()),
+    };
+
+    match deserialize_history_root(&serialized, false) {
+        Some((
+            repo_id,
+            HistoryRoot::Chunks {
+                mut pointers,
+                generation,
+                linknode_filter,
+                ..
+            },
+        )) if repo_id == key.repo_id => {
+            if pointers.len() > max_pointers {
+                STATS::gaf_too_many_pointers.add_value(1);
+                return (generation, Vec::new());
+            }
+            pointers.extend(linknode_filter);
+            (generation, pointers)
+        }
+        _ => (None, Vec::new()),
+    }
+}
+
+/// Infinite iterator over unique and random i64 values
+///
+/// Random values that were already returned are drawn again, and counted as `pointer_collisions`.
+/// Among the `n` pointers of a history, about `n^2 / 2^65` are expected to collide, which is less
+/// than one in 10^11 for `DEFAULT_MAX_HISTORY_POINTERS`, so any collision at all is a sign that
+/// the random values aren't.
+struct PointersIter {
+    seen: HashSet<Pointer>,
+    collisions: usize,
+    random: fn() -> Pointer,
+}
+
+impl PointersIter {
+    fn new() -> Self {
+        Self::with_random(random)
+    }
+
+    fn with_random(random: fn() -> Pointer) -> Self {
+        Self {
+            seen: HashSet::new(),
+            collisions: 0,
+            random,
+        }
+    }
+
+    /// Number of values drawn again so far.
+    #[cfg(test)]
+    fn collisions(&self) -> usize {
+        self.collisions
+    }
+}
+
+impl Iterator for PointersIter {
+    type Item = Pointer;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let pointer = (self.random)();
+            if self.seen.insert(pointer) {
+                break Some(pointer);
+            }
+            self.collisions += 1;
+            STATS::pointer_collisions.add_value(1);
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::cell::Cell;
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicI64;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use caching_ext::MockStoreStats;
+    use fbinit::FacebookInit;
+    use futures::stream;
+    use futures::TryStreamExt;
+    use maplit::btreemap;
+    use maplit::hashmap;
+    use mercurial_types::HgFileNodeId;
+    use mercurial_types::HgNodeHash;
+    use mercurial_types_mocks::nodehash::ONES_CSID;
+    use mercurial_types_mocks::nodehash::ONES_FNID;
+    use mercurial_types_mocks::nodehash::THREES_FNID;
+    use mercurial_types_mocks::nodehash::TWOS_CSID;
+    use mercurial_types_mocks::nodehash::TWOS_FNID;
+    use mononoke_types::RepoPath;
+    use mononoke_types_mocks::contentid::ONES_CTID;
+    use mononoke_types_mocks::contentid::TWOS_CTID;
+    use mononoke_types_mocks::repo::REPO_ONE;
+    use mononoke_types_mocks::repo::REPO_TWO;
+    use mononoke_types_mocks::repo::REPO_ZERO;
+    use path_hash::PathWithHash;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+    use tokio::time;
+
+    use super::*;
+    use crate::reader::escape_key_segment;
+    use crate::reader::filenode_cache_key;
+    use crate::reader::history_cache_key;
+    use crate::reader::PATH_NORMALIZATION_VERSION;
+
+    const TIMEOUT_MS: u64 = 100;
+    const SLEEP_MS: u64 = 5;
+
+    fn filenode() -> FilenodeInfo {
+        FilenodeInfo {
+            filenode: ONES_FNID,
+            p1: None,
+            p2: None,
+            copyfrom: Some((RepoPath::file("copiedfrom").unwrap(), ONES_FNID)),
+            linknode: ONES_CSID,
+        }
+    }
+
+    fn distinct_filenodes(count: usize) -> Vec<FilenodeInfo> {
+        (0..count)
+            .map(|i| {
+                let mut hash = [0; 20];
+                hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+                FilenodeInfo {
+                    filenode: HgFileNodeId::new(HgNodeHash::from_bytes(&hash).unwrap()),
+                    ..filenode()
+                }
+            })
+            .collect()
+    }
+
+    pub async fn wait_for_filenode(
+        cache: &RemoteCache,
+        key: &CacheKey<FilenodeInfo>,
+    ) -> Result<FilenodeInfo, Error> {
+        let r = time::timeout(Duration::from_millis(TIMEOUT_MS), async {
+            loop {
+                match cache.get_filenode(key).await {
+                    Some(f) => {
+                        break f;
+                    }
+                    None => {}
+                }
+                time::sleep(Duration::from_millis(SLEEP_MS)).await;
+            }
+        })
+        .await?;
+
+        Ok(r)
+    }
+
+    pub async fn wait_for_history(
+        cache: &RemoteCache,
+        key: &CacheKey<FilenodeRange>,
+    ) -> Result<FilenodeRange, Error> {
+        let r = time::timeout(Duration::from_millis(TIMEOUT_MS), async {
+            loop {
+                match cache.get_history(key).await {
+                    Some(f) => {
+                        break f;
+                    }
+                    None => {}
+                }
+                time::sleep(Duration::from_millis(SLEEP_MS)).await;
+            }
+        })
+        .await?;
+
+        Ok(r)
+    }
+
+    #[fbinit::test]
+    async fn test_store_filenode(_fb: FacebookInit) -> Result<(), Error> {
+        let (cache, fills) = RemoteCache::new_mock().with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let info = filenode();
+
+        let key = filenode_cache_key(
+            REPO_ZERO,
+            &PathWithHash::from_repo_path(&path),
+            &info.filenode,
+        );
+
+        cache.fill_filenode(&key, info.clone());
+        assert_eq!(fills.inner.pending.load(Ordering::SeqCst), 1);
+        fills.wait_for_fills().await;
+
+        assert_eq!(cache.get_filenode(&key).await, Some(info));
+        // Nothing is kept for fills that completed, and waiting again returns at once.
+        assert_eq!(fills.inner.pending.load(Ordering::SeqCst), 0);
+        fills.wait_for_fills().await;
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_store_short_history(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+        let info = filenode();
+        let history = FilenodeRange::Filenodes(vec![info.clone(), info.clone(), info.clone()]);
+
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+        cache.fill_history(&key, history.clone());
+        let from_cache = wait_for_history(&cache, &key).await?;
+
+        assert_eq!(from_cache, history);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_store_long_history(_fb: FacebookInit) -> Result<(), Error> {
+        const VALUE_MAX_SIZE: usize = 100;
+
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            value_max_size: Some(VALUE_MAX_SIZE),
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+
+        let history = FilenodeRange::Filenodes(distinct_filenodes(5));
+        let serialized_len =
+            compact_protocol::serialize(&history_into_thrift(history.clone())).len();
+        assert!(serialized_len >= VALUE_MAX_SIZE);
+
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+        cache.fill_history(&key, history.clone());
+        let from_cache = wait_for_history(&cache, &key).await?;
+
+        assert_eq!(from_cache, history);
+        assert_eq!(
+            get_root_chunks(&cache, &key).await?.pointers.len(),
+            serialized_len.div_ceil(chunk_data_size(VALUE_MAX_SIZE))
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_store_too_long_history(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        cache.fill_history(&key, FilenodeRange::TooBig);
+        let from_cache = wait_for_history(&cache, &key).await?;
+
+        assert_eq!(from_cache, FilenodeRange::TooBig);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_history_reversed(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+
+        for (name, count) in [("short", 3), ("long", 100_000)] {
+            let path = RepoPath::file(name)?;
+            let filenodes = distinct_filenodes(count);
+            let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+            cache.fill_history(&key, FilenodeRange::Filenodes(filenodes.clone()));
+            wait_for_history(&cache, &key).await?;
+
+            let mut expected = filenodes;
+            expected.reverse();
+            assert_eq!(
+                cache.get_history_reversed(&key).await,
+                Some(FilenodeRange::Filenodes(expected))
+            );
+        }
+
+        // The chunks of a streamed history are read in reverse, and each is reversed.
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
+        let path = RepoPath::file("streamed")?;
+        let filenodes = distinct_filenodes(1000);
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        cache
+            .fill_history_stream(REPO_ZERO, &path, stream::iter(filenodes.clone()))
+            .await?;
+        let root = get_root_chunks(&cache, &key).await?;
+        assert!(root.segmented == Some(true) && root.pointers.len() > 1);
+
+        let mut expected = filenodes;
+        expected.reverse();
+        assert_eq!(
+            cache.get_history_reversed(&key).await,
+            Some(FilenodeRange::Filenodes(expected))
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_history_filtered(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        let has_copyfrom = |filenode: &FilenodeInfo| filenode.copyfrom.is_some();
+
+        assert_eq!(
+            cache
+                .get_history_filtered(REPO_ZERO, &path, has_copyfrom)
+                .await,
+            None
+        );
+
+        // Every third entry is a copy, in a history big enough to be chunked.
+        let filenodes = distinct_filenodes(100)
+            .into_iter()
+            .enumerate()
+            .map(|(i, filenode)| FilenodeInfo {
+                copyfrom: filenode.copyfrom.filter(|_| i % 3 == 0),
+                ..filenode
+            })
+            .collect::<Vec<_>>();
+        cache.fill_history(&key, FilenodeRange::Filenodes(filenodes.clone()));
+        wait_for_history(&cache, &key).await?;
+        assert!(get_root_chunks(&cache, &key).await?.pointers.len() > 1);
+
+        let copies = cache
+            .get_history_filtered(REPO_ZERO, &path, has_copyfrom)
+            .await
+            .unwrap();
+        assert_eq!(copies.len(), 34);
+        assert_eq!(
+            copies,
+            filenodes
+                .into_iter()
+                .filter(|filenode| filenode.copyfrom.is_some())
+                .collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_history_stream(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        assert!(cache.get_history_stream(REPO_ZERO, &path).await.is_none());
+
+        // A history that can't be decoded a chunk at a time is read whole.
+        let filenodes = distinct_filenodes(100);
+        cache.fill_history(&key, FilenodeRange::Filenodes(filenodes.clone()));
+        wait_for_history(&cache, &key).await?;
+        assert!(get_root_chunks(&cache, &key).await?.pointers.len() > 1);
+
+        let entries = cache.get_history_stream(REPO_ZERO, &path).await.unwrap();
+        let entries = entries.try_collect::<Vec<_>>().await?;
+        assert_eq!(entries, filenodes);
+
+        // The chunks of a streamed history are read as the entries are.
+        let filenodes = distinct_filenodes(1000);
+        cache
+            .fill_history_stream(REPO_ZERO, &path, stream::iter(filenodes.clone()))
+            .await?;
+        let root = get_root_chunks(&cache, &key).await?;
+        assert!(root.segmented == Some(true) && root.pointers.len() > 2);
+
+        let gets = mock_stats(&cache).gets;
+        let mut entries = cache.get_history_stream(REPO_ZERO, &path).await.unwrap();
+        let first = entries.by_ref().take(3).try_collect::<Vec<_>>().await?;
+        assert_eq!(first, filenodes[..3]);
+        assert_eq!(mock_stats(&cache).gets, gets + 2);
+        let rest = entries.try_collect::<Vec<_>>().await?;
+        assert_eq!(rest, filenodes[3..]);
+        assert_eq!(mock_stats(&cache).gets, gets + 1 + root.pointers.len());
+
+        // A chunk that can't be read ends the stream with an error.
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store,
+            _ => unreachable!("not a mock cache"),
+        };
+        let pointer = root.pointers[1];
+        store.del(&get_mc_key_for_filenodes_list_chunk(
+            &cache.keygen,
+            &key,
+            pointer,
+        )?);
+        let entries = cache.get_history_stream(REPO_ZERO, &path).await.unwrap();
+        let entries = entries.collect::<Vec<_>>().await;
+        let first_chunk = entries.len() - 1;
+        assert!(entries[..first_chunk].iter().all(Result::is_ok));
+        assert!(entries[first_chunk].is_err());
+
+        let key = history_cache_key(REPO_ONE, &PathWithHash::from_repo_path(&path), None);
+        cache.fill_history(&key, FilenodeRange::TooBig);
+        wait_for_history(&cache, &key).await?;
+        assert!(cache.get_history_stream(REPO_ONE, &path).await.is_none());
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_history_distinct(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+        let filenodes = distinct_filenodes(2);
+        let (first, second) = (filenodes[0].clone(), filenodes[1].clone());
+        let history = vec![
+            first.clone(),
+            first.clone(),
+            first.clone(),
+            second.clone(),
+            second.clone(),
+            first.clone(),
+        ];
+        cache.fill_history(&key, FilenodeRange::Filenodes(history.clone()));
+        wait_for_history(&cache, &key).await?;
+
+        // Only runs are collapsed, so the last entry is kept.
+        assert_eq!(
+            cache.get_history_distinct(&key).await,
+            Some(FilenodeRange::Filenodes(vec![first.clone(), second, first]))
+        );
+        assert_eq!(
+            cache.get_history(&key).await,
+            Some(FilenodeRange::Filenodes(history))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_hash_key_encoding() -> Result<(), Error> {
+        let keys = (0..1000)
+            .map(|i| {
+                let path = RepoPath::file(format!("dir/file{}", i).as_str())?;
+                let pwh = PathWithHash::from_repo_path(&path);
+                Ok(filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID).key)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        assert_eq!(keys.iter().collect::<HashSet<_>>().len(), keys.len());
+
+        for key in &keys {
+            assert!(
+                key.bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'_' || b == b'-')
+            );
+
+            // filenode.<repo_id>.<filenode>.n<normalization>.<path_hash>.<is_tree>: a hex
+            // encoding of the 32 byte path hash used to take 64 characters, and a base64 one 43.
+            let path_hash = key.split('.').nth(4).unwrap();
+            assert_eq!(path_hash.len(), 22);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_escape_key_segment() {
+        let safe = "AZaz09-_".to_string();
+        assert_eq!(escape_key_segment(safe.clone()), safe);
+        assert_eq!(
+            escape_key_segment("a b.c\n%é".to_string()),
+            "a%20b%2Ec%0A%25%C3%A9"
+        );
+    }
+
+    #[test]
+    fn test_truncated_path_hash() -> Result<(), Error> {
+        let paths = [
+            "a",
+            "b",
+            "dir/file",
+            "dir/file2",
+            "dir2/file",
+            "a/very/deep/dir/file.rs",
+        ]
+        .into_iter()
+        .map(RepoPath::file)
+        .collect::<Result<Vec<_>, _>>()?;
+
+        let mut keys = HashSet::new();
+        for path in &paths {
+            let pwh = PathWithHash::from_repo_path(path);
+            let full = base64::encode_config(&pwh.hash.0, base64::URL_SAFE_NO_PAD);
+            let key = history_cache_key(REPO_ZERO, &pwh, None);
+            let path_hash = key.path_hash().unwrap().split_once('.').unwrap().1;
+            assert!(path_hash.len() < full.len());
+            // The first 15 bytes of the hash are encoded the same way in both.
+            assert_eq!(path_hash[..20], full[..20]);
+            assert!(keys.insert(key.key));
+        }
+
+        // The number of bytes kept is set by the cache, which builds the keys of the reader too.
+        let default = RemoteCache::new_mock();
+        let full_hash = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            path_hash_key_bytes: Some(32),
+            ..Default::default()
+        });
+        let pwh = PathWithHash::from_repo_path(&paths[0]);
+        let full = base64::encode_config(&pwh.hash.0, base64::URL_SAFE_NO_PAD);
+        let key = full_hash.history_cache_key(REPO_ZERO, &pwh, None);
+        assert_eq!(key.path_hash(), Some(format!("n1.{}", full).as_str()));
+        assert_eq!(
+            default.history_cache_key(REPO_ZERO, &pwh, None).key,
+            history_cache_key(REPO_ZERO, &pwh, None).key
+        );
+        let key = full_hash.filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        assert!(key.key.contains(&full));
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_or_fill_filenode_backfill_latency(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+        let info = filenode();
+
+        let key = filenode_cache_key(
+            REPO_ZERO,
+            &PathWithHash::from_repo_path(&path),
+            &info.filenode,
+        );
+
+        let backing_store_delay = Duration::from_millis(50);
+
+        // A miss calls the (slow) backing store, and the recorded latency covers it.
+        let (backfill_latency, ret) = cache
+            .get_or_fill_filenode_timed(&key, || async {
+                time::sleep(backing_store_delay).await;
+                Ok(Some(info.clone()))
+            })
+            .await;
+        assert_eq!(ret?, Some(info.clone()));
+        assert!(backfill_latency.unwrap() >= backing_store_delay);
+
+        wait_for_filenode(&cache, &key).await?;
+
+        // A hit is served from the cache alone, so no backfill latency is recorded.
+        let (backfill_latency, ret) = cache
+            .get_or_fill_filenode_timed(&key, || async {
+                panic!("backing store should not be called on a hit")
+            })
+            .await;
+        assert_eq!(ret?, Some(info));
+        assert_eq!(backfill_latency, None);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_noop_cache(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_noop();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let info = filenode();
+
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &info.filenode);
+        cache.fill_filenode(&key, info.clone());
+        assert!(wait_for_filenode(&cache, &key).await.is_err());
+
+        let key = history_cache_key(REPO_ZERO, &pwh, None);
+        cache.fill_history(&key, FilenodeRange::Filenodes(vec![info]));
+        assert!(wait_for_history(&cache, &key).await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_deserialize_bad_bytes() {
+        let bad: &[&[u8]] = &[b"", b"\x00", b"\xff\xff\xff\xff", b"not thrift at all"];
+        for bytes in bad {
+            assert_eq!(try_deserialize_filenode(bytes), None);
+            assert_eq!(try_deserialize_history(bytes), None);
+        }
+
+        let serialized = compact_protocol::serialize(&thrift::CachedFilenodeInfo {
+            repo_id: REPO_ZERO.id(),
+            info: filenode().into_thrift(),
+            written_at: None,
+            soft_ttl_secs: None,
+            epoch: None,
+        });
+        let serialized = with_kind_tag(FILENODE_TAG, &serialized);
+        assert_eq!(try_deserialize_filenode(&serialized), Some(filenode()));
+        for len in 0..serialized.len() {
+            assert_eq!(try_deserialize_filenode(&serialized[..len]), None);
+        }
+
+        let history = FilenodeRange::Filenodes(vec![filenode(), filenode()]);
+        let serialized = compact_protocol::serialize(&thrift::CachedFilenodeInfoList {
+            repo_id: REPO_ZERO.id(),
+            list: history_into_thrift(history.clone()),
+        });
+        let serialized = with_kind_tag(HISTORY_TAG, &serialized);
+        assert_eq!(try_deserialize_history(&serialized), Some(history));
+        for len in 0..serialized.len() {
+            assert_eq!(try_deserialize_history(&serialized[..len]), None);
+        }
+
+        // Pointers can't be followed without memcache.
+        let pointers = compact_protocol::serialize(&thrift::CachedFilenodeInfoList {
+            repo_id: REPO_ZERO.id(),
+            list: thrift::FilenodeInfoList::Pointers(vec![1, 2]),
+        });
+        let pointers = with_kind_tag(HISTORY_TAG, &pointers);
+        assert_eq!(try_deserialize_history(&pointers), None);
+    }
+
+    async fn get_root_chunks(
+        cache: &RemoteCache,
+        key: &CacheKey<FilenodeRange>,
+    ) -> Result<thrift::FilenodeInfoChunks, Error> {
+        let root = cache
+            .memcache
+            .get(cache.keygen.key(&key.key))
+            .await?
+            .unwrap();
+        let root = strip_kind_tag(HISTORY_TAG, &root).expect("history root should be tagged");
+        let root: thrift::CachedFilenodeInfoList = compact_protocol::deserialize(root)?;
+        match root.list {
+            thrift::FilenodeInfoList::Chunks(chunks) => Ok(chunks),
+            _ => panic!("history should be chunked"),
+        }
+    }
+
+    fn mock_stats(cache: &RemoteCache) -> MockStoreStats {
+        match &cache.memcache {
+            MemcacheHandler::Mock(store) => store.stats(),
+            _ => unreachable!("not a mock cache"),
+        }
+    }
+
+    #[fbinit::test]
+    async fn test_refill_near_expiry(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            refill_ttl_threshold: Some(Duration::from_secs(60 * 60)),
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let info = filenode();
+
+        let history =
+            FilenodeRange::Filenodes((0..100_000).map(|_| info.clone()).collect::<Vec<_>>());
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+        cache.fill_history(&key, history.clone());
+        wait_for_history(&cache, &key).await?;
+
+        // A fresh hit doesn't trigger a refill.
+        let sets = mock_stats(&cache).sets;
+        assert_eq!(cache.get_history(&key).await, Some(history.clone()));
+        time::sleep(Duration::from_millis(TIMEOUT_MS)).await;
+        assert_eq!(mock_stats(&cache).sets, sets);
+
+        // Make the root look like it's about to expire.
+        let chunks = get_root_chunks(&cache, &key).await?;
+        let root = compact_protocol::serialize(&thrift::CachedFilenodeInfoList {
+            repo_id: key.repo_id.id(),
+            list: thrift::FilenodeInfoList::Chunks(thrift::FilenodeInfoChunks {
+                expires_at: unix_timestamp() + 60,
+                ..chunks
+            }),
+        });
+        let root = with_kind_tag(HISTORY_TAG, &root);
+        cache.memcache.set(cache.keygen.key(&key.key), root).await?;
+
+        // A hit close to expiry refills the history.
+        let sets = mock_stats(&cache).sets;
+        assert_eq!(cache.get_history(&key).await, Some(history));
+        time::timeout(Duration::from_millis(TIMEOUT_MS), async {
+            while mock_stats(&cache).sets == sets {
+                time::sleep(Duration::from_millis(SLEEP_MS)).await;
+            }
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_key_too_long(_fb: FacebookInit) -> Result<(), Error> {
+        let long_prefix = "x".repeat(MEMCACHE_KEY_MAX_LEN);
+        let cache = RemoteCache::new(&CacheHandlerFactory::Mocked, "newfilenodes", &long_prefix);
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let info = filenode();
+
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &info.filenode);
+        assert!(matches!(
+            get_mc_key(&cache.keygen, &key.key),
+            Err(KeyTooLong(_))
+        ));
+        cache.fill_filenode(&key, info.clone());
+        assert!(wait_for_filenode(&cache, &key).await.is_err());
+
+        let key = history_cache_key(REPO_ZERO, &pwh, None);
+        assert!(matches!(
+            get_mc_key(&cache.keygen, &key.key),
+            Err(KeyTooLong(_))
+        ));
+        cache.fill_history(&key, FilenodeRange::Filenodes(vec![info]));
+        assert!(wait_for_history(&cache, &key).await.is_err());
+
+        // Nothing was sent to memcache.
+        let stats = mock_stats(&cache);
+        assert_eq!(stats.sets, 0);
+        assert_eq!(stats.gets, 0);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_recent_fills(_fb: FacebookInit) -> Result<(), Error> {
+        let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            recent_fills_capacity: 3,
+            ..Default::default()
+        })
+        .with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+
+        let expected = distinct_filenodes(5)
+            .into_iter()
+            .map(|info| {
+                let key = filenode_cache_key(REPO_ZERO, &pwh, &info.filenode);
+                cache.fill_filenode(&key, info.clone());
+                (key.key, FilledValue::Filenode(info))
+            })
+            .collect::<Vec<_>>();
+        fills.wait_for_fills().await;
+        assert_eq!(cache.recent_fills(), expected[2..]);
+
+        // Histories are remembered with their entries.
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(vec![filenode()]);
+        cache.fill_history(&history_key, history.clone());
+        fills.wait_for_fills().await;
+        assert_eq!(
+            cache.recent_fills().last(),
+            Some(&(history_key.key, FilledValue::History(history)))
+        );
+
+        // Fills that fail to be written aren't remembered.
+        let before = cache.recent_fills();
+        match &cache.memcache {
+            MemcacheHandler::Mock(store) => store.fail_next_sets(1),
+            _ => unreachable!("not a mock cache"),
+        }
+        cache.fill_filenode(&filenode_cache_key(REPO_ONE, &pwh, &ONES_FNID), filenode());
+        fills.wait_for_fills().await;
+        assert_eq!(cache.recent_fills(), before);
+
+        // Disabled by default.
+        let cache = RemoteCache::new_mock();
+        cache.fill_filenode(&filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID), filenode());
+        assert!(cache.recent_fills().is_empty());
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_read_only(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let mut infos = distinct_filenodes(2).into_iter();
+
+        let cached = infos.next().unwrap();
+        let cached_key = filenode_cache_key(REPO_ZERO, &pwh, &cached.filenode);
+        cache.fill_filenode(&cached_key, cached.clone());
+        wait_for_filenode(&cache, &cached_key).await?;
+        let sets = mock_stats(&cache).sets;
+
+        cache.set_read_only(true);
+
+        let suppressed = infos.next().unwrap();
+        let suppressed_key = filenode_cache_key(REPO_ZERO, &pwh, &suppressed.filenode);
+        cache.fill_filenode(&suppressed_key, suppressed.clone());
+        time::sleep(Duration::from_millis(SLEEP_MS)).await;
+
+        assert_eq!(mock_stats(&cache).sets, sets);
+        assert_eq!(cache.get_filenode(&suppressed_key).await, None);
+        assert_eq!(cache.get_filenode(&cached_key).await, Some(cached));
+
+        cache.set_read_only(false);
+        cache.fill_filenode(&suppressed_key, suppressed.clone());
+        let from_cache = wait_for_filenode(&cache, &suppressed_key).await?;
+        assert_eq!(from_cache, suppressed);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_repo_mismatch(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let info = filenode();
+
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &info.filenode);
+        cache.fill_filenode(&key, info.clone());
+        wait_for_filenode(&cache, &key).await?;
+
+        // A key that wrongly maps a filenode from another repo to the same memcache key.
+        let leaked_key = CacheKey {
+            repo_id: REPO_ONE,
+            ..key.clone()
+        };
+        assert_eq!(cache.get_filenode(&leaked_key).await, None);
+
+        let key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(vec![info]);
+        cache.fill_history(&key, history.clone());
+        wait_for_history(&cache, &key).await?;
+
+        let leaked_key = CacheKey {
+            repo_id: REPO_ONE,
+            ..key.clone()
+        };
+        assert_eq!(cache.get_history(&leaked_key).await, None);
+        assert_eq!(cache.get_history(&key).await, Some(history));
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_chunk_size(_fb: FacebookInit) -> Result<(), Error> {
+        const CHUNK_SIZE: usize = 1024;
+
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(CHUNK_SIZE),
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+        let history = FilenodeRange::Filenodes(distinct_filenodes(100));
+        let serialized_len =
+            compact_protocol::serialize(&history_into_thrift(history.clone())).len();
+        assert!(serialized_len > 2 * CHUNK_SIZE);
+
+        cache.fill_history(&key, history.clone());
+        let from_cache = wait_for_history(&cache, &key).await?;
+        assert_eq!(from_cache, history);
+
+        // All the chunks, the linknode filter, and the root.
+        let chunks = serialized_len.div_ceil(chunk_data_size(CHUNK_SIZE));
+        assert_eq!(mock_stats(&cache).sets, chunks + 2);
+
+        let options = RemoteCacheOptions {
+            chunk_size: Some(usize::MAX),
+            ..Default::default()
+        };
+        assert_eq!(options.chunk_size(), MEMCACHE_VALUE_MAX_SIZE);
+        assert_eq!(
+            RemoteCacheOptions::default().chunk_size(),
+            MEMCACHE_VALUE_MAX_SIZE
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_chunk_size_includes_envelope(_fb: FacebookInit) -> Result<(), Error> {
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        let history = FilenodeRange::Filenodes(distinct_filenodes(20));
+        let serialized_len =
+            compact_protocol::serialize(&history_into_thrift(history.clone())).len();
+
+        // A history of exactly the chunk size doesn't fit in a root of that size, and neither
+        // would chunks of that size with their envelope.
+        for chunk_size in [serialized_len, serialized_len / 4] {
+            let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+                chunk_size: Some(chunk_size),
+                ..Default::default()
+            });
+            cache.fill_history(&key, history.clone());
+            assert_eq!(wait_for_history(&cache, &key).await?, history);
+
+            let root = cache
+                .memcache
+                .get(cache.keygen.key(&key.key))
+                .await?
+                .unwrap();
+            assert!(root.len() <= chunk_size);
+            let chunks = get_root_chunks(&cache, &key).await?;
+            assert!(chunks.pointers.len() > 1);
+            for pointer in chunks.pointers {
+                let chunk_key = get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, pointer)?;
+                let chunk = cache.memcache.get(chunk_key).await?.unwrap();
+                assert!(chunk.len() <= chunk_size);
+            }
+        }
+
+        // Nor are the segments of a history written from a stream.
+        let chunk_size = serialized_len / 4;
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(chunk_size),
+            ..Default::default()
+        });
+        let filenodes = match history.clone() {
+            FilenodeRange::Filenodes(filenodes) => filenodes,
+            FilenodeRange::TooBig => unreachable!(),
+        };
+        cache
+            .fill_history_stream(REPO_ZERO, &path, stream::iter(filenodes))
+            .await?;
+        assert_eq!(cache.get_history(&key).await, Some(history));
+        let chunks = get_root_chunks(&cache, &key).await?;
+        for pointer in chunks.pointers {
+            let chunk_key = get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, pointer)?;
+            let chunk = cache.memcache.get(chunk_key).await?.unwrap();
+            assert!(chunk.len() <= chunk_size);
+        }
+
+        // The envelopes are no bigger than assumed.
+        let chunk = compact_protocol::serialize(&thrift::FilenodeInfoChunk {
+            generation: i64::MIN,
+            data: vec![0; 1024],
+            compressed: Some(true),
+        });
+        assert!(chunk.len() <= 1024 + CHUNK_ENVELOPE_BYTES);
+        let list = thrift::FilenodeInfoList::Data(vec![filenode().into_thrift(); 100]);
+        let list_len = compact_protocol::serialize(&list).len();
+        let root = compact_protocol::serialize(&thrift::CachedFilenodeInfoList {
+            repo_id: i32::MIN,
+            list,
+        });
+        let root = with_kind_tag(HISTORY_TAG, &root);
+        assert!(root.len() <= list_len + ROOT_ENVELOPE_BYTES);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_preferred_chunk_size(_fb: FacebookInit) -> Result<(), Error> {
+        const PREFERRED: usize = 256;
+
+        let chunk_sizes = |cache: RemoteCache, key: CacheKey<FilenodeRange>| async move {
+            let chunks = get_root_chunks(&cache, &key).await?;
+            let mut sizes = Vec::new();
+            for pointer in chunks.pointers {
+                let chunk_key = get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, pointer)?;
+                let chunk = cache.memcache.get(chunk_key).await?.unwrap();
+                let data = unwrap_chunk(&chunk, pointer, chunks.generation, false)?;
+                sizes.push(data.len());
+            }
+            Ok::<_, Error>(sizes)
+        };
+
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        let history = FilenodeRange::Filenodes(distinct_filenodes(20));
+        let serialized_len =
+            compact_protocol::serialize(&history_into_thrift(history.clone())).len();
+        assert!(serialized_len > 4 * PREFERRED);
+
+        // Split into chunks of the preferred size, rather than stored as a single value.
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            preferred_chunk_size: Some(PREFERRED),
+            ..Default::default()
+        });
+        cache.fill_history(&key, history.clone());
+        assert_eq!(wait_for_history(&cache, &key).await?, history);
+        let sizes = chunk_sizes(cache, key.clone()).await?;
+        assert_eq!(
+            sizes.len(),
+            serialized_len.div_ceil(chunk_data_size(PREFERRED))
+        );
+        assert!(
+            sizes[..sizes.len() - 1]
+                .iter()
+                .all(|size| *size == chunk_data_size(PREFERRED))
+        );
+
+        // Bigger chunks, rather than more than the maximum number of them.
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            preferred_chunk_size: Some(PREFERRED),
+            max_history_pointers: Some(2),
+            ..Default::default()
+        });
+        cache.fill_history(&key, history.clone());
+        assert_eq!(wait_for_history(&cache, &key).await?, history);
+        let sizes = chunk_sizes(cache, key.clone()).await?;
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes[0], serialized_len.div_ceil(2));
+
+        // But never bigger than the maximum size.
+        let options = RemoteCacheOptions {
+            chunk_size: Some(1024),
+            preferred_chunk_size: Some(4096),
+            ..Default::default()
+        };
+        assert_eq!(options.preferred_chunk_size(), 1024);
+        let options = RemoteCacheOptions {
+            chunk_size: Some(1024),
+            preferred_chunk_size: Some(PREFERRED),
+            max_history_pointers: Some(2),
+            ..Default::default()
+        };
+        let logger = Logger::root(slog::Discard, o!());
+        assert_eq!(history_chunk_size(&logger, &key, 4096, &options), 1024);
+
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct RecordingCache {
+        calls: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl FilenodeCache for RecordingCache {
+        async fn get_filenode(&self, key: &CacheKey<FilenodeInfo>) -> Option<FilenodeInfo> {
+            self.calls.lock().unwrap().push(format!("get {}", key.key));
+            None
+        }
+
+        fn fill_filenode(&self, key: &CacheKey<FilenodeInfo>, _filenode: FilenodeInfo) {
+            self.calls.lock().unwrap().push(format!("fill {}", key.key));
+        }
+
+        async fn get_history(&self, key: &CacheKey<FilenodeRange>) -> Option<FilenodeRange> {
+            self.calls.lock().unwrap().push(format!("get {}", key.key));
+            None
+        }
+
+        fn fill_history(&self, key: &CacheKey<FilenodeRange>, _filenodes: FilenodeRange) {
+            self.calls.lock().unwrap().push(format!("fill {}", key.key));
+        }
+    }
+
+    async fn get_or_fill(cache: &dyn FilenodeCache, key: &CacheKey<FilenodeInfo>) -> FilenodeInfo {
+        match cache.get_filenode(key).await {
+            Some(info) => info,
+            None => {
+                cache.fill_filenode(key, filenode());
+                filenode()
+            }
+        }
+    }
+
+    #[fbinit::test]
+    async fn test_filenode_cache_trait(_fb: FacebookInit) -> Result<(), Error> {
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+
+        let recording = RecordingCache::default();
+        assert_eq!(get_or_fill(&recording, &key).await, filenode());
+        assert_eq!(
+            *recording.calls.lock().unwrap(),
+            vec![format!("get {}", key.key), format!("fill {}", key.key)]
+        );
+
+        let cache = RemoteCache::new_mock();
+        assert_eq!(get_or_fill(&cache, &key).await, filenode());
+        assert_eq!(wait_for_filenode(&cache, &key).await?, filenode());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize_time() {
+        let history = FilenodeRange::Filenodes((0..100_000).map(|_| filenode()).collect());
+        let list = history_into_thrift(history);
+
+        let (serialized, elapsed) = timed(|| compact_protocol::serialize(&list));
+        assert!(serialized.len() >= MEMCACHE_VALUE_MAX_SIZE);
+        assert!(elapsed.as_micros_unchecked() > 0);
+    }
+
+    #[fbinit::test]
+    async fn test_serde_timings_recorded(_fb: FacebookInit) -> Result<(), Error> {
+        let recorded = || SERDE_RECORDED.with(|recorded| recorded.get());
+        let (cache, fills) = RemoteCache::new_mock().with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+
+        // Nothing is deserialized on a miss.
+        let before = recorded();
+        assert_eq!(cache.get_filenode(&key).await, None);
+        assert_eq!(cache.get_history(&history_key).await, None);
+        assert_eq!(recorded(), before);
+
+        cache.fill_filenode(&key, filenode());
+        fills.wait_for_fills().await;
+        let filled = recorded();
+        assert!(filled.0 > before.0);
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+        assert!(recorded().1 > filled.1);
+
+        let before = recorded();
+        cache.fill_history(&history_key, FilenodeRange::Filenodes(vec![filenode()]));
+        fills.wait_for_fills().await;
+        let filled = recorded();
+        assert!(filled.0 > before.0);
+        assert!(cache.get_history(&history_key).await.is_some());
+        assert!(recorded().1 > filled.1);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_content_index(_fb: FacebookInit) -> Result<(), Error> {
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let info = filenode();
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &info.filenode);
+
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            content_index: true,
+            ..Default::default()
+        });
+        cache.fill_filenode_with_content(&key, info.clone(), &ONES_CTID);
+        wait_for_filenode(&cache, &key).await?;
+        let content_key = filenode_content_cache_key(REPO_ZERO, &ONES_CTID);
+        wait_for_filenode(&cache, &content_key).await?;
+
+        assert_eq!(
+            cache.get_filenode_by_content(REPO_ZERO, &ONES_CTID).await,
+            Some(info.clone())
+        );
+        assert_eq!(
+            cache.get_filenode_by_content(REPO_ZERO, &TWOS_CTID).await,
+            None
+        );
+        assert_eq!(
+            cache.get_filenode_by_content(REPO_ONE, &ONES_CTID).await,
+            None
+        );
+
+        // Without the index, only the filenode itself is written.
+        let cache = RemoteCache::new_mock();
+        cache.fill_filenode_with_content(&key, info.clone(), &ONES_CTID);
+        wait_for_filenode(&cache, &key).await?;
+        assert_eq!(mock_stats(&cache).sets, 1);
+        assert_eq!(
+            cache.get_filenode_by_content(REPO_ZERO, &ONES_CTID).await,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fill_without_runtime() -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+
+        cache.fill_filenode(&filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID), filenode());
+        cache.fill_history(
+            &history_cache_key(REPO_ZERO, &pwh, None),
+            FilenodeRange::TooBig,
+        );
+
+        assert_eq!(mock_stats(&cache).sets, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_depth_bucket() -> Result<(), Error> {
+        let deep = (0..20).map(|i| i.to_string()).collect::<Vec<_>>().join("/");
+        let paths = [
+            (RepoPath::RootPath, "0"),
+            (RepoPath::file("a")?, "1"),
+            (RepoPath::dir("a/b/c")?, "3"),
+            (RepoPath::file("a/b/c/d/e/f/g/h/i/j")?, "10+"),
+            (RepoPath::file(deep.as_str())?, "10+"),
+        ];
+
+        for (path, bucket) in paths {
+            let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+            assert_eq!(path_depth_bucket(key.path_depth.unwrap()), bucket);
+        }
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_with_handler(_fb: FacebookInit) -> Result<(), Error> {
+        let memcache = MemcacheHandler::create_mock();
+        let keygen = KeyGen::new("scm.mononoke.filenodes.custom", 0, 0);
+        let cache = RemoteCache::with_handler(memcache.clone(), keygen.clone());
+
+        let path = RepoPath::file("copiedto")?;
+        let key = filenode_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+        cache.fill_filenode(&key, filenode());
+        let from_cache = wait_for_filenode(&cache, &key).await?;
+        assert_eq!(from_cache, filenode());
+
+        // The value went through the handler that was passed in, under its key prefix.
+        assert!(memcache.get(keygen.key(&key.key)).await?.is_some());
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_chunk_read_errors(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+        let history = FilenodeRange::Filenodes(distinct_filenodes(100));
+        cache.fill_history(&key, history.clone());
+        wait_for_history(&cache, &key).await?;
+
+        let thrift::FilenodeInfoChunks {
+            pointers,
+            generation,
+            ..
+        } = get_root_chunks(&cache, &key).await?;
+        let chunk_key = get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, pointers[0])?;
+        let chunk = cache.memcache.get(chunk_key.clone()).await?.unwrap();
+
+        let read = |pointers| {
+            read_history_chunks(
+                &cache.memcache,
+                &cache.keygen,
+                &key,
+                pointers,
+                Some(generation),
+                false,
+            )
+        };
+
+        // A pointer to a chunk that isn't there.
+        let mut with_missing = pointers.clone();
+        with_missing.push(1);
+        assert_eq!(read(with_missing).await, Err(ChunkReadError::Missing(1)));
+
+        // A chunk that's there, but empty.
+        cache.memcache.set(chunk_key.clone(), Bytes::new()).await?;
+        assert_eq!(
+            read(pointers.clone()).await,
+            Err(ChunkReadError::Empty(pointers[0]))
+        );
+        assert_eq!(cache.get_history(&key).await, None);
+
+        // A chunk that isn't a chunk at all.
+        cache
+            .memcache
+            .set(chunk_key.clone(), Bytes::from_static(b"garbage"))
+            .await?;
+        assert_eq!(
+            read(pointers.clone()).await,
+            Err(ChunkReadError::Malformed(pointers[0]))
+        );
+
+        // Chunks that are all there, but don't reassemble into a history.
+        let garbage = compact_protocol::serialize(&thrift::FilenodeInfoChunk {
+            generation,
+            data: b"garbage".to_vec(),
+            compressed: None,
+        });
+        cache.memcache.set(chunk_key.clone(), garbage).await?;
+        let chunks = read(pointers.clone()).await.unwrap();
+        assert_eq!(
+            deserialize_history_chunks(&chunks, false, false, &mut Vec::new()),
+            None
+        );
+        assert_eq!(cache.get_history(&key).await, None);
+
+        // Once the chunk is restored, the history can be read again.
+        cache.memcache.set(chunk_key, chunk).await?;
+        assert_eq!(cache.get_history(&key).await, Some(history));
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_history_with_layout(_fb: FacebookInit) -> Result<(), Error> {
+        const CHUNK_SIZE: usize = 1024;
+
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(CHUNK_SIZE),
+            ..Default::default()
+        });
+
+        let short_key = history_cache_key(
+            REPO_ZERO,
+            &PathWithHash::from_repo_path(&RepoPath::file("short")?),
+            None,
+        );
+        assert_eq!(
+            cache.get_history_with_layout(&short_key).await,
+            (None, HistoryLayout::Missed)
+        );
+
+        let short = FilenodeRange::Filenodes(vec![filenode()]);
+        cache.fill_history(&short_key, short.clone());
+        wait_for_history(&cache, &short_key).await?;
+        assert_eq!(
+            cache.get_history_with_layout(&short_key).await,
+            (Some(short), HistoryLayout::Inline)
+        );
+
+        let long_key = history_cache_key(
+            REPO_ZERO,
+            &PathWithHash::from_repo_path(&RepoPath::file("long")?),
+            None,
+        );
+        let long = FilenodeRange::Filenodes(distinct_filenodes(100));
+        let chunks = compact_protocol::serialize(&history_into_thrift(long.clone()))
+            .len()
+            .div_ceil(CHUNK_SIZE);
+        cache.fill_history(&long_key, long.clone());
+        wait_for_history(&cache, &long_key).await?;
+        assert_eq!(
+            cache.get_history_with_layout(&long_key).await,
+            (Some(long), HistoryLayout::Chunked { chunks })
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_replace_chunked_history(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            delete_replaced_chunks: true,
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+        let mut previous: Option<thrift::FilenodeInfoChunks> = None;
+        for (generation, count) in [(0, 100), (1, 90), (2, 80)] {
+            let history = FilenodeRange::Filenodes(distinct_filenodes(count));
+            cache.fill_history(&key, history.clone());
+            time::timeout(Duration::from_millis(TIMEOUT_MS), async {
+                while cache.get_history(&key).await.as_ref() != Some(&history) {
+                    time::sleep(Duration::from_millis(SLEEP_MS)).await;
+                }
+            })
+            .await?;
+
+            let chunks = get_root_chunks(&cache, &key).await?;
+            assert_eq!(chunks.generation, generation);
+
+            if let Some(previous) = previous {
+                // The chunks of the replaced history are gone.
+                for pointer in &previous.pointers {
+                    let chunk_key =
+                        get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, *pointer)?;
+                    assert_eq!(cache.memcache.get(chunk_key).await?, None);
+                }
+
+                // A chunk from the replaced history is never mixed into the new one.
+                let stale = compact_protocol::serialize(&thrift::FilenodeInfoChunk {
+                    generation: previous.generation,
+                    data: b"stale".to_vec(),
+                    compressed: None,
+                });
+                let chunk_key =
+                    get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, chunks.pointers[0])?;
+                let chunk = cache.memcache.get(chunk_key.clone()).await?.unwrap();
+                cache.memcache.set(chunk_key.clone(), stale).await?;
+                assert_eq!(cache.get_history(&key).await, None);
+                cache.memcache.set(chunk_key, chunk).await?;
+                assert_eq!(cache.get_history(&key).await, Some(history));
+            }
+
+            previous = Some(chunks);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_summary() {
+        assert_eq!(
+            RemoteCache::new_noop().config_summary(),
+            CacheConfigSummary::Noop
+        );
+
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            refill_ttl_threshold: Some(Duration::from_secs(60)),
+            rechunk_on_read: true,
+            recent_fills_capacity: 10,
+            chunk_size: Some(1024),
+            preferred_chunk_size: Some(512),
+            content_index: true,
+            path_depth_stats: false,
+            delete_replaced_chunks: true,
+            soft_ttl: Some(Duration::from_secs(120)),
+            hard_ttl: None,
+            lenient_conversion: true,
+            canary_interval: None,
+            negative_caching: None,
+            read_retry: None,
+            miss_classification_capacity: 0,
+            hot_paths_capacity: 0,
+            max_history_pointers: None,
+            filenode_checksum: true,
+            min_history_entries: 2,
+            ttl_overrides: hashmap! { REPO_ONE => Duration::from_secs(3600) },
+            salt: Some("cohort".to_string()),
+            path_hash_key_bytes: None,
+            adaptive_ttl: None,
+            max_value_bytes: Some(1 << 20),
+            chunk_compression_level: Some(3),
+            fill_verify_sample_rate: 100,
+            stat_sample_rate: 0,
+            max_concurrent_fills: Some(100),
+            coalesce_history_reads: true,
+            stale_fallback_capacity: 100,
+            evict_corrupt: true,
+            circuit_breaker: None,
+            compress_min_bytes: 512,
+            history_error_log_sample_rate: Some(10),
+            fill_bytes_budget: Some(1 << 30),
+            intern_history_paths: true,
+            value_max_size: None,
+        });
+        cache.set_read_only(true);
+
+        let key_prefix =
+            RemoteCache::create_key_gen("newfilenodes", "test", Some("cohort"), None).key("");
+        assert!(key_prefix.starts_with("scm.mononoke.filenodes.newfilenodes.test.salt.cohort"));
+        assert_eq!(
+            cache.config_summary(),
+            CacheConfigSummary::Memcache {
+                key_prefix,
+                ttl_secs: TTL_SEC,
+                ttl_jitter_secs: TTL_SEC_RAND,
+                chunk_size: 1024,
+                preferred_chunk_size: 512,
+                refill_ttl_threshold_secs: Some(60),
+                rechunk_on_read: true,
+                recent_fills_capacity: 10,
+                content_index: true,
+                path_depth_stats: false,
+                delete_replaced_chunks: true,
+                soft_ttl_secs: Some(120),
+                hard_ttl_secs: None,
+                lenient_conversion: true,
+                canary_interval_secs: None,
+                negative_caching: None,
+                adaptive_ttl: None,
+                read_retry: None,
+                miss_classification_capacity: 0,
+                hot_paths_capacity: 0,
+                max_history_pointers: DEFAULT_MAX_HISTORY_POINTERS,
+                max_value_bytes: Some(1 << 20),
+                chunk_compression_level: Some(3),
+                fill_verify_sample_rate: 100,
+                stat_sample_rate: 0,
+                max_concurrent_fills: Some(100),
+                coalesce_history_reads: true,
+                stale_fallback_capacity: 100,
+                evict_corrupt: true,
+                circuit_breaker: None,
+                compress_min_bytes: 512,
+                history_error_log_sample_rate: Some(10),
+                fill_bytes_budget: Some(1 << 30),
+                intern_history_paths: true,
+                filenode_checksum: true,
+                min_history_entries: 2,
+                ttl_overrides_secs: btreemap! { REPO_ONE.id() => 3600 },
+                salt: Some("cohort".to_string()),
+                path_hash_key_bytes: DEFAULT_PATH_HASH_KEY_BYTES,
+                read_only: true,
+            }
+        );
+    }
+
+    #[fbinit::test]
+    async fn test_soft_and_hard_ttl(_fb: FacebookInit) -> Result<(), Error> {
+        const WRITTEN_AT: i64 = 1_000_000;
+        let now = Arc::new(AtomicI64::new(WRITTEN_AT));
+        let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            soft_ttl: Some(Duration::from_secs(60)),
+            hard_ttl: Some(Duration::from_secs(600)),
+            ..Default::default()
+        })
+        .with_fill_tracker();
+        let cache = cache.with_clock({
+            let now = now.clone();
+            move || now.load(Ordering::Relaxed)
+        });
+
+        let path = RepoPath::file("copiedto")?;
+        let key = filenode_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+        cache.fill_filenode(&key, filenode());
+        wait_for_filenode(&cache, &key).await?;
+        let sets = mock_stats(&cache).sets;
+
+        // Within the soft TTL: served as is.
+        now.fetch_add(59, Ordering::Relaxed);
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+        time::sleep(Duration::from_millis(SLEEP_MS)).await;
+        assert_eq!(mock_stats(&cache).sets, sets);
+
+        // Between the soft and hard TTLs: served, and refreshed in the background.
+        now.fetch_add(60, Ordering::Relaxed);
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+        time::timeout(Duration::from_millis(TIMEOUT_MS), async {
+            while mock_stats(&cache).sets == sets {
+                time::sleep(Duration::from_millis(SLEEP_MS)).await;
+            }
+        })
+        .await?;
+
+        // The refresh extended the soft TTL, so it's fresh again.
+        let sets = mock_stats(&cache).sets;
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+        time::sleep(Duration::from_millis(SLEEP_MS)).await;
+        assert_eq!(mock_stats(&cache).sets, sets);
+
+        // Reading it repeatedly keeps refreshing it, but it's a miss once past the hard TTL of
+        // the original write.
+        loop {
+            now.fetch_add(61, Ordering::Relaxed);
+            let read = cache.get_filenode(&key).await;
+            if now.load(Ordering::Relaxed) - WRITTEN_AT >= 600 {
+                assert_eq!(read, None);
+                break;
+            }
+            assert_eq!(read, Some(filenode()));
+            fills.wait_for_fills().await;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_normalization_version() -> Result<(), Error> {
+        let path = RepoPath::file("dir/file")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+
+        let current = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let next_encoding = PathHashEncoding {
+            normalization: PATH_NORMALIZATION_VERSION + 1,
+            ..Default::default()
+        };
+        let next = filenode_cache_key_with_encoding(REPO_ZERO, &pwh, &ONES_FNID, next_encoding);
+        assert_ne!(current.key, next.key);
+        assert!(
+            current
+                .key
+                .contains(&format!(".n{}.", PATH_NORMALIZATION_VERSION))
+        );
+
+        let current = history_cache_key(REPO_ZERO, &pwh, None);
+        let next = history_cache_key_with_encoding(REPO_ZERO, &pwh, None, next_encoding);
+        assert_ne!(current.key, next.key);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_filenodes_grouped(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let first = RepoPath::file("first")?;
+        let second = RepoPath::file("dir/second")?;
+
+        let mut infos = distinct_filenodes(5).into_iter();
+        let first_infos = infos.by_ref().take(3).collect::<Vec<_>>();
+        let second_infos = infos.collect::<Vec<_>>();
+
+        for (path, infos) in [(&first, &first_infos), (&second, &second_infos)] {
+            let pwh = PathWithHash::from_repo_path(path);
+            for info in infos {
+                let key = filenode_cache_key(REPO_ZERO, &pwh, &info.filenode);
+                cache.fill_filenode(&key, info.clone());
+                wait_for_filenode(&cache, &key).await?;
+            }
+        }
+
+        // Also ask for a filenode that isn't cached.
+        let by_path = [
+            (
+                &first,
+                first_infos
+                    .iter()
+                    .map(|info| info.filenode)
+                    .chain(std::iter::once(ONES_FNID))
+                    .collect(),
+            ),
+            (
+                &second,
+                second_infos.iter().map(|info| info.filenode).collect(),
+            ),
+        ];
+
+        let mut hashed = Vec::new();
+        let grouped = cache
+            .get_filenodes_grouped_with(REPO_ZERO, &by_path, |path| {
+                hashed.push(path.clone());
+                PathWithHash::from_repo_path(path)
+            })
+            .await;
+        assert_eq!(hashed, vec![first.clone(), second.clone()]);
+
+        let expected = |infos: &[FilenodeInfo]| {
+            infos
+                .iter()
+                .map(|info| (info.filenode, info.clone()))
+                .collect::<HashMap<_, _>>()
+        };
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[&first], expected(&first_infos));
+        assert_eq!(grouped[&second], expected(&second_infos));
+        assert_eq!(
+            cache.get_filenodes_grouped(REPO_ZERO, &by_path).await,
+            grouped
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_lenient_conversion(_fb: FacebookInit) -> Result<(), Error> {
+        // A value written before the copy source's filenode was recorded: the field is absent,
+        // so it is read back as its default, which isn't a valid hash.
+        let mut info = filenode().into_thrift();
+        info.copyfrom.as_mut().unwrap().filenode = Default::default();
+        let serialized = compact_protocol::serialize(&thrift::CachedFilenodeInfo {
+            repo_id: REPO_ZERO.id(),
+            info,
+            written_at: None,
+            soft_ttl_secs: None,
+            epoch: None,
+        });
+        let serialized = with_kind_tag(FILENODE_TAG, &serialized);
+
+        let expected = FilenodeInfo {
+            copyfrom: None,
+            ..filenode()
+        };
+        assert!(deserialize_filenode(&serialized, false).is_none());
+        assert_eq!(
+            deserialize_filenode(&serialized, true).map(|cached| cached.info),
+            Some(expected.clone())
+        );
+
+        let path = RepoPath::file("copiedto")?;
+        let key = filenode_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+        for (lenient_conversion, res) in [(false, None), (true, Some(expected))] {
+            let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+                lenient_conversion,
+                ..Default::default()
+            });
+            cache
+                .memcache
+                .set(cache.keygen.key(&key.key), serialized.clone())
+                .await?;
+            assert_eq!(cache.get_filenode(&key).await, res);
+        }
+
+        // Fields that are there but aren't valid hashes aren't old, but corrupt.
+        let mut info = filenode().into_thrift();
+        info.p1 = Some(mercurial_types::thrift::HgNodeHash(
+            mercurial_types::thrift::Sha1(vec![1; 3].into()),
+        ));
+        let serialized = compact_protocol::serialize(&thrift::CachedFilenodeInfo {
+            repo_id: REPO_ZERO.id(),
+            info,
+            written_at: None,
+            soft_ttl_secs: None,
+            epoch: None,
+        });
+        let serialized = with_kind_tag(FILENODE_TAG, &serialized);
+        assert!(deserialize_filenode(&serialized, true).is_none());
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_canary(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        assert!(cache.check_canary().await);
+
+        // The canary is written under a key of this process, and expires quickly.
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store,
+            _ => unreachable!("not a mock cache"),
+        };
+        let prefix = format!("{}.testhost.{}.", CANARY_KEY, std::process::id());
+        assert!(CANARY_PREFIX.starts_with(&prefix), "{}", *CANARY_PREFIX);
+        assert!(CANARY_PREFIX.len() > prefix.len());
+        assert_eq!(
+            store.ttl(&cache.keygen.key(&*CANARY_PREFIX)),
+            Some(CANARY_TTL)
+        );
+
+        // A pool that accepts writes but drops them.
+        store.set_drop_sets(true);
+        assert!(!cache.check_canary().await);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_canary_runs_periodically(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            canary_interval: Some(Duration::from_millis(SLEEP_MS)),
+            ..Default::default()
+        });
+
+        time::timeout(Duration::from_millis(TIMEOUT_MS), async {
+            while mock_stats(&cache).sets < 2 {
+                time::sleep(Duration::from_millis(SLEEP_MS)).await;
+            }
+        })
+        .await?;
+
+        // The canary stops once the cache is gone.
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store.clone(),
+            _ => unreachable!("not a mock cache"),
+        };
+        drop(cache);
+        time::sleep(Duration::from_millis(SLEEP_MS * 2)).await;
+        let sets = store.stats().sets;
+        time::sleep(Duration::from_millis(SLEEP_MS * 5)).await;
+        assert_eq!(store.stats().sets, sets);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_raw(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        assert_eq!(
+            cache.get_filenode_raw(REPO_ZERO, &path, ONES_FNID).await,
+            None
+        );
+        cache.fill_filenode(&key, filenode());
+        let typed = wait_for_filenode(&cache, &key).await?;
+        let raw = cache
+            .get_filenode_raw(REPO_ZERO, &path, ONES_FNID)
+            .await
+            .expect("filenode is cached");
+        assert_eq!(try_deserialize_filenode(&raw), Some(typed));
+
+        // A history small enough to be stored as a single value.
+        let key = history_cache_key(REPO_ZERO, &pwh, Some(1));
+        cache.fill_history(&key, FilenodeRange::Filenodes(vec![filenode()]));
+        let typed = wait_for_history(&cache, &key).await?;
+        let raw = cache
+            .get_history_raw(REPO_ZERO, &path, Some(1))
+            .await
+            .expect("history is cached");
+        assert_eq!(try_deserialize_history(&raw), Some(typed));
+
+        // A history split into chunks.
+        let key = history_cache_key(REPO_ZERO, &pwh, None);
+        cache.fill_history(&key, FilenodeRange::Filenodes(distinct_filenodes(100)));
+        let (typed, layout) = time::timeout(Duration::from_millis(TIMEOUT_MS), async {
+            loop {
+                if let (Some(history), layout) = cache.get_history_with_layout(&key).await {
+                    break (history, layout);
+                }
+                time::sleep(Duration::from_millis(SLEEP_MS)).await;
+            }
+        })
+        .await?;
+        assert!(matches!(layout, HistoryLayout::Chunked { .. }));
+        let raw = cache
+            .get_history_raw(REPO_ZERO, &path, None)
+            .await
+            .expect("history is cached");
+        assert_eq!(deserialize_history_blob(&raw, false), Some(typed));
+
+        // Values of another repo found under the keys of this one aren't returned.
+        let other_path = RepoPath::file("other")?;
+        let other_pwh = PathWithHash::from_repo_path(&other_path);
+        let filenode_raw = cache.get_filenode_raw(REPO_ZERO, &path, ONES_FNID).await;
+        let history_raw = cache.get_history_raw(REPO_ZERO, &path, Some(1)).await;
+        let other_key = filenode_cache_key(REPO_ONE, &other_pwh, &ONES_FNID);
+        cache
+            .memcache
+            .set(cache.keygen.key(&other_key.key), filenode_raw.unwrap())
+            .await?;
+        let other_key = history_cache_key(REPO_ONE, &other_pwh, Some(1));
+        cache
+            .memcache
+            .set(cache.keygen.key(&other_key.key), history_raw.unwrap())
+            .await?;
+        assert_eq!(
+            cache
+                .get_filenode_raw(REPO_ONE, &other_path, ONES_FNID)
+                .await,
+            None
+        );
+        assert_eq!(
+            cache.get_history_raw(REPO_ONE, &other_path, Some(1)).await,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_negative_caching(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            negative_caching: Some(NegativeCachingOptions {
+                miss_threshold: 3,
+                window: Duration::from_secs(60),
+                ttl: Duration::from_secs(60),
+            }),
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+
+        let calls = AtomicUsize::new(0);
+        let missing = || async {
+            calls.fetch_add(1, Ordering::Relaxed);
+            Ok(None)
+        };
+
+        for _ in 0..5 {
+            assert_eq!(cache.get_or_fill_filenode(&key, missing).await?, None);
+        }
+        // Only the misses up to the threshold reached the backing store.
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        // Other keys are unaffected.
+        let other_key = filenode_cache_key(REPO_ZERO, &pwh, &TWOS_FNID);
+        assert_eq!(cache.get_or_fill_filenode(&other_key, missing).await?, None);
+        assert_eq!(calls.load(Ordering::Relaxed), 4);
+
+        // Without negative caching, every miss reaches the backing store.
+        let cache = RemoteCache::new_mock();
+        for _ in 0..5 {
+            assert_eq!(cache.get_or_fill_filenode(&key, missing).await?, None);
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 9);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_read_retry(_fb: FacebookInit) -> Result<(), Error> {
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(vec![filenode()]);
+
+        for read_retry in [
+            None,
+            Some(ReadRetryPolicy {
+                retries: 2,
+                backoff: Duration::from_millis(1),
+            }),
+        ] {
+            let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+                read_retry,
+                ..Default::default()
+            });
+            cache.fill_filenode(&key, filenode());
+            wait_for_filenode(&cache, &key).await?;
+            cache.fill_history(&history_key, history.clone());
+            wait_for_history(&cache, &history_key).await?;
+
+            let store = match &cache.memcache {
+                MemcacheHandler::Mock(store) => store,
+                _ => unreachable!("not a mock cache"),
+            };
+
+            // A single failure is retried if there is a retry policy, and is a miss otherwise.
+            let (expected_filenode, expected_history) = match read_retry {
+                Some(_) => (Some(filenode()), Some(history.clone())),
+                None => (None, None),
+            };
+            store.fail_next_gets(1);
+            assert_eq!(cache.get_filenode(&key).await, expected_filenode);
+            store.fail_next_gets(1);
+            assert_eq!(cache.get_history(&history_key).await, expected_history);
+
+            // Running out of retries is a miss.
+            store.fail_next_gets(3);
+            assert_eq!(cache.get_filenode(&key).await, None);
+            store.fail_next_gets(0);
+        }
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_filenode_by_key(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+        let key = cache.filenode_key(REPO_ZERO, &path, ONES_FNID);
+
+        assert_eq!(cache.get_filenode_by_key(&key).await, None);
+        cache.fill_filenode_by_key(&key, filenode());
+        wait_for_filenode(&cache, &key.key).await?;
+        assert_eq!(cache.get_filenode_by_key(&key).await, Some(filenode()));
+
+        // The same entry as the one used by the other methods.
+        let cache_key =
+            filenode_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+        assert_eq!(key.key.key, cache_key.key);
+        assert_eq!(cache.get_filenode(&cache_key).await, Some(filenode()));
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_miss_classification(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            miss_classification_capacity: 100,
+            ..Default::default()
+        });
+        let recent_writes = cache.recent_writes.as_ref().unwrap();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let other_key = filenode_cache_key(REPO_ZERO, &pwh, &TWOS_FNID);
+
+        cache.fill_filenode(&key, filenode());
+        wait_for_filenode(&cache, &key).await?;
+
+        // Evict the filenode.
+        match &cache.memcache {
+            MemcacheHandler::Mock(store) => store.del(&cache.keygen.key(&key.key)),
+            _ => unreachable!("not a mock cache"),
+        }
+        assert_eq!(cache.get_filenode(&key).await, None);
+        assert_eq!(recent_writes.classify_miss(&key.key), MissKind::Evicted);
+
+        // A filenode that was never filled.
+        assert_eq!(cache.get_filenode(&other_key).await, None);
+        assert_eq!(recent_writes.classify_miss(&other_key.key), MissKind::Cold);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_estimated_eviction_pressure(_fb: FacebookInit) -> Result<(), Error> {
+        let now = Arc::new(AtomicI64::new(1_000_000));
+        let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            miss_classification_capacity: 100,
+            ..Default::default()
+        })
+        .with_clock({
+            let now = now.clone();
+            move || now.load(Ordering::Relaxed)
+        })
+        .with_fill_tracker();
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store,
+            _ => unreachable!("not a mock cache"),
+        };
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let keys = distinct_filenodes(20)
+            .into_iter()
+            .map(|info| (filenode_cache_key(REPO_ZERO, &pwh, &info.filenode), info))
+            .collect::<Vec<_>>();
+        assert_eq!(cache.estimated_eviction_pressure(), 0.0);
+
+        // Misses of keys that were never written, then hits once they are.
+        for (key, info) in &keys {
+            assert_eq!(cache.get_filenode(key).await, None);
+            cache.fill_filenode(key, info.clone());
+        }
+        fills.wait_for_fills().await;
+        for (key, info) in &keys {
+            assert_eq!(cache.get_filenode(key).await, Some(info.clone()));
+        }
+        assert_eq!(cache.estimated_eviction_pressure(), 0.0);
+
+        // Evict half of them.
+        for (key, _) in &keys[..10] {
+            store.del(&cache.keygen.key(&key.key));
+            assert_eq!(cache.get_filenode(key).await, None);
+        }
+        assert_eq!(cache.estimated_eviction_pressure(), 0.2);
+        for (key, _) in &keys[..10] {
+            assert_eq!(cache.get_filenode(key).await, None);
+        }
+        assert_eq!(cache.estimated_eviction_pressure(), 1.0 / 3.0);
+
+        // Reads of earlier seconds are still counted along with those of the latest one.
+        now.fetch_add(1, Ordering::Relaxed);
+        let (key, info) = &keys[10];
+        assert_eq!(cache.get_filenode(key).await, Some(info.clone()));
+        assert_eq!(cache.estimated_eviction_pressure(), 20.0 / 61.0);
+
+        // The reads leave the window.
+        now.fetch_add(FILL_RATE_WINDOW_SECS as i64, Ordering::Relaxed);
+        assert_eq!(cache.estimated_eviction_pressure(), 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recent_writes_rotation() {
+        let recent_writes = RecentWrites::new(10);
+        recent_writes.insert("first");
+        for i in 0..10 {
+            recent_writes.insert(&format!("key {}", i));
+        }
+        // Still in the older filter.
+        assert_eq!(recent_writes.classify_miss("first"), MissKind::Evicted);
+
+        for i in 10..20 {
+            recent_writes.insert(&format!("key {}", i));
+        }
+        assert_eq!(recent_writes.classify_miss("first"), MissKind::Cold);
+        assert_eq!(recent_writes.classify_miss("key 19"), MissKind::Evicted);
+    }
+
+    #[fbinit::test]
+    async fn test_fallback_cache(_fb: FacebookInit) -> Result<(), Error> {
+        let failing = Arc::new(RemoteCache::new_mock());
+        let working = Arc::new(RemoteCache::new_mock());
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(vec![filenode()]);
+
+        working.fill_filenode(&key, filenode());
+        wait_for_filenode(&working, &key).await?;
+        working.fill_history(&history_key, history.clone());
+        wait_for_history(&working, &history_key).await?;
+
+        let fail_next_gets = |count| match &failing.memcache {
+            MemcacheHandler::Mock(store) => store.fail_next_gets(count),
+            _ => unreachable!("not a mock cache"),
+        };
+
+        let chain = FallbackCache::new(vec![failing.clone(), working.clone()]);
+
+        // The first cache fails, so the second one serves the values, and the first one is
+        // backfilled with them.
+        fail_next_gets(1);
+        assert_eq!(chain.get_filenode(&key).await, Some(filenode()));
+        assert_eq!(wait_for_filenode(&failing, &key).await?, filenode());
+        fail_next_gets(1);
+        assert_eq!(chain.get_history(&history_key).await, Some(history.clone()));
+        assert_eq!(wait_for_history(&failing, &history_key).await?, history);
+
+        // Without backfilling, the first cache is skipped.
+        let skipping = Arc::new(RemoteCache::new_mock());
+        let chain =
+            FallbackCache::new(vec![skipping.clone(), working.clone()]).with_backfill(false);
+        assert_eq!(chain.get_filenode(&key).await, Some(filenode()));
+        time::sleep(Duration::from_millis(TIMEOUT_MS)).await;
+        assert_eq!(mock_stats(&skipping).sets, 0);
+
+        // Fills go to all the caches.
+        let other_key = filenode_cache_key(REPO_ZERO, &pwh, &TWOS_FNID);
+        chain.fill_filenode(&other_key, filenode());
+        wait_for_filenode(&skipping, &other_key).await?;
+        wait_for_filenode(&working, &other_key).await?;
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_fallback_on_corrupt(_fb: FacebookInit) -> Result<(), Error> {
+        let primary = Arc::new(RemoteCache::new_mock());
+        let secondary = Arc::new(RemoteCache::new_mock());
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(vec![filenode()]);
+
+        secondary.fill_filenode(&key, filenode());
+        wait_for_filenode(&secondary, &key).await?;
+        secondary.fill_history(&history_key, history.clone());
+        wait_for_history(&secondary, &history_key).await?;
+
+        let corrupt = Bytes::from_static(b"corrupt");
+        for key in [&key.key, &history_key.key] {
+            primary
+                .memcache
+                .set(primary.keygen.key(key), corrupt.clone())
+                .await?;
+        }
+        assert_eq!(primary.read_filenode(&key).await, CacheRead::Corrupt);
+        assert_eq!(primary.read_history(&history_key).await, CacheRead::Corrupt);
+
+        // Repairing the primary is independent of backfilling it.
+        let chain =
+            FallbackCache::new(vec![primary.clone(), secondary.clone()]).with_backfill(false);
+        assert_eq!(chain.get_filenode(&key).await, Some(filenode()));
+        assert_eq!(wait_for_filenode(&primary, &key).await?, filenode());
+        assert_eq!(chain.get_history(&history_key).await, Some(history.clone()));
+        assert_eq!(wait_for_history(&primary, &history_key).await?, history);
+
+        // Without repairing, the corrupt value is left in place.
+        primary
+            .memcache
+            .set(primary.keygen.key(&key.key), corrupt.clone())
+            .await?;
+        let chain = FallbackCache::new(vec![primary.clone(), secondary.clone()]).with_repair(false);
+        assert_eq!(chain.read_filenode(&key).await, CacheRead::Hit(filenode()));
+        time::sleep(Duration::from_millis(TIMEOUT_MS)).await;
+        assert_eq!(primary.read_filenode(&key).await, CacheRead::Corrupt);
+
+        // With no valid value anywhere, the chain reports the corruption.
+        let chain = FallbackCache::new(vec![primary.clone()]);
+        assert_eq!(chain.read_filenode(&key).await, CacheRead::Corrupt);
+        let other_key = filenode_cache_key(REPO_ZERO, &pwh, &TWOS_FNID);
+        assert_eq!(chain.read_filenode(&other_key).await, CacheRead::Miss);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_too_many_pointers(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            max_history_pointers: Some(50),
+            delete_replaced_chunks: true,
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+        let history = FilenodeRange::Filenodes(distinct_filenodes(100));
+        cache.fill_history(&key, history.clone());
+        wait_for_history(&cache, &key).await?;
+
+        // A corrupt root pointing to more chunks than allowed.
+        let chunks = get_root_chunks(&cache, &key).await?;
+        let root = compact_protocol::serialize(&thrift::CachedFilenodeInfoList {
+            repo_id: key.repo_id.id(),
+            list: thrift::FilenodeInfoList::Chunks(thrift::FilenodeInfoChunks {
+                pointers: (0..51).collect(),
+                ..chunks
+            }),
+        });
+        let root = with_kind_tag(HISTORY_TAG, &root);
+        cache.memcache.set(cache.keygen.key(&key.key), root).await?;
+
+        // Rejected after reading the root, without reading any of the chunks.
+        let gets = mock_stats(&cache).gets;
+        assert_eq!(
+            cache.get_history_with_layout(&key).await,
+            (None, HistoryLayout::Missed)
+        );
+        assert_eq!(mock_stats(&cache).gets, gets + 1);
+
+        // The history can still be replaced.
+        cache.fill_history(&key, history.clone());
+        assert_eq!(wait_for_history(&cache, &key).await?, history);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_history_summary(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
+
+        // A history small enough to be stored as a single value.
+        let path = RepoPath::file("copiedfrom")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        assert_eq!(cache.history_summary(REPO_ZERO, &path).await, None);
+        cache.fill_history(&key, FilenodeRange::Filenodes(distinct_filenodes(3)));
+        wait_for_history(&cache, &key).await?;
+        let summary = cache
+            .history_summary(REPO_ZERO, &path)
+            .await
+            .expect("history is cached");
+        assert_eq!(summary.entry_count, Some(3));
+        assert_eq!(summary.layout, HistoryLayout::Inline);
+        assert!(summary.approx_bytes > 0);
+
+        // A history split into chunks is summarized from its root alone.
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        cache.fill_history(&key, FilenodeRange::Filenodes(distinct_filenodes(100)));
+        wait_for_history(&cache, &key).await?;
+        let gets = mock_stats(&cache).gets;
+        let summary = cache
+            .history_summary(REPO_ZERO, &path)
+            .await
+            .expect("history is cached");
+        assert_eq!(mock_stats(&cache).gets, gets + 1);
+        assert_eq!(summary.entry_count, Some(100));
+        let chunks = get_root_chunks(&cache, &key).await?;
+        assert_eq!(
+            summary.layout,
+            HistoryLayout::Chunked {
+                chunks: chunks.pointers.len()
+            }
+        );
+        assert!(summary.approx_bytes > 1024);
+
+        // Roots written before the count was recorded need the chunks to be read.
+        let root = compact_protocol::serialize(&thrift::CachedFilenodeInfoList {
+            repo_id: key.repo_id.id(),
+            list: thrift::FilenodeInfoList::Chunks(thrift::FilenodeInfoChunks {
+                entry_count: None,
+                size: None,
+                ..chunks
+            }),
+        });
+        let root = with_kind_tag(HISTORY_TAG, &root);
+        cache.memcache.set(cache.keygen.key(&key.key), root).await?;
+        assert_eq!(cache.history_summary(REPO_ZERO, &path).await, Some(summary));
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_filenode_checksum(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            filenode_checksum: true,
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let key = filenode_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+        let mc_key = cache.keygen.key(&key.key);
+
+        cache.fill_filenode(&key, filenode());
+        wait_for_filenode(&cache, &key).await?;
+        let value = cache
+            .get_filenode_raw(REPO_ZERO, &path, ONES_FNID)
+            .await
+            .expect("filenode is cached");
+        let data = strip_checksum(&value).expect("valid checksum");
+        assert_eq!(try_deserialize_filenode(data), Some(filenode()));
+
+        // Flip a bit of the linknode. The value still deserializes, to the wrong filenode, so
+        // only the checksum can catch it.
+        let mut corrupt = value.to_vec();
+        let linknode = corrupt
+            .windows(20)
+            .position(|window| window == ONES_CSID.as_bytes())
+            .expect("linknode is stored");
+        corrupt[linknode] ^= 1;
+        let corrupt_data = &corrupt[..corrupt.len() - CHECKSUM_LEN];
+        assert!(matches!(
+            try_deserialize_filenode(corrupt_data),
+            Some(info) if info != filenode()
+        ));
+        assert_eq!(strip_checksum(&corrupt), None);
+        cache
+            .memcache
+            .set(mc_key.clone(), Bytes::from(corrupt))
+            .await?;
+        assert_eq!(cache.get_filenode(&key).await, None);
+
+        // A value with a valid checksum that doesn't deserialize fails after the checksum.
+        let garbage = with_checksum(b"garbage");
+        assert_eq!(strip_checksum(&garbage), Some(&b"garbage"[..]));
+        cache.memcache.set(mc_key, garbage).await?;
+        assert_eq!(cache.get_filenode(&key).await, None);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_cache_events(_fb: FacebookInit) -> Result<(), Error> {
+        let (sender, mut receiver) = mpsc::channel(2);
+        let cache = RemoteCache::new_mock().with_events(sender, true);
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let filenode_key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+
+        async fn next_event(
+            receiver: &mut mpsc::Receiver<CacheEvent>,
+        ) -> Result<CacheEvent, Error> {
+            let event = time::timeout(Duration::from_millis(TIMEOUT_MS), receiver.recv()).await?;
+            event.ok_or_else(|| Error::msg("sender was dropped"))
+        }
+
+        cache.fill_filenode(&filenode_key, filenode());
+        let event = next_event(&mut receiver).await?;
+        assert_eq!(event.repo_id, REPO_ZERO);
+        assert_eq!(event.key, filenode_key.key);
+        assert_eq!(event.kind, CacheEventKind::FilenodeFill);
+        assert!(event.bytes > 0);
+
+        cache.fill_history(&history_key, FilenodeRange::Filenodes(vec![filenode()]));
+        let event = next_event(&mut receiver).await?;
+        assert_eq!(event.key, history_key.key);
+        assert_eq!(event.kind, CacheEventKind::HistoryFill);
+        assert!(event.bytes > 0);
+
+        // Reads are reported too.
+        assert_eq!(cache.get_filenode(&filenode_key).await, Some(filenode()));
+        let event = next_event(&mut receiver).await?;
+        assert_eq!(event.key, filenode_key.key);
+        assert_eq!(event.kind, CacheEventKind::Hit);
+        assert!(event.bytes > 0);
+
+        let missing_key = history_cache_key(REPO_ZERO, &pwh, Some(1));
+        assert_eq!(cache.get_history(&missing_key).await, None);
+        let event = next_event(&mut receiver).await?;
+        assert_eq!(event.key, missing_key.key);
+        assert_eq!((event.kind, event.bytes), (CacheEventKind::Miss, 0));
+
+        // Fills still complete once the channel is full, and the extra events are dropped.
+        let filenodes = distinct_filenodes(5);
+        for info in &filenodes {
+            let key = filenode_cache_key(REPO_ZERO, &pwh, &info.filenode);
+            cache.fill_filenode(&key, info.clone());
+        }
+        for info in &filenodes {
+            let key = filenode_cache_key(REPO_ZERO, &pwh, &info.filenode);
+            assert_eq!(wait_for_filenode(&cache, &key).await?, info.clone());
+        }
+        let mut queued = 0;
+        while receiver.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, 2);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_kind_tag(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let filenode_key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+
+        cache.fill_history(&history_key, FilenodeRange::Filenodes(vec![filenode()]));
+        wait_for_history(&cache, &history_key).await?;
+        let value = cache
+            .memcache
+            .get(cache.keygen.key(&history_key.key))
+            .await?
+            .expect("history is cached");
+        assert_eq!(value[0], HISTORY_TAG);
+
+        // A history stored where a filenode is expected isn't read as one.
+        assert_eq!(strip_kind_tag(FILENODE_TAG, &value), None);
+        assert!(deserialize_filenode(&value, false).is_none());
+        cache
+            .memcache
+            .set(cache.keygen.key(&filenode_key.key), value)
+            .await?;
+        assert_eq!(cache.get_filenode(&filenode_key).await, None);
+
+        // And the other way around.
+        cache.fill_filenode(&filenode_key, filenode());
+        wait_for_filenode(&cache, &filenode_key).await?;
+        let value = cache
+            .memcache
+            .get(cache.keygen.key(&filenode_key.key))
+            .await?
+            .expect("filenode is cached");
+        assert_eq!(value[0], FILENODE_TAG);
+        cache
+            .memcache
+            .set(cache.keygen.key(&history_key.key), value)
+            .await?;
+        assert_eq!(cache.get_history(&history_key).await, None);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_debug_history_layout(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
+
+        let path = RepoPath::file("copiedfrom")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        assert_eq!(cache.debug_history_layout(REPO_ZERO, &path).await, None);
+        cache.fill_history(&key, FilenodeRange::Filenodes(distinct_filenodes(3)));
+        wait_for_history(&cache, &key).await?;
+        let root = cache
+            .memcache
+            .get(cache.keygen.key(&key.key))
+            .await?
+            .expect("history is cached");
+        assert_eq!(
+            cache.debug_history_layout(REPO_ZERO, &path).await,
+            Some(format!("inline: 3 entries, {} bytes", root.len()))
+        );
+
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        cache.fill_history(&key, FilenodeRange::Filenodes(distinct_filenodes(100)));
+        wait_for_history(&cache, &key).await?;
+
+        // Pin the root TTL, which is otherwise randomized.
+        let chunks = get_root_chunks(&cache, &key).await?;
+        let root = compact_protocol::serialize(&thrift::CachedFilenodeInfoList {
+            repo_id: key.repo_id.id(),
+            list: thrift::FilenodeInfoList::Chunks(thrift::FilenodeInfoChunks {
+                expires_at: unix_timestamp() + 2 * 60 * 60 + 60,
+                ..chunks.clone()
+            }),
+        });
+        let root = with_kind_tag(HISTORY_TAG, &root);
+        cache.memcache.set(cache.keygen.key(&key.key), root).await?;
+
+        let pointers = &chunks.pointers;
+        assert!(pointers.len() > DEBUG_POINTERS_SHOWN);
+        assert_eq!(
+            cache.debug_history_layout(REPO_ZERO, &path).await,
+            Some(format!(
+                "chunked: {} pointers [{}, {}, {}, {}, ...], root TTL ~2h",
+                pointers.len(),
+                pointers[0],
+                pointers[1],
+                pointers[2],
+                pointers[3]
+            ))
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_min_history_entries(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            min_history_entries: 2,
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+
+        let short_key = history_cache_key(REPO_ZERO, &pwh, Some(1));
+        cache.fill_history(&short_key, FilenodeRange::Filenodes(vec![filenode()]));
+
+        let key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(distinct_filenodes(3));
+        cache.fill_history(&key, history.clone());
+        assert_eq!(wait_for_history(&cache, &key).await?, history);
+
+        // Only the longer history was written.
+        assert_eq!(mock_stats(&cache).sets, 1);
+        assert_eq!(cache.get_history(&short_key).await, None);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_ttl_overrides(_fb: FacebookInit) -> Result<(), Error> {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let hour = Duration::from_secs(60 * 60);
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            ttl_overrides: hashmap! { REPO_ZERO => day, REPO_ONE => hour },
+            ..Default::default()
+        });
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store.clone(),
+            _ => unreachable!("not a mock cache"),
+        };
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+
+        for (repo_id, ttl) in [
+            (REPO_ZERO, Some(day)),
+            (REPO_ONE, Some(hour)),
+            (REPO_TWO, None),
+        ] {
+            let key = filenode_cache_key(repo_id, &pwh, &ONES_FNID);
+            cache.fill_filenode(&key, filenode());
+            wait_for_filenode(&cache, &key).await?;
+            assert_eq!(store.ttl(&cache.keygen.key(&key.key)), ttl);
+
+            let key = history_cache_key(repo_id, &pwh, None);
+            cache.fill_history(&key, FilenodeRange::Filenodes(vec![filenode()]));
+            wait_for_history(&cache, &key).await?;
+            let root_ttl = store
+                .ttl(&cache.keygen.key(&key.key))
+                .expect("roots have a TTL");
+            let base_ttl = ttl.unwrap_or(Duration::from_secs(TTL_SEC));
+            assert!(root_ttl >= base_ttl);
+            assert!(root_ttl < base_ttl + Duration::from_secs(TTL_SEC_RAND));
+        }
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_fill_history_stream(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        let filenodes = distinct_filenodes(1000);
+
+        // Chunks are written while the stream is still being read.
+        let last = filenodes.last().cloned();
+        let sets_before_last = Cell::new(None);
+        let stream = stream::iter(filenodes.clone()).inspect(|filenode| {
+            if Some(filenode) == last.as_ref() {
+                sets_before_last.set(Some(mock_stats(&cache).sets));
+            }
+        });
+        cache.fill_history_stream(REPO_ZERO, &path, stream).await?;
+        assert!(sets_before_last.get().expect("stream was read") > 0);
+
+        let history = FilenodeRange::Filenodes(filenodes.clone());
+        let (read, layout) = cache.get_history_with_layout(&key).await;
+        assert_eq!(read, Some(history.clone()));
+        assert!(matches!(layout, HistoryLayout::Chunked { chunks } if chunks > 1));
+
+        let summary = cache
+            .history_summary(REPO_ZERO, &path)
+            .await
+            .expect("history is cached");
+        assert_eq!(summary.entry_count, Some(filenodes.len()));
+        let raw = cache
+            .get_history_raw(REPO_ZERO, &path, None)
+            .await
+            .expect("history is cached");
+        assert_eq!(deserialize_history_blob(&raw, false), Some(history));
+
+        // A history needing more chunks than readers accept isn't written.
+        let limited = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            max_history_pointers: Some(2),
+            ..Default::default()
+        });
+        let res = limited
+            .fill_history_stream(REPO_ZERO, &path, stream::iter(filenodes))
+            .await;
+        assert!(res.is_err());
+        assert_eq!(limited.get_history(&key).await, None);
+
+        // A history that turns out to be too short leaves no chunks behind, even if it fills
+        // several.
+        let min = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            min_history_entries: 1001,
+            ..Default::default()
+        });
+        min.fill_history_stream(REPO_ZERO, &path, stream::iter(distinct_filenodes(1000)))
+            .await?;
+        assert_eq!(mock_stats(&min).sets, 0);
+        assert_eq!(min.get_history(&key).await, None);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_fill_stats(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            min_history_entries: 2,
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        assert_eq!(cache.fill_stats(), FillStats::default());
+
+        // Written.
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        cache.fill_filenode(&key, filenode());
+        wait_for_filenode(&cache, &key).await?;
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(distinct_filenodes(2));
+        cache.fill_history(&history_key, history);
+        wait_for_history(&cache, &history_key).await?;
+
+        // Skipped, as too short.
+        let short_key = history_cache_key(REPO_ZERO, &pwh, Some(1));
+        cache.fill_history(&short_key, FilenodeRange::Filenodes(vec![filenode()]));
+
+        // Skipped, as the cache is read-only.
+        cache.set_read_only(true);
+        let other_key = filenode_cache_key(REPO_ZERO, &pwh, &TWOS_FNID);
+        cache.fill_filenode(&other_key, filenode());
+        cache.set_read_only(false);
+
+        time::sleep(Duration::from_millis(SLEEP_MS)).await;
+        let stats = cache.fill_stats();
+        assert_eq!(
+            stats,
+            FillStats {
+                attempted: 4,
+                written: 2,
+                started: 2,
+                spawn_lag: stats.spawn_lag,
+            }
+        );
+        assert_eq!(stats.skipped(), 2);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_salt(_fb: FacebookInit) -> Result<(), Error> {
+        // Caches sharing a store, as hosts in different cohorts share memcache.
+        let memcache = MemcacheHandler::create_mock();
+        let cache_with_salt = |salt: Option<&str>| {
+            let options = RemoteCacheOptions {
+                salt: salt.map(str::to_owned),
+                ..Default::default()
+            };
+            let keygen = RemoteCache::create_key_gen("newfilenodes", "test", salt, None);
+            RemoteCache::from_parts(memcache.clone(), keygen, options)
+        };
+        let unsalted = cache_with_salt(None);
+        let salted = cache_with_salt(Some("cohort a"));
+        let other_salted = cache_with_salt(Some("cohort b"));
+
+        let path = RepoPath::file("copiedto")?;
+        let key = unsalted.filenode_key(REPO_ZERO, &path, ONES_FNID);
+        let mc_keys = [&unsalted, &salted, &other_salted]
+            .iter()
+            .map(|cache| cache.filenode_key(REPO_ZERO, &path, ONES_FNID).mc_key)
+            .collect::<HashSet<_>>();
+        assert_eq!(mc_keys.len(), 3);
+
+        unsalted.fill_filenode(&key.key, filenode());
+        wait_for_filenode(&unsalted, &key.key).await?;
+        assert_eq!(salted.get_filenode(&key.key).await, None);
+
+        let info = distinct_filenodes(2).pop().unwrap();
+        salted.fill_filenode(&key.key, info.clone());
+        assert_eq!(wait_for_filenode(&salted, &key.key).await?, info);
+        assert_eq!(other_salted.get_filenode(&key.key).await, None);
+        assert_eq!(unsalted.get_filenode(&key.key).await, Some(filenode()));
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_cache_stats_accumulator(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let missing_key = filenode_cache_key(REPO_ZERO, &pwh, &TWOS_FNID);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+
+        cache.fill_filenode(&key, filenode());
+        wait_for_filenode(&cache, &key).await?;
+        cache.fill_history(&history_key, FilenodeRange::Filenodes(vec![filenode()]));
+        wait_for_history(&cache, &history_key).await?;
+        let filenode_bytes = cache
+            .memcache
+            .get(cache.keygen.key(&key.key))
+            .await?
+            .unwrap()
+            .len();
+        let history_bytes = cache
+            .memcache
+            .get(cache.keygen.key(&history_key.key))
+            .await?
+            .unwrap()
+            .len();
+
+        // A request doing a few reads, alongside reads that aren't part of it.
+        let stats = CacheStatsAccumulator::new();
+        assert!(cache.get_filenode_with_stats(&key, &stats).await.is_some());
+        assert!(cache.get_filenode_with_stats(&key, &stats).await.is_some());
+        assert!(
+            cache
+                .get_filenode_with_stats(&missing_key, &stats)
+                .await
+                .is_none()
+        );
+        assert!(
+            cache
+                .get_history_with_stats(&history_key, &stats)
+                .await
+                .is_some()
+        );
+        assert!(cache.get_filenode(&key).await.is_some());
+        assert!(cache.get_filenode(&missing_key).await.is_none());
+
+        assert_eq!(
+            stats.stats(),
+            RequestCacheStats {
+                hits: 3,
+                misses: 1,
+                bytes_read: (2 * filenode_bytes + history_bytes) as u64,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_adaptive_ttl(_fb: FacebookInit) -> Result<(), Error> {
+        let hour = Duration::from_secs(60 * 60);
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            adaptive_ttl: Some(AdaptiveTtlOptions {
+                min_ttl: hour,
+                max_ttl: 24 * hour,
+                max_paths: 100,
+            }),
+            ..Default::default()
+        });
+        let root_ttl = |key: &CacheKey<FilenodeRange>| match &cache.memcache {
+            MemcacheHandler::Mock(store) => store.ttl(&cache.keygen.key(&key.key)).unwrap(),
+            _ => unreachable!("not a mock cache"),
+        };
+        let stable = RepoPath::file("stable")?;
+        let churny = RepoPath::file("churny")?;
+        let stable_key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&stable), None);
+        let churny_key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&churny), None);
+
+        cache.fill_history(&stable_key, FilenodeRange::Filenodes(vec![filenode()]));
+        wait_for_history(&cache, &stable_key).await?;
+        assert!(root_ttl(&stable_key) >= 24 * hour);
+
+        let mut history = Vec::new();
+        for info in distinct_filenodes(4) {
+            history.push(info);
+            let history = FilenodeRange::Filenodes(history.clone());
+            cache.fill_history(&churny_key, history.clone());
+            time::timeout(Duration::from_millis(TIMEOUT_MS), async {
+                while cache.get_history(&churny_key).await != Some(history.clone()) {
+                    time::sleep(Duration::from_millis(SLEEP_MS)).await;
+                }
+            })
+            .await?;
+        }
+
+        // Written 4 times, so it lives for a quarter of the maximum TTL.
+        let ttl = root_ttl(&churny_key);
+        assert!(ttl >= 6 * hour);
+        assert!(ttl < 6 * hour + Duration::from_secs(TTL_SEC_RAND));
+        assert!(ttl < root_ttl(&stable_key));
+
+        // Past `max_paths`, the paths written least recently are forgotten.
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            adaptive_ttl: Some(AdaptiveTtlOptions {
+                min_ttl: hour,
+                max_ttl: 24 * hour,
+                max_paths: 1,
+            }),
+            ..Default::default()
+        });
+        assert_eq!(cache.history_ttl(&churny_key), 24 * hour);
+        assert_eq!(cache.history_ttl(&churny_key), 12 * hour);
+        assert_eq!(cache.history_ttl(&stable_key), 24 * hour);
+        assert_eq!(cache.history_ttl(&churny_key), 24 * hour);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_history_with_scratch(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
+        let mut scratch = Vec::new();
+
+        // Missing histories are still misses.
+        let path = RepoPath::file("missing")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        assert_eq!(
+            cache.get_history_with_scratch(&key, &mut scratch).await,
+            None
+        );
+
+        // Inline histories don't need the buffer.
+        let path = RepoPath::file("inline")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        let history = FilenodeRange::Filenodes(vec![filenode()]);
+        cache.fill_history(&key, history.clone());
+        wait_for_history(&cache, &key).await?;
+        let read = cache.get_history_with_scratch(&key, &mut scratch).await;
+        assert_eq!(read, Some(history));
+        assert_eq!(scratch.capacity(), 0);
+
+        // Chunked histories are reassembled in the buffer, whatever it held before.
+        let path = RepoPath::file("chunked")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        let history = FilenodeRange::Filenodes(distinct_filenodes(500));
+        cache.fill_history(&key, history.clone());
+        wait_for_history(&cache, &key).await?;
+        scratch.extend_from_slice(b"leftovers");
+        let read = cache.get_history_with_scratch(&key, &mut scratch).await;
+        assert_eq!(read, cache.get_history(&key).await);
+        assert_eq!(read, Some(history.clone()));
+        let capacity = scratch.capacity();
+        assert!(capacity > 1024);
+
+        // Once it has grown, the buffer is reused rather than reallocated.
+        let ptr = scratch.as_ptr();
+        let read = cache.get_history_with_scratch(&key, &mut scratch).await;
+        assert_eq!(read, Some(history));
+        assert_eq!((scratch.as_ptr(), scratch.capacity()), (ptr, capacity));
+
+        // Streamed histories are decoded chunk by chunk.
+        let path = RepoPath::file("streamed")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        let filenodes = distinct_filenodes(500);
+        cache
+            .fill_history_stream(REPO_ZERO, &path, stream::iter(filenodes.clone()))
+            .await?;
+        let read = cache.get_history_with_scratch(&key, &mut scratch).await;
+        assert_eq!(read, Some(FilenodeRange::Filenodes(filenodes)));
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_refresh_history(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+        let stale = FilenodeRange::Filenodes(vec![filenode()]);
+        cache.fill_history(&key, stale.clone());
+        assert_eq!(wait_for_history(&cache, &key).await?, stale);
+
+        // The backing store is read even though the history is cached, and the fresh history is
+        // cached by the time the refresh returns.
+        let fresh = FilenodeRange::Filenodes(distinct_filenodes(3));
+        let called = Cell::new(false);
+        let refreshed = cache
+            .refresh_history(REPO_ZERO, &path, || async {
+                called.set(true);
+                Ok(fresh.clone())
+            })
+            .await?;
+        assert!(called.get());
+        assert_eq!(refreshed, fresh);
+        assert_eq!(cache.get_history(&key).await, Some(fresh.clone()));
+
+        // Backing store errors are returned, and leave the cache alone.
+        let res = cache
+            .refresh_history(REPO_ZERO, &path, || async {
+                Err(Error::msg("unavailable"))
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(cache.get_history(&key).await, Some(fresh));
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_format_reads(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
+        let (cache, fills) = cache.with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let info = filenode();
+        let filenode_key = filenode_cache_key(REPO_ZERO, &pwh, &info.filenode);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+
+        // Misses aren't reads of any format.
+        assert_eq!(cache.get_filenode(&filenode_key).await, None);
+        assert!(cache.format_reads().is_empty());
+
+        cache.fill_filenode(&filenode_key, info.clone());
+        cache.fill_history(
+            &history_key,
+            FilenodeRange::Filenodes(distinct_filenodes(500)),
+        );
+        fills.wait_for_fills().await;
+
+        for _ in 0..2 {
+            assert_eq!(cache.get_filenode(&filenode_key).await, Some(info.clone()));
+        }
+        assert!(cache.get_history(&history_key).await.is_some());
+        assert_eq!(
+            cache.format_reads(),
+            HashMap::from([(ValueFormat::Compact, 2), (ValueFormat::CompactChunked, 1)])
+        );
+        assert_eq!(ValueFormat::Compact.tag(), "compact.uncompressed");
+        // Reads are counted at the index of their format.
+        for (index, format) in ValueFormat::ALL.into_iter().enumerate() {
+            assert_eq!(format as usize, index);
+        }
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_self_check(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let result = cache.self_check().await;
+        assert!(result.ok(), "{:?}", result);
+        // Nothing is left in flight, and checks can be repeated.
+        assert!(cache.self_check().await.ok());
+
+        // The values are written under keys of this process, and expire quickly.
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store,
+            _ => unreachable!("not a mock cache"),
+        };
+        let filenode_key = self_check_key::<FilenodeInfo>("filenode");
+        assert!(filenode_key.key.starts_with(&*CANARY_PREFIX));
+        assert_eq!(
+            store.ttl(&get_mc_key(&cache.keygen, &filenode_key.key)?),
+            Some(CANARY_TTL)
+        );
+        let history_key = self_check_key::<FilenodeRange>("history");
+        assert_eq!(
+            store.ttl(&get_mc_key(&cache.keygen, &history_key.key)?),
+            Some(CANARY_TTL)
+        );
+
+        // A cache that loses writes fails the check.
+        store.set_drop_sets(true);
+        let result = cache.self_check().await;
+        assert!(!result.filenode_ok);
+        assert!(!result.history_ok);
+        assert!(!result.ok());
+
+        // So does a cache that can't be written to at all.
+        let noop = RemoteCache::new_noop();
+        assert!(!noop.self_check().await.ok());
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_bypass(_fb: FacebookInit) -> Result<(), Error> {
+        let (cache, fills) = RemoteCache::new_mock().with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(vec![filenode()]);
+
+        // Bypassed operations don't touch memcache.
+        cache.fill_filenode_with_bypass(&key, filenode(), true);
+        cache.fill_history_with_bypass(&history_key, history.clone(), true);
+        fills.wait_for_fills().await;
+        assert_eq!(cache.get_filenode_with_bypass(&key, true).await, None);
+        assert_eq!(
+            cache.get_history_with_bypass(&history_key, true).await,
+            None
+        );
+        let stats = mock_stats(&cache);
+        assert_eq!((stats.gets, stats.sets), (0, 0));
+        assert_eq!(cache.fill_stats().attempted, 0);
+
+        // Without bypass, they behave like the plain getters and fillers.
+        cache.fill_filenode_with_bypass(&key, filenode(), false);
+        cache.fill_history_with_bypass(&history_key, history.clone(), false);
+        fills.wait_for_fills().await;
+        assert_eq!(
+            cache.get_filenode_with_bypass(&key, false).await,
+            Some(filenode())
+        );
+        assert_eq!(
+            cache.get_history_with_bypass(&history_key, false).await,
+            Some(history)
+        );
+
+        // Bypassed reads don't see cached values either.
+        let gets = mock_stats(&cache).gets;
+        assert_eq!(cache.get_filenode_with_bypass(&key, true).await, None);
+        assert_eq!(mock_stats(&cache).gets, gets);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_legacy_keygen(_fb: FacebookInit) -> Result<(), Error> {
+        // An old and a new version of the cache sharing a store, with different sitevers.
+        let memcache = MemcacheHandler::create_mock();
+        let old_keygen = KeyGen::new("scm.mononoke.filenodes.newfilenodes.test", 1, 1);
+        let new_keygen = KeyGen::new("scm.mononoke.filenodes.newfilenodes.test", 1, 2);
+        let (old, old_fills) =
+            RemoteCache::from_parts(memcache.clone(), old_keygen.clone(), Default::default())
+                .with_clock(|| 1000)
+                .with_fill_tracker();
+        let new = RemoteCache::from_parts(memcache.clone(), new_keygen.clone(), Default::default());
+        let (migrating, fills) =
+            RemoteCache::from_parts(memcache.clone(), new_keygen.clone(), Default::default())
+                .with_legacy_keygen(old_keygen.clone(), Duration::from_secs(3600))
+                .with_clock(|| 2000)
+                .with_fill_tracker();
+        let expired = RemoteCache::from_parts(memcache.clone(), new_keygen, Default::default())
+            .with_legacy_keygen(old_keygen, Duration::ZERO);
+
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(vec![filenode()]);
+        old.fill_filenode(&key, filenode());
+        old.fill_history(&history_key, history.clone());
+        old_fills.wait_for_fills().await;
+
+        // Entries under the old scheme are unreachable under the new one, unless it migrates
+        // them. Once the migration window has passed, they aren't looked for anymore.
+        assert_eq!(new.get_filenode(&key).await, None);
+        assert_eq!(new.get_history(&history_key).await, None);
+        let store = match &memcache {
+            MemcacheHandler::Mock(store) => store,
+            _ => unreachable!("not a mock cache"),
+        };
+        let gets = store.stats().gets;
+        assert_eq!(expired.get_filenode(&key).await, None);
+        assert_eq!(store.stats().gets, gets + 1);
+        assert_eq!(migrating.get_filenode(&key).await, Some(filenode()));
+        assert_eq!(
+            migrating.get_history(&history_key).await,
+            Some(history.clone())
+        );
+
+        // Once migrated, they are there under the new scheme too, as old as they were.
+        fills.wait_for_fills().await;
+        assert_eq!(new.get_filenode(&key).await, Some(filenode()));
+        assert_eq!(new.get_history(&history_key).await, Some(history));
+        let mc_key = get_mc_key(&new.keygen, &key.key)?;
+        let read =
+            get_single_filenode_from_memcache(&memcache, mc_key, &key, new.read_context(None))
+                .await;
+        assert!(matches!(read, CacheRead::Hit(cached) if cached.written_at == Some(1000)));
+
+        // Missing entries are still misses.
+        let other_key = filenode_cache_key(REPO_ZERO, &pwh, &TWOS_FNID);
+        assert_eq!(migrating.get_filenode(&other_key).await, None);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_histories(_fb: FacebookInit) -> Result<(), Error> {
+        let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        })
+        .with_fill_tracker();
+        let paths = ["a", "b", "c", "inline", "missing"]
+            .into_iter()
+            .map(RepoPath::file)
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = |path| history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(path), None);
+
+        let mut histories = Vec::new();
+        for (i, path) in paths[..3].iter().enumerate() {
+            let history = FilenodeRange::Filenodes(distinct_filenodes(400 + i));
+            cache.fill_history(&key(path), history.clone());
+            histories.push(Some(history));
+        }
+        let inline = FilenodeRange::Filenodes(vec![filenode()]);
+        cache.fill_history(&key(&paths[3]), inline.clone());
+        histories.extend([Some(inline), None]);
+        fills.wait_for_fills().await;
+        for path in &paths[..3] {
+            let (_, layout) = cache.get_history_with_layout(&key(path)).await;
+            assert!(matches!(layout, HistoryLayout::Chunked { chunks } if chunks > 1));
+        }
+
+        assert_eq!(cache.get_histories(REPO_ZERO, &paths).await, histories);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_max_value_bytes(_fb: FacebookInit) -> Result<(), Error> {
+        let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            max_value_bytes: Some(4096),
+            ..Default::default()
+        })
+        .with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let mc_key = cache.keygen.key(&key.key);
+
+        // Garbage within the limit is deserialized, and found to be corrupt, but garbage over
+        // the limit is rejected before that.
+        let garbage = with_kind_tag(FILENODE_TAG, &[0xff; 100]);
+        cache.memcache.set(mc_key.clone(), garbage).await?;
+        assert!(matches!(
+            cache.read_filenode(&key).await,
+            CacheRead::Corrupt
+        ));
+        let garbage = with_kind_tag(FILENODE_TAG, &[0xff; 5000]);
+        cache.memcache.set(mc_key.clone(), garbage).await?;
+        assert!(matches!(cache.read_filenode(&key).await, CacheRead::Miss));
+
+        // The same goes for history roots.
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let root_key = cache.keygen.key(&history_key.key);
+        let garbage = with_kind_tag(HISTORY_TAG, &[0xff; 5000]);
+        cache.memcache.set(root_key, garbage).await?;
+        assert!(matches!(
+            cache.read_history(&history_key).await,
+            CacheRead::Miss
+        ));
+
+        // Chunked histories are checked once reassembled, even if each chunk is small.
+        let small = FilenodeRange::Filenodes(distinct_filenodes(20));
+        let big = FilenodeRange::Filenodes(distinct_filenodes(500));
+        cache.fill_history(&history_key, small.clone());
+        fills.wait_for_fills().await;
+        assert_eq!(cache.get_history(&history_key).await, Some(small));
+        cache.fill_history(&history_key, big);
+        fills.wait_for_fills().await;
+        let (_, layout) = cache.get_history_with_layout(&history_key).await;
+        assert_eq!(layout, HistoryLayout::Missed);
+        assert!(get_root_chunks(&cache, &history_key).await?.pointers.len() > 4);
+        assert_eq!(cache.get_histories(REPO_ZERO, &[path]).await, vec![None]);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_warm(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = Arc::new(RemoteCache::new_mock());
+        let paths = ["a", "b", "c", "d"]
+            .into_iter()
+            .map(RepoPath::file)
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = |path| history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(path), None);
+        let history = |i| FilenodeRange::Filenodes(distinct_filenodes(i + 1));
+        let rate = NonZeroU32::new(1000).unwrap();
+
+        let entries = paths[..3].iter().enumerate().map(|(i, path)| {
+            let fetch = move || async move { Ok(history(i)) };
+            (REPO_ZERO, path.clone(), fetch)
+        });
+        let warmer = cache.warm(stream::iter(entries.collect::<Vec<_>>()), rate);
+        assert_eq!(warmer.wait().await, 3);
+        for (i, path) in paths[..3].iter().enumerate() {
+            assert_eq!(cache.get_history(&key(path)).await, Some(history(i)));
+        }
+
+        // The warmer stops at the entry it's on when it's cancelled, and doesn't go on with the
+        // next ones.
+        let cache = Arc::new(RemoteCache::new_mock());
+        let fetched = Arc::new(Mutex::new(Vec::new()));
+        let entries = paths.iter().enumerate().map(|(i, path)| {
+            let fetched = fetched.clone();
+            let fetch = move || async move {
+                fetched.lock().unwrap().push(i);
+                if i == 2 {
+                    futures::future::pending::<()>().await;
                 }
+                Ok(history(i))
+            };
+            (REPO_ZERO, path.clone(), fetch)
+        });
+        let warmer = cache.warm(stream::iter(entries.collect::<Vec<_>>()), rate);
+        time::timeout(Duration::from_millis(TIMEOUT_MS), async {
+            while fetched.lock().unwrap().len() < 3 {
                 time::sleep(Duration::from_millis(SLEEP_MS)).await;
             }
         })
         .await?;
+        warmer.cancel();
+        assert_eq!(warmer.wait().await, 2);
+        assert_eq!(*fetched.lock().unwrap(), vec![0, 1, 2]);
+        assert_eq!(cache.get_history(&key(&paths[1])).await, Some(history(1)));
+        assert_eq!(cache.get_history(&key(&paths[2])).await, None);
+        assert_eq!(cache.get_history(&key(&paths[3])).await, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_namespace() {
+        let default = RemoteCache::new_mock().key_namespace();
+        assert!(default.starts_with("scm.mononoke.filenodes.newfilenodes.test."));
+        assert!(!default.ends_with('.'));
+
+        let sitever = MC_SITEVER as u32 + 100;
+        let cache = RemoteCache::new_with_sitever(
+            &CacheHandlerFactory::Mocked,
+            "newfilenodes",
+            "test",
+            Default::default(),
+            sitever,
+        );
+        let namespace = cache.key_namespace();
+        assert_ne!(namespace, default);
+        assert!(namespace.ends_with(&format!(".{}.{}", MC_CODEVER, sitever)));
+        assert!(cache.keygen.key("key").starts_with(&namespace));
+    }
+
+    #[fbinit::test]
+    async fn test_chunk_compression(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            chunk_compression_level: Some(3),
+            ..Default::default()
+        });
+        let (cache, fills) = cache.with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+        let history = FilenodeRange::Filenodes(distinct_filenodes(100));
+        cache.fill_history(&key, history.clone());
+        fills.wait_for_fills().await;
+        assert_eq!(cache.get_history(&key).await, Some(history));
+
+        // Chunks written by fill_history_stream are each a part of the history, and can be
+        // decompressed and deserialized without the others.
+        let filenodes = distinct_filenodes(100);
+        cache
+            .fill_history_stream(REPO_ZERO, &path, stream::iter(filenodes.clone()))
+            .await?;
+        let chunks = get_root_chunks(&cache, &key).await?;
+        assert_eq!(chunks.compressed, Some(true));
+        assert!(chunks.pointers.len() > 1);
+
+        let chunk_key =
+            get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, chunks.pointers[0])?;
+        let chunk = cache.memcache.get(chunk_key).await?;
+        let data = check_chunk(
+            chunk.as_deref(),
+            chunks.pointers[0],
+            Some(chunks.generation),
+            true,
+        );
+        let part = match deserialize_history_blob(&data.unwrap(), false) {
+            Some(FilenodeRange::Filenodes(part)) => part,
+            other => panic!("unexpected first chunk: {:?}", other),
+        };
+        assert!(!part.is_empty() && part.len() < filenodes.len());
+        assert_eq!(part[..], filenodes[..part.len()]);
+
+        assert_eq!(
+            cache.get_history(&key).await,
+            Some(FilenodeRange::Filenodes(filenodes))
+        );
+        assert_eq!(
+            cache.format_reads(),
+            HashMap::from([
+                (ValueFormat::CompactChunkedZstd, 1),
+                (ValueFormat::CompactSegmentedZstd, 1)
+            ])
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_chunk_compression_skipped(_fb: FacebookInit) -> Result<(), Error> {
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        let history = FilenodeRange::Filenodes(distinct_filenodes(100));
+
+        // Chunks this small only grow when compressed, while bigger ones of the same history
+        // shrink. Either way, chunks are no bigger than the chunk size.
+        for (chunk_size, compressed) in [(32, false), (1024, true)] {
+            let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+                chunk_size: Some(chunk_size),
+                chunk_compression_level: Some(3),
+                ..Default::default()
+            })
+            .with_fill_tracker();
+            cache.fill_history(&key, history.clone());
+            fills.wait_for_fills().await;
+
+            let chunks = get_root_chunks(&cache, &key).await?;
+            for pointer in &chunks.pointers {
+                let chunk_key = get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, *pointer)?;
+                let chunk = cache.memcache.get(chunk_key).await?.unwrap();
+                assert!(chunk.len() <= chunk_size);
+                let chunk: thrift::FilenodeInfoChunk = compact_protocol::deserialize(&chunk)?;
+                assert_eq!(chunk.compressed, Some(compressed));
+            }
+            assert_eq!(cache.get_history(&key).await, Some(history.clone()));
+        }
+
+        assert_eq!(compress_chunk(vec![0; 100], None, 0)?, (vec![0; 100], None));
+        let incompressible = (0..100).map(|_| random::<u8>()).collect::<Vec<_>>();
+        assert_eq!(
+            compress_chunk(incompressible.clone(), Some(3), 0)?,
+            (incompressible, Some(false))
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_fill_verify(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            fill_verify_sample_rate: 1,
+            ..Default::default()
+        });
+        let (cache, fills) = cache.with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(vec![filenode()]);
+
+        // Writes that land are read back and found.
+        cache.fill_filenode(&key, filenode());
+        cache.fill_history(&history_key, history.clone());
+        fills.wait_for_fills().await;
+        let stats = mock_stats(&cache);
+        assert_eq!((stats.gets, stats.hits), (2, 2));
+
+        // Writes that the pool drops are read back and missed.
+        match &cache.memcache {
+            MemcacheHandler::Mock(store) => store.set_drop_sets(true),
+            _ => unreachable!("not a mock cache"),
+        }
+        let other_key = filenode_cache_key(REPO_ZERO, &pwh, &TWOS_FNID);
+        let other_history_key = history_cache_key(REPO_ZERO, &pwh, Some(1));
+        cache.fill_filenode(&other_key, filenode());
+        cache.fill_history(&other_history_key, history);
+        fills.wait_for_fills().await;
+        let stats = mock_stats(&cache);
+        assert_eq!((stats.gets, stats.misses), (4, 2));
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_ttl_jitter(_fb: FacebookInit) -> Result<(), Error> {
+        let jitter = Duration::from_secs(123);
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        })
+        .with_ttl_jitter(move || jitter);
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store.clone(),
+            _ => unreachable!("not a mock cache"),
+        };
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let ttl = Duration::from_secs(TTL_SEC);
+
+        for (limit, filenodes) in [(None, 1), (Some(1000), 100)] {
+            let key = history_cache_key(REPO_ZERO, &pwh, limit);
+            cache.fill_history(
+                &key,
+                FilenodeRange::Filenodes(distinct_filenodes(filenodes)),
+            );
+            wait_for_history(&cache, &key).await?;
+            assert_eq!(store.ttl(&cache.keygen.key(&key.key)), Some(ttl + jitter));
+        }
+
+        // Chunks outlive the root regardless of the jitter.
+        let key = history_cache_key(REPO_ZERO, &pwh, Some(1000));
+        let chunks = get_root_chunks(&cache, &key).await?;
+        let chunk_key =
+            get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, chunks.pointers[0])?;
+        assert_eq!(
+            store.ttl(&chunk_key),
+            Some(ttl + Duration::from_secs(TTL_SEC_RAND))
+        );
+
+        Ok(())
+    }
+
+    // No FacebookInit, unlike the other tests.
+    #[tokio::test]
+    async fn test_with_handler_and_options() -> Result<(), Error> {
+        let cache = RemoteCache::with_handler_and_options(
+            MemcacheHandler::create_mock(),
+            KeyGen::new("standalone", 0, 0),
+            RemoteCacheOptions {
+                chunk_size: Some(1024),
+                ..Default::default()
+            },
+        );
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        cache.fill_filenode(&key, filenode());
+        assert_eq!(wait_for_filenode(&cache, &key).await?, filenode());
+
+        let key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(distinct_filenodes(100));
+        cache.fill_history(&key, history.clone());
+        assert_eq!(wait_for_history(&cache, &key).await?, history);
+        assert!(get_root_chunks(&cache, &key).await?.pointers.len() > 1);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_ttl_jitter_spread(_fb: FacebookInit) -> Result<(), Error> {
+        const FILLS: u64 = 1000;
+        const BUCKETS: u64 = 10;
+
+        let rng = Mutex::new(SmallRng::seed_from_u64(0));
+        let (cache, fills) = RemoteCache::new_mock()
+            .with_ttl_jitter(move || ttl_jitter_from(&mut *rng.lock().unwrap()))
+            .with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+
+        let keys = (0..FILLS)
+            .map(|limit| history_cache_key(REPO_ZERO, &pwh, Some(limit)))
+            .collect::<Vec<_>>();
+        for key in &keys {
+            cache.fill_history(key, FilenodeRange::Filenodes(vec![filenode()]));
+        }
+        fills.wait_for_fills().await;
+
+        // Expirations are spread over the whole jitter range, rather than clustered.
+        let mut buckets = [0; BUCKETS as usize];
+        for key in &keys {
+            let jitter = cache.applied_ttl(key).unwrap() - Duration::from_secs(TTL_SEC);
+            assert!(jitter < Duration::from_secs(TTL_SEC_RAND));
+            buckets[(jitter.as_secs() * BUCKETS / TTL_SEC_RAND) as usize] += 1;
+        }
+        for count in buckets {
+            assert!(count > FILLS / BUCKETS / 2, "{:?}", buckets);
+        }
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_fill_rate(_fb: FacebookInit) -> Result<(), Error> {
+        let now = Arc::new(AtomicI64::new(1_000_000));
+        let cache = RemoteCache::new_mock().with_clock({
+            let now = now.clone();
+            move || now.load(Ordering::Relaxed)
+        });
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        assert_eq!(cache.fill_rate_per_sec(), 0.0);
+
+        // A burst of fills, within a single second.
+        for info in distinct_filenodes(120) {
+            let key = filenode_cache_key(REPO_ZERO, &pwh, &info.filenode);
+            cache.fill_filenode(&key, info);
+        }
+        assert_eq!(cache.fill_rate_per_sec(), 2.0);
+
+        // Steady state: one fill per second.
+        for info in distinct_filenodes(30) {
+            now.fetch_add(1, Ordering::Relaxed);
+            let key = filenode_cache_key(REPO_ONE, &pwh, &info.filenode);
+            cache.fill_filenode(&key, info);
+        }
+        assert_eq!(cache.fill_rate_per_sec(), 2.5);
+
+        // The burst leaves the window, then the steady fills do.
+        now.fetch_add(30, Ordering::Relaxed);
+        assert_eq!(cache.fill_rate_per_sec(), 0.5);
+        now.fetch_add(3600, Ordering::Relaxed);
+        assert_eq!(cache.fill_rate_per_sec(), 0.0);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_filenode_validated(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let info = FilenodeInfo {
+            p1: Some(TWOS_FNID),
+            ..filenode()
+        };
+        cache.fill_filenode(&key, info.clone());
+        wait_for_filenode(&cache, &key).await?;
+
+        let get = |expected_parents| {
+            cache.get_filenode_validated(REPO_ZERO, &path, ONES_FNID, expected_parents)
+        };
+        assert_eq!(get((Some(TWOS_FNID), None)).await, Some(info));
+
+        // A cached filenode with other parents is rejected.
+        assert_eq!(get((None, None)).await, None);
+        assert_eq!(get((Some(TWOS_FNID), Some(ONES_FNID))).await, None);
+
+        // As is one that isn't cached.
+        let missing = cache.get_filenode_validated(REPO_ONE, &path, ONES_FNID, (None, None));
+        assert_eq!(missing.await, None);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_filenode_if(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let path = RepoPath::file("copiedto")?;
+        let key = filenode_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+        cache.fill_filenode(&key, filenode());
+        wait_for_filenode(&cache, &key).await?;
+
+        let get = |bogus_linknode| {
+            cache.get_filenode_if(REPO_ZERO, &path, ONES_FNID, move |info| {
+                info.linknode != bogus_linknode
+            })
+        };
+        assert_eq!(get(TWOS_CSID).await, Some(filenode()));
+        assert_eq!(get(ONES_CSID).await, None);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_kill_switch(_fb: FacebookInit) -> Result<(), Error> {
+        // Not the process-wide switch, which would kill the caches of the other tests.
+        static KILLED: AtomicBool = AtomicBool::new(false);
+        let (cache, fills) = RemoteCache::new_mock()
+            .with_kill_switch(&KILLED)
+            .with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(vec![filenode()]);
+
+        cache.fill_filenode(&key, filenode());
+        fills.wait_for_fills().await;
+
+        KILLED.store(true, Ordering::Relaxed);
+        let stats = mock_stats(&cache);
+        assert_eq!(cache.get_filenode(&key).await, None);
+        cache.fill_history(&history_key, history.clone());
+        cache
+            .refresh_history(REPO_ZERO, &path, || async { Ok(history.clone()) })
+            .await?;
+        fills.wait_for_fills().await;
+        assert_eq!(mock_stats(&cache), stats);
+
+        // Nothing was lost while the cache was killed.
+        KILLED.store(false, Ordering::Relaxed);
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+        assert_eq!(cache.get_history(&history_key).await, None);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_key_bytes(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store.clone(),
+            _ => unreachable!("not a mock cache"),
+        };
+        let path = RepoPath::file("dir/subdir/file")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        cache.fill_history(&key, FilenodeRange::Filenodes(distinct_filenodes(100)));
+        wait_for_history(&cache, &key).await?;
+
+        // The chunk keys whose sizes were recorded: the root key, a separator and the pointer,
+        // well within what memcache accepts.
+        let root_key = cache.keygen.key(&key.key);
+        for pointer in get_root_chunks(&cache, &key).await?.pointers {
+            let chunk_key = get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, pointer)?;
+            assert!(store.ttl(&chunk_key).is_some());
+            assert_eq!(
+                chunk_key.len(),
+                root_key.len() + 1 + pointer.to_string().len()
+            );
+            assert!(chunk_key.len() < MEMCACHE_KEY_MAX_LEN);
+        }
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_try_get(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock();
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store.clone(),
+            _ => unreachable!("not a mock cache"),
+        };
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let history = FilenodeRange::Filenodes(vec![filenode()]);
+
+        assert_eq!(cache.try_get_filenode(&key).await, Ok(None));
+        assert_eq!(cache.try_get_history(&history_key).await, Ok(None));
+
+        cache.fill_filenode(&key, filenode());
+        cache.fill_history(&history_key, history.clone());
+        wait_for_filenode(&cache, &key).await?;
+        wait_for_history(&cache, &history_key).await?;
+        assert_eq!(cache.try_get_filenode(&key).await, Ok(Some(filenode())));
+        assert_eq!(cache.try_get_history(&history_key).await, Ok(Some(history)));
+
+        // Failures are errors, rather than misses as for the lenient reads.
+        store.fail_next_gets(1);
+        assert_eq!(
+            cache.try_get_filenode(&key).await,
+            Err(CacheError::Internal)
+        );
+        store.fail_next_gets(1);
+        assert_eq!(
+            cache.try_get_history(&history_key).await,
+            Err(CacheError::Internal)
+        );
+        store.fail_next_gets(1);
+        assert_eq!(cache.get_filenode(&key).await, None);
+
+        cache
+            .memcache
+            .set(cache.keygen.key(&key.key), Bytes::from("garbage"))
+            .await?;
+        assert_eq!(
+            cache.try_get_filenode(&key).await,
+            Err(CacheError::Deserialize)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stat_sampling() {
+        assert_eq!(sample_weight(0), Some(1));
+        assert_eq!(sample_weight(1), Some(1));
+
+        // Sampled counts add up to about the number of calls.
+        let calls = 100_000;
+        let total: i64 = (0..calls).filter_map(|_| sample_weight(10)).sum();
+        assert!(
+            (total - calls).abs() < calls / 20,
+            "{} for {} calls",
+            total,
+            calls
+        );
+    }
+
+    #[fbinit::test]
+    async fn test_explain_get_history(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+        let explain = cache.explain_get_history(REPO_ZERO, &path).await;
+        assert_eq!(explain.key, key.key);
+        assert_eq!(explain.root_bytes, None);
+        assert_eq!(explain.layout, HistoryLayout::Missed);
+        assert_eq!(explain.outcome, CacheRead::Miss);
+
+        let history = FilenodeRange::Filenodes(distinct_filenodes(100));
+        cache.fill_history(&key, history.clone());
+        wait_for_history(&cache, &key).await?;
+        let pointers = get_root_chunks(&cache, &key).await?.pointers;
+        assert!(pointers.len() > 1);
+
+        let explain = cache.explain_get_history(REPO_ZERO, &path).await;
+        assert!(explain.root_bytes.is_some());
+        assert_eq!(
+            explain.layout,
+            HistoryLayout::Chunked {
+                chunks: pointers.len()
+            }
+        );
+        assert_eq!(
+            explain
+                .chunks
+                .iter()
+                .map(|chunk| chunk.pointer)
+                .collect::<Vec<_>>(),
+            pointers
+        );
+        assert!(explain.chunks.iter().all(|chunk| chunk.bytes.is_some()));
+        assert!(explain.errors.is_empty());
+        assert_eq!(explain.outcome, CacheRead::Hit(history));
+
+        // Evict the second chunk. The others are still fetched.
+        let missing = pointers[1];
+        match &cache.memcache {
+            MemcacheHandler::Mock(store) => {
+                store.del(&cache.keygen.key(format!("{}.{}", key.key, missing)))
+            }
+            _ => unreachable!("not a mock cache"),
+        }
+        let explain = cache.explain_get_history(REPO_ZERO, &path).await;
+        assert_eq!(explain.chunks.len(), pointers.len());
+        assert_eq!(
+            explain.chunks[1],
+            ChunkExplain {
+                pointer: missing,
+                bytes: None,
+            }
+        );
+        assert!(explain.chunks[0].bytes.is_some());
+        assert_eq!(
+            explain.errors,
+            vec![format!("Chunk {} is missing", missing)]
+        );
+        assert_eq!(explain.outcome, CacheRead::Miss);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_hot_paths(_fb: FacebookInit) -> Result<(), Error> {
+        assert_eq!(RemoteCache::new_mock().hot_paths(10), vec![]);
+
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            hot_paths_capacity: 2,
+            ..Default::default()
+        });
+        let mut keys = Vec::new();
+        for (name, reads) in [("hot", 50), ("warm", 10), ("cold", 1)] {
+            let path = RepoPath::file(name)?;
+            let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+            keys.push(key.clone());
+            for _ in 0..reads {
+                cache.get_history(&key).await;
+            }
+        }
+        let hot_paths = cache.hot_paths(10);
+        assert_eq!(hot_paths.len(), 2);
+        assert_eq!(hot_paths[0], (keys[0].path_hash().unwrap().to_string(), 50));
+        assert_eq!(hot_paths[1].0, keys[1].path_hash().unwrap());
+        assert_eq!(cache.hot_paths(1), hot_paths[..1]);
+
+        // A path read more than the least read hot path takes its place.
+        for _ in 0..20 {
+            cache.get_history(&keys[2]).await;
+        }
+        let hot_paths = cache.hot_paths(10);
+        assert_eq!(hot_paths.len(), 2);
+        assert_eq!(hot_paths[0].0, keys[0].path_hash().unwrap());
+        assert_eq!(hot_paths[1], (keys[2].path_hash().unwrap().to_string(), 21));
+
+        // Reads that aren't sampled for stats aren't counted either, while sampled ones count as
+        // many reads as they stand for.
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            hot_paths_capacity: 2,
+            stat_sample_rate: u32::MAX,
+            ..Default::default()
+        });
+        for _ in 0..10 {
+            cache.get_history(&keys[0]).await;
+        }
+        assert_eq!(cache.hot_paths(10), vec![]);
+        cache
+            .read_context(None)
+            .record_access(&keys[0], i64::from(u32::MAX));
+        assert_eq!(cache.hot_paths(10)[0].1, u64::from(u32::MAX));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_hash_of_key() -> Result<(), Error> {
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let history_key = history_cache_key(REPO_ZERO, &pwh, None);
+        let path_hash = history_key.path_hash().unwrap();
+        assert!(path_hash.starts_with('n'));
+        assert!(history_key.key.ends_with(&format!(".{}.0", path_hash)));
+        assert_eq!(
+            history_cache_key(REPO_ZERO, &pwh, Some(1)).path_hash(),
+            Some(path_hash)
+        );
+        assert_eq!(
+            filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID).path_hash(),
+            Some(path_hash)
+        );
+        assert_eq!(self_check_key::<FilenodeInfo>("filenode").path_hash(), None);
+        Ok(())
+    }
 
-        Ok(r)
+    #[fbinit::test]
+    async fn test_fill_spawn_lag(_fb: FacebookInit) -> Result<(), Error> {
+        let (cache, fills) = RemoteCache::new_mock().with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let key = filenode_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+
+        // Keep the runtime busy, so that the fill can't start right away.
+        let busy = (0..4)
+            .map(|_| tokio::spawn(async { std::thread::sleep(Duration::from_millis(20)) }))
+            .collect::<Vec<_>>();
+        cache.fill_filenode(&key, filenode());
+        fills.wait_for_fills().await;
+        join_all(busy).await;
+
+        let stats = cache.fill_stats();
+        assert_eq!(stats.started, 1);
+        assert!(stats.spawn_lag > Duration::ZERO);
+
+        Ok(())
     }
 
-    pub async fn wait_for_history(
-        cache: &RemoteCache,
-        key: &CacheKey<FilenodeRange>,
-    ) -> Result<FilenodeRange, Error> {
-        let r = time::timeout(Duration::from_millis(TIMEOUT_MS), async {
-            loop {
-                match cache.get_history(key).await {
-                    Some(f) => {
-                        break f;
-                    }
-                    None => {}
-                }
-                time::sleep(Duration::from_millis(SLEEP_MS)).await;
-            }
+    #[fbinit::test]
+    async fn test_max_concurrent_fills(_fb: FacebookInit) -> Result<(), Error> {
+        let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            max_concurrent_fills: Some(2),
+            ..Default::default()
         })
-        .await?;
+        .with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let keys = [ONES_FNID, TWOS_FNID, THREES_FNID]
+            .iter()
+            .map(|filenode_id| filenode_cache_key(REPO_ZERO, &pwh, filenode_id))
+            .collect::<Vec<_>>();
 
-        Ok(r)
+        // The first two fills hold the permits until they run, so the third is dropped.
+        for key in &keys {
+            cache.fill_filenode(key, filenode());
+        }
+        fills.wait_for_fills().await;
+        assert_eq!(cache.get_filenode(&keys[0]).await, Some(filenode()));
+        assert_eq!(cache.get_filenode(&keys[1]).await, Some(filenode()));
+        assert_eq!(cache.get_filenode(&keys[2]).await, None);
+        assert_eq!(cache.fill_stats().written, 2);
+
+        // Permits are released once the fills are done.
+        cache.fill_filenode(&keys[2], filenode());
+        fills.wait_for_fills().await;
+        assert_eq!(cache.get_filenode(&keys[2]).await, Some(filenode()));
+
+        Ok(())
     }
 
     #[fbinit::test]
-    async fn test_store_filenode(_fb: FacebookInit) -> Result<(), Error> {
+    async fn test_fill_handle(_fb: FacebookInit) -> Result<(), Error> {
         let cache = RemoteCache::new_mock();
         let path = RepoPath::file("copiedto")?;
-        let info = filenode();
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
 
-        let key = filenode_cache_key(
-            REPO_ZERO,
-            &PathWithHash::from_repo_path(&path),
-            &info.filenode,
+        assert!(
+            cache
+                .fill_filenode_with_handle(&key, filenode())
+                .wait()
+                .await
         );
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
 
-        cache.fill_filenode(&key, info.clone());
-        let from_cache = wait_for_filenode(&cache, &key).await?;
+        // Nothing is written by a read-only cache.
+        cache.set_read_only(true);
+        let other_key = filenode_cache_key(REPO_ZERO, &pwh, &TWOS_FNID);
+        assert!(
+            !cache
+                .fill_filenode_with_handle(&other_key, filenode())
+                .wait()
+                .await
+        );
+
+        Ok(())
+    }
 
-        assert_eq!(from_cache, info);
+    #[fbinit::test]
+    async fn test_repo_disabled(_fb: FacebookInit) -> Result<(), Error> {
+        let (cache, fills) = RemoteCache::new_mock().with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+        let other_key = filenode_cache_key(REPO_ONE, &pwh, &ONES_FNID);
+        let history = FilenodeRange::Filenodes(vec![filenode()]);
+
+        cache.fill_filenode(&key, filenode());
+        fills.wait_for_fills().await;
+
+        cache.set_repo_disabled(REPO_ZERO, true);
+        let stats = mock_stats(&cache);
+        assert_eq!(cache.get_filenode(&key).await, None);
+        // Skipped reads aren't misses.
+        let request_stats = CacheStatsAccumulator::new();
+        assert_eq!(
+            cache.get_filenode_with_stats(&key, &request_stats).await,
+            None
+        );
+        assert_eq!(request_stats.stats(), RequestCacheStats::default());
+        cache.fill_history(&history_cache_key(REPO_ZERO, &pwh, None), history.clone());
+        fills.wait_for_fills().await;
+        assert_eq!(mock_stats(&cache), stats);
+
+        // Other repos are still cached.
+        let other_history_key = history_cache_key(REPO_ONE, &pwh, None);
+        cache.fill_filenode(&other_key, filenode());
+        cache.fill_history(&other_history_key, history.clone());
+        fills.wait_for_fills().await;
+        assert_eq!(cache.get_filenode(&other_key).await, Some(filenode()));
+        assert_eq!(cache.get_history(&other_history_key).await, Some(history));
+
+        // Nothing was lost while the repo was disabled.
+        cache.set_repo_disabled(REPO_ZERO, false);
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+        assert_eq!(
+            cache
+                .get_history(&history_cache_key(REPO_ZERO, &pwh, None))
+                .await,
+            None
+        );
 
         Ok(())
     }
 
     #[fbinit::test]
-    async fn test_store_short_history(_fb: FacebookInit) -> Result<(), Error> {
-        let cache = RemoteCache::new_mock();
+    async fn test_history_contains_linknode(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            ..Default::default()
+        });
         let path = RepoPath::file("copiedto")?;
-        let info = filenode();
-        let history = FilenodeRange::Filenodes(vec![info.clone(), info.clone(), info.clone()]);
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+
+        assert_eq!(
+            cache
+                .history_contains_linknode(REPO_ZERO, &path, ONES_CSID)
+                .await,
+            None
+        );
+
+        let history = FilenodeRange::Filenodes(distinct_filenodes(100));
+        cache.fill_history(&key, history);
+        wait_for_history(&cache, &key).await?;
+        let chunks = get_root_chunks(&cache, &key).await?;
+        assert!(chunks.pointers.len() > 1);
+        let filter_pointer = chunks.linknode_filter_pointer.unwrap();
+        assert!(!chunks.pointers.contains(&filter_pointer));
 
+        // Only the root and the filter are read for a linknode that isn't in the history.
+        let gets = mock_stats(&cache).gets;
+        assert_eq!(
+            cache
+                .history_contains_linknode(REPO_ZERO, &path, TWOS_CSID)
+                .await,
+            Some(false)
+        );
+        assert_eq!(mock_stats(&cache).gets, gets + 2);
+
+        // A linknode in the history is confirmed from the chunks, without reading the root again.
+        let gets = mock_stats(&cache).gets;
+        assert_eq!(
+            cache
+                .history_contains_linknode(REPO_ZERO, &path, ONES_CSID)
+                .await,
+            Some(true)
+        );
+        assert_eq!(mock_stats(&cache).gets, gets + 2 + chunks.pointers.len());
+
+        // Without its filter, the chunks are read.
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store,
+            _ => unreachable!("not a mock cache"),
+        };
+        let filter_key = get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, filter_pointer)?;
+        store.del(&filter_key);
+        let gets = mock_stats(&cache).gets;
+        assert_eq!(
+            cache
+                .history_contains_linknode(REPO_ZERO, &path, TWOS_CSID)
+                .await,
+            Some(false)
+        );
+        assert_eq!(mock_stats(&cache).gets, gets + 2 + chunks.pointers.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_linknode_filter() {
+        let list = thrift::FilenodeInfoList::Data(
+            distinct_filenodes(1000)
+                .into_iter()
+                .enumerate()
+                .map(|(i, filenode)| {
+                    let mut hash = [1; 20];
+                    hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+                    FilenodeInfo {
+                        linknode: HgChangesetId::new(HgNodeHash::from_bytes(&hash).unwrap()),
+                        ..filenode
+                    }
+                    .into_thrift()
+                })
+                .collect(),
+        );
+        let filter = build_linknode_filter(&list).unwrap();
+
+        let linknode = |i: u64, fill| {
+            let mut hash = [fill; 20];
+            hash[..8].copy_from_slice(&i.to_be_bytes());
+            HgChangesetId::new(HgNodeHash::from_bytes(&hash).unwrap())
+        };
+        assert!((0..1000).all(|i| linknode_filter_contains(&filter, &linknode(i, 1))));
+        let false_positives = (0..1000)
+            .filter(|i| linknode_filter_contains(&filter, &linknode(*i, 2)))
+            .count();
+        assert!(false_positives < 50, "{} false positives", false_positives);
+        assert!(linknode_filter_contains(&[], &linknode(0, 2)));
+
+        assert_eq!(
+            build_linknode_filter(&thrift::FilenodeInfoList::TooBig(0)),
+            None
+        );
+    }
+
+    #[fbinit::test]
+    async fn test_rechunk_on_read(_fb: FacebookInit) -> Result<(), Error> {
+        let path = RepoPath::file("copiedto")?;
         let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        let history = FilenodeRange::Filenodes(distinct_filenodes(100));
+        let memcache = MemcacheHandler::create_mock();
+        let cache = |chunk_size| {
+            RemoteCache::with_handler_and_options(
+                memcache.clone(),
+                KeyGen::new("rechunk", 0, 0),
+                RemoteCacheOptions {
+                    chunk_size: Some(chunk_size),
+                    rechunk_on_read: true,
+                    ..Default::default()
+                },
+            )
+            .with_fill_tracker()
+        };
+
+        let (old_cache, fills) = cache(2048);
+        old_cache.fill_history(&key, history.clone());
+        fills.wait_for_fills().await;
+        let old_chunks = get_root_chunks(&old_cache, &key).await?;
+        assert_eq!(old_chunks.chunk_size, Some(2048));
 
+        // Reading with the current size doesn't write anything.
+        let sets = mock_stats(&old_cache).sets;
+        assert_eq!(old_cache.get_history(&key).await, Some(history.clone()));
+        fills.wait_for_fills().await;
+        assert_eq!(mock_stats(&old_cache).sets, sets);
+
+        // Once the size is lowered, the history is split again when read.
+        let (new_cache, fills) = cache(1024);
+        assert_eq!(new_cache.get_history(&key).await, Some(history.clone()));
+        fills.wait_for_fills().await;
+        let new_chunks = get_root_chunks(&new_cache, &key).await?;
+        assert_eq!(new_chunks.chunk_size, Some(1024));
+        assert!(new_chunks.pointers.len() > old_chunks.pointers.len());
+        assert_eq!(new_cache.get_history(&key).await, Some(history.clone()));
+
+        // A history already being split again isn't split again by other reads.
+        let (old_cache, fills) = cache(2048);
+        old_cache.fill_history(&key, history.clone());
+        fills.wait_for_fills().await;
+        let (new_cache, fills) = cache(1024);
+        let refill = PendingRefill::start(&new_cache.history_refills, &key.key).unwrap();
+        let sets = mock_stats(&new_cache).sets;
+        assert_eq!(new_cache.get_history(&key).await, Some(history.clone()));
+        fills.wait_for_fills().await;
+        assert_eq!(mock_stats(&new_cache).sets, sets);
+        drop(refill);
+        assert_eq!(new_cache.get_history(&key).await, Some(history.clone()));
+        fills.wait_for_fills().await;
+        assert_eq!(
+            get_root_chunks(&new_cache, &key).await?.chunk_size,
+            Some(1024)
+        );
+        assert!(new_cache.history_refills.lock().unwrap().is_empty());
+
+        // Compressed chunks are sized like uncompressed ones, so reading them doesn't split them
+        // again.
+        let (cache, fills) = RemoteCache::with_handler_and_options(
+            MemcacheHandler::create_mock(),
+            KeyGen::new("rechunk", 0, 0),
+            RemoteCacheOptions {
+                chunk_size: Some(4096),
+                preferred_chunk_size: Some(1024),
+                max_history_pointers: Some(4),
+                chunk_compression_level: Some(1),
+                rechunk_on_read: true,
+                ..Default::default()
+            },
+        )
+        .with_fill_tracker();
         cache.fill_history(&key, history.clone());
-        let from_cache = wait_for_history(&cache, &key).await?;
+        fills.wait_for_fills().await;
+        let chunks = get_root_chunks(&cache, &key).await?;
+        assert!(chunks.chunk_size > Some(1024));
+        let sets = mock_stats(&cache).sets;
+        assert_eq!(cache.get_history(&key).await, Some(history));
+        fills.wait_for_fills().await;
+        assert_eq!(mock_stats(&cache).sets, sets);
 
-        assert_eq!(from_cache, history);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_get_filenode_since(_fb: FacebookInit) -> Result<(), Error> {
+        let (cache, fills) = RemoteCache::new_mock().with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let key = filenode_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+
+        // A filenode written before an epoch is set records none, so it is older than all of them.
+        cache.fill_filenode(&key, filenode());
+        fills.wait_for_fills().await;
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+        assert_eq!(
+            cache
+                .get_filenode_since(REPO_ZERO, &path, ONES_FNID, i64::MIN)
+                .await,
+            None
+        );
+
+        // A value written before epochs were recorded is older than all of them.
+        let serialized = compact_protocol::serialize(&thrift::CachedFilenodeInfo {
+            repo_id: REPO_ZERO.id(),
+            info: filenode().into_thrift(),
+            written_at: None,
+            soft_ttl_secs: None,
+            epoch: None,
+        });
+        cache
+            .memcache
+            .set(
+                cache.keygen.key(&key.key),
+                with_kind_tag(FILENODE_TAG, &serialized),
+            )
+            .await?;
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+        assert_eq!(
+            cache
+                .get_filenode_since(REPO_ZERO, &path, ONES_FNID, 0)
+                .await,
+            None
+        );
+
+        cache.set_write_epoch(1);
+        cache.fill_filenode(&key, filenode());
+        fills.wait_for_fills().await;
+
+        // Advancing the epoch invalidates the filenode for readers of the new one.
+        cache.set_write_epoch(2);
+        assert_eq!(
+            cache
+                .get_filenode_since(REPO_ZERO, &path, ONES_FNID, 1)
+                .await,
+            Some(filenode())
+        );
+        assert_eq!(
+            cache
+                .get_filenode_since(REPO_ZERO, &path, ONES_FNID, 2)
+                .await,
+            None
+        );
+
+        cache.fill_filenode(&key, filenode());
+        fills.wait_for_fills().await;
+        assert_eq!(
+            cache
+                .get_filenode_since(REPO_ZERO, &path, ONES_FNID, 2)
+                .await,
+            Some(filenode())
+        );
 
         Ok(())
     }
 
     #[fbinit::test]
-    async fn test_store_long_history(_fb: FacebookInit) -> Result<(), Error> {
-        let cache = RemoteCache::new_mock();
+    async fn test_history_timings(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            value_max_size: Some(100),
+            ..Default::default()
+        });
         let path = RepoPath::file("copiedto")?;
-        let info = filenode();
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        let history = FilenodeRange::Filenodes(distinct_filenodes(5));
+        cache.fill_history(&key, history.clone());
+        wait_for_history(&cache, &key).await?;
 
-        let history =
-            FilenodeRange::Filenodes((0..100_000).map(|_| info.clone()).collect::<Vec<_>>());
-        assert!(serialize_history(history.clone()).len() >= MEMCACHE_VALUE_MAX_SIZE);
+        let memcache = match &cache.memcache {
+            MemcacheHandler::Mock(memcache) => memcache,
+            _ => unreachable!(),
+        };
+        let delay = Duration::from_millis(50);
+        for pointer in get_root_chunks(&cache, &key).await?.pointers {
+            let chunk_key = get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, pointer)?;
+            memcache.delay_gets(&chunk_key, delay);
+        }
+
+        let history_read = match cache.get_cached_history(&key, None, &mut Vec::new()).await {
+            CacheRead::Hit(history_read) => history_read,
+            _ => panic!("history should be cached"),
+        };
+        assert_eq!(history_read.range, history);
+        let timings = history_read.timings;
+        assert!(timings.chunk_read >= delay);
+        assert!(timings.chunk_read > timings.root_read + timings.deserialize);
 
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_coalesce_history_reads(_fb: FacebookInit) -> Result<(), Error> {
+        let cache = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            coalesce_history_reads: true,
+            ..Default::default()
+        });
+        let path = RepoPath::file("copiedto")?;
         let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        let history = FilenodeRange::Filenodes(vec![filenode()]);
+        cache.fill_history(&key, history.clone());
+        wait_for_history(&cache, &key).await?;
+
+        // Slow the root read down, so that all the calls start before it completes.
+        let memcache = match &cache.memcache {
+            MemcacheHandler::Mock(memcache) => memcache,
+            _ => unreachable!(),
+        };
+        memcache.delay_gets(
+            &get_mc_key(&cache.keygen, &key.key)?,
+            Duration::from_millis(50),
+        );
+
+        let gets = mock_stats(&cache).gets;
+        let reads = join_all((0..10).map(|_| cache.get_history(&key))).await;
+        assert_eq!(reads, vec![Some(history.clone()); 10]);
+        assert_eq!(mock_stats(&cache).gets, gets + 1);
+        assert!(cache.history_reads.lock().unwrap().is_empty());
+
+        // Once the read is done, later calls read again.
+        assert_eq!(cache.get_history(&key).await, Some(history.clone()));
+        assert_eq!(mock_stats(&cache).gets, gets + 2);
+
+        // Reads of many histories wait for a read of the same history in flight too.
+        let paths = [path];
+        let (read, many) = futures::join!(
+            cache.get_history(&key),
+            cache.get_histories(REPO_ZERO, &paths)
+        );
+        assert_eq!((read, many), (Some(history.clone()), vec![Some(history)]));
+        assert_eq!(mock_stats(&cache).gets, gets + 3);
+        assert!(cache.history_reads.lock().unwrap().is_empty());
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_serve_stale_on_error(_fb: FacebookInit) -> Result<(), Error> {
+        let path = RepoPath::file("copiedto")?;
+        let key = filenode_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+        for stale_fallback_capacity in [0, 10] {
+            let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+                stale_fallback_capacity,
+                ..Default::default()
+            })
+            .with_fill_tracker();
+            cache.fill_filenode(&key, filenode());
+            fills.wait_for_fills().await;
+            assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+
+            let store = match &cache.memcache {
+                MemcacheHandler::Mock(store) => store,
+                _ => unreachable!("not a mock cache"),
+            };
+            store.fail_next_gets(1);
+            if stale_fallback_capacity == 0 {
+                assert_eq!(cache.read_filenode(&key).await, CacheRead::Failed);
+                continue;
+            }
+            assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+
+            // The filenode is read again in the background, and dropped once it's gone.
+            let gets = mock_stats(&cache).gets;
+            fills.wait_for_fills().await;
+            assert_eq!(mock_stats(&cache).gets, gets + 1);
+            store.del(&cache.keygen.key(&key.key));
+            store.fail_next_gets(1);
+            assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+            fills.wait_for_fills().await;
+            store.fail_next_gets(1);
+            assert_eq!(cache.read_filenode(&key).await, CacheRead::Failed);
+        }
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_fill_kept_for_stale_fallback(_fb: FacebookInit) -> Result<(), Error> {
+        let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            stale_fallback_capacity: 10,
+            ..Default::default()
+        })
+        .with_fill_tracker();
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store,
+            _ => unreachable!("not a mock cache"),
+        };
+        let path = RepoPath::file("copiedto")?;
+        let key = filenode_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+
+        // The filenode is served right after it is filled, before the write has run, and although
+        // memcache drops it.
+        store.set_drop_sets(true);
+        cache.fill_filenode(&key, filenode());
+        store.fail_next_gets(1);
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+        fills.wait_for_fills().await;
+        assert_eq!(cache.read_filenode(&key).await, CacheRead::Miss);
+
+        // A newer fill replaces it.
+        let uncopied = FilenodeInfo {
+            copyfrom: None,
+            ..filenode()
+        };
+        cache.fill_filenode(&key, uncopied.clone());
+        store.fail_next_gets(1);
+        assert_eq!(cache.get_filenode(&key).await, Some(uncopied));
+
+        // What is kept is what is written, so it's checked like it would be if read.
+        fills.wait_for_fills().await;
+        store.set_drop_sets(false);
+        cache.set_write_epoch(3);
+        cache.fill_filenode(&key, filenode());
+        fills.wait_for_fills().await;
+        let kept = cache.stale_filenodes.as_ref().unwrap().get(&key.key);
+        assert_eq!(kept, store.get(&cache.keygen.key(&key.key)));
+        store.fail_next_gets(1);
+        assert_eq!(
+            cache
+                .get_filenode_since(REPO_ZERO, &path, ONES_FNID, 4)
+                .await,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_stale_fallback_checks(_fb: FacebookInit) -> Result<(), Error> {
+        const WRITTEN_AT: i64 = 1000;
+        let now = Arc::new(AtomicI64::new(WRITTEN_AT));
+        let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            stale_fallback_capacity: 2,
+            hard_ttl: Some(Duration::from_secs(600)),
+            ..Default::default()
+        })
+        .with_fill_tracker();
+        let cache = cache.with_clock({
+            let now = now.clone();
+            move || now.load(Ordering::Relaxed)
+        });
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store,
+            _ => unreachable!("not a mock cache"),
+        };
+        let path = RepoPath::file("copiedto")?;
+        let pwh = PathWithHash::from_repo_path(&path);
+        let keys = [ONES_FNID, TWOS_FNID, THREES_FNID]
+            .map(|filenode_id| filenode_cache_key(REPO_ZERO, &pwh, &filenode_id));
+        cache.set_write_epoch(5);
+        cache.fill_filenode(&keys[0], filenode());
+        fills.wait_for_fills().await;
+
+        // Filenodes written before the epoch asked for aren't served.
+        store.fail_next_gets(1);
+        assert_eq!(
+            cache
+                .get_filenode_since(REPO_ZERO, &path, ONES_FNID, 6)
+                .await,
+            None
+        );
+        store.fail_next_gets(1);
+        assert_eq!(
+            cache
+                .get_filenode_since(REPO_ZERO, &path, ONES_FNID, 5)
+                .await,
+            Some(filenode())
+        );
+        fills.wait_for_fills().await;
+
+        // Nor are filenodes past the hard TTL.
+        now.store(WRITTEN_AT + 600, Ordering::Relaxed);
+        store.fail_next_gets(1);
+        assert_eq!(cache.read_filenode(&keys[0]).await, CacheRead::Failed);
+        now.store(WRITTEN_AT, Ordering::Relaxed);
+
+        // Reading a filenode keeps it over ones filled since, but not read again.
+        cache.fill_filenode(&keys[1], filenode());
+        fills.wait_for_fills().await;
+        assert_eq!(cache.get_filenode(&keys[0]).await, Some(filenode()));
+        cache.fill_filenode(&keys[2], filenode());
+        fills.wait_for_fills().await;
+        store.fail_next_gets(1);
+        assert_eq!(cache.get_filenode(&keys[0]).await, Some(filenode()));
+        fills.wait_for_fills().await;
+        store.fail_next_gets(1);
+        assert_eq!(cache.read_filenode(&keys[1]).await, CacheRead::Failed);
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_evict_corrupt(_fb: FacebookInit) -> Result<(), Error> {
+        let path = RepoPath::file("copiedto")?;
+        let key = filenode_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+        for evict_corrupt in [false, true] {
+            let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+                evict_corrupt,
+                ..Default::default()
+            })
+            .with_fill_tracker();
+            let store = match &cache.memcache {
+                MemcacheHandler::Mock(store) => store,
+                _ => unreachable!("not a mock cache"),
+            };
+            let mc_key = cache.keygen.key(&key.key);
+            store.set(&mc_key, Bytes::from_static(b"corrupt"));
+
+            assert_eq!(cache.read_filenode(&key).await, CacheRead::Corrupt);
+            fills.wait_for_fills().await;
+            if evict_corrupt {
+                assert_eq!(store.get(&mc_key), None);
+                assert_eq!(cache.read_filenode(&key).await, CacheRead::Miss);
+            } else {
+                assert!(store.get(&mc_key).is_some());
+                assert_eq!(cache.read_filenode(&key).await, CacheRead::Corrupt);
+            }
+        }
+
+        Ok(())
+    }
 
+    #[fbinit::test]
+    async fn test_compress_min_bytes(_fb: FacebookInit) -> Result<(), Error> {
+        let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        let history = FilenodeRange::Filenodes(distinct_filenodes(100));
+        let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            chunk_compression_level: Some(3),
+            compress_min_bytes: chunk_data_size(1024),
+            ..Default::default()
+        })
+        .with_fill_tracker();
         cache.fill_history(&key, history.clone());
-        let from_cache = wait_for_history(&cache, &key).await?;
+        fills.wait_for_fills().await;
 
-        assert_eq!(from_cache, history);
+        // All the chunks are full, and compressed, except for the last one.
+        let chunks = get_root_chunks(&cache, &key).await?;
+        let mut compressed = Vec::new();
+        for pointer in &chunks.pointers {
+            let chunk_key = get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, *pointer)?;
+            let chunk = cache.memcache.get(chunk_key).await?.unwrap();
+            let chunk: thrift::FilenodeInfoChunk = compact_protocol::deserialize(&chunk)?;
+            compressed.push(chunk.compressed);
+        }
+        let mut expected = vec![Some(true); chunks.pointers.len()];
+        *expected.last_mut().unwrap() = Some(false);
+        assert!(expected.len() > 1);
+        assert_eq!(compressed, expected);
+        assert_eq!(cache.get_history(&key).await, Some(history));
+
+        assert_eq!(
+            compress_chunk(vec![0; 100], Some(3), 101)?,
+            (vec![0; 100], Some(false))
+        );
 
         Ok(())
     }
 
+    /// Collects the messages logged, along with their key-values.
+    struct LogCollector(Arc<Mutex<Vec<String>>>);
+
+    impl slog::Drain for LogCollector {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            let mut line = record.msg().to_string();
+            let _ = slog::KV::serialize(&record.kv(), record, &mut KvCollector(&mut line));
+            self.0.lock().unwrap().push(line);
+            Ok(())
+        }
+    }
+
+    struct KvCollector<'a>(&'a mut String);
+
+    impl slog::Serializer for KvCollector<'_> {
+        fn emit_arguments(&mut self, key: slog::Key, val: &std::fmt::Arguments) -> slog::Result {
+            self.0.push_str(&format!(" {}={}", key, val));
+            Ok(())
+        }
+    }
+
     #[fbinit::test]
-    async fn test_store_too_long_history(_fb: FacebookInit) -> Result<(), Error> {
-        let cache = RemoteCache::new_mock();
+    async fn test_history_error_log(_fb: FacebookInit) -> Result<(), Error> {
+        let logs = Arc::new(Mutex::new(Vec::new()));
+        let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            chunk_size: Some(1024),
+            history_error_log_sample_rate: Some(1),
+            ..Default::default()
+        })
+        .with_logger(Logger::root(LogCollector(logs.clone()), o!()))
+        .with_fill_tracker();
         let path = RepoPath::file("copiedto")?;
+        let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
+        cache.fill_history(&key, FilenodeRange::Filenodes(distinct_filenodes(100)));
+        fills.wait_for_fills().await;
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store,
+            _ => unreachable!("not a mock cache"),
+        };
+        let root_key = get_mc_key(&cache.keygen, &key.key)?;
+
+        store.fail_next_gets(1);
+        assert_eq!(cache.read_history(&key).await, CacheRead::Failed);
+        let logged = logs
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("root read failure is logged");
+        assert!(logged.contains(&format!("root_key={}", root_key)));
+
+        let pointer = get_root_chunks(&cache, &key).await?.pointers[1];
+        let chunk_key = get_mc_key_for_filenodes_list_chunk(&cache.keygen, &key, pointer)?;
+        store.del(&chunk_key);
+        assert_eq!(cache.read_history(&key).await, CacheRead::Miss);
+        let logged = logs
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("chunk read failure is logged");
+        assert!(logged.contains(&format!("root_key={}", root_key)));
+        assert!(logged.contains(&format!("chunk_key={}", chunk_key)));
+
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_fill_bytes_budget(_fb: FacebookInit) -> Result<(), Error> {
+        let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            fill_bytes_budget: Some(1),
+            ..Default::default()
+        })
+        .with_fill_tracker();
+        let history = FilenodeRange::Filenodes(distinct_filenodes(100));
+        let keys = ["a", "b", "c"]
+            .into_iter()
+            .map(|path| {
+                let path = RepoPath::file(path)?;
+                Ok(history_cache_key(
+                    REPO_ZERO,
+                    &PathWithHash::from_repo_path(&path),
+                    None,
+                ))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        // A history fill counts the size it serializes to against the budget. The first one
+        // saturates it, so the ones started before it completes are dropped.
+        cache.fill_history(&keys[0], history.clone());
+        assert_eq!(
+            cache.fill_bytes_in_flight.load(Ordering::Relaxed),
+            serialize_history(history.clone(), &cache.options)
+                .serialized
+                .len()
+        );
+        for key in &keys[1..] {
+            cache.fill_history(key, history.clone());
+        }
+        fills.wait_for_fills().await;
+        assert_eq!(cache.get_history(&keys[0]).await, Some(history.clone()));
+        assert_eq!(cache.get_history(&keys[1]).await, None);
+        assert_eq!(cache.get_history(&keys[2]).await, None);
+        assert_eq!(cache.fill_bytes_in_flight.load(Ordering::Relaxed), 0);
+
+        // Once it completes, fills run again.
+        cache.fill_history(&keys[1], history.clone());
+        fills.wait_for_fills().await;
+        assert_eq!(cache.get_history(&keys[1]).await, Some(history));
+
+        Ok(())
+    }
 
+    #[fbinit::test]
+    async fn test_intern_history_paths(_fb: FacebookInit) -> Result<(), Error> {
+        let copied = |i: usize, from: &str| -> Result<FilenodeInfo, Error> {
+            let copyfrom = Some((RepoPath::file(from)?, TWOS_FNID));
+            Ok(FilenodeInfo {
+                copyfrom,
+                ..distinct_filenodes(i + 1).pop().unwrap()
+            })
+        };
+        let mut filenodes = Vec::new();
+        for i in 0..50 {
+            filenodes.push(copied(i, "some/long/directory/before/the/move/file")?);
+            filenodes.push(FilenodeInfo {
+                copyfrom: None,
+                ..distinct_filenodes(100 + i).pop().unwrap()
+            });
+        }
+        filenodes.push(copied(200, "other")?);
+        let history = FilenodeRange::Filenodes(filenodes);
+
+        let plain = compact_protocol::serialize(&history_into_thrift(history.clone()));
+        let interned = compact_protocol::serialize(&history_into_thrift_interned(history.clone()));
+        assert_eq!(
+            deserialize_history_blob(&interned, false),
+            Some(history.clone())
+        );
+        assert!(interned.len() < plain.len() * 3 / 4);
+
+        // Interned histories can be chunked, and are read like others.
+        let path = RepoPath::file("copiedto")?;
         let key = history_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), None);
-        cache.fill_history(&key, FilenodeRange::TooBig);
-        let from_cache = wait_for_history(&cache, &key).await?;
+        for chunk_size in [None, Some(1024)] {
+            let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+                chunk_size,
+                intern_history_paths: true,
+                ..Default::default()
+            })
+            .with_fill_tracker();
+            cache.fill_history(&key, history.clone());
+            fills.wait_for_fills().await;
+            assert_eq!(cache.get_history(&key).await, Some(history.clone()));
+            let format = match chunk_size {
+                None => ValueFormat::CompactInterned,
+                Some(_) => ValueFormat::CompactChunked,
+            };
+            assert_eq!(cache.format_reads(), HashMap::from([(format, 1)]));
+            let contains = |linknode| cache.history_contains_linknode(REPO_ZERO, &path, linknode);
+            assert_eq!(contains(ONES_CSID).await, Some(true));
+            assert_eq!(contains(TWOS_CSID).await, Some(false));
+        }
 
-        assert_eq!(from_cache, FilenodeRange::TooBig);
+        Ok(())
+    }
+
+    #[fbinit::test]
+    async fn test_circuit_breaker(_fb: FacebookInit) -> Result<(), Error> {
+        tokio::time::pause();
+        let (cache, fills) = RemoteCache::new_mock_with_options(RemoteCacheOptions {
+            circuit_breaker: Some(CircuitBreakerOptions {
+                errors: 3,
+                window: Duration::from_secs(60),
+                cooldown: Duration::from_millis(100),
+            }),
+            ..Default::default()
+        })
+        .with_fill_tracker();
+        let path = RepoPath::file("copiedto")?;
+        let key = filenode_cache_key(REPO_ZERO, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+        cache.fill_filenode(&key, filenode());
+        fills.wait_for_fills().await;
+
+        let store = match &cache.memcache {
+            MemcacheHandler::Mock(store) => store,
+            _ => unreachable!("not a mock cache"),
+        };
+
+        // A success in between resets the count of failures.
+        store.fail_next_gets(2);
+        assert_eq!(cache.get_filenode(&key).await, None);
+        assert_eq!(cache.get_filenode(&key).await, None);
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+        store.fail_next_gets(2);
+        assert_eq!(cache.get_filenode(&key).await, None);
+        assert_eq!(cache.get_filenode(&key).await, None);
+        assert!(!cache.memcache().is_noop());
+
+        // The third failure in a row trips the breaker.
+        store.fail_next_gets(1);
+        assert_eq!(cache.get_filenode(&key).await, None);
+        assert!(cache.memcache().is_noop());
+
+        // During the cooldown, memcache is neither read nor written.
+        let before = mock_stats(&cache);
+        assert_eq!(cache.get_filenode(&key).await, None);
+        let other = filenode_cache_key(REPO_ONE, &PathWithHash::from_repo_path(&path), &ONES_FNID);
+        cache.fill_filenode(&other, filenode());
+        fills.wait_for_fills().await;
+        let after = mock_stats(&cache);
+        assert_eq!(after.gets, before.gets);
+        assert_eq!(after.sets, before.sets);
+
+        // Once the cooldown is over, a single probe is let through, and a failed one starts
+        // another cooldown.
+        tokio::time::advance(Duration::from_millis(150)).await;
+        store.fail_next_gets(1);
+        assert_eq!(cache.get_filenode(&key).await, None);
+        assert!(cache.memcache().is_noop());
+
+        // If the probe doesn't report, e.g. because it was a write, the other operations are still
+        // skipped until another probe is let through.
+        tokio::time::advance(Duration::from_millis(150)).await;
+        assert!(!cache.memcache().is_noop());
+        assert!(cache.memcache().is_noop());
+        tokio::time::advance(Duration::from_millis(50)).await;
+        assert!(cache.memcache().is_noop());
+
+        // And a successful one closes the breaker.
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
+        assert!(!cache.memcache().is_noop());
+        assert_eq!(cache.get_filenode(&key).await, Some(filenode()));
 
         Ok(())
     }
+
+    #[test]
+    fn test_pointer_collisions() {
+        let mut pointers = PointersIter::new();
+        let drawn = pointers
+            .by_ref()
+            .take(DEFAULT_MAX_HISTORY_POINTERS)
+            .collect::<HashSet<_>>();
+        assert_eq!(drawn.len(), DEFAULT_MAX_HISTORY_POINTERS);
+        assert_eq!(pointers.collisions(), 0);
+
+        // Each value is drawn twice in a row, so every other draw collides.
+        let mut pointers = PointersIter::with_random(|| {
+            static DRAWS: AtomicI64 = AtomicI64::new(0);
+            DRAWS.fetch_add(1, Ordering::Relaxed) / 2
+        });
+        assert_eq!(pointers.by_ref().take(3).collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(pointers.collisions(), 2);
+    }
 }
@@ -14,6 +14,7 @@ use fbinit::FacebookInit;
 use filenodes::FilenodeInfo;
 use filenodes::FilenodeRange;
 use filenodes::PreparedFilenode;
+use mercurial_types::HgFileNodeId;
 use mercurial_types_mocks::nodehash::ONES_CSID;
 use mercurial_types_mocks::nodehash::ONES_FNID;
 use mercurial_types_mocks::nodehash::TWOS_CSID;
@@ -26,6 +27,7 @@ use vec1::vec1;
 use super::util::build_reader_writer;
 use super::util::build_shard;
 use crate::local_cache::LocalCache;
+use crate::local_cache::MAX_PINNED_FILENODES;
 use crate::reader::filenode_cache_key;
 use crate::reader::history_cache_key;
 use crate::remote_cache::test::wait_for_filenode;
@@ -209,3 +211,95 @@ async fn test_too_big_caching(fb: FacebookInit) -> Result<(), Error> {
 
     Ok(())
 }
+
+#[fbinit::test]
+async fn test_pinned_filenode(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let (mut reader, writer) = build_reader_writer(vec1![build_shard()?]);
+
+    reader.local_cache = LocalCache::new_mock();
+    reader.remote_cache = RemoteCache::new_mock();
+    let reader = Arc::new(reader);
+
+    let path = RepoPath::file("file")?;
+    let info = filenode();
+    let second_info = second_filenode();
+
+    writer
+        .insert_filenodes(
+            &ctx,
+            REPO_ZERO,
+            vec![
+                PreparedFilenode {
+                    path: path.clone(),
+                    info: info.clone(),
+                },
+                PreparedFilenode {
+                    path: path.clone(),
+                    info: second_info.clone(),
+                },
+            ],
+            false,
+        )
+        .await?
+        .do_not_handle_disabled_filenodes()?;
+
+    reader
+        .clone()
+        .pin_filenode(&ctx, REPO_ZERO, &path, info.filenode)
+        .await?
+        .do_not_handle_disabled_filenodes()?;
+    reader
+        .clone()
+        .get_filenode(&ctx, REPO_ZERO, &path, second_info.filenode)
+        .await?
+        .do_not_handle_disabled_filenodes()?;
+
+    let pwh = PathWithHash::from_repo_path(&path);
+    let key = filenode_cache_key(REPO_ZERO, &pwh, &info.filenode);
+    let second_key = filenode_cache_key(REPO_ZERO, &pwh, &second_info.filenode);
+
+    // Under eviction pressure, only the pinned filenode is still served.
+    reader.local_cache.evict_filenode(&key);
+    reader.local_cache.evict_filenode(&second_key);
+    assert_eq!(reader.local_cache.get_filenode(&key), Some(info));
+    assert!(reader.local_cache.get_filenode(&second_key).is_none());
+
+    // Once unpinned, it can be evicted like the others.
+    assert!(reader.unpin_filenode(REPO_ZERO, &path, ONES_FNID));
+    assert!(!reader.unpin_filenode(REPO_ZERO, &path, ONES_FNID));
+    reader.local_cache.evict_filenode(&key);
+    assert!(reader.local_cache.get_filenode(&key).is_none());
+
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_pin_filenode_limits(_fb: FacebookInit) -> Result<(), Error> {
+    let path = RepoPath::file("file")?;
+    let pwh = PathWithHash::from_repo_path(&path);
+    let key = filenode_cache_key(REPO_ZERO, &pwh, &ONES_FNID);
+
+    // Without a local cache, nothing is pinned.
+    let noop = LocalCache::new_noop();
+    assert!(!noop.pin_filenode(&key, &filenode()));
+    assert!(noop.get_filenode(&key).is_none());
+
+    // Past the limit, pins are refused, but pinned filenodes can still be pinned again.
+    let cache = LocalCache::new_mock();
+    let filenode_id = |i: usize| {
+        let mut hash = [0; 20];
+        hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        HgFileNodeId::from_bytes(&hash)
+    };
+    for i in 0..MAX_PINNED_FILENODES {
+        let key = filenode_cache_key(REPO_ZERO, &pwh, &filenode_id(i)?);
+        assert!(cache.pin_filenode(&key, &filenode()));
+    }
+    let refused = filenode_cache_key(REPO_ZERO, &pwh, &filenode_id(MAX_PINNED_FILENODES)?);
+    assert!(!cache.pin_filenode(&refused, &filenode()));
+    let pinned = filenode_cache_key(REPO_ZERO, &pwh, &filenode_id(0)?);
+    assert!(cache.pin_filenode(&pinned, &filenode()));
+
+    Ok(())
+}